@@ -0,0 +1,96 @@
+//! Combine every collected `.data` file into one CSV, with the experiment parameters from each
+//! file's name as extra columns.
+
+use crate::sweep::{ExpParams, PARAM_COLUMNS};
+use color_eyre::eyre::{eyre, Report, WrapErr};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+pub fn find_data_files(dir: &Path, found: &mut Vec<PathBuf>) -> Result<(), Report> {
+    for e in std::fs::read_dir(dir).wrap_err_with(|| format!("read dir {}", dir.display()))? {
+        let p = e?.path();
+        if p.is_dir() {
+            find_data_files(&p, found)?;
+        } else if p.extension().map(|e| e == "data").unwrap_or(false) {
+            found.push(p);
+        }
+    }
+
+    Ok(())
+}
+
+/// Combine all `.data` files under `dir` into `out`.
+///
+/// Each row gets the parameter columns, plus `dir` (the file's directory relative to `dir`, e.g. the
+/// repetition). Data columns that clash with those are dropped in favor of the file name's values.
+pub fn aggregate(dir: &Path, out: &Path) -> Result<(), Report> {
+    let mut files = vec![];
+    find_data_files(dir, &mut files)?;
+    files.sort();
+
+    let mut header: Option<Vec<String>> = None;
+    let mut rows = String::new();
+    let mut used = 0;
+    for f in &files {
+        let fname = f.file_name().unwrap().to_str().unwrap();
+        let params = match ExpParams::from_filename(fname) {
+            Ok(p) => p,
+            Err(err) => {
+                warn!(?err, "skipping");
+                continue;
+            }
+        };
+        let contents =
+            std::fs::read_to_string(f).wrap_err_with(|| format!("read {}", f.display()))?;
+        let mut lines = contents.lines();
+        let cols: Vec<String> = match lines.next() {
+            Some(h) => h.split_whitespace().map(str::to_owned).collect(),
+            None => {
+                warn!(file = ?f, "empty data file");
+                continue;
+            }
+        };
+        match header {
+            None => header = Some(cols.clone()),
+            Some(ref h) if *h != cols => {
+                warn!(file = ?f, expected = ?h, got = ?cols, "mismatched columns, skipping");
+                continue;
+            }
+            _ => (),
+        }
+
+        let rel = f
+            .parent()
+            .unwrap()
+            .strip_prefix(dir)
+            .unwrap_or_else(|_| Path::new(""));
+        let meta = params.columns().join(",");
+        for l in lines.filter(|l| !l.trim().is_empty()) {
+            let data: Vec<&str> = l
+                .split_whitespace()
+                .zip(&cols)
+                .filter(|(_, c)| !PARAM_COLUMNS.contains(&c.as_str()))
+                .map(|(v, _)| v)
+                .collect();
+            rows.push_str(&format!("{},{},{}\n", meta, rel.display(), data.join(",")));
+        }
+
+        used += 1;
+    }
+
+    let header = header.ok_or_else(|| eyre!("no result files under {}", dir.display()))?;
+    let data_cols: Vec<&str> = header
+        .iter()
+        .map(String::as_str)
+        .filter(|c| !PARAM_COLUMNS.contains(c))
+        .collect();
+    let contents = format!(
+        "{},dir,{}\n{}",
+        PARAM_COLUMNS.join(","),
+        data_cols.join(","),
+        rows
+    );
+    std::fs::write(out, contents).wrap_err_with(|| format!("write {}", out.display()))?;
+    info!(?out, files = ?used, "wrote aggregated results");
+    Ok(())
+}
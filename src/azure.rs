@@ -0,0 +1,255 @@
+//! Launching Azure VMs ourselves, for nodes that choose their resource group. tsunami makes a new
+//! randomly-named `tsunami_resourcegroup_*` group for every launch, which runs into
+//! subscriptions' resource-group quotas and naming policies.
+//!
+//! With `reuse`, the VM goes in the existing group `name`, and at teardown it is deleted from it,
+//! along with the network resources made for it; the group itself is left alone. Otherwise each
+//! launch makes a group named `<name>-<machine>-<unix time>`, and deletes it at teardown.
+
+use crate::ratelimit;
+use crate::tags::{with_marker, Cloud, Tags};
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use tokio::process::Command;
+use tracing::{debug, info, instrument, warn};
+
+/// Ubuntu 20.04, like the other providers.
+pub const IMAGE: &str = "Canonical:0001-com-ubuntu-server-focal:20_04-lts:latest";
+
+/// Set on VMs launched into a group we didn't make, so teardown deletes just them.
+pub const SHARED_TAG: &str = "burrito-cloud-exp-shared-group";
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ResourceGroup {
+    /// The group to use, or with `reuse: false`, the prefix of the ones to make.
+    pub name: String,
+    #[serde(default)]
+    pub reuse: bool,
+}
+
+impl ResourceGroup {
+    pub fn check(&self) -> Result<(), Report> {
+        // azure allows up to 90; made groups' names get the machine name and a time appended.
+        let max = if self.reuse { 90 } else { 50 };
+        ensure!(
+            !self.name.is_empty()
+                && self.name.len() <= max
+                && !self.name.ends_with('.')
+                && self
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.()".contains(c)),
+            "resource group {:?} must be at most {} letters, digits, and -_.(), not ending in .",
+            self.name,
+            max
+        );
+        Ok(())
+    }
+}
+
+pub async fn az(args: &[&str]) -> Result<String, Report> {
+    ratelimit::acquire(&Cloud::Azure).await;
+    let out = Command::new("az")
+        .args(args)
+        .output()
+        .await
+        .wrap_err_with(|| format!("az {}", args[..2.min(args.len())].join(" ")))?;
+    ensure!(
+        out.status.success(),
+        "az {} failed: {}",
+        args[..2.min(args.len())].join(" "),
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+}
+
+fn tag_args(tags: &Tags) -> Vec<String> {
+    with_marker(tags)
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect()
+}
+
+/// Launch a VM named `name` into `rg`, authorizing `pubkey` for `ubuntu`, and return its public
+/// IP.
+#[instrument(skip(pubkey, tags), level = "debug")]
+pub async fn launch(
+    rg: &ResourceGroup,
+    region: &str,
+    instance_type: &str,
+    name: &str,
+    pubkey: &str,
+    tags: &Tags,
+) -> Result<String, Report> {
+    let mut vm_tags = tag_args(tags);
+    let group = if rg.reuse {
+        let exists = az(&["group", "exists", "--name", &rg.name]).await?;
+        ensure!(
+            exists == "true",
+            "resource group {:?} does not exist",
+            rg.name
+        );
+        vm_tags.push(format!("{}=true", SHARED_TAG));
+        rg.name.clone()
+    } else {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let group = format!("{}-{}-{}", rg.name, name, now.as_secs());
+        let mut args = vec!["group", "create", "--name", &group, "--location", region];
+        let t = tag_args(tags);
+        args.push("--tags");
+        args.extend(t.iter().map(String::as_str));
+        az(&args).await?;
+        info!(?group, "created resource group");
+        group
+    };
+
+    let created = async {
+        let (ip, nsg, vnet) = (
+            format!("{}-ip", name),
+            format!("{}-nsg", name),
+            format!("{}-vnet", name),
+        );
+        let mut args = vec![
+            "vm",
+            "create",
+            "--resource-group",
+            &group,
+            "--name",
+            name,
+            "--image",
+            IMAGE,
+            "--size",
+            instance_type,
+            "--admin-username",
+            "ubuntu",
+            "--ssh-key-values",
+            pubkey,
+            // named, so that teardown can find them in a shared group.
+            "--public-ip-address",
+            &ip,
+            "--nsg",
+            &nsg,
+            "--vnet-name",
+            &vnet,
+            "--nic-delete-option",
+            "Delete",
+            "--os-disk-delete-option",
+            "Delete",
+            "--query",
+            "publicIpAddress",
+            "-o",
+            "tsv",
+            "--tags",
+        ];
+        args.extend(vm_tags.iter().map(String::as_str));
+        let public_ip = az(&args).await?;
+        ensure!(!public_ip.is_empty(), "vm {} got no public ip", name);
+        // like tsunami, leave the experiment's ports to the experiment.
+        az(&[
+            "vm",
+            "open-port",
+            "--port",
+            "0-65535",
+            "--resource-group",
+            &group,
+            "--name",
+            name,
+        ])
+        .await?;
+        Ok::<_, Report>(public_ip)
+    }
+    .await;
+    match created {
+        Ok(ip) => {
+            info!(?group, ?ip, "launched vm");
+            Ok(ip)
+        }
+        Err(err) => {
+            let cleanup = if rg.reuse {
+                delete_vm(&group, name).await
+            } else {
+                delete_group(&group).await
+            };
+            if let Err(e) = cleanup {
+                warn!(err = ?e, ?group, "could not clean up after failed launch");
+            }
+
+            Err(err)
+        }
+    }
+}
+
+/// Whether the VM `name` in `rg` was launched into a group we didn't make.
+pub async fn is_shared(rg: &str, name: &str) -> Result<bool, Report> {
+    let query = format!("tags.\"{}\"", SHARED_TAG);
+    let tag = az(&[
+        "vm",
+        "show",
+        "--resource-group",
+        rg,
+        "--name",
+        name,
+        "--query",
+        &query,
+        "-o",
+        "tsv",
+    ])
+    .await?;
+    Ok(tag == "true")
+}
+
+/// Whether the VM `name` in `rg` is gone.
+pub async fn vm_gone(rg: &str, name: &str) -> Result<bool, Report> {
+    let names = az(&[
+        "vm",
+        "list",
+        "--resource-group",
+        rg,
+        "--query",
+        "[].name",
+        "-o",
+        "tsv",
+    ])
+    .await?;
+    Ok(!names.lines().any(|n| n == name))
+}
+
+/// Delete the VM `name` from `rg`, and the network resources made with it (its NIC and disk go
+/// with the VM).
+pub async fn delete_vm(rg: &str, name: &str) -> Result<(), Report> {
+    az(&[
+        "vm",
+        "delete",
+        "--yes",
+        "--resource-group",
+        rg,
+        "--name",
+        name,
+    ])
+    .await
+    .wrap_err_with(|| eyre!("delete vm {}", name))?;
+    for (kind, suffix) in [("public-ip", "ip"), ("nsg", "nsg"), ("vnet", "vnet")] {
+        let res = format!("{}-{}", name, suffix);
+        if let Err(err) = az(&[
+            "network",
+            kind,
+            "delete",
+            "--resource-group",
+            rg,
+            "--name",
+            &res,
+        ])
+        .await
+        {
+            warn!(?err, resource = ?res, "could not delete vm's network resource");
+        }
+    }
+
+    info!(?rg, ?name, "deleted vm");
+    Ok(())
+}
+
+async fn delete_group(rg: &str) -> Result<(), Report> {
+    az(&["group", "delete", "--yes", "--no-wait", "--name", rg]).await?;
+    debug!(?rg, "deleting resource group");
+    Ok(())
+}
@@ -0,0 +1,198 @@
+//! A cap on the cloud instance-hours a run (or a whole `--queue` of them) may use.
+//!
+//! Machines we launch are metered from launch to teardown. A launch doesn't start once the hours
+//! used, plus what it is expected to take (the average per machine of the launches so far), would
+//! go over the budget; the node fails saying so. With the `abandon` policy, nodes that are
+//! already running also stop after their current repetition once the budget is used up, rather
+//! than finish. Only cloud machines are metered: not hosts we don't launch, local VMs, pods, or
+//! pool machines.
+
+use color_eyre::eyre::{bail, ensure, Report};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// What running nodes do once the budget is used up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Finish their repetitions; only new launches are refused.
+    #[default]
+    Finish,
+    /// Stop after the current repetition.
+    Abandon,
+}
+
+impl std::str::FromStr for Policy {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "finish" => Policy::Finish,
+            "abandon" => Policy::Abandon,
+            _ => bail!("unknown budget policy {:?}, expected finish or abandon", s),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Spend {
+    /// Instance-hours of launches that are over.
+    hours: f64,
+    /// Machine-launches that are over, for the estimate.
+    launches: usize,
+    /// Running launches, by id: when they started, and how many machines.
+    live: BTreeMap<u64, (Instant, usize)>,
+    next: u64,
+}
+
+impl Spend {
+    fn used(&self) -> f64 {
+        self.hours
+            + self
+                .live
+                .values()
+                .map(|(t, n)| t.elapsed().as_secs_f64() / 3600. * *n as f64)
+                .sum::<f64>()
+    }
+}
+
+/// Shared by every node it applies to. Without a limit, it only keeps count.
+#[derive(Clone, Debug, Default)]
+pub struct Budget {
+    hours: Option<f64>,
+    policy: Policy,
+    spend: Arc<Mutex<Spend>>,
+}
+
+/// A launch being metered, until this is dropped.
+#[derive(Debug)]
+pub struct Meter {
+    id: u64,
+    spend: Arc<Mutex<Spend>>,
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        let mut s = self.spend.lock().unwrap();
+        if let Some((t, n)) = s.live.remove(&self.id) {
+            s.hours += t.elapsed().as_secs_f64() / 3600. * n as f64;
+            s.launches += n;
+        }
+    }
+}
+
+impl Budget {
+    pub fn new(hours: Option<f64>, policy: Policy) -> Result<Self, Report> {
+        ensure!(
+            hours.is_none_or(|h| h > 0.),
+            "the budget must be more than 0 instance-hours"
+        );
+        Ok(Self {
+            hours,
+            policy,
+            spend: Default::default(),
+        })
+    }
+
+    /// Instance-hours used so far.
+    pub fn used(&self) -> f64 {
+        self.spend.lock().unwrap().used()
+    }
+
+    /// Start metering a launch of `machines` machines, or refuse it if it would go over budget.
+    pub fn launch(&self, machines: usize) -> Result<Meter, Report> {
+        let mut s = self.spend.lock().unwrap();
+        let used = s.used();
+        if let Some(limit) = self.hours {
+            let estimate = match s.launches {
+                0 => 0.,
+                l => s.hours / l as f64 * machines as f64,
+            };
+            ensure!(
+                used + estimate < limit,
+                "budget of {} instance-hours reached: {:.2} used, and this launch is expected to take {:.2}",
+                limit,
+                used,
+                estimate
+            );
+        }
+
+        let id = s.next;
+        s.next += 1;
+        s.live.insert(id, (Instant::now(), machines));
+        info!(?machines, used = %format!("{:.2}", used), limit = ?self.hours, "metering launch");
+        Ok(Meter {
+            id,
+            spend: self.spend.clone(),
+        })
+    }
+
+    /// Whether running nodes should stop after their current repetition.
+    pub fn abandoning(&self) -> bool {
+        let Some(limit) = self.hours else {
+            return false;
+        };
+        let used = self.used();
+        let stop = self.policy == Policy::Abandon && used >= limit;
+        if stop {
+            warn!(used = %format!("{:.2}", used), ?limit, "budget used up, abandoning the node");
+        }
+
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_only_counts() {
+        let b = Budget::default();
+        let meters: Vec<_> = (0..3).map(|_| b.launch(10).unwrap()).collect();
+        assert_eq!(b.spend.lock().unwrap().live.len(), 3);
+        drop(meters);
+        let s = b.spend.lock().unwrap();
+        assert!(s.live.is_empty());
+        assert_eq!(s.launches, 30);
+        assert!(!b.abandoning());
+    }
+
+    #[test]
+    fn launch_refused_once_estimate_goes_over() {
+        let b = Budget::new(Some(10.), Policy::Finish).unwrap();
+        // nothing to estimate from yet.
+        drop(b.launch(100).unwrap());
+        {
+            let mut s = b.spend.lock().unwrap();
+            s.hours = 4.;
+            s.launches = 4;
+        }
+        // 1 instance-hour per machine so far.
+        let err = b.launch(6).unwrap_err();
+        assert!(
+            format!("{}", err).contains("budget of 10 instance-hours reached"),
+            "{}",
+            err
+        );
+        let _meter = b.launch(5).unwrap();
+        assert!(!b.abandoning());
+    }
+
+    #[test]
+    fn abandon_once_used_up() {
+        let b = Budget::new(Some(1.), Policy::Abandon).unwrap();
+        assert!(!b.abandoning());
+        b.spend.lock().unwrap().hours = 1.;
+        assert!(b.abandoning());
+        assert!(b.launch(1).is_err());
+    }
+
+    #[test]
+    fn bad_budgets() {
+        assert!(Budget::new(Some(0.), Policy::Finish).is_err());
+        assert!("finish".parse::<Policy>().is_ok());
+        assert!("stop".parse::<Policy>().is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! Checking nodes' regions and instance types against what their provider offers, before anything
+//! is launched: a typo otherwise only fails deep inside a launch, with an opaque error.
+//!
+//! Catalogs we can't get (no credentials, no CLI) are skipped with a warning; only a name the
+//! provider certainly doesn't have fails the check.
+
+use crate::azure;
+use crate::node::Node;
+use crate::ratelimit;
+use crate::tags::{ec2_client, Cloud};
+use color_eyre::eyre::{bail, ensure, Report, WrapErr};
+use rusoto_ec2::Ec2;
+use std::collections::BTreeMap;
+use tracing::{debug, info, warn};
+
+/// Where AWS regions are listed from: every account has it.
+const AWS_HOME_REGION: &str = "us-east-1";
+
+/// Fail if any of `nodes` asks for a region or instance type its provider doesn't offer.
+pub async fn check(nodes: &[Node]) -> Result<(), Report> {
+    // (provider, credentials) -> regions, and (provider, credentials, region) -> instance types.
+    let mut regions: BTreeMap<String, Option<Vec<String>>> = BTreeMap::new();
+    let mut types: BTreeMap<String, Option<Vec<String>>> = BTreeMap::new();
+    for n in nodes {
+        let (cloud, region, instance_types) = match n.placement() {
+            Some(p) => p,
+            None => continue,
+        };
+        let account = format!("{:?}", without_region(&cloud));
+        if !regions.contains_key(&account) {
+            let got = match list_regions(&cloud).await {
+                Ok(r) => Some(r),
+                Err(err) => {
+                    warn!(?cloud, err = %format!("{:#}", err), "could not list regions, not checking them");
+                    None
+                }
+            };
+            regions.insert(account.clone(), got);
+        }
+
+        if let Some(Some(ref rs)) = regions.get(&account) {
+            if !rs.contains(&region) {
+                bail!(
+                    "{} has no region {:?}{}",
+                    n.provider_name(),
+                    region,
+                    suggest(&region, rs)
+                );
+            }
+        }
+
+        let key = format!("{} {}", account, region);
+        if !types.contains_key(&key) {
+            let got = match list_instance_types(&cloud, &region).await {
+                Ok(t) => Some(t),
+                Err(err) => {
+                    warn!(?cloud, ?region, err = %format!("{:#}", err), "could not list instance types, not checking them");
+                    None
+                }
+            };
+            types.insert(key.clone(), got);
+        }
+
+        if let Some(Some(ref ts)) = types.get(&key) {
+            for t in &instance_types {
+                ensure!(
+                    ts.contains(t),
+                    "{} doesn't offer {:?} in {}{}",
+                    n.provider_name(),
+                    t,
+                    region,
+                    suggest(t, ts)
+                );
+            }
+        }
+    }
+
+    info!(nodes = nodes.len(), "regions and instance types check out");
+    Ok(())
+}
+
+/// `cloud`, minus the region: regions are listed once per account.
+fn without_region(cloud: &Cloud) -> Cloud {
+    match cloud {
+        Cloud::Aws { profile, .. } => Cloud::Aws {
+            region: String::new(),
+            profile: profile.clone(),
+        },
+        c => c.clone(),
+    }
+}
+
+async fn list_regions(cloud: &Cloud) -> Result<Vec<String>, Report> {
+    match cloud {
+        Cloud::Aws { profile, .. } => {
+            let client = ec2_client(AWS_HOME_REGION, profile.as_deref())?;
+            ratelimit::acquire(cloud).await;
+            let resp = client
+                .describe_regions(Default::default())
+                .await
+                .wrap_err("describe regions")?;
+            Ok(resp
+                .regions
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|r| r.region_name)
+                .collect())
+        }
+        Cloud::Azure => az_names(&["account", "list-locations", "--query", "[].name"]).await,
+        Cloud::Linode => ids(&crate::vps::api(cloud, "GET", "/regions", None).await?["data"]),
+        Cloud::Vultr => ids(&crate::vps::api(cloud, "GET", "/regions", None).await?["regions"]),
+        _ => bail!("no catalog for {:?}", cloud),
+    }
+}
+
+async fn list_instance_types(cloud: &Cloud, region: &str) -> Result<Vec<String>, Report> {
+    match cloud {
+        Cloud::Aws { profile, .. } => {
+            let client = ec2_client(region, profile.as_deref())?;
+            let mut types = vec![];
+            let mut next_token = None;
+            loop {
+                ratelimit::acquire(cloud).await;
+                let resp = client
+                    .describe_instance_type_offerings(
+                        rusoto_ec2::DescribeInstanceTypeOfferingsRequest {
+                            location_type: Some("region".to_owned()),
+                            next_token,
+                            ..Default::default()
+                        },
+                    )
+                    .await
+                    .wrap_err("describe instance type offerings")?;
+                types.extend(
+                    resp.instance_type_offerings
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|o| o.instance_type),
+                );
+                next_token = resp.next_token;
+                if next_token.is_none() {
+                    break;
+                }
+            }
+
+            Ok(types)
+        }
+        Cloud::Azure => {
+            az_names(&[
+                "vm",
+                "list-skus",
+                "--location",
+                region,
+                "--resource-type",
+                "virtualMachines",
+                "--query",
+                "[].name",
+            ])
+            .await
+        }
+        Cloud::Linode => ids(&crate::vps::api(cloud, "GET", "/linode/types", None).await?["data"]),
+        Cloud::Vultr => {
+            let plans = crate::vps::api(cloud, "GET", "/plans?per_page=500", None).await?;
+            Ok(plans["plans"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|p| {
+                    p["locations"]
+                        .as_array()
+                        .is_some_and(|ls| ls.iter().any(|l| l == region))
+                })
+                .filter_map(|p| p["id"].as_str().map(str::to_owned))
+                .collect())
+        }
+        _ => bail!("no catalog for {:?}", cloud),
+    }
+}
+
+/// The `id`s of an array of objects.
+fn ids(v: &serde_json::Value) -> Result<Vec<String>, Report> {
+    let items = match v.as_array() {
+        Some(a) => a,
+        None => bail!("expected a list, got {}", v),
+    };
+    Ok(items
+        .iter()
+        .filter_map(|i| i["id"].as_str().map(str::to_owned))
+        .collect())
+}
+
+async fn az_names(args: &[&str]) -> Result<Vec<String>, Report> {
+    let args: Vec<&str> = args.iter().copied().chain(["-o", "tsv"]).collect();
+    let names: Vec<String> = azure::az(&args)
+        .await?
+        .lines()
+        .map(|l| l.trim().to_owned())
+        .filter(|l| !l.is_empty())
+        .collect();
+    debug!(n = names.len(), "listed");
+    Ok(names)
+}
+
+/// `, did you mean ...?` for the names in `known` close to `name`, if there are any.
+fn suggest(name: &str, known: &[String]) -> String {
+    let max = (name.len() / 3).max(2);
+    let mut close: Vec<(usize, &String)> = known
+        .iter()
+        .map(|k| (edit_distance(&name.to_lowercase(), &k.to_lowercase()), k))
+        .filter(|(d, _)| *d <= max)
+        .collect();
+    close.sort();
+    let close: Vec<&str> = close.iter().take(3).map(|(_, k)| k.as_str()).collect();
+    if close.is_empty() {
+        String::new()
+    } else {
+        format!(", did you mean {}?", close.join(" or "))
+    }
+}
+
+/// Levenshtein distance.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + usize::from(ca != *cb);
+            cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
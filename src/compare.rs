@@ -0,0 +1,117 @@
+//! Compare the results of two runs, experiment by experiment.
+
+use crate::aggregate::find_data_files;
+use crate::summary::{read_latencies, Stats};
+use crate::sweep::ExpParams;
+use color_eyre::eyre::{ensure, Report};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::warn;
+
+/// Latency statistics for each experiment under `dir`, pooling samples across repetitions.
+pub fn load_run(dir: &Path) -> Result<BTreeMap<ExpParams, Stats>, Report> {
+    let mut files = vec![];
+    find_data_files(dir, &mut files)?;
+    let mut samples: BTreeMap<ExpParams, Vec<f64>> = BTreeMap::new();
+    for f in files {
+        let fname = f.file_name().unwrap().to_str().unwrap();
+        let params = match ExpParams::from_filename(fname) {
+            Ok(p) => p,
+            Err(err) => {
+                warn!(?err, "skipping");
+                continue;
+            }
+        };
+        match read_latencies(&f) {
+            Ok(s) => samples.entry(params).or_default().extend(s),
+            Err(err) => warn!(?err, "skipping"),
+        }
+    }
+
+    Ok(samples
+        .into_iter()
+        .filter_map(|(p, s)| Some((p, Stats::from_samples(s)?)))
+        .collect())
+}
+
+/// One experiment's change between two runs.
+#[derive(Clone, Debug)]
+pub struct Delta {
+    pub params: ExpParams,
+    pub a: Stats,
+    pub b: Stats,
+    /// Whether any metric got worse by more than the threshold.
+    pub regressed: bool,
+}
+
+fn pct_change(a: f64, b: f64) -> f64 {
+    if a == 0. {
+        0.
+    } else {
+        (b - a) / a * 100.
+    }
+}
+
+fn metrics(s: &Stats) -> [f64; 4] {
+    [s.mean, s.p50, s.p95, s.p99]
+}
+
+/// Match experiments in runs `a` and `b`, and flag those whose latency grew by more than
+/// `threshold_pct` percent.
+pub fn compare(a: &Path, b: &Path, threshold_pct: f64) -> Result<Vec<Delta>, Report> {
+    let a = load_run(a)?;
+    let mut b = load_run(b)?;
+    ensure!(!a.is_empty(), "no results in first run");
+
+    let mut deltas = vec![];
+    for (params, sa) in a {
+        match b.remove(&params) {
+            Some(sb) => {
+                let regressed = metrics(&sa)
+                    .iter()
+                    .zip(metrics(&sb).iter())
+                    .any(|(x, y)| pct_change(*x, *y) > threshold_pct);
+                deltas.push(Delta {
+                    params,
+                    a: sa,
+                    b: sb,
+                    regressed,
+                });
+            }
+            None => warn!(exp = %params.name(), "only in first run"),
+        }
+    }
+
+    for params in b.keys() {
+        warn!(exp = %params.name(), "only in second run");
+    }
+
+    Ok(deltas)
+}
+
+/// Print a table of `deltas`, with regressions marked.
+pub fn print_deltas(deltas: &[Delta]) {
+    println!(
+        "{:<50} {:>18} {:>18} {:>18} {:>18}",
+        "experiment", "mean", "p50", "p95", "p99"
+    );
+    for d in deltas {
+        let cols: Vec<String> = metrics(&d.a)
+            .iter()
+            .zip(metrics(&d.b).iter())
+            .map(|(x, y)| format!("{:.0} ({:+.1}%)", y, pct_change(*x, *y)))
+            .collect();
+        println!(
+            "{:<50} {:>18} {:>18} {:>18} {:>18}{}",
+            d.params.name(),
+            cols[0],
+            cols[1],
+            cols[2],
+            cols[3],
+            if d.regressed { "  REGRESSION" } else { "" }
+        );
+    }
+
+    let n = deltas.iter().filter(|d| d.regressed).count();
+    println!("{} of {} experiments regressed", n, deltas.len());
+}
@@ -0,0 +1,135 @@
+//! Steering a run while it goes, through a unix socket in the output directory.
+//!
+//! Each line written to the socket is a request:
+//! - `skip-exp`: kill the script, and start it again on the experiments it hasn't got to yet.
+//!   This relies on the script honoring `BURRITO_EXP_ONLY`.
+//! - `skip-node`: kill the script, and collect what it got through. The node's remaining
+//!   repetitions don't run.
+//! - `stop`: like `skip-node`, and no nodes after it run either.
+//!
+//! e.g. `echo skip-exp | nc -U <out-dir>/control.sock`.
+
+use color_eyre::eyre::{Report, WrapErr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Default)]
+struct Requests {
+    skip_exp: bool,
+    skip_node: bool,
+    stop: bool,
+}
+
+/// The requests made so far. Without a socket, there never are any.
+#[derive(Clone, Debug, Default)]
+pub struct Control {
+    requests: Arc<Mutex<Requests>>,
+    path: Option<PathBuf>,
+}
+
+impl Control {
+    /// Take requests on a socket at `path`.
+    pub fn listen(path: &Path) -> Result<Self, Report> {
+        // left over from a run that didn't exit cleanly.
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+
+        if let Some(d) = path.parent() {
+            std::fs::create_dir_all(d)?;
+        }
+
+        let listener =
+            UnixListener::bind(path).wrap_err_with(|| format!("bind control socket {:?}", path))?;
+        let ctl = Self {
+            requests: Default::default(),
+            path: Some(path.to_path_buf()),
+        };
+        let c = ctl.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((s, _)) => {
+                        let c = c.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) = c.serve(s).await {
+                                debug!(?err, "control connection failed");
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        warn!(?err, "control socket failed");
+                        return;
+                    }
+                }
+            }
+        });
+
+        info!(?path, "listening for control requests");
+        Ok(ctl)
+    }
+
+    async fn serve(&self, s: UnixStream) -> Result<(), Report> {
+        let (r, mut w) = s.into_split();
+        let mut lines = BufReader::new(r).lines();
+        while let Some(l) = lines.next_line().await? {
+            let resp = {
+                let mut reqs = self.requests.lock().unwrap();
+                match l.trim() {
+                    "skip-exp" => {
+                        reqs.skip_exp = true;
+                        "ok"
+                    }
+                    "skip-node" => {
+                        reqs.skip_node = true;
+                        "ok"
+                    }
+                    "stop" => {
+                        reqs.stop = true;
+                        "ok"
+                    }
+                    _ => "unknown request, expected skip-exp, skip-node, or stop",
+                }
+            };
+            info!(request = ?l.trim(), ?resp, "control request");
+            w.write_all(format!("{}\n", resp).as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the current experiment should be skipped. Only says so once per request.
+    pub fn take_skip_exp(&self) -> bool {
+        std::mem::take(&mut self.requests.lock().unwrap().skip_exp)
+    }
+
+    /// Whether the current node should wrap up now.
+    pub fn skip_node(&self) -> bool {
+        let reqs = self.requests.lock().unwrap();
+        reqs.skip_node || reqs.stop
+    }
+
+    /// Whether the run should wrap up now.
+    pub fn stopping(&self) -> bool {
+        self.requests.lock().unwrap().stop
+    }
+
+    /// The current node is done: requests to skip it are spent.
+    pub fn node_done(&self) {
+        let mut reqs = self.requests.lock().unwrap();
+        reqs.skip_exp = false;
+        reqs.skip_node = false;
+    }
+
+    /// Stop taking requests.
+    pub fn close(&self) {
+        if let Some(ref p) = self.path {
+            if let Err(err) = std::fs::remove_file(p) {
+                debug!(?err, "could not remove control socket");
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Running nodes that depend on each other (`depends_on`): each starts once the nodes it depends
+//! on are done, and nodes that don't wait on each other run at the same time.
+
+use crate::node::{Node, OnError, RunOpts};
+use color_eyre::eyre::{bail, eyre, Report};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tracing::{info, warn};
+
+/// For each node, the indices of the nodes it depends on. Fails on unknown names and cycles.
+pub fn deps(nodes: &[Node], ids: &[String]) -> Result<Vec<Vec<usize>>, Report> {
+    let deps = nodes
+        .iter()
+        .zip(ids)
+        .map(|(n, id)| {
+            n.depends_on()
+                .iter()
+                .map(|d| {
+                    ids.iter().position(|i| i == d).ok_or_else(|| {
+                        eyre!("node {:?} depends on {:?}, which isn't a node", id, d)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // peel off nodes whose dependencies are all peeled off; whatever is left is in a cycle.
+    let mut done = vec![false; nodes.len()];
+    loop {
+        let ready: Vec<usize> = (0..nodes.len())
+            .filter(|&i| !done[i] && deps[i].iter().all(|&d| done[d]))
+            .collect();
+        if ready.is_empty() {
+            break;
+        }
+
+        for i in ready {
+            done[i] = true;
+        }
+    }
+
+    let cycle: Vec<&str> = (0..nodes.len())
+        .filter(|&i| !done[i])
+        .map(|i| ids[i].as_str())
+        .collect();
+    if !cycle.is_empty() {
+        bail!("nodes {} depend on each other in a cycle", cycle.join(", "));
+    }
+
+    Ok(deps)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Waiting,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Fail every waiting node that depends, however indirectly, on a failed one. Returns each with
+/// the failed dependency that blocked it.
+fn fail_blocked(deps: &[Vec<usize>], state: &mut [State]) -> Vec<(usize, usize)> {
+    let mut blocked = vec![];
+    loop {
+        let newly: Vec<(usize, usize)> = (0..state.len())
+            .filter(|&i| state[i] == State::Waiting)
+            .filter_map(|i| Some((i, *deps[i].iter().find(|&&d| state[d] == State::Failed)?)))
+            .collect();
+        if newly.is_empty() {
+            return blocked;
+        }
+
+        for &(i, _) in &newly {
+            state[i] = State::Failed;
+        }
+        blocked.extend(newly);
+    }
+}
+
+/// Run `nodes` as their dependencies allow. Returns the nodes that failed (or couldn't run,
+/// because a dependency failed) under [`OnError::Continue`]; under [`OnError::Abort`], the first
+/// failure, once the nodes already running have finished.
+pub async fn run(
+    nodes: &[Node],
+    ids: &[String],
+    opts: &RunOpts,
+) -> Result<Vec<(String, Report)>, Report> {
+    let deps = deps(nodes, ids)?;
+    let mut state = vec![State::Waiting; nodes.len()];
+    let mut failures = vec![];
+    let mut abort: Option<Report> = None;
+    let mut running = FuturesUnordered::new();
+    loop {
+        let stop = abort.is_some() || opts.control.stopping();
+        if !stop {
+            for (i, d) in fail_blocked(&deps, &mut state) {
+                let err = eyre!("dependency {:?} failed", ids[d]);
+                warn!(node = ?ids[i], %err, "not running node");
+                failures.push((ids[i].clone(), err));
+            }
+        }
+
+        for i in 0..nodes.len() {
+            if state[i] != State::Waiting || stop {
+                continue;
+            }
+
+            if deps[i].iter().all(|&d| state[d] == State::Done) {
+                info!(node = ?ids[i], "dependencies done, starting");
+                state[i] = State::Running;
+                running.push(async move { (i, nodes[i].run(opts, &ids[i], None).await) });
+            }
+        }
+
+        let (i, res) = match running.next().await {
+            Some(r) => r,
+            // nothing is running, and nothing more can start.
+            None => break,
+        };
+        opts.control.node_done();
+        match res {
+            Ok(()) => state[i] = State::Done,
+            Err(err) => {
+                state[i] = State::Failed;
+                match opts.on_error {
+                    OnError::Abort if abort.is_none() => {
+                        warn!(node = ?ids[i], "node failed, waiting for the running ones to finish");
+                        abort = Some(err);
+                    }
+                    _ => {
+                        warn!(node = ?ids[i], err = %format!("{:#}", err), "node failed");
+                        failures.push((ids[i].clone(), err));
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(err) = abort {
+        return Err(err);
+    }
+
+    if opts.control.stopping() {
+        warn!("stopping early by request");
+    }
+
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(deps: &[(&str, &[&str])]) -> (Vec<Node>, Vec<String>) {
+        let nodes = deps
+            .iter()
+            .map(|(name, on)| {
+                serde_json::from_value(serde_json::json!({
+                    "name": name,
+                    "Existing": { "provider": "gcp", "host": "203.0.113.7", "user": "ubuntu" },
+                    "depends_on": on,
+                }))
+                .unwrap()
+            })
+            .collect();
+        (nodes, deps.iter().map(|(n, _)| n.to_string()).collect())
+    }
+
+    #[test]
+    fn deps_by_index() {
+        let (n, ids) = nodes(&[("a", &["b", "c"]), ("b", &["c"]), ("c", &[])]);
+        assert_eq!(deps(&n, &ids).unwrap(), vec![vec![1, 2], vec![2], vec![]]);
+    }
+
+    #[test]
+    fn deps_unknown_name() {
+        let (n, ids) = nodes(&[("a", &["nope"])]);
+        let err = deps(&n, &ids).unwrap_err().to_string();
+        assert!(err.contains("isn't a node"), "{}", err);
+    }
+
+    #[test]
+    fn deps_cycle() {
+        let (n, ids) = nodes(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"]), ("d", &[])]);
+        let err = deps(&n, &ids).unwrap_err().to_string();
+        assert!(err.contains("nodes a, b, c depend"), "{}", err);
+    }
+
+    #[test]
+    fn failure_blocks_transitively() {
+        // a depends on b depends on c, listed before them.
+        let deps = vec![vec![1], vec![2], vec![]];
+        let mut state = vec![State::Waiting, State::Waiting, State::Failed];
+        let mut blocked = fail_blocked(&deps, &mut state);
+        blocked.sort();
+        assert_eq!(blocked, vec![(0, 1), (1, 2)]);
+        assert_eq!(state, vec![State::Failed; 3]);
+    }
+
+    #[test]
+    fn failure_leaves_independent_nodes() {
+        let deps = vec![vec![], vec![0], vec![]];
+        let mut state = vec![State::Failed, State::Waiting, State::Waiting];
+        assert_eq!(fail_blocked(&deps, &mut state), vec![(1, 0)]);
+        assert_eq!(state[2], State::Waiting);
+    }
+}
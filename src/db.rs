@@ -0,0 +1,138 @@
+//! A SQLite database of every run's experiments and summary statistics, for querying across runs.
+
+use crate::exp::RepResult;
+use crate::summary::{read_latencies, Stats};
+use crate::sweep::ExpParams;
+use color_eyre::eyre::{Report, WrapErr};
+use rusqlite::{params, Connection};
+use std::path::Path;
+use tracing::{info, warn};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    label TEXT,
+    provider TEXT NOT NULL,
+    region TEXT,
+    instance_type TEXT,
+    out_dir TEXT NOT NULL,
+    tags TEXT NOT NULL,
+    config TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS experiments (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    rep INTEGER NOT NULL,
+    exit_code INTEGER,
+    file TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    groups INTEGER,
+    inter_req_ms INTEGER NOT NULL,
+    rcvrs INTEGER NOT NULL,
+    batch INTEGER NOT NULL,
+    batch_type TEXT NOT NULL,
+    impl TEXT NOT NULL,
+    count INTEGER NOT NULL,
+    mean REAL NOT NULL,
+    stddev REAL NOT NULL,
+    p50 REAL NOT NULL,
+    p95 REAL NOT NULL,
+    p99 REAL NOT NULL
+);
+";
+
+/// Metadata about one node's run.
+#[derive(Debug)]
+pub struct RunRecord<'a> {
+    /// Unix time, in seconds.
+    pub started_at: u64,
+    /// The run's `--name`.
+    pub label: Option<&'a str>,
+    pub provider: &'a str,
+    pub region: Option<&'a str>,
+    pub instance_type: Option<&'a str>,
+    pub out_dir: &'a Path,
+    /// JSON.
+    pub tags: String,
+    /// The node's configuration, as JSON.
+    pub config: String,
+}
+
+/// Record a run and its repetitions' results into the database at `path`, creating it if needed.
+pub fn record_run(path: &Path, run: &RunRecord, results: &[RepResult]) -> Result<(), Report> {
+    let mut conn = Connection::open(path).wrap_err_with(|| format!("open db {:?}", path))?;
+    conn.execute_batch(SCHEMA).wrap_err("create tables")?;
+    // databases created before runs were labeled.
+    if conn.prepare("SELECT label FROM runs LIMIT 0").is_err() {
+        conn.execute_batch("ALTER TABLE runs ADD COLUMN label TEXT")
+            .wrap_err("add label column")?;
+    }
+
+    let tx = conn.transaction()?;
+    tx.execute(
+        "INSERT INTO runs (started_at, label, provider, region, instance_type, out_dir, tags, config)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            run.started_at as i64,
+            run.label,
+            run.provider,
+            run.region,
+            run.instance_type,
+            run.out_dir.to_string_lossy(),
+            run.tags,
+            run.config,
+        ],
+    )?;
+    let run_id = tx.last_insert_rowid();
+
+    let mut n = 0;
+    for r in results {
+        for f in &r.files {
+            let params = match ExpParams::from_filename(f) {
+                Ok(p) => p,
+                Err(err) => {
+                    warn!(?err, "not recording");
+                    continue;
+                }
+            };
+            let stats = match read_latencies(&r.dir.join(f)) {
+                Ok(s) => Stats::from_samples(s).unwrap(),
+                Err(err) => {
+                    warn!(?err, file = ?f, "not recording");
+                    continue;
+                }
+            };
+
+            tx.execute(
+                "INSERT INTO experiments (run_id, rep, exit_code, file, provider, groups,
+                    inter_req_ms, rcvrs, batch, batch_type, impl,
+                    count, mean, stddev, p50, p95, p99)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+                params![
+                    run_id,
+                    r.rep as i64,
+                    r.code,
+                    f,
+                    params.provider,
+                    params.groups.map(|g| g as i64),
+                    params.inter_req_ms as i64,
+                    params.rcvrs as i64,
+                    params.batch as i64,
+                    params.batch_type,
+                    params.imp,
+                    stats.count as i64,
+                    stats.mean,
+                    stats.stddev,
+                    stats.p50,
+                    stats.p95,
+                    stats.p99,
+                ],
+            )?;
+            n += 1;
+        }
+    }
+
+    tx.commit()?;
+    info!(?run_id, experiments = ?n, "recorded run in db");
+    Ok(())
+}
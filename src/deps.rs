@@ -0,0 +1,542 @@
+//! Remote dependency installation.
+
+use crate::retry::RetryPolicy;
+use crate::state::{timed, NodeCheckpoint};
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use openssh::Session;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info, instrument, warn};
+
+/// Per-node dependency installation options.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct DepsCfg {
+    /// Override the package manager detected from the remote distro.
+    pub pkg_manager: Option<PkgManager>,
+    /// Retry policy for `apt`, which commonly fails transiently on fresh cloud instances.
+    pub apt_retry: RetryPolicy,
+    /// How long to wait for another process (e.g. `unattended-upgrades`) to release the dpkg lock
+    /// before each apt attempt.
+    pub dpkg_lock_timeout_secs: u64,
+    /// A local requirements.txt to upload and install for the experiment script.
+    ///
+    /// Implies a virtualenv (see `venv`).
+    pub requirements: Option<PathBuf>,
+    /// Remote path of a virtualenv to install python dependencies into, instead of installing
+    /// them globally with `sudo pip3`.
+    pub venv: Option<PathBuf>,
+    /// Which coordination store the experiment script expects to find installed.
+    pub coord_store: CoordStore,
+    /// Pin the redis package to this version (as understood by the package manager, e.g.
+    /// `6:6.2.6-1rl1~focal1` for apt). Defaults to whatever is current.
+    pub redis_version: Option<String>,
+    /// Whether the remote user can run `sudo` non-interactively. If not, system packages are
+    /// assumed to be present already and python packages are installed with `pip3 --user`.
+    pub use_sudo: bool,
+    /// Skip dependency installation entirely.
+    pub deps_installed: bool,
+    /// Point apt's sources at this mirror (e.g. `http://mirror.example.com/ubuntu/`) before
+    /// installing, instead of the image's default. `auto` picks a nearby Ubuntu mirror by the
+    /// machine's location, which helps a lot in regions far from the defaults.
+    pub apt_mirror: Option<String>,
+    /// Install python packages from this index instead of PyPI.
+    pub pip_index_url: Option<String>,
+}
+
+/// Coordination/discovery stores the experiment script can use.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CoordStore {
+    #[default]
+    Redis,
+    Memcached,
+    Etcd,
+}
+
+impl Default for DepsCfg {
+    fn default() -> Self {
+        Self {
+            pkg_manager: None,
+            apt_retry: Default::default(),
+            dpkg_lock_timeout_secs: 600,
+            requirements: None,
+            venv: None,
+            coord_store: CoordStore::Redis,
+            redis_version: None,
+            use_sudo: true,
+            deps_installed: false,
+            apt_mirror: None,
+            pip_index_url: None,
+        }
+    }
+}
+
+impl DepsCfg {
+    fn venv_path(&self) -> Option<PathBuf> {
+        match (&self.venv, &self.requirements) {
+            (Some(v), _) => Some(v.clone()),
+            (None, Some(_)) => Some(PathBuf::from("exp-venv")),
+            (None, None) => None,
+        }
+    }
+
+    /// The coordination store's package names and service name under `pm`.
+    fn coord_packages(&self, pm: PkgManager) -> Result<(Vec<String>, &'static str), Report> {
+        let pinned = |pkg: &str| match (&self.redis_version, pm) {
+            (None, _) => Ok(pkg.to_owned()),
+            (Some(v), PkgManager::Apt) => Ok(format!("{}={}", pkg, v)),
+            (Some(v), PkgManager::Dnf) => Ok(format!("{}-{}", pkg, v)),
+            (Some(_), PkgManager::Pacman) => Err(eyre!("pacman does not support version pinning")),
+        };
+
+        Ok(match (self.coord_store, pm) {
+            (CoordStore::Redis, PkgManager::Apt) => (
+                vec![
+                    pinned("redis")?,
+                    pinned("redis-server")?,
+                    pinned("redis-tools")?,
+                ],
+                "redis-server",
+            ),
+            (CoordStore::Redis, _) => (vec![pinned("redis")?], "redis"),
+            (CoordStore::Memcached, _) => (vec!["memcached".to_owned()], "memcached"),
+            (CoordStore::Etcd, PkgManager::Pacman) => bail!("etcd is not packaged for pacman"),
+            (CoordStore::Etcd, _) => (vec!["etcd".to_owned()], "etcd"),
+        })
+    }
+
+    /// The coordination store's server binary, to check for when we can't install it.
+    fn coord_bin(&self) -> &'static str {
+        match self.coord_store {
+            CoordStore::Redis => "redis-server",
+            CoordStore::Memcached => "memcached",
+            CoordStore::Etcd => "etcd",
+        }
+    }
+
+    /// `pip install`, with the configured index.
+    fn pip_install(&self, pip: &str) -> String {
+        match self.pip_index_url {
+            Some(ref i) => format!("{} install --index-url {}", pip, i),
+            None => format!("{} install", pip),
+        }
+    }
+
+    /// The command rewriting apt's sources to `apt_mirror`, for `distro`.
+    fn apt_mirror_cmd(&self, distro: &Distro) -> Result<Option<String>, Report> {
+        let mirror = match self.apt_mirror.as_deref() {
+            None => return Ok(None),
+            Some("auto") if distro.id == "ubuntu" => "mirror://mirrors.ubuntu.com/mirrors.txt",
+            Some("auto") => bail!("apt_mirror auto is only supported on ubuntu"),
+            Some(m) => m.trim_end_matches('/'),
+        };
+        let default = if distro.id == "ubuntu" {
+            r"https?://[^ ]*archive\.ubuntu\.com/ubuntu/?"
+        } else {
+            r"https?://deb\.debian\.org/debian/?"
+        };
+        // 22.04 and earlier use sources.list, later releases deb822 .sources files.
+        Ok(Some(format!(
+            "for f in /etc/apt/sources.list /etc/apt/sources.list.d/*.sources; do \
+            [ -f \"$f\" ] && sudo sed -i -E 's#{}#{}#' \"$f\"; done; true",
+            default, mirror
+        )))
+    }
+
+    /// The python interpreter the experiment script should be run with.
+    pub fn python(&self) -> String {
+        match self.venv_path() {
+            Some(v) => v.join("bin/python").to_string_lossy().into_owned(),
+            None => "python3".to_owned(),
+        }
+    }
+}
+
+/// Package managers we know how to drive on the remote host.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PkgManager {
+    Apt,
+    Dnf,
+    Pacman,
+}
+
+/// The remote distro, as reported by `/etc/os-release`.
+#[derive(Clone, Debug)]
+pub struct Distro {
+    pub id: String,
+    pub id_like: Vec<String>,
+}
+
+impl Distro {
+    fn is(&self, name: &str) -> bool {
+        self.id == name || self.id_like.iter().any(|l| l == name)
+    }
+
+    pub fn pkg_manager(&self) -> Result<PkgManager, Report> {
+        if self.is("debian") || self.is("ubuntu") {
+            Ok(PkgManager::Apt)
+        } else if self.is("fedora") || self.is("rhel") || self.is("centos") {
+            Ok(PkgManager::Dnf)
+        } else if self.is("arch") {
+            Ok(PkgManager::Pacman)
+        } else {
+            Err(eyre!("no known package manager for distro {:?}", self))
+        }
+    }
+}
+
+#[instrument(skip(ssh), level = "debug")]
+pub async fn detect_distro(ssh: &Session) -> Result<Distro, Report> {
+    let out = ssh
+        .shell(". /etc/os-release && echo \"$ID\" && echo \"$ID_LIKE\"")
+        .output()
+        .await
+        .wrap_err("read /etc/os-release")?;
+    ensure!(out.status.success(), "read /etc/os-release");
+    let out = String::from_utf8(out.stdout)?;
+    let mut lines = out.lines();
+    let id = lines.next().unwrap_or_default().trim().to_owned();
+    let id_like = lines
+        .next()
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect();
+    let d = Distro { id, id_like };
+    debug!(?d, "detected distro");
+    Ok(d)
+}
+
+/// Install the experiment's dependencies (python3 + pip, redis, agenda).
+///
+/// If `cfg.pkg_manager` is `None`, detect it from the remote distro. How long system packages and
+/// pip took is recorded in `timings`.
+pub async fn install_deps(
+    ssh: &Session,
+    cfg: &DepsCfg,
+    timings: Option<&NodeCheckpoint>,
+) -> Result<(), Report> {
+    if cfg.deps_installed {
+        info!("dependencies already installed, skipping");
+        return Ok(());
+    }
+
+    if !cfg.use_sudo {
+        info!("no sudo, only installing python dependencies");
+        for bin in &["python3", cfg.coord_bin()] {
+            let st = ssh.command("which").arg(bin).status().await?;
+            ensure!(
+                st.success(),
+                "{} not installed, and cannot install without sudo",
+                bin
+            );
+        }
+
+        return timed(timings, "setup.pip", pip_install(ssh, cfg)).await;
+    }
+
+    timed(timings, "setup.packages", install_packages(ssh, cfg)).await?;
+    timed(timings, "setup.pip", pip_install(ssh, cfg)).await
+}
+
+async fn install_packages(ssh: &Session, cfg: &DepsCfg) -> Result<(), Report> {
+    let distro = detect_distro(ssh).await?;
+    let pm = match cfg.pkg_manager {
+        Some(pm) => pm,
+        None => distro.pkg_manager()?,
+    };
+    info!(?pm, distro = ?distro.id, "installing dependencies");
+    let (pkgs, svc) = cfg.coord_packages(pm)?;
+    let pkgs = pkgs.join(" ");
+    match pm {
+        PkgManager::Apt => {
+            if let Some(cmd) = cfg.apt_mirror_cmd(&distro)? {
+                info!(mirror = ?cfg.apt_mirror, "switching apt mirror");
+                run_checked(ssh, &cmd, "switch apt mirror").await?;
+            }
+
+            // the redislabs PPA only exists for ubuntu; elsewhere use the distro's redis.
+            let ppa = distro.is("ubuntu") && cfg.coord_store == CoordStore::Redis;
+            apt_install(ssh, ppa, &pkgs, svc, cfg).await?
+        }
+        PkgManager::Dnf => dnf_install(ssh, &pkgs, svc).await?,
+        PkgManager::Pacman => pacman_install(ssh, &pkgs, svc).await?,
+    }
+
+    Ok(())
+}
+
+async fn pip_install(ssh: &Session, cfg: &DepsCfg) -> Result<(), Report> {
+    let venv = match cfg.venv_path() {
+        None if cfg.use_sudo => {
+            let cmd = format!("{} agenda", cfg.pip_install("sudo pip3"));
+            return run_checked(ssh, &cmd, "pip install").await;
+        }
+        None => {
+            let cmd = format!("{} --user agenda", cfg.pip_install("pip3"));
+            return run_checked(ssh, &cmd, "pip install").await;
+        }
+        Some(v) => v,
+    };
+
+    let venv = venv.to_str().unwrap();
+    run_checked(ssh, &format!("python3 -m venv {}", venv), "create venv").await?;
+    let pip = format!("{}/bin/pip", venv);
+    run_checked(
+        ssh,
+        &format!("{} -U pip agenda", cfg.pip_install(&pip)),
+        "pip install",
+    )
+    .await?;
+    if let Some(ref req) = cfg.requirements {
+        let remote_req = Path::new("requirements.txt");
+        crate::write_file(ssh, req, remote_req).await?;
+        run_checked(
+            ssh,
+            &format!("{} -r {}", cfg.pip_install(&pip), remote_req.display()),
+            "pip install requirements",
+        )
+        .await?;
+    }
+
+    info!(?venv, "installed python dependencies into venv");
+    Ok(())
+}
+
+/// Wait until no process holds the dpkg frontend lock.
+async fn wait_dpkg_lock(ssh: &Session, timeout: Duration) -> Result<(), Report> {
+    let start = std::time::Instant::now();
+    loop {
+        // both exit 0 if some process has the files open, 1 if none does. `sudo fuser` would exit 1
+        // if there were no fuser, so look for it first.
+        let st = ssh
+            .shell(format!(
+                "if command -v fuser >/dev/null; then sudo fuser {locks}; \
+                elif command -v lsof >/dev/null; then sudo lsof {locks} >/dev/null; \
+                else exit 127; fi",
+                locks = "/var/lib/dpkg/lock-frontend /var/lib/dpkg/lock"
+            ))
+            .status()
+            .await
+            .wrap_err("check dpkg lock")?;
+        match st.code() {
+            Some(0) => (),
+            Some(1) => return Ok(()),
+            Some(127) => {
+                warn!("neither fuser nor lsof is installed, so not waiting for the dpkg lock; apt is retried if it's held");
+                return Ok(());
+            }
+            c => {
+                warn!(code = ?c, "could not check dpkg lock, not waiting for it; apt is retried if it's held");
+                return Ok(());
+            }
+        }
+
+        if start.elapsed() > timeout {
+            bail!("dpkg lock still held after {:?}", timeout);
+        }
+
+        info!(waited = ?start.elapsed(), "dpkg lock held, waiting");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn run_checked(ssh: &Session, cmd: &str, what: &str) -> Result<(), Report> {
+    let out = ssh.shell(cmd).output().await.wrap_err(eyre!("{}", what))?;
+    ensure!(
+        out.status.success(),
+        "{} failed: {}",
+        what,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    Ok(())
+}
+
+async fn apt_install(
+    ssh: &Session,
+    redis_ppa: bool,
+    pkgs: &str,
+    svc: &str,
+    cfg: &DepsCfg,
+) -> Result<(), Report> {
+    let install = format!(
+        "sudo apt update && sudo DEBIAN_FRONTEND=noninteractive apt install -y python3-pip python3-venv {} && sudo systemctl stop {}",
+        pkgs, svc
+    );
+    let mut steps = vec![];
+    if redis_ppa {
+        steps.push((
+            "sudo add-apt-repository -y ppa:redislabs/redis",
+            "redis apt-add-repository",
+        ));
+    }
+    steps.push((install.as_str(), "apt install"));
+    apt_retrying(ssh, cfg, &steps).await
+}
+
+/// Run `steps` (command, description) in order, retrying all of them per `cfg.apt_retry`.
+async fn apt_retrying(ssh: &Session, cfg: &DepsCfg, steps: &[(&str, &str)]) -> Result<(), Report> {
+    let policy = &cfg.apt_retry;
+    let lock_timeout = Duration::from_secs(cfg.dpkg_lock_timeout_secs);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let res = async {
+            wait_dpkg_lock(ssh, lock_timeout).await?;
+            for (cmd, what) in steps {
+                run_checked(ssh, cmd, what).await?;
+            }
+            Ok::<_, Report>(())
+        }
+        .await;
+
+        match res {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= policy.max_attempts => {
+                return Err(e.wrap_err(eyre!("apt failed after {} attempts", attempt)));
+            }
+            Err(e) => {
+                let backoff = policy.backoff(attempt);
+                warn!(?attempt, ?backoff, err = %format!("{:#}", e), "apt failed");
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+/// A kernel to boot into during setup, e.g. for AF_XDP features that need 5.10 or later.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct KernelCfg {
+    /// The oldest acceptable version, e.g. `5.15`, checked against `uname -r` once setup is done.
+    pub version: String,
+    /// The apt package to install if the running kernel is older. Defaults to the release's HWE
+    /// kernel, `linux-generic-hwe-<release>`; for a mainline kernel, install it in a setup step
+    /// and leave this as is.
+    #[serde(default)]
+    pub package: Option<String>,
+}
+
+/// The numeric parts of a kernel release, e.g. `[5, 15, 0]` for `5.15.0-1019-aws`.
+fn kernel_parts(release: &str) -> Vec<u64> {
+    release
+        .split(|c: char| !c.is_ascii_digit())
+        .take_while(|p| !p.is_empty())
+        .map(|p| p.parse().unwrap_or(0))
+        .collect()
+}
+
+impl KernelCfg {
+    pub fn check(&self) -> Result<(), Report> {
+        ensure!(
+            !kernel_parts(&self.version).is_empty(),
+            "kernel version {:?} must be like 5.15",
+            self.version
+        );
+        Ok(())
+    }
+
+    fn satisfied_by(&self, release: &str) -> bool {
+        kernel_parts(release) >= kernel_parts(&self.version)
+    }
+}
+
+async fn running_kernel(ssh: &Session) -> Result<String, Report> {
+    let out = ssh.command("uname").arg("-r").output().await?;
+    ensure!(out.status.success(), "uname -r");
+    Ok(String::from_utf8(out.stdout)?.trim().to_owned())
+}
+
+/// Install `k`'s kernel if the running one is older. Returns whether it did, in which case the
+/// machine needs a reboot into it.
+pub async fn install_kernel(ssh: &Session, cfg: &DepsCfg, k: &KernelCfg) -> Result<bool, Report> {
+    let running = running_kernel(ssh).await?;
+    if k.satisfied_by(&running) {
+        debug!(?running, want = ?k.version, "kernel is new enough");
+        return Ok(false);
+    }
+
+    let distro = detect_distro(ssh).await?;
+    ensure!(
+        distro.is("ubuntu") || k.package.is_some(),
+        "the default kernel package is only for ubuntu, not {:?}: set kernel.package",
+        distro.id
+    );
+    let pkg = match k.package {
+        Some(ref p) => p.clone(),
+        None => "linux-generic-hwe-$(lsb_release -rs)".to_owned(),
+    };
+    info!(?running, want = ?k.version, ?pkg, "installing kernel");
+    let install = format!(
+        "sudo apt update && sudo DEBIAN_FRONTEND=noninteractive apt install -y {}",
+        pkg
+    );
+    apt_retrying(ssh, cfg, &[(install.as_str(), "apt install kernel")]).await?;
+    Ok(true)
+}
+
+/// Fail unless the machine runs a kernel `k` accepts.
+pub async fn check_kernel(ssh: &Session, k: &KernelCfg) -> Result<(), Report> {
+    let running = running_kernel(ssh).await?;
+    ensure!(
+        k.satisfied_by(&running),
+        "machine runs kernel {}, older than the {} asked for",
+        running,
+        k.version
+    );
+    info!(?running, "kernel checks out");
+    Ok(())
+}
+
+/// Install the NVIDIA driver and CUDA toolkit. The driver is only loaded after a reboot.
+pub async fn install_gpu_driver(ssh: &Session, cfg: &DepsCfg) -> Result<(), Report> {
+    let distro = detect_distro(ssh).await?;
+    ensure!(
+        distro.is("ubuntu"),
+        "installing the nvidia driver is only supported on ubuntu, not {:?}",
+        distro.id
+    );
+    info!("installing nvidia driver and cuda toolkit");
+    apt_retrying(
+        ssh,
+        cfg,
+        &[
+            (
+                "sudo apt update && sudo DEBIAN_FRONTEND=noninteractive apt install -y ubuntu-drivers-common",
+                "apt install ubuntu-drivers-common",
+            ),
+            // picks the recommended driver for the card.
+            ("sudo DEBIAN_FRONTEND=noninteractive ubuntu-drivers install", "install nvidia driver"),
+            (
+                "sudo DEBIAN_FRONTEND=noninteractive apt install -y nvidia-cuda-toolkit",
+                "apt install cuda toolkit",
+            ),
+        ],
+    )
+    .await
+}
+
+async fn dnf_install(ssh: &Session, pkgs: &str, svc: &str) -> Result<(), Report> {
+    run_checked(
+        ssh,
+        &format!(
+            "sudo dnf install -y python3-pip {} && sudo systemctl stop {}",
+            pkgs, svc
+        ),
+        "dnf install",
+    )
+    .await
+}
+
+async fn pacman_install(ssh: &Session, pkgs: &str, svc: &str) -> Result<(), Report> {
+    run_checked(
+        ssh,
+        &format!(
+            "sudo pacman -Sy --noconfirm --needed python-pip {} && sudo systemctl stop {}",
+            pkgs, svc
+        ),
+        "pacman install",
+    )
+    .await
+}
@@ -0,0 +1,217 @@
+//! Resizing (and changing the type of) a launched machine's root disk.
+//!
+//! tsunami always launches with the image's default root disk, so we change it once the machine
+//! is up, before any other setup. EBS volumes can be modified in place; Azure OS disks can only be
+//! changed while the VM is deallocated, so there we stop and restart the VM.
+
+use crate::azure::az;
+use crate::ratelimit;
+use crate::ssh::{reconnect, ConnInfo};
+use crate::tags::{azure_vm, ec2_client, find_instances, Cloud};
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use openssh::Session;
+use rusoto_ec2::Ec2;
+use std::time::Duration;
+use tracing::{debug, info, instrument};
+
+/// Root disk configuration. Unset fields are left as the image has them.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DiskCfg {
+    pub size_gb: Option<i64>,
+    /// An EBS volume type (e.g. `gp3`, `io2`) on AWS; on Azure `premium-ssd`, `standard-ssd`,
+    /// `standard-hdd`, or a disk SKU name.
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// Provisioned IOPS, for volume types that take it.
+    pub iops: Option<i64>,
+}
+
+impl DiskCfg {
+    fn azure_sku(&self) -> Option<&str> {
+        self.kind.as_deref().map(|k| match k {
+            "premium-ssd" => "Premium_LRS",
+            "standard-ssd" => "StandardSSD_LRS",
+            "standard-hdd" => "Standard_LRS",
+            sku => sku,
+        })
+    }
+}
+
+/// Apply `cfg` to the root disk of `cloud`'s machine at `conn`, and grow its root filesystem to
+/// fill it.
+///
+/// Returns a new session if the machine had to be restarted, in which case `ssh` is unusable.
+#[instrument(skip(ssh, conn), level = "debug")]
+pub async fn apply(
+    ssh: &Session,
+    conn: &ConnInfo,
+    cloud: &Cloud,
+    cfg: &DiskCfg,
+    restart_timeout: Duration,
+) -> Result<Option<Session>, Report> {
+    let fresh = match cloud {
+        Cloud::Aws { region, profile } => {
+            modify_ebs(region, profile.as_deref(), &conn.host, cfg).await?;
+            None
+        }
+        Cloud::Azure => {
+            update_azure(&conn.host, cfg).await?;
+            Some(reconnect(conn, restart_timeout).await?)
+        }
+        _ => bail!("disk is only supported for aws and azure nodes"),
+    };
+
+    grow_root_fs(fresh.as_ref().unwrap_or(ssh)).await?;
+    Ok(fresh)
+}
+
+async fn modify_ebs(
+    region: &str,
+    profile: Option<&str>,
+    public_ip: &str,
+    cfg: &DiskCfg,
+) -> Result<(), Report> {
+    let client = ec2_client(region, profile)?;
+    let cloud = Cloud::Aws {
+        region: region.to_owned(),
+        profile: profile.map(str::to_owned),
+    };
+    ratelimit::acquire(&cloud).await;
+    let inst = find_instances(&client, public_ip)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| eyre!("no instance with ip {}", public_ip))?;
+    let root = inst.root_device_name.clone();
+    let volume_id = inst
+        .block_device_mappings
+        .unwrap_or_default()
+        .into_iter()
+        .find(|m| m.device_name == root)
+        .and_then(|m| m.ebs?.volume_id)
+        .ok_or_else(|| eyre!("no root volume found for {}", public_ip))?;
+
+    ratelimit::acquire(&cloud).await;
+    client
+        .modify_volume(rusoto_ec2::ModifyVolumeRequest {
+            volume_id: volume_id.clone(),
+            size: cfg.size_gb,
+            volume_type: cfg.kind.clone(),
+            iops: cfg.iops,
+            ..Default::default()
+        })
+        .await
+        .wrap_err("modify root volume")?;
+    info!(?volume_id, ?cfg, "modifying root volume");
+
+    // the new size is usable once the modification reaches "optimizing".
+    loop {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        ratelimit::acquire(&cloud).await;
+        let resp = client
+            .describe_volumes_modifications(rusoto_ec2::DescribeVolumesModificationsRequest {
+                volume_ids: Some(vec![volume_id.clone()]),
+                ..Default::default()
+            })
+            .await
+            .wrap_err("describe volume modifications")?;
+        let m = resp
+            .volumes_modifications
+            .unwrap_or_default()
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre!("volume modification for {} disappeared", volume_id))?;
+        match m.modification_state.as_deref() {
+            Some("optimizing") | Some("completed") => return Ok(()),
+            Some("failed") => bail!(
+                "modifying root volume {} failed: {}",
+                volume_id,
+                m.status_message.unwrap_or_default()
+            ),
+            st => debug!(?st, progress = ?m.progress, "volume still modifying"),
+        }
+    }
+}
+
+async fn update_azure(public_ip: &str, cfg: &DiskCfg) -> Result<(), Report> {
+    let (rg, vm) = azure_vm(public_ip).await?;
+    let disk = az(&[
+        "vm",
+        "show",
+        "-g",
+        &rg,
+        "-n",
+        &vm,
+        "-o",
+        "tsv",
+        "--query",
+        "storageProfile.osDisk.name",
+    ])
+    .await?;
+    // otherwise the VM may come back with a different address.
+    let ips = az(&[
+        "network",
+        "public-ip",
+        "list",
+        "-g",
+        &rg,
+        "-o",
+        "tsv",
+        "--query",
+        "[].name",
+    ])
+    .await?;
+    for ip in ips.lines() {
+        az(&[
+            "network",
+            "public-ip",
+            "update",
+            "-g",
+            &rg,
+            "-n",
+            ip,
+            "--allocation-method",
+            "Static",
+        ])
+        .await?;
+    }
+
+    info!(?vm, ?disk, ?cfg, "deallocating vm to update its os disk");
+    az(&["vm", "deallocate", "-g", &rg, "-n", &vm]).await?;
+    let size = cfg.size_gb.map(|s| s.to_string());
+    let iops = cfg.iops.map(|i| i.to_string());
+    let mut args = vec!["disk", "update", "-g", &rg, "-n", &disk];
+    if let Some(ref s) = size {
+        args.extend(["--size-gb", s]);
+    }
+    if let Some(sku) = cfg.azure_sku() {
+        args.extend(["--sku", sku]);
+    }
+    if let Some(ref i) = iops {
+        args.extend(["--disk-iops-read-write", i]);
+    }
+    az(&args).await?;
+    az(&["vm", "start", "-g", &rg, "-n", &vm]).await?;
+    Ok(())
+}
+
+/// Grow the root partition and filesystem to fill the disk.
+async fn grow_root_fs(ssh: &Session) -> Result<(), Report> {
+    // growpart fails with NOCHANGE if the partition already fills the disk (e.g. cloud-init grew
+    // it on boot), which is fine.
+    let cmd = "src=$(findmnt -n -o SOURCE /) && \
+        disk=/dev/$(lsblk -no pkname \"$src\") && \
+        part=$(cat /sys/class/block/$(basename \"$src\")/partition) && \
+        { sudo growpart \"$disk\" \"$part\" || true; } && \
+        sudo resize2fs \"$src\"";
+    let out = ssh.shell(cmd).output().await.wrap_err("grow root fs")?;
+    ensure!(
+        out.status.success(),
+        "grow root fs failed: {}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let df = ssh.command("df").args(["-h", "/"]).output().await?;
+    info!(df = %String::from_utf8_lossy(&df.stdout).trim(), "grew root filesystem");
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! Launching EC2 instances ourselves, for nodes that choose their key pair and security group.
+//! tsunami makes randomly-named `tsunami_*` ones for every launch, which accounts whose IAM
+//! policies only allow creating resources under certain names (or not at all) refuse.
+//!
+//! A given `key_pair` or `security_group` is used as it is, and left alone. Whichever isn't given
+//! is made for the launch, named `<prefix><machine>-<unix time>`, and deleted at teardown. A made
+//! security group lets in ssh and ping from anywhere, and everything from inside the default VPC,
+//! like tsunami's. Instances are on-demand. Machines kept in a pool are terminated by IP later,
+//! which leaves what was made for them behind, tagged like everything else we launch.
+
+use crate::tags::{ec2_client, with_marker, Tags};
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use rusoto_ec2::{Ec2, Ec2Client};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument, warn};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct AwsAccess {
+    /// An existing key pair, whose private key is the node's `ssh.key_path`.
+    #[serde(default)]
+    pub key_pair: Option<String>,
+    /// An existing security group, by id (`sg-...`) or name.
+    #[serde(default)]
+    pub security_group: Option<String>,
+    /// For the names of the key pair and security group made when these aren't given.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+fn default_prefix() -> String {
+    "burrito-exp-".to_owned()
+}
+
+/// How long a new instance may take to get an address.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(600);
+
+impl AwsAccess {
+    pub fn check(&self) -> Result<(), Report> {
+        ensure!(
+            self.prefix.len() <= 64
+                && self
+                    .prefix
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)),
+            "aws name prefix {:?} must be at most 64 letters, digits, -, _ and .",
+            self.prefix
+        );
+        Ok(())
+    }
+}
+
+/// What a launch made, to delete at teardown.
+#[derive(Debug, Default)]
+pub struct Made {
+    pub instance_id: Option<String>,
+    key_pair: Option<String>,
+    security_group_id: Option<String>,
+}
+
+fn tag_spec(kind: &str, tags: &Tags) -> rusoto_ec2::TagSpecification {
+    rusoto_ec2::TagSpecification {
+        resource_type: Some(kind.to_owned()),
+        tags: Some(
+            with_marker(tags)
+                .into_iter()
+                .map(|(k, v)| rusoto_ec2::Tag {
+                    key: Some(k),
+                    value: Some(v),
+                })
+                .collect(),
+        ),
+    }
+}
+
+async fn security_group_id(client: &Ec2Client, sg: &str) -> Result<String, Report> {
+    if sg.starts_with("sg-") {
+        return Ok(sg.to_owned());
+    }
+
+    let resp = client
+        .describe_security_groups(rusoto_ec2::DescribeSecurityGroupsRequest {
+            group_names: Some(vec![sg.to_owned()]),
+            ..Default::default()
+        })
+        .await
+        .wrap_err_with(|| format!("find security group {:?}", sg))?;
+    resp.security_groups
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|g| g.group_id)
+        .ok_or_else(|| eyre!("no security group named {:?}", sg))
+}
+
+async fn make_security_group(
+    client: &Ec2Client,
+    name: &str,
+    tags: &Tags,
+) -> Result<String, Report> {
+    let id = client
+        .create_security_group(rusoto_ec2::CreateSecurityGroupRequest {
+            group_name: name.to_owned(),
+            description: "burrito-cloud-exp experiment machines".to_owned(),
+            tag_specifications: Some(vec![tag_spec("security-group", tags)]),
+            ..Default::default()
+        })
+        .await
+        .wrap_err("create security group")?
+        .group_id
+        .ok_or_else(|| eyre!("created security group has no id"))?;
+    // the default VPC's addresses.
+    let vpc = "172.31.0.0/16";
+    for (proto, from, to, cidr) in [
+        ("icmp", -1, -1, "0.0.0.0/0"),
+        ("tcp", 22, 22, "0.0.0.0/0"),
+        ("tcp", 0, 65535, vpc),
+        ("udp", 0, 65535, vpc),
+    ] {
+        client
+            .authorize_security_group_ingress(rusoto_ec2::AuthorizeSecurityGroupIngressRequest {
+                group_id: Some(id.clone()),
+                ip_protocol: Some(proto.to_owned()),
+                from_port: Some(from),
+                to_port: Some(to),
+                cidr_ip: Some(cidr.to_owned()),
+                ..Default::default()
+            })
+            .await
+            .wrap_err("fill in security group")?;
+    }
+
+    Ok(id)
+}
+
+/// Launch an instance named `name`, reachable with `pubkey` (unless `access` names a key pair),
+/// and return its public IP. What it made is recorded in `made` as it goes, so that a failed
+/// launch can be cleaned up with [`cleanup`] too.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(client, pubkey, tags, made), level = "debug")]
+pub async fn launch(
+    client: &Ec2Client,
+    access: &AwsAccess,
+    ami: &str,
+    instance_type: &str,
+    availability_zone: Option<&str>,
+    name: &str,
+    pubkey: &str,
+    tags: &Tags,
+    made: &mut Made,
+) -> Result<String, Report> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let made_name = format!("{}{}-{}", access.prefix, name, now.as_secs());
+    let key_name = match access.key_pair {
+        Some(ref k) => k.clone(),
+        None => {
+            client
+                .import_key_pair(rusoto_ec2::ImportKeyPairRequest {
+                    key_name: made_name.clone(),
+                    public_key_material: pubkey.to_owned().into(),
+                    tag_specifications: Some(vec![tag_spec("key-pair", tags)]),
+                    ..Default::default()
+                })
+                .await
+                .wrap_err("import key pair")?;
+            made.key_pair = Some(made_name.clone());
+            made_name.clone()
+        }
+    };
+    let sg = match access.security_group {
+        Some(ref sg) => security_group_id(client, sg).await?,
+        None => {
+            let id = make_security_group(client, &made_name, tags).await?;
+            made.security_group_id = Some(id.clone());
+            id
+        }
+    };
+
+    let mut instance_tags = tags.clone();
+    instance_tags.insert("Name".to_owned(), name.to_owned());
+    let resp = client
+        .run_instances(rusoto_ec2::RunInstancesRequest {
+            image_id: Some(ami.to_owned()),
+            instance_type: Some(instance_type.to_owned()),
+            min_count: 1,
+            max_count: 1,
+            key_name: Some(key_name.clone()),
+            security_group_ids: Some(vec![sg.clone()]),
+            placement: availability_zone.map(|z| rusoto_ec2::Placement {
+                availability_zone: Some(z.to_owned()),
+                ..Default::default()
+            }),
+            tag_specifications: Some(vec![
+                tag_spec("instance", &instance_tags),
+                tag_spec("volume", tags),
+            ]),
+            ..Default::default()
+        })
+        .await
+        .wrap_err("run instances")?;
+    let id = resp
+        .instances
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|i| i.instance_id)
+        .ok_or_else(|| eyre!("run instances returned no instance"))?;
+    made.instance_id = Some(id.clone());
+    info!(?id, key_pair = ?key_name, security_group = ?sg, "launched instance");
+
+    let start = Instant::now();
+    loop {
+        let inst = client
+            .describe_instances(rusoto_ec2::DescribeInstancesRequest {
+                instance_ids: Some(vec![id.clone()]),
+                ..Default::default()
+            })
+            .await
+            .ok()
+            .and_then(|r| {
+                r.reservations?
+                    .into_iter()
+                    .next()?
+                    .instances?
+                    .into_iter()
+                    .next()
+            });
+        let state = inst.as_ref().and_then(|i| i.state.as_ref()?.name.clone());
+        debug!(?state, "waiting for instance");
+        match (state.as_deref(), inst.and_then(|i| i.public_ip_address)) {
+            (Some("running"), Some(ip)) => return Ok(ip),
+            (Some("shutting-down" | "terminated"), _) => {
+                return Err(eyre!("instance {} went away while booting", id))
+            }
+            _ => (),
+        }
+
+        ensure!(
+            start.elapsed() < BOOT_TIMEOUT,
+            "instance {} did not get a public ip",
+            id
+        );
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Terminate the instance `made`, wait for it to be gone, and delete the key pair and security
+/// group made for it.
+pub async fn cleanup(region: &str, profile: Option<&str>, made: &Made) -> Result<(), Report> {
+    let client = ec2_client(region, profile)?;
+    if let Some(ref id) = made.instance_id {
+        client
+            .terminate_instances(rusoto_ec2::TerminateInstancesRequest {
+                instance_ids: vec![id.clone()],
+                ..Default::default()
+            })
+            .await
+            .wrap_err("terminate instances")?;
+        // the security group can't go until the instance has.
+        crate::teardown::verify(&[crate::teardown::Resource::AwsInstance {
+            region: region.to_owned(),
+            profile: profile.map(str::to_owned),
+            id: id.clone(),
+        }])
+        .await?;
+    }
+
+    if let Some(ref sg) = made.security_group_id {
+        if let Err(err) = client
+            .delete_security_group(rusoto_ec2::DeleteSecurityGroupRequest {
+                group_id: Some(sg.clone()),
+                ..Default::default()
+            })
+            .await
+        {
+            warn!(?err, ?sg, "could not delete security group");
+        }
+    }
+
+    if let Some(ref k) = made.key_pair {
+        if let Err(err) = client
+            .delete_key_pair(rusoto_ec2::DeleteKeyPairRequest {
+                key_name: Some(k.clone()),
+                ..Default::default()
+            })
+            .await
+        {
+            warn!(?err, key_pair = ?k, "could not delete key pair");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+//! A stream of the run's progress, for dashboards and chat bots: one JSON object per event, as it
+//! happens, `POST`ed to an HTTP endpoint or written as a line to a unix socket.
+//!
+//! Every event has `event` and `time` (RFC 3339), and `node` and `rep` where they apply:
+//! - `run_started` (`nodes`), `run_done` (`failed`: the nodes that failed, or `error` if the run
+//!   stopped at one)
+//! - `node_started`, `node_done`, `node_failed` (`error`)
+//! - `setup_done`: the machine is set up, and the repetition's experiments are starting
+//! - `exp_done` (`exp`, `done`, `total`, `wall_secs`): the script moved past an experiment
+//! - `exp_stalled` (`exp`): the script went quiet for the node's stall window
+//! - `collection_done` (`files`, `missing`): the repetition's results are collected
+//!
+//! Delivery is best effort, and never holds up the run: events that can't be delivered are
+//! dropped, and a unix socket that isn't listening is tried again on the next event.
+
+use color_eyre::eyre::{ensure, Report, WrapErr};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+/// Where to send events: an `http://` or `https://` URL, or else a unix socket's path.
+#[derive(Clone, Debug)]
+pub enum Target {
+    Http(String),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for Target {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(if s.starts_with("http://") || s.starts_with("https://") {
+            Target::Http(s.to_owned())
+        } else {
+            Target::Unix(PathBuf::from(s))
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Msg {
+    Event(Value),
+    Flush(oneshot::Sender<()>),
+}
+
+/// A handle to send events through. Without a target, events go nowhere.
+#[derive(Clone, Debug, Default)]
+pub struct Events {
+    tx: Option<mpsc::UnboundedSender<Msg>>,
+    /// Fields every event sent through this handle carries.
+    context: Map<String, Value>,
+    /// The last event, and when (in seconds since the epoch), whether or not there is a target.
+    last: Arc<Mutex<Option<(u64, String)>>>,
+}
+
+impl Events {
+    /// Start delivering events to `target`, in the order they're sent.
+    pub fn start(target: Target) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        info!(?target, "sending events");
+        tokio::spawn(async move {
+            let mut sock: Option<UnixStream> = None;
+            while let Some(msg) = rx.recv().await {
+                let ev = match msg {
+                    Msg::Event(ev) => ev,
+                    Msg::Flush(done) => {
+                        let _ = done.send(());
+                        continue;
+                    }
+                };
+                let res = match target {
+                    Target::Http(ref url) => post(url, &ev).await,
+                    Target::Unix(ref path) => write_line(path, &mut sock, &ev).await,
+                };
+                if let Err(err) = res {
+                    debug!(?err, event = ?ev["event"], "could not deliver event");
+                }
+            }
+        });
+
+        Self {
+            tx: Some(tx),
+            context: Default::default(),
+            last: Default::default(),
+        }
+    }
+
+    /// This handle, with `key: value` added to every event sent through it.
+    pub fn with(&self, key: &str, value: impl Into<Value>) -> Self {
+        let mut e = self.clone();
+        e.context.insert(key.to_owned(), value.into());
+        e
+    }
+
+    /// Send `event`, with the object `fields` merged in.
+    pub fn emit(&self, event: &str, fields: Value) {
+        if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            *self.last.lock().unwrap() = Some((now.as_secs(), event.to_owned()));
+        }
+
+        self.send(event, fields);
+    }
+
+    /// The last event emitted, and when, as seconds since the epoch.
+    pub fn last(&self) -> Option<(u64, String)> {
+        self.last.lock().unwrap().clone()
+    }
+
+    /// Send `event` without it counting as the last one, for events that aren't progress.
+    pub fn send(&self, event: &str, fields: Value) {
+        let Some(ref tx) = self.tx else {
+            return;
+        };
+
+        let mut ev = self.context.clone();
+        ev.insert("event".to_owned(), event.into());
+        ev.insert("time".to_owned(), chrono::Local::now().to_rfc3339().into());
+        if let Value::Object(f) = fields {
+            ev.extend(f);
+        }
+
+        if tx.send(Msg::Event(Value::Object(ev))).is_err() {
+            warn!(?event, "event delivery stopped");
+        }
+    }
+
+    /// Wait for the events sent so far to be delivered (or given up on).
+    pub async fn flush(&self) {
+        if let Some(ref tx) = self.tx {
+            let (done, wait) = oneshot::channel();
+            if tx.send(Msg::Flush(done)).is_ok() {
+                let _ = wait.await;
+            }
+        }
+    }
+}
+
+async fn post(url: &str, ev: &Value) -> Result<(), Report> {
+    let out = tokio::process::Command::new("curl")
+        .args(["-sS", "-f", "-m", "10", "-X", "POST"])
+        .args(["-H", "Content-Type: application/json", "--data-binary"])
+        .arg(ev.to_string())
+        .arg(url)
+        .output()
+        .await
+        .wrap_err("run curl")?;
+    ensure!(
+        out.status.success(),
+        "POST {}: {}",
+        url,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    Ok(())
+}
+
+async fn write_line(path: &Path, sock: &mut Option<UnixStream>, ev: &Value) -> Result<(), Report> {
+    if sock.is_none() {
+        *sock = Some(
+            UnixStream::connect(path)
+                .await
+                .wrap_err_with(|| format!("connect to {:?}", path))?,
+        );
+    }
+
+    let line = format!("{}\n", ev);
+    let res = sock.as_mut().unwrap().write_all(line.as_bytes()).await;
+    if res.is_err() {
+        // reconnect next time.
+        *sock = None;
+    }
+
+    res.wrap_err("write event")
+}
@@ -0,0 +1,1659 @@
+//! Running the experiment script and collecting its results.
+
+use crate::budget::Budget;
+use crate::control::Control;
+use crate::events::Events;
+use crate::machine;
+use crate::net::{self, IpVersion};
+use crate::progress::{Progress, WallTimes, REMOTE_PROGRESS};
+use crate::provenance::{self, Provenance};
+use crate::ready::Readiness;
+use crate::redis::{self, RedisCfg};
+use crate::retry::RetryPolicy;
+use crate::sidecar::{self, Sidecar};
+use crate::ssh::{reconnect, ConnInfo, SshCfg};
+use crate::state::{timed, NodeCheckpoint};
+use crate::summary::{read_latencies, write_summary};
+use crate::sweep::{self, ExpParams, Filter, Filters};
+use crate::tags::Cloud;
+use crate::throttle::{Monitor, ThrottleCfg};
+use crate::transfer::{self, Fetched, Throughput, Transfer};
+use crate::wait_for_continue;
+use color_eyre::eyre::{bail, ensure, Report, WrapErr};
+use openssh::Session;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument, warn};
+
+/// What to run on a machine once it is set up.
+#[derive(Clone, Debug)]
+pub struct Exp {
+    pub python: String,
+    pub script_remote_path: PathBuf,
+    pub bench_remote_path: PathBuf,
+    pub prov: String,
+    pub ssh: SshCfg,
+    /// Local directory results are collected into.
+    pub out_dir: PathBuf,
+    /// Local directory the script's output is kept in, across runs.
+    pub log_dir: PathBuf,
+    /// The node's id, to tell its logs apart.
+    pub node: String,
+    /// Total repetitions of this node, across all its instances.
+    pub reps: usize,
+    /// How many times to re-run experiments whose results did not validate.
+    pub rerun_invalid: usize,
+    pub filters: Filters,
+    pub pause: bool,
+    /// Remote directory everything is uploaded to, run in, and collected from, instead of the home
+    /// directory. Relative to the home directory, unless absolute.
+    pub workdir: Option<String>,
+    /// Remote directory to run the script in, instead of `workdir`. Results are copied back to
+    /// `workdir` once the script is done.
+    pub scratch_dir: Option<String>,
+    pub disk_guard: Option<DiskGuard>,
+    pub stall: Option<StallGuard>,
+    /// Kill the script if it is still running after this long.
+    pub script_timeout: Duration,
+    pub debug: Option<DebugWrapper>,
+    pub redis: Option<RedisCfg>,
+    pub sidecars: Vec<Sidecar>,
+    /// All the machines of a multi-host node, as `user@host`, passed to the script in
+    /// [`HOSTS_ENV`].
+    pub hosts: Vec<String>,
+    /// The machines of a multi-machine cloud node, as (role, `user@host`), passed to the script
+    /// in [`ROLES_ENV`].
+    pub roles: Vec<(String, String)>,
+    /// More script arguments, after the provider: where the services the node uses are.
+    pub args: Vec<String>,
+    pub ready: Option<Readiness>,
+    pub ip_version: IpVersion,
+    pub mtu: Option<u32>,
+    /// More environment variables for the script.
+    pub env: Vec<(String, String)>,
+    /// Put this in result file names (see [`provenance::stamped`]).
+    pub stamp: Option<String>,
+    /// Remove the results (and logs) of earlier runs before starting the script.
+    pub clean: bool,
+    /// Skip result files older than the script run, rather than collect leftovers.
+    pub skip_stale: bool,
+    pub pre_exp: Hooks,
+    pub post_exp: Hooks,
+    pub control: Control,
+    /// Scoped to the node (and in a repetition, to it).
+    pub events: Events,
+    pub budget: Budget,
+    /// The provider's API, for machines we launched on one.
+    pub cloud: Option<Cloud>,
+    pub throttle: ThrottleCfg,
+    pub transfer: transfer::Backend,
+    pub bwlimit: Option<u64>,
+    pub transfer_retry: RetryPolicy,
+    pub ckpt: NodeCheckpoint,
+}
+
+/// Abort the script if free space where it runs drops below `min_free_mb`, rather than let it
+/// write truncated results.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct DiskGuard {
+    pub min_free_mb: u64,
+    #[serde(default = "default_guard_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_guard_interval_secs() -> u64 {
+    30
+}
+
+/// Count the script as stalled once it has written no output and no result files for
+/// `window_secs`: record what the machine is doing (`ps`, `ss`, and a `py-spy dump` of the
+/// script) in the log, and with `retry`, kill the script and run the experiment it was on again,
+/// once.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct StallGuard {
+    pub window_secs: u64,
+    #[serde(default)]
+    pub retry: bool,
+}
+
+/// Once a repetition's results are collected, run some of its experiments again with the bench
+/// under `wrapper`, e.g. `strace -f -o {out}`, `ltrace -f -o {out}` or `rr record -o {out}`, and
+/// fetch what it wrote into the repetition's `traces.tar.gz`. `{out}` is a fresh path for each
+/// bench invocation, under the remote `traces` directory. The traced run's results stay on the
+/// machine: tracing skews them.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct DebugWrapper {
+    pub wrapper: String,
+    /// The experiments to trace, written like `--only` filters; all of them if empty.
+    #[serde(default)]
+    pub exps: Vec<Filter>,
+    /// Only trace those that failed, or whose results did not validate.
+    #[serde(default)]
+    pub failed_only: bool,
+}
+
+impl DebugWrapper {
+    pub fn check(&self) -> Result<(), Report> {
+        ensure!(
+            self.wrapper.contains("{out}"),
+            "debug wrapper {:?} must write its trace to {{out}}, to be fetched",
+            self.wrapper
+        );
+        Ok(())
+    }
+}
+
+/// Commands to run around each repetition, local ones first. A failing `pre_exp` hook fails the
+/// repetition; `post_exp` hooks run once results are collected, so their failures are only
+/// logged.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct Hooks {
+    /// Run here, with `BURRITO_EXP_HOST` (`user@host`), `BURRITO_EXP_PORT` and
+    /// `BURRITO_EXP_OUT_DIR` (the repetition's output directory) set.
+    #[serde(default)]
+    pub local: Vec<String>,
+    /// Run on the machine, in the directory the script runs in.
+    #[serde(default)]
+    pub remote: Vec<String>,
+}
+
+impl Hooks {
+    async fn run(
+        &self,
+        which: &str,
+        ssh: &Session,
+        conn: &ConnInfo,
+        workdir: Option<&str>,
+        dir: &Path,
+    ) -> Result<(), Report> {
+        for c in &self.local {
+            info!(hook = which, cmd = ?c, "local hook");
+            let st = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(c)
+                .env("BURRITO_EXP_HOST", format!("{}@{}", conn.user, conn.host))
+                .env("BURRITO_EXP_PORT", conn.port.to_string())
+                .env("BURRITO_EXP_OUT_DIR", dir)
+                .status()
+                .await
+                .wrap_err_with(|| format!("{} hook", which))?;
+            ensure!(st.success(), "{} hook {:?} failed", which, c);
+        }
+
+        for c in &self.remote {
+            info!(hook = which, cmd = ?c, "remote hook");
+            let cmd = match workdir {
+                Some(wd) => format!("cd {} && {}", wd, c),
+                None => c.clone(),
+            };
+            let st = ssh
+                .shell(&cmd)
+                .status()
+                .await
+                .wrap_err_with(|| format!("{} hook", which))?;
+            ensure!(st.success(), "{} hook {:?} failed", which, c);
+        }
+
+        Ok(())
+    }
+}
+
+// remote files the detached script's output and exit code are written to.
+const REMOTE_STDOUT: &str = "exp.stdout";
+const REMOTE_STDERR: &str = "exp.stderr";
+const REMOTE_STATUS: &str = "exp.status";
+// the exit code is written here first, so the status file only appears once progress is complete.
+const REMOTE_CODE: &str = "exp.code";
+// the bench binary is swapped for this, to run it under the debug wrapper, which writes traces
+// here.
+const REMOTE_DEBUG_BENCH: &str = "bench-debug.sh";
+const REMOTE_TRACES: &str = "traces";
+// the script's process group, so it can be killed with everything it started.
+pub const REMOTE_PID: &str = "exp.pid";
+
+/// How much of a failed script's stderr to print; all of it is in the log directory.
+const STDERR_TAIL: usize = 40;
+
+/// The experiments (result file names, comma-separated) the script should run. Scripts that ignore
+/// this run the whole sweep, which is also fine: we only collect the ones we asked for.
+const ONLY_ENV: &str = "BURRITO_EXP_ONLY";
+
+/// The machines of a multi-host node (comma-separated `user@host`), starting with the one the
+/// script runs on. Unset for single-machine nodes.
+const HOSTS_ENV: &str = "BURRITO_EXP_HOSTS";
+
+/// The machines of a multi-machine cloud node by role (comma-separated `role=user@host`, with
+/// private addresses), starting with the one the script runs on, `main`.
+const ROLES_ENV: &str = "BURRITO_EXP_ROLES";
+
+/// The script may report how each experiment went by writing this file, a JSON object from result
+/// file name to [`ExpStatus`].
+const REMOTE_EXP_STATUS: &str = "status.json";
+
+/// One experiment's outcome, as reported by the script.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ExpStatus {
+    pub ok: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+pub type ExpStatuses = BTreeMap<String, ExpStatus>;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The result of a detached script run.
+#[derive(Debug)]
+struct ScriptOutput {
+    code: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    walls: WallTimes,
+    /// Why we killed the script, if we did.
+    aborted: Option<String>,
+    interrupted: Option<Interrupt>,
+    /// The experiment it stalled on (if we could tell), and what the machine was doing, for
+    /// each stall.
+    stalls: Vec<(Option<String>, String)>,
+}
+
+/// Why we killed the script at the user's request.
+#[derive(Debug)]
+enum Interrupt {
+    /// Skip the experiment it was on, if we could tell which.
+    SkipExp(Option<String>),
+    SkipNode,
+    /// The script stalled on this experiment, which should run again.
+    Stalled(String),
+}
+
+/// What a finished (or aborted) script left us with.
+#[derive(Debug)]
+struct ScriptRun {
+    code: Option<i32>,
+    walls: WallTimes,
+    aborted: Option<String>,
+    /// The rest of the node was skipped at the user's request.
+    skipped_node: bool,
+    /// Statuses from runs of the script that were interrupted and restarted.
+    statuses: ExpStatuses,
+    /// Experiments the script stalled on.
+    stalled: Vec<String>,
+}
+
+/// What one repetition produced, as recorded in the index.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct RepResult {
+    pub rep: usize,
+    pub dir: PathBuf,
+    pub code: Option<i32>,
+    pub files: Vec<String>,
+    /// Files that were collected but did not validate.
+    pub invalid: Vec<String>,
+    /// Experiments the script reported as failed.
+    #[serde(default)]
+    pub failed: Vec<String>,
+    /// Experiments that ran with the CPU throttled.
+    #[serde(default)]
+    pub throttled: Vec<String>,
+    /// Result files that weren't on the machine.
+    #[serde(default)]
+    pub missing: Vec<String>,
+    /// Result files that were on the machine, but could not be fetched.
+    #[serde(default)]
+    pub transfer_failed: Vec<String>,
+    /// Experiments the script stalled on.
+    #[serde(default)]
+    pub stalled: Vec<String>,
+    /// Every download of result files, reruns' included.
+    #[serde(default)]
+    pub collected: Throughput,
+}
+
+/// What collecting some result files got.
+#[derive(Debug, Default)]
+struct Collected {
+    got: Vec<String>,
+    missing: Vec<String>,
+    failed: Vec<String>,
+    /// Per file, and over all of them.
+    rates: BTreeMap<String, Throughput>,
+    total: Throughput,
+}
+
+impl Exp {
+    /// Where repetition `rep`'s results go. With a single repetition, that's `out_dir` itself.
+    fn rep_dir(&self, rep: usize) -> PathBuf {
+        if self.reps == 1 {
+            self.out_dir.clone()
+        } else {
+            self.out_dir.join(format!("rep-{}", rep))
+        }
+    }
+
+    /// A fresh log file for a script run, e.g. `<log_dir>/aws-node-20260101T120000.rerun-1.log`.
+    /// `what` tells reruns apart.
+    fn log_path(&self, rep: usize, what: &str) -> PathBuf {
+        let rep = if self.reps == 1 {
+            String::new()
+        } else {
+            format!("-rep-{}", rep)
+        };
+        self.log_dir.join(format!(
+            "{}-{}{}-{}{}.log",
+            self.prov,
+            self.node,
+            rep,
+            chrono::Local::now().format("%Y%m%dT%H%M%S"),
+            what
+        ))
+    }
+
+    /// `name`, in the working directory.
+    fn remote(&self, name: &str) -> String {
+        match self.workdir {
+            Some(ref wd) => format!("{}/{}", wd, name),
+            None => name.to_owned(),
+        }
+    }
+
+    /// The working directory, for a shell anywhere.
+    fn abs_workdir(&self) -> String {
+        match self.workdir {
+            Some(ref wd) if wd.starts_with('/') => wd.clone(),
+            Some(ref wd) => format!("$HOME/{}", wd),
+            None => "$HOME".to_owned(),
+        }
+    }
+
+    /// This, with the addresses of the node's machines found (and checked) over IPv6.
+    async fn with_v6(&self, v: IpVersion, ssh: &Session, conn: &ConnInfo) -> Result<Exp, Report> {
+        let hosts = if self.hosts.is_empty() {
+            vec![format!("{}@{}", conn.user, conn.host)]
+        } else {
+            self.hosts.clone()
+        };
+        let v6 = net::check_v6(ssh, &hosts).await?;
+        let mut exp = self.clone();
+        exp.env.push((net::IPV6_ENV.to_owned(), v6.addr));
+        if !self.hosts.is_empty() {
+            match v {
+                IpVersion::Dual => exp
+                    .env
+                    .push((net::HOSTS6_ENV.to_owned(), v6.hosts.join(","))),
+                _ => exp.hosts = v6.hosts,
+            }
+        }
+
+        Ok(exp)
+    }
+
+    /// Where the script runs, relative to the home directory unless absolute.
+    fn run_dir(&self) -> Option<&str> {
+        self.scratch_dir.as_deref().or(self.workdir.as_deref())
+    }
+
+    fn script_cmd(&self, only: Option<&[String]>) -> String {
+        // python only flushes stdout line by line if it's a terminal, and we need lines as they
+        // happen to timestamp them.
+        let mut env = "PYTHONUNBUFFERED=1 ".to_owned();
+        if let Some(fnames) = only {
+            env.push_str(&format!("{}={} ", ONLY_ENV, fnames.join(",")));
+        }
+        if !self.hosts.is_empty() {
+            env.push_str(&format!("{}={} ", HOSTS_ENV, self.hosts.join(",")));
+        }
+        if !self.roles.is_empty() {
+            let roles: Vec<String> = self
+                .roles
+                .iter()
+                .map(|(r, h)| format!("{}={}", r, h))
+                .collect();
+            env.push_str(&format!("{}={} ", ROLES_ENV, roles.join(",")));
+        }
+        let redis_env = self.redis.iter().flat_map(|r| r.env(&self.abs_workdir()));
+        for (k, v) in self.env.iter().cloned().chain(redis_env) {
+            env.push_str(&format!("{}={} ", k, v));
+        }
+        // the arguments are names and addresses, so they need no quoting.
+        let args: String = self.args.iter().map(|a| format!(" {}", a)).collect();
+        match self.scratch_dir {
+            None => format!(
+                "{}{} {} {} {}{}",
+                env,
+                self.python,
+                self.script_remote_path.to_str().unwrap(),
+                Path::new(".")
+                    .join(&self.bench_remote_path)
+                    .to_str()
+                    .unwrap(),
+                self.prov,
+                args,
+            ),
+            // in a subshell, so the status files still go in the working directory.
+            Some(ref sd) => format!(
+                "(cd {} && {}{} {wd}/{} {wd}/{} {}{})",
+                sd,
+                env,
+                self.python,
+                self.script_remote_path.to_str().unwrap(),
+                self.bench_remote_path.to_str().unwrap(),
+                self.prov,
+                args,
+                wd = self.abs_workdir(),
+            ),
+        }
+    }
+
+    /// Copy `fnames` (and the script's experiment statuses) from the scratch directory to the
+    /// working directory, where we collect them from.
+    async fn stage(&self, ssh: &Session, fnames: &[String]) -> Result<(), Report> {
+        let sd = match self.scratch_dir {
+            Some(ref sd) => sd,
+            None => return Ok(()),
+        };
+
+        let cmd = format!(
+            "cd {} && {{ for f in {} {}; do [ -e \"$f\" ] && cp -fp \"$f\" {}/; done; true; }}",
+            sd,
+            fnames.join(" "),
+            REMOTE_EXP_STATUS,
+            self.abs_workdir(),
+        );
+        let st = ssh.shell(cmd).status().await.wrap_err("stage results")?;
+        ensure!(st.success(), "could not copy results out of {}", sd);
+        Ok(())
+    }
+
+    /// Remove `fnames`, and the last script run's output, so nothing stale gets collected.
+    async fn clean(&self, ssh: &Session, fnames: &[String]) -> Result<(), Report> {
+        let mut cmd = format!(
+            "cd {} && rm -f {} {} {} {} {} {} {} {}",
+            self.abs_workdir(),
+            fnames.join(" "),
+            REMOTE_STDOUT,
+            REMOTE_STDERR,
+            REMOTE_STATUS,
+            REMOTE_CODE,
+            REMOTE_PID,
+            REMOTE_PROGRESS,
+            REMOTE_EXP_STATUS,
+        );
+        if let Some(ref sd) = self.scratch_dir {
+            cmd.push_str(&format!(
+                " && cd {} && rm -f {} {}",
+                sd,
+                fnames.join(" "),
+                REMOTE_EXP_STATUS
+            ));
+        }
+
+        info!(files = ?fnames.len(), "removing earlier results");
+        let st = ssh.shell(cmd).status().await.wrap_err("clean")?;
+        ensure!(st.success(), "could not remove earlier results");
+        Ok(())
+    }
+
+    /// Start the script detached from our ssh session, so a dropped connection doesn't kill
+    /// it.
+    async fn start(&self, ssh: &Session, only: Option<&[String]>) -> Result<(), Report> {
+        let st = ssh
+            .command("sh")
+            .arg("-c")
+            .arg(self.start_cmd())
+            .arg("sh")
+            .arg(self.pipeline(only))
+            .status()
+            .await
+            .wrap_err("start script")?;
+        ensure!(st.success(), "could not start script");
+        Ok(())
+    }
+
+    /// The shell command starting `$1`, the [`Self::pipeline`], detached.
+    fn start_cmd(&self) -> String {
+        format!(
+            "{cd}rm -f {status} {progress} {exp_status}{wd_exp_status}; nohup setsid sh -c \"$1\" \
+            > /dev/null 2>&1 < /dev/null & echo $! > {pid}",
+            cd = match self.workdir {
+                Some(ref wd) => format!("cd {} && ", wd),
+                None => String::new(),
+            },
+            status = REMOTE_STATUS,
+            progress = REMOTE_PROGRESS,
+            pid = REMOTE_PID,
+            exp_status = REMOTE_EXP_STATUS,
+            wd_exp_status = match self.scratch_dir {
+                Some(ref sd) => format!(" {}/{}", sd, REMOTE_EXP_STATUS),
+                None => String::new(),
+            },
+        )
+    }
+
+    /// What the detached shell runs: the script, its output timestamped line by line, and then
+    /// its exit status.
+    fn pipeline(&self, only: Option<&[String]>) -> String {
+        let cmd = self.script_cmd(only);
+        info!(?cmd, "running");
+        format!(
+            "{{ {cmd} 2> {err}; echo $? > {code}; }} | tee {out} \
+            | {{ while IFS= read -r l; do printf \"%s %s\\n\" \"$(date +%s.%N)\" \"$l\"; done; date +%s.%N; }} > {progress}; \
+            mv {code} {status}",
+            cmd = cmd,
+            out = REMOTE_STDOUT,
+            err = REMOTE_STDERR,
+            code = REMOTE_CODE,
+            status = REMOTE_STATUS,
+            progress = REMOTE_PROGRESS,
+        )
+    }
+
+    /// Poll until the script is done, re-establishing the session whenever it breaks.
+    async fn wait(
+        &self,
+        conn: &ConnInfo,
+        ssh: &mut Session,
+        expected: &[String],
+        monitor: &mut Monitor,
+    ) -> Result<ScriptOutput, Report> {
+        let reconnect_timeout = Duration::from_secs(self.ssh.reconnect_timeout_secs);
+        let mut progress = Progress::new(expected, self.events.clone());
+        let guard_interval = self
+            .disk_guard
+            .as_ref()
+            .map(|g| Duration::from_secs(g.interval_secs));
+        let mut last_guard = Instant::now();
+        let mut stalls = vec![];
+        // the progress file's length, and the result files, as of the last change to either.
+        let mut last_seen = (0, String::new());
+        let mut last_change = Instant::now();
+        let started = Instant::now();
+        loop {
+            tokio::time::sleep(guard_interval.map_or(POLL_INTERVAL, |g| g.min(POLL_INTERVAL)))
+                .await;
+            if guard_interval.is_some_and(|g| last_guard.elapsed() >= g) {
+                last_guard = Instant::now();
+                if let Some(why) = self.check_disk(ssh).await {
+                    warn!(%why, "aborting script");
+                    kill_script(ssh, &self.remote(REMOTE_PID)).await?;
+                    poll_progress(ssh, &self.remote(REMOTE_PROGRESS), &mut progress).await;
+                    return Ok(ScriptOutput {
+                        code: None,
+                        stdout: read_remote(ssh, &self.remote(REMOTE_STDOUT)).await?,
+                        stderr: read_remote(ssh, &self.remote(REMOTE_STDERR)).await?,
+                        walls: progress.into_wall_times(),
+                        aborted: Some(why),
+                        interrupted: None,
+                        stalls,
+                    });
+                }
+            }
+
+            match ssh
+                .command("cat")
+                .arg(self.remote(REMOTE_STATUS))
+                .output()
+                .await
+            {
+                Ok(out) if out.status.success() => {
+                    let code = String::from_utf8_lossy(&out.stdout).trim().parse().ok();
+                    poll_progress(ssh, &self.remote(REMOTE_PROGRESS), &mut progress).await;
+                    let stdout = read_remote(ssh, &self.remote(REMOTE_STDOUT)).await?;
+                    let stderr = read_remote(ssh, &self.remote(REMOTE_STDERR)).await?;
+                    return Ok(ScriptOutput {
+                        code,
+                        stdout,
+                        stderr,
+                        walls: progress.into_wall_times(),
+                        aborted: None,
+                        interrupted: None,
+                        stalls,
+                    });
+                }
+                Ok(_) => {
+                    // it could have finished since we looked, so check the status file again.
+                    let alive = ssh
+                        .shell(format!(
+                            "kill -0 -- -$(cat {}) 2>/dev/null || [ -e {} ]",
+                            self.remote(REMOTE_PID),
+                            self.remote(REMOTE_STATUS)
+                        ))
+                        .status()
+                        .await;
+                    let why = match alive {
+                        Ok(st) if st.code() == Some(1) => {
+                            Some("the script exited without writing its exit status".to_owned())
+                        }
+                        _ if started.elapsed() >= self.script_timeout => {
+                            kill_script(ssh, &self.remote(REMOTE_PID)).await?;
+                            Some(format!(
+                                "the script was still running after {}s",
+                                self.script_timeout.as_secs()
+                            ))
+                        }
+                        _ => None,
+                    };
+                    if let Some(why) = why {
+                        warn!(%why, "giving up on script");
+                        poll_progress(ssh, &self.remote(REMOTE_PROGRESS), &mut progress).await;
+                        return Ok(ScriptOutput {
+                            code: None,
+                            stdout: read_remote(ssh, &self.remote(REMOTE_STDOUT)).await?,
+                            stderr: read_remote(ssh, &self.remote(REMOTE_STDERR)).await?,
+                            walls: progress.into_wall_times(),
+                            aborted: Some(why),
+                            interrupted: None,
+                            stalls,
+                        });
+                    }
+
+                    debug!("script still running");
+                    poll_progress(ssh, &self.remote(REMOTE_PROGRESS), &mut progress).await;
+                    monitor.sample(ssh, progress.current()).await;
+                    let skip_exp = self.control.take_skip_exp();
+                    if skip_exp || self.control.skip_node() {
+                        let interrupt = if skip_exp {
+                            Interrupt::SkipExp(progress.current().map(str::to_owned))
+                        } else {
+                            Interrupt::SkipNode
+                        };
+                        warn!(?interrupt, "killing script by request");
+                        kill_script(ssh, &self.remote(REMOTE_PID)).await?;
+                        return Ok(ScriptOutput {
+                            code: None,
+                            stdout: read_remote(ssh, &self.remote(REMOTE_STDOUT)).await?,
+                            stderr: read_remote(ssh, &self.remote(REMOTE_STDERR)).await?,
+                            walls: progress.into_wall_times(),
+                            aborted: None,
+                            interrupted: Some(interrupt),
+                            stalls,
+                        });
+                    }
+
+                    if let Some(ref g) = self.stall {
+                        let seen = (progress.offset(), self.result_files(ssh).await);
+                        if seen != last_seen {
+                            last_seen = seen;
+                            last_change = Instant::now();
+                        } else if last_change.elapsed() >= Duration::from_secs(g.window_secs) {
+                            last_change = Instant::now();
+                            let current = progress.current().map(str::to_owned);
+                            warn!(exp = ?current, window_secs = g.window_secs, "script stalled");
+                            self.events
+                                .emit("exp_stalled", serde_json::json!({ "exp": current }));
+                            stalls.push((current.clone(), self.diagnose(ssh).await));
+                            // without knowing what it's on, there's nothing to run again.
+                            if let Some(cur) = current.filter(|_| g.retry) {
+                                warn!(exp = ?cur, "killing stalled script");
+                                kill_script(ssh, &self.remote(REMOTE_PID)).await?;
+                                return Ok(ScriptOutput {
+                                    code: None,
+                                    stdout: read_remote(ssh, &self.remote(REMOTE_STDOUT)).await?,
+                                    stderr: read_remote(ssh, &self.remote(REMOTE_STDERR)).await?,
+                                    walls: progress.into_wall_times(),
+                                    aborted: None,
+                                    interrupted: Some(Interrupt::Stalled(cur)),
+                                    stalls,
+                                });
+                            }
+                        }
+                    }
+                }
+                Err(err) => {
+                    if ssh.check().await.is_ok() {
+                        debug!(?err, "poll failed, but connection is alive");
+                        continue;
+                    }
+
+                    warn!(?err, "connection lost, reconnecting");
+                    *ssh = reconnect(conn, reconnect_timeout).await?;
+                }
+            }
+        }
+    }
+
+    /// Run the script to completion on `fnames`, writing its output to `log`, and return its exit
+    /// code and experiments' wall times.
+    async fn run_script(
+        &self,
+        conn: &ConnInfo,
+        ssh: &mut Session,
+        fnames: &[String],
+        only: bool,
+        log: &Path,
+        monitor: &mut Monitor,
+    ) -> Result<ScriptRun, Report> {
+        self.start(ssh, Some(fnames).filter(|_| only)).await?;
+        self.finish_script(conn, ssh, fnames, log, monitor).await
+    }
+
+    /// Wait for an already-started script, writing its output to `log`, and return its exit code
+    /// and experiments' wall times. If asked to skip an experiment, the script is restarted on
+    /// the ones it hasn't got to.
+    async fn finish_script(
+        &self,
+        conn: &ConnInfo,
+        ssh: &mut Session,
+        fnames: &[String],
+        log: &Path,
+        monitor: &mut Monitor,
+    ) -> Result<ScriptRun, Report> {
+        let mut out = self.wait(conn, ssh, fnames, monitor).await?;
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        let mut walls = WallTimes::new();
+        let mut statuses = ExpStatuses::new();
+        let mut skipped = vec![];
+        let mut stalls = vec![];
+        let mut retried = vec![];
+        while matches!(
+            out.interrupted,
+            Some(Interrupt::SkipExp(_) | Interrupt::Stalled(_))
+        ) {
+            match out.interrupted.take() {
+                Some(Interrupt::SkipExp(cur)) => skipped.extend(cur),
+                // each stalled experiment runs again once.
+                Some(Interrupt::Stalled(cur)) if retried.contains(&cur) => skipped.push(cur),
+                Some(Interrupt::Stalled(cur)) => retried.push(cur),
+                _ => unreachable!(),
+            }
+
+            stalls.append(&mut out.stalls);
+
+            stdout.append(&mut out.stdout);
+            stderr.append(&mut out.stderr);
+            walls.append(&mut out.walls);
+            // restarting clears the statuses.
+            self.stage(ssh, fnames).await?;
+            statuses.extend(fetch_statuses(ssh, &self.remote(REMOTE_EXP_STATUS)).await);
+            let left: Vec<String> = fnames
+                .iter()
+                .filter(|f| !walls.contains_key(*f) && !skipped.contains(f))
+                .cloned()
+                .collect();
+            if left.is_empty() {
+                break;
+            }
+
+            info!(?skipped, left = ?left.len(), "restarting script on the remaining experiments");
+            // the restarted script's output overwrites the remote files.
+            stderr.extend(format!("--- restarted on {} experiments\n", left.len()).bytes());
+            self.start(ssh, Some(&left)).await?;
+            out = self.wait(conn, ssh, &left, monitor).await?;
+        }
+
+        self.stage(ssh, fnames).await?;
+        stdout.append(&mut out.stdout);
+        stderr.append(&mut out.stderr);
+        walls.append(&mut out.walls);
+        stalls.append(&mut out.stalls);
+        let err_log = log.with_extension("stderr.log");
+        tokio::fs::write(log, stdout).await?;
+        tokio::fs::write(&err_log, &stderr).await?;
+        if !stalls.is_empty() {
+            let stall_log = log.with_extension("stalls.log");
+            let text: String = stalls
+                .iter()
+                .map(|(exp, diag)| {
+                    format!(
+                        "=== stalled on {}\n{}\n",
+                        exp.as_deref().unwrap_or("?"),
+                        diag
+                    )
+                })
+                .collect();
+            tokio::fs::write(&stall_log, text).await?;
+            warn!(stalls = stalls.len(), log = ?stall_log, "script stalled");
+        }
+        if out.code != Some(0) {
+            let stderr = String::from_utf8_lossy(&stderr);
+            let lines: Vec<&str> = stderr.lines().collect();
+            let tail = lines[lines.len().saturating_sub(STDERR_TAIL)..].join("\n");
+            warn!(code = ?out.code, stderr = ?err_log, %tail, "script failed");
+        }
+
+        Ok(ScriptRun {
+            code: out.code,
+            walls,
+            aborted: out.aborted,
+            skipped_node: matches!(out.interrupted, Some(Interrupt::SkipNode)),
+            statuses,
+            stalled: stalls.into_iter().filter_map(|(e, _)| e).collect(),
+        })
+    }
+
+    /// Run `fnames` again with the bench under the debug wrapper, and fetch the traces into
+    /// `dir`.
+    #[allow(clippy::too_many_arguments)]
+    async fn trace(
+        &self,
+        conn: &ConnInfo,
+        ssh: &mut Session,
+        dbg: &DebugWrapper,
+        fnames: &[String],
+        dir: &Path,
+        log: &Path,
+        monitor: &mut Monitor,
+    ) -> Result<(), Report> {
+        let wd = self.abs_workdir();
+        let wrapper = dbg.wrapper.replace("{out}", "\"$out\"");
+        let bench = format!("{}/{}", wd, self.bench_remote_path.to_str().unwrap());
+        let cmd = format!(
+            "cd {wd} && rm -rf {traces} {traces}.tar.gz && mkdir {traces} && cat > {wrapped} <<'EOF'
+#!/bin/sh
+out={wd}/{traces}/$(date +%s.%N).$$
+exec {wrapper} {bench} \"$@\"
+EOF
+chmod +x {wrapped}",
+            wd = wd,
+            traces = REMOTE_TRACES,
+            wrapped = REMOTE_DEBUG_BENCH,
+            wrapper = wrapper,
+            bench = bench,
+        );
+        let st = ssh
+            .shell(cmd)
+            .status()
+            .await
+            .wrap_err("write debug wrapper")?;
+        ensure!(st.success(), "could not write debug wrapper");
+
+        info!(wrapper = ?dbg.wrapper, exps = ?fnames, "tracing experiments");
+        let traced = Exp {
+            bench_remote_path: PathBuf::from(REMOTE_DEBUG_BENCH),
+            ..self.clone()
+        };
+        let run = traced
+            .run_script(conn, ssh, fnames, true, log, monitor)
+            .await?;
+        if run.code != Some(0) {
+            warn!(code = ?run.code, "traced script run failed");
+        }
+
+        let st = ssh
+            .shell(format!(
+                "cd {} && tar -czf {traces}.tar.gz {traces}",
+                wd,
+                traces = REMOTE_TRACES
+            ))
+            .status()
+            .await
+            .wrap_err("pack traces")?;
+        ensure!(st.success(), "could not pack traces");
+        let tarball = format!("{}.tar.gz", REMOTE_TRACES);
+        let to = dir.join(&tarball);
+        match self.fetch(conn, ssh, &tarball, &to).await? {
+            Fetched::Got(t) => info!(path = ?to, bytes = t.bytes, "fetched traces"),
+            Fetched::Absent => warn!("no traces to fetch"),
+        }
+
+        Ok(())
+    }
+
+    /// Fetch `name`, from the working directory, to `to`.
+    async fn fetch(
+        &self,
+        conn: &ConnInfo,
+        ssh: &Session,
+        name: &str,
+        to: &Path,
+    ) -> Result<Fetched, Report> {
+        let xfer = Transfer::new(
+            ssh,
+            conn,
+            self.transfer,
+            self.bwlimit,
+            self.transfer_retry.clone(),
+        )
+        .await?;
+        xfer.download(ssh, &self.remote(name), to).await
+    }
+
+    /// The result files where the script runs, with their sizes and modification times.
+    async fn result_files(&self, ssh: &Session) -> String {
+        let dir = self.run_dir().unwrap_or(".");
+        match ssh
+            .shell(format!(
+                "cd {} && stat -c '%n %s %Y' *.data 2>/dev/null | sort",
+                dir
+            ))
+            .output()
+            .await
+        {
+            Ok(out) => String::from_utf8_lossy(&out.stdout).into_owned(),
+            Err(err) => {
+                debug!(?err, "could not list result files");
+                String::new()
+            }
+        }
+    }
+
+    /// What the machine is doing: the script's processes, sockets, and python stacks.
+    async fn diagnose(&self, ssh: &Session) -> String {
+        let cmd = format!(
+            "pid=$(cat {pid}); echo '== ps'; ps -o pid,ppid,stat,etime,time,args -g $pid; \
+            echo '== ss'; ss -tanp; \
+            echo '== py-spy'; export PATH=$PATH:$HOME/.local/bin; \
+            command -v py-spy >/dev/null || {python} -m pip install -q --user py-spy >/dev/null 2>&1; \
+            for p in $(pgrep -g $pid python); do echo \"-- $p\"; \
+            timeout 60 sudo -n $(command -v py-spy) dump --pid $p 2>&1; done",
+            pid = self.remote(REMOTE_PID),
+            python = self.python,
+        );
+        match ssh.shell(cmd).output().await {
+            Ok(out) => format!(
+                "{}{}",
+                String::from_utf8_lossy(&out.stdout),
+                String::from_utf8_lossy(&out.stderr)
+            ),
+            Err(err) => format!("could not get diagnostics: {:#}", err),
+        }
+    }
+
+    /// If the disk guard is tripped, why.
+    async fn check_disk(&self, ssh: &Session) -> Option<String> {
+        let guard = self.disk_guard.as_ref()?;
+        let dir = self.run_dir().unwrap_or(".");
+        let out = match ssh.command("df").args(["-Pk", dir]).output().await {
+            Ok(out) if out.status.success() => out.stdout,
+            Ok(_) | Err(_) => {
+                debug!("could not check free disk space");
+                return None;
+            }
+        };
+
+        // the available column of the second line.
+        let free_kb: u64 = String::from_utf8_lossy(&out)
+            .lines()
+            .nth(1)?
+            .split_whitespace()
+            .nth(3)?
+            .parse()
+            .ok()?;
+        let free_mb = free_kb / 1024;
+        debug!(?free_mb, "free disk space");
+        if free_mb < guard.min_free_mb {
+            Some(format!(
+                "only {} MB free in {}, below the {} MB guard",
+                free_mb, dir, guard.min_free_mb
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+async fn kill_script(ssh: &Session, pid_file: &str) -> Result<(), Report> {
+    let st = ssh
+        .shell(format!("kill -TERM -- -$(cat {})", pid_file))
+        .status()
+        .await
+        .wrap_err("kill script")?;
+    ensure!(st.success(), "could not kill script");
+    Ok(())
+}
+
+async fn read_remote(ssh: &Session, path: &str) -> Result<Vec<u8>, Report> {
+    let out = ssh
+        .command("cat")
+        .arg(path)
+        .output()
+        .await
+        .wrap_err_with(|| format!("read remote {}", path))?;
+    Ok(out.stdout)
+}
+
+/// The script's per-experiment statuses, if it wrote any.
+async fn fetch_statuses(ssh: &Session, path: &str) -> ExpStatuses {
+    let out = match ssh.command("cat").arg(path).output().await {
+        Ok(out) if out.status.success() => out.stdout,
+        Ok(_) => {
+            debug!("script did not report experiment statuses");
+            return Default::default();
+        }
+        Err(err) => {
+            warn!(?err, "could not read experiment statuses");
+            return Default::default();
+        }
+    };
+
+    serde_json::from_slice(&out).unwrap_or_else(|err| {
+        warn!(?err, "malformed experiment statuses");
+        Default::default()
+    })
+}
+
+async fn poll_progress(ssh: &Session, path: &str, progress: &mut Progress) {
+    let from = format!("+{}", progress.offset() + 1);
+    match ssh.command("tail").args(["-c", &from, path]).output().await {
+        Ok(out) if out.status.success() => progress.feed(&out.stdout),
+        Ok(_) => debug!("no progress output yet"),
+        Err(err) => debug!(?err, "could not read progress"),
+    }
+}
+
+/// The machine's clock, in seconds since the epoch.
+async fn remote_now(ssh: &Session) -> Result<u64, Report> {
+    let out = ssh.command("date").arg("+%s").output().await?;
+    ensure!(out.status.success(), "date failed");
+    Ok(String::from_utf8(out.stdout)?.trim().parse()?)
+}
+
+async fn remote_mtime(ssh: &Session, path: &str) -> Option<u64> {
+    let out = ssh
+        .command("stat")
+        .args(["-c", "%Y", path])
+        .output()
+        .await
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&out.stdout).trim().parse().ok()
+}
+
+/// Run repetitions `reps` one after another on the same machine.
+pub async fn run_reps(
+    conn: &ConnInfo,
+    exp: &Exp,
+    reps: Range<usize>,
+) -> Result<Vec<RepResult>, Report> {
+    let mut results = vec![];
+    for rep in reps {
+        results.push(do_exp(conn, exp, rep).await?);
+        if exp.control.skip_node() || exp.budget.abandoning() {
+            info!("skipping the node's remaining repetitions");
+            break;
+        }
+    }
+
+    if exp.pause {
+        wait_for_continue();
+    }
+
+    Ok(results)
+}
+
+/// Write the index of all of a node's repetitions into `out_dir`.
+pub fn write_index(
+    out_dir: &Path,
+    label: Option<&str>,
+    build: &Provenance,
+    results: &[RepResult],
+) -> Result<(), Report> {
+    let f = std::fs::File::create(out_dir.join("index.json")).wrap_err("create index")?;
+    let index = serde_json::json!({ "label": label, "build": build, "reps": results });
+    serde_json::to_writer_pretty(f, &index).wrap_err("write index")?;
+    Ok(())
+}
+
+#[instrument(skip(conn, exp), fields(host = %conn.host))]
+async fn do_exp(conn: &ConnInfo, exp: &Exp, rep: usize) -> Result<RepResult, Report> {
+    let dir = exp.rep_dir(rep);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .wrap_err_with(|| format!("create output dir {:?}", dir))?;
+    // events from here on say which repetition they're from.
+    let rep_exp = Exp {
+        events: exp.events.with("rep", rep),
+        ..exp.clone()
+    };
+    let exp = &rep_exp;
+    exp.events.emit("setup_done", serde_json::json!({}));
+    let conn = conn.clone().with_cfg(&exp.ssh);
+    let mut ssh = conn.connect(None).await?;
+    let v6_exp;
+    let exp = match exp.ip_version {
+        IpVersion::V4 => exp,
+        v => {
+            v6_exp = exp.with_v6(v, &ssh, &conn).await?;
+            &v6_exp
+        }
+    };
+    if let Some(mtu) = exp.mtu {
+        net::check_mtu(&ssh, &exp.hosts, mtu).await?;
+    }
+    let info = match machine::record(&ssh, exp.cloud.as_ref(), &dir).await {
+        Ok(info) => Some(info),
+        Err(err) => {
+            warn!(?err, "could not record machine info");
+            None
+        }
+    };
+    if let Err(err) = machine::record_environment(&ssh, &exp.python, &dir).await {
+        warn!(?err, "could not record machine environment");
+    }
+    let mut monitor = Monitor::new(exp.throttle.clone(), exp.cloud.as_ref(), info.as_ref()).await;
+
+    let prov = exp.prov.as_str();
+    tokio::fs::create_dir_all(&exp.log_dir)
+        .await
+        .wrap_err_with(|| format!("create log dir {:?}", exp.log_dir))?;
+    let log = exp.log_path(rep, "");
+    //let fnames = ["transition-25ms-aws-ord5g.data"];
+    let fnames: Vec<String> = sweep::expected(prov)
+        .iter()
+        .filter(|p| exp.filters.includes(p))
+        .map(ExpParams::filename)
+        .collect();
+    ensure!(!fnames.is_empty(), "filters exclude every experiment");
+    let only = Some(&fnames[..]).filter(|_| !exp.filters.is_empty());
+    let prev = exp.ckpt.get();
+    let resumed = prev.started_rep == Some(rep);
+    let mut started_at = prev.started_at.filter(|_| resumed);
+    // a resumed script's services were started before, and are still up.
+    let mut redis_up = resumed;
+    let mut sidecars_up = resumed;
+    // everything the services are up for, so that they're stopped however it goes.
+    let ran = async {
+        let run = if resumed {
+            info!("script was started before resuming, waiting for it");
+            timed(
+                Some(&exp.ckpt),
+                "run",
+                exp.finish_script(&conn, &mut ssh, &fnames, &log, &mut monitor),
+            )
+            .await?
+        } else {
+            if exp.clean {
+                exp.clean(&ssh, &fnames).await?;
+            }
+
+            exp.pre_exp
+                .run("pre_exp", &ssh, &conn, exp.run_dir(), &dir)
+                .await?;
+            if let Some(ref r) = exp.redis {
+                redis_up = true;
+                r.start(&ssh, &exp.abs_workdir()).await?;
+            }
+            sidecar::start_all(&ssh, &exp.sidecars, &exp.abs_workdir()).await?;
+            sidecars_up = true;
+            if let Some(ref r) = exp.ready {
+                let log = log.with_extension("ready.log");
+                timed(Some(&exp.ckpt), "ready", r.wait(&ssh, exp.run_dir(), &log)).await?;
+            }
+            if exp.skip_stale {
+                started_at = Some(remote_now(&ssh).await?);
+            }
+
+            exp.start(&ssh, only).await?;
+            exp.ckpt.update(|s| {
+                s.started_rep = Some(rep);
+                s.started_at = started_at;
+                s.fetched.clear();
+            });
+            timed(
+                Some(&exp.ckpt),
+                "run",
+                exp.finish_script(&conn, &mut ssh, &fnames, &log, &mut monitor),
+            )
+            .await?
+        };
+        let code = run.code;
+        let mut walls = run.walls;
+        let mut aborted = run.aborted;
+        let mut skipped_node = run.skipped_node;
+        let mut stalled = run.stalled;
+        let mut statuses = run.statuses;
+        statuses.extend(fetch_statuses(&ssh, &exp.remote(REMOTE_EXP_STATUS)).await);
+        info!("done, getting files");
+
+        let (mut gotten, todo): (Vec<String>, Vec<String>) = fnames
+            .iter()
+            .cloned()
+            .partition(|f| resumed && prev.fetched.contains(f));
+        let collected = timed(
+            Some(&exp.ckpt),
+            "collect",
+            collect(&conn, &mut ssh, exp, &todo, &dir, started_at),
+        )
+        .await?;
+        gotten.extend(collected.got);
+        let missing = collected.missing;
+        let mut transfer_failed = collected.failed;
+        let mut rates = collected.rates;
+        let mut total = collected.total;
+        info!(
+            considered = ?fnames.len(),
+            gotten = ?gotten.len(),
+            bytes = total.bytes,
+            mb_per_s = ?total.mb_per_s().map(|r| format!("{:.2}", r)),
+            "done getting files"
+        );
+        exp.events.emit(
+            "collection_done",
+            serde_json::json!({ "files": gotten.len(), "missing": missing.len() }),
+        );
+
+        let mut invalid = find_invalid(&dir, &gotten);
+        for attempt in 1..=exp.rerun_invalid {
+            if invalid.is_empty() || aborted.is_some() || skipped_node {
+                break;
+            }
+
+            warn!(?attempt, ?invalid, "re-running invalid experiments");
+            let log = exp.log_path(rep, &format!(".rerun-{}", attempt));
+            let rerun = timed(
+                Some(&exp.ckpt),
+                "run",
+                exp.run_script(&conn, &mut ssh, &invalid, true, &log, &mut monitor),
+            )
+            .await?;
+            walls.extend(rerun.walls);
+            aborted = rerun.aborted;
+            skipped_node = rerun.skipped_node;
+            stalled.extend(rerun.stalled);
+            statuses.extend(rerun.statuses);
+            statuses.extend(fetch_statuses(&ssh, &exp.remote(REMOTE_EXP_STATUS)).await);
+            // anything we can't get again keeps its old, invalid, contents.
+            let again = timed(
+                Some(&exp.ckpt),
+                "collect",
+                collect(&conn, &mut ssh, exp, &invalid, &dir, started_at),
+            )
+            .await?;
+            transfer_failed.extend(again.failed);
+            rates.extend(again.rates);
+            total.add(again.total);
+            invalid = find_invalid(&dir, &invalid);
+        }
+
+        let mut throttled = monitor.throttled();
+        for attempt in 1..=monitor.rerun() {
+            if throttled.is_empty() || aborted.is_some() || skipped_node {
+                break;
+            }
+
+            warn!(?attempt, ?throttled, "re-running throttled experiments");
+            let log = exp.log_path(rep, &format!(".rerun-throttled-{}", attempt));
+            monitor.forget(&throttled);
+            let rerun = timed(
+                Some(&exp.ckpt),
+                "run",
+                exp.run_script(&conn, &mut ssh, &throttled, true, &log, &mut monitor),
+            )
+            .await?;
+            walls.extend(rerun.walls);
+            aborted = rerun.aborted;
+            skipped_node = rerun.skipped_node;
+            stalled.extend(rerun.stalled);
+            statuses.extend(rerun.statuses);
+            statuses.extend(fetch_statuses(&ssh, &exp.remote(REMOTE_EXP_STATUS)).await);
+            let again = timed(
+                Some(&exp.ckpt),
+                "collect",
+                collect(&conn, &mut ssh, exp, &throttled, &dir, started_at),
+            )
+            .await?;
+            transfer_failed.extend(again.failed);
+            rates.extend(again.rates);
+            total.add(again.total);
+            invalid.retain(|f| !throttled.contains(f));
+            invalid.extend(find_invalid(&dir, &throttled));
+            throttled = monitor.throttled();
+        }
+
+        if !throttled.is_empty() {
+            warn!(?throttled, "experiments ran with the cpu throttled");
+        }
+
+        if let Some(ref dbg) = exp.debug {
+            let todo: Vec<String> = sweep::expected(prov)
+                .iter()
+                .filter(|p| dbg.exps.is_empty() || dbg.exps.iter().any(|f| f.matches(p)))
+                .map(ExpParams::filename)
+                .filter(|f| fnames.contains(f))
+                .filter(|f| {
+                    !dbg.failed_only
+                        || invalid.contains(f)
+                        || missing.contains(f)
+                        || statuses.get(f).is_some_and(|st| !st.ok)
+                })
+                .collect();
+            if todo.is_empty() || aborted.is_some() || skipped_node {
+                debug!("no experiments to trace");
+            } else {
+                let log = exp.log_path(rep, ".traced");
+                let traced = timed(
+                    Some(&exp.ckpt),
+                    "trace",
+                    exp.trace(&conn, &mut ssh, dbg, &todo, &dir, &log, &mut monitor),
+                )
+                .await;
+                if let Err(err) = traced {
+                    warn!(?err, "could not trace experiments");
+                }
+            }
+        }
+
+        Ok::<_, Report>((
+            code,
+            walls,
+            aborted,
+            stalled,
+            statuses,
+            gotten,
+            missing,
+            transfer_failed,
+            rates,
+            total,
+            invalid,
+            throttled,
+        ))
+    }
+    .await;
+    if sidecars_up {
+        stop_sidecars(&conn, &ssh, exp, &dir).await;
+    }
+    if redis_up {
+        stop_redis(&conn, &ssh, exp, &dir).await;
+    }
+    let (
+        code,
+        walls,
+        aborted,
+        mut stalled,
+        statuses,
+        mut gotten,
+        missing,
+        transfer_failed,
+        rates,
+        total,
+        mut invalid,
+        mut throttled,
+    ) = ran?;
+
+    stalled.sort();
+    stalled.dedup();
+    if let Err(err) = write_summary(&dir, &gotten, &walls, &statuses, &monitor, &rates, &stalled) {
+        warn!(?err, "could not write summary");
+    }
+
+    if let Err(err) = exp
+        .post_exp
+        .run("post_exp", &ssh, &conn, exp.run_dir(), &dir)
+        .await
+    {
+        warn!(?err, "post_exp hook failed");
+    }
+
+    let mut failed: Vec<String> = statuses
+        .iter()
+        .filter(|(_, st)| !st.ok)
+        .map(|(f, st)| {
+            warn!(exp = ?f, error = ?st.error, "script reported experiment failure");
+            f.clone()
+        })
+        .collect();
+
+    if let Some(ref stamp) = exp.stamp {
+        let mut renamed = vec![];
+        for f in &gotten {
+            let to = provenance::stamped(f, stamp);
+            match std::fs::rename(dir.join(f), dir.join(&to)) {
+                Ok(()) => renamed.push(f.clone()),
+                Err(err) => warn!(?err, file = ?f, "could not stamp result file name"),
+            }
+        }
+
+        for names in [
+            &mut gotten,
+            &mut invalid,
+            &mut failed,
+            &mut throttled,
+            &mut stalled,
+        ] {
+            for f in names.iter_mut().filter(|f| renamed.contains(f)) {
+                *f = provenance::stamped(f, stamp);
+            }
+        }
+    }
+
+    let res = RepResult {
+        rep,
+        dir,
+        code,
+        files: gotten,
+        invalid,
+        failed,
+        throttled,
+        missing,
+        transfer_failed,
+        stalled,
+        collected: total,
+    };
+    exp.ckpt.update(|s| {
+        s.done.push(res.clone());
+        s.started_rep = None;
+        s.started_at = None;
+        s.fetched.clear();
+    });
+    // what the script got through is collected, but there's no point going on.
+    if let Some(why) = aborted {
+        bail!("repetition {} aborted: {}", rep, why);
+    }
+
+    Ok(res)
+}
+
+/// Stop `exp`'s service binaries, and fetch their logs into `dir`.
+async fn stop_sidecars(conn: &ConnInfo, ssh: &Session, exp: &Exp, dir: &Path) {
+    sidecar::stop_all(ssh, &exp.sidecars, &exp.abs_workdir()).await;
+    for sc in &exp.sidecars {
+        let log = sc.logfile();
+        let to = dir.join(Path::new(&log).file_name().unwrap_or_default());
+        if let Err(err) = exp.fetch(conn, ssh, &log, &to).await {
+            warn!(?err, service = ?sc.name(), "could not fetch service binary log");
+        }
+    }
+}
+
+/// Stop `exp`'s redis, and fetch its log into `dir`.
+async fn stop_redis(conn: &ConnInfo, ssh: &Session, exp: &Exp, dir: &Path) {
+    let r = match exp.redis {
+        Some(ref r) => r,
+        None => return,
+    };
+    if let Err(err) = r.stop(ssh).await {
+        warn!(?err, "could not stop redis");
+    }
+    match exp
+        .fetch(conn, ssh, redis::REMOTE_LOG, &dir.join(redis::REMOTE_LOG))
+        .await
+    {
+        Ok(Fetched::Got(_)) => debug!("fetched redis log"),
+        Ok(Fetched::Absent) => warn!("redis wrote no log"),
+        Err(err) => warn!(?err, "could not fetch redis log"),
+    }
+}
+
+/// Fetch `fnames` into `dir`. With `since`, files last modified before then are left alone.
+async fn collect(
+    conn: &ConnInfo,
+    ssh: &mut Session,
+    exp: &Exp,
+    fnames: &[String],
+    dir: &Path,
+    since: Option<u64>,
+) -> Result<Collected, Report> {
+    let reconnect_timeout = Duration::from_secs(exp.ssh.reconnect_timeout_secs);
+    let xfer = Transfer::new(
+        ssh,
+        conn,
+        exp.transfer,
+        exp.bwlimit,
+        exp.transfer_retry.clone(),
+    )
+    .await?;
+    let mut collected = Collected::default();
+    for fname in fnames {
+        if let Some(since) = since {
+            match remote_mtime(ssh, &exp.remote(fname)).await {
+                Some(mtime) if mtime < since => {
+                    warn!(?fname, ?mtime, started = ?since, "skipping stale result file from an earlier run");
+                    continue;
+                }
+                // missing files are dealt with below.
+                _ => (),
+            }
+        }
+
+        let mut res = xfer
+            .download(ssh, &exp.remote(fname), &dir.join(fname))
+            .await;
+        if res.is_err() && ssh.check().await.is_err() {
+            warn!(?fname, "connection lost during collection, reconnecting");
+            *ssh = reconnect(conn, reconnect_timeout).await?;
+            res = xfer
+                .download(ssh, &exp.remote(fname), &dir.join(fname))
+                .await;
+        }
+
+        match res {
+            // the file not existing is not necessarily a problem, it's possible that experiment
+            // was not run this time.
+            Ok(Fetched::Absent) => {
+                debug!(?fname, "no result file");
+                collected.missing.push(fname.clone());
+            }
+            Err(err) => {
+                warn!(?err, ?fname, "could not fetch result file");
+                collected.failed.push(fname.clone());
+            }
+            Ok(Fetched::Got(t)) => {
+                collected.rates.insert(fname.clone(), t);
+                collected.total.add(t);
+                exp.ckpt.update(|s| {
+                    if !s.fetched.contains(fname) {
+                        s.fetched.push(fname.clone());
+                    }
+                });
+                collected.got.push(fname.clone());
+            }
+        }
+    }
+
+    Ok(collected)
+}
+
+/// Which of `fnames` (in `dir`) are empty, truncated, or otherwise unparseable.
+fn find_invalid(dir: &Path, fnames: &[String]) -> Vec<String> {
+    fnames
+        .iter()
+        .filter(|f| match read_latencies(&dir.join(f)) {
+            Ok(_) => false,
+            Err(err) => {
+                warn!(?err, file = ?f, "invalid result file");
+                true
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exp(scratch_dir: Option<&str>) -> Exp {
+        Exp {
+            python: "python3".to_owned(),
+            script_remote_path: "exp.py".into(),
+            bench_remote_path: "bench".into(),
+            prov: "aws".to_owned(),
+            ssh: Default::default(),
+            out_dir: "out".into(),
+            log_dir: "out/logs".into(),
+            node: "aws".to_owned(),
+            reps: 1,
+            rerun_invalid: 0,
+            filters: Default::default(),
+            pause: false,
+            workdir: Some("wd".to_owned()),
+            scratch_dir: scratch_dir.map(str::to_owned),
+            disk_guard: None,
+            stall: None,
+            script_timeout: Duration::from_secs(60),
+            debug: None,
+            redis: None,
+            sidecars: vec![],
+            hosts: vec!["u@a".to_owned(), "u@b".to_owned()],
+            roles: vec![],
+            args: vec!["--redis-host".to_owned(), "10.0.0.1".to_owned()],
+            ready: None,
+            ip_version: Default::default(),
+            mtu: None,
+            env: vec![("K".to_owned(), "v".to_owned())],
+            stamp: None,
+            clean: false,
+            skip_stale: false,
+            pre_exp: Default::default(),
+            post_exp: Default::default(),
+            control: Default::default(),
+            events: Default::default(),
+            budget: Default::default(),
+            cloud: None,
+            throttle: Default::default(),
+            transfer: Default::default(),
+            bwlimit: None,
+            transfer_retry: Default::default(),
+            ckpt: crate::state::Checkpoint::new("state.json".into()).node("aws"),
+        }
+    }
+
+    fn parses(cmd: &str) -> bool {
+        std::process::Command::new("sh")
+            .args(["-n", "-c", cmd])
+            .status()
+            .unwrap()
+            .success()
+    }
+
+    #[test]
+    fn start_cmd_parses() {
+        let only = ["a.data".to_owned(), "b.data".to_owned()];
+        for sd in [None, Some("/mnt/scratch")] {
+            let e = exp(sd);
+            for only in [None, Some(&only[..])] {
+                // the detached shell parses what it's given only once it runs.
+                for cmd in [e.start_cmd(), e.pipeline(only)] {
+                    assert!(parses(&cmd), "does not parse: {}", cmd);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pipeline_is_passed_unquoted() {
+        let mut e = exp(None);
+        e.env.push(("MSG".to_owned(), "'it'\"s\"'".to_owned()));
+        let out = std::process::Command::new("sh")
+            .args(["-c", "printf %s \"$1\"", "sh"])
+            .arg(e.pipeline(None))
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout).trim(),
+            e.pipeline(None)
+        );
+        assert!(e.start_cmd().contains("sh -c \"$1\""));
+    }
+
+    #[test]
+    fn scratch_cmd_runs_in_scratch_dir() {
+        let cmd = exp(Some("/mnt/scratch")).script_cmd(None);
+        assert!(
+            cmd.starts_with("(cd /mnt/scratch && PYTHONUNBUFFERED=1 "),
+            "{}",
+            cmd
+        );
+        assert!(
+            cmd.contains("$HOME/wd/exp.py $HOME/wd/bench aws"),
+            "{}",
+            cmd
+        );
+    }
+}
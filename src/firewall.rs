@@ -0,0 +1,104 @@
+//! Opening experiment ports in the local firewall of hosts we don't launch (cloud machines get
+//! security groups instead).
+//!
+//! Setup adds the rules with `ufw` if it is active, and `iptables` otherwise, skipping rules that
+//! are already there. How to remove the ones it added goes in the host's undo file (see
+//! [`crate::setup::undo`]), so the host's own rules are left alone.
+
+use crate::setup::UNDO_FILE;
+use color_eyre::eyre::{bail, ensure, Report, WrapErr};
+use openssh::Session;
+use tracing::info;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct Firewall {
+    /// Ports to let in, as `port` or `first:last`, with `/tcp` (the default) or `/udp`.
+    pub allow: Vec<String>,
+}
+
+/// A parsed `allow` entry.
+struct Rule {
+    /// `port` or `first:last`, as both ufw and iptables take them.
+    ports: String,
+    proto: &'static str,
+}
+
+impl std::str::FromStr for Rule {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ports, proto) = match s.split_once('/') {
+            None => (s, "tcp"),
+            Some((p, "tcp")) => (p, "tcp"),
+            Some((p, "udp")) => (p, "udp"),
+            Some((_, proto)) => bail!(
+                "firewall rule {:?}: protocol {:?} is not tcp or udp",
+                s,
+                proto
+            ),
+        };
+        let ok = ports
+            .split(':')
+            .map(|p| p.parse::<u16>().is_ok_and(|p| p > 0))
+            .collect::<Vec<_>>();
+        ensure!(
+            matches!(ok[..], [true] | [true, true]),
+            "firewall rule {:?} must be port or first:last, then optionally /tcp or /udp",
+            s
+        );
+        Ok(Rule {
+            ports: ports.to_owned(),
+            proto,
+        })
+    }
+}
+
+impl Rule {
+    /// Add the rule if it isn't there, and if we did, note how to remove it.
+    fn apply_cmd(&self) -> String {
+        let ufw = format!("{}/{}", self.ports, self.proto);
+        let ipt = format!("INPUT -p {} --dport {} -j ACCEPT", self.proto, self.ports);
+        format!(
+            "if command -v ufw > /dev/null && sudo ufw status | grep -q '^Status: active'; then \
+            sudo ufw show added | grep -qxF 'ufw allow {ufw}' \
+            || {{ sudo ufw allow {ufw} > /dev/null && echo 'sudo ufw delete allow {ufw}' >> {f}; }}; \
+            else \
+            sudo iptables -C {ipt} 2> /dev/null \
+            || {{ sudo iptables -I {ipt} && echo 'sudo iptables -D {ipt}' >> {f}; }}; \
+            fi",
+            ufw = ufw,
+            ipt = ipt,
+            f = UNDO_FILE,
+        )
+    }
+}
+
+impl Firewall {
+    pub fn check(&self) -> Result<(), Report> {
+        for r in &self.allow {
+            r.parse::<Rule>()?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn apply(&self, ssh: &Session) -> Result<(), Report> {
+        for r in &self.allow {
+            let rule: Rule = r.parse()?;
+            let out = ssh
+                .shell(rule.apply_cmd())
+                .output()
+                .await
+                .wrap_err("open firewall port")?;
+            ensure!(
+                out.status.success(),
+                "could not open {} in the firewall: {}",
+                r,
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+
+        info!(allow = ?self.allow, "opened firewall ports");
+        Ok(())
+    }
+}
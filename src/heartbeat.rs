@@ -0,0 +1,198 @@
+//! A heartbeat for unattended runs: every `--heartbeat-secs`, the run writes `heartbeat.json` to
+//! its output directory, with when it last made progress (its last event, see
+//! [`crate::events`]) and every node's phase. `status` reads it back, and fails when the run
+//! has stopped beating, or has beaten without progress for too long, e.g. on a stuck `apt` or an
+//! ssh session that hangs overnight.
+
+use crate::events::Events;
+use crate::state::{Checkpoint, Phase};
+use color_eyre::eyre::{bail, Report, WrapErr};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+const FILE: &str = "heartbeat.json";
+
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+struct Beat {
+    /// Seconds since the epoch, as are the other times.
+    time: u64,
+    started: u64,
+    interval_secs: u64,
+    pid: u32,
+    last_event: Option<(u64, String)>,
+    nodes: BTreeMap<String, Phase>,
+    finished: bool,
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Writes the heartbeat until dropped, and then marks the run finished.
+#[derive(Debug)]
+pub struct Heartbeat {
+    path: PathBuf,
+    started: u64,
+    interval: Duration,
+    events: Events,
+    ckpt: Checkpoint,
+    task: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// Beat every `interval` into `out_dir`, also sending a `heartbeat` event each time with
+    /// `send_events`. A zero `interval` never beats.
+    pub fn start(
+        out_dir: &Path,
+        interval: Duration,
+        events: &Events,
+        ckpt: &Checkpoint,
+        send_events: bool,
+    ) -> Self {
+        let mut hb = Self {
+            path: out_dir.join(FILE),
+            started: now(),
+            interval,
+            events: events.clone(),
+            ckpt: ckpt.clone(),
+            task: None,
+        };
+        if interval.is_zero() {
+            return hb;
+        }
+
+        let (path, started, events, ckpt) = (
+            hb.path.clone(),
+            hb.started,
+            hb.events.clone(),
+            hb.ckpt.clone(),
+        );
+        hb.task = Some(tokio::spawn(async move {
+            let mut tick = tokio::time::interval(interval);
+            loop {
+                tick.tick().await;
+                let beat = beat(started, interval, &events, &ckpt, false);
+                if send_events {
+                    events.send(
+                        "heartbeat",
+                        serde_json::json!({ "last_event": beat.last_event, "nodes": beat.nodes }),
+                    );
+                }
+                if let Err(err) = write(&path, &beat) {
+                    warn!(?err, "could not write heartbeat");
+                }
+            }
+        }));
+        hb
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        if let Some(t) = self.task.take() {
+            t.abort();
+            let beat = beat(self.started, self.interval, &self.events, &self.ckpt, true);
+            if let Err(err) = write(&self.path, &beat) {
+                warn!(?err, "could not write final heartbeat");
+            }
+        }
+    }
+}
+
+fn beat(
+    started: u64,
+    interval: Duration,
+    events: &Events,
+    ckpt: &Checkpoint,
+    finished: bool,
+) -> Beat {
+    Beat {
+        time: now(),
+        started,
+        interval_secs: interval.as_secs(),
+        pid: std::process::id(),
+        last_event: events.last(),
+        nodes: ckpt.phases(),
+        finished,
+    }
+}
+
+fn write(path: &Path, beat: &Beat) -> Result<(), Report> {
+    if let Some(d) = path.parent() {
+        std::fs::create_dir_all(d)?;
+    }
+
+    // write-then-rename, so `status` never reads half a heartbeat.
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(beat)?)?;
+    std::fs::rename(&tmp, path)?;
+    debug!(?path, "heartbeat");
+    Ok(())
+}
+
+fn alive(pid: u32) -> bool {
+    // safe: signal 0 only checks the process exists.
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Print the state of the run in `dir`, and fail if it has stopped, or made no progress for
+/// `stale`.
+pub fn status(dir: &Path, stale: Duration) -> Result<(), Report> {
+    let path = dir.join(FILE);
+    let beat: Beat = serde_json::from_slice(
+        &std::fs::read(&path).wrap_err_with(|| format!("read heartbeat {:?}", path))?,
+    )
+    .wrap_err("parse heartbeat")?;
+    let now = now();
+    let since = now.saturating_sub(beat.time);
+    let (progress_at, last) = beat
+        .last_event
+        .clone()
+        .unwrap_or((beat.started, "start".to_owned()));
+    let progress_since = now.saturating_sub(progress_at);
+    println!(
+        "run {:?} (pid {}): started {}s ago, last heartbeat {}s ago, last progress ({}) {}s ago",
+        dir,
+        beat.pid,
+        now.saturating_sub(beat.started),
+        since,
+        last,
+        progress_since
+    );
+    for (node, phase) in &beat.nodes {
+        println!("  {:<32} {:?}", node, phase);
+    }
+
+    if beat.finished {
+        println!("the run finished");
+        return Ok(());
+    }
+
+    if since > 3 * beat.interval_secs.max(1) {
+        let why = if alive(beat.pid) {
+            "its process is still there, but stuck"
+        } else {
+            "its process is gone"
+        };
+        bail!(
+            "the run has stopped: no heartbeat for {}s, and {}",
+            since,
+            why
+        );
+    }
+
+    if progress_since > stale.as_secs() {
+        bail!(
+            "the run appears hung: no progress for {} minutes, since {}",
+            progress_since / 60,
+            last
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! A starter node config for `init`, with a node for each provider.
+//!
+//! JSON has no comments, so the example explains itself in `//` keys, which are ignored when the
+//! config is loaded.
+
+use color_eyre::eyre::{ensure, Report, WrapErr};
+use std::path::Path;
+use tracing::info;
+
+const EXAMPLE: &str = r#"[
+  {
+    "//": "Each entry is a node: a machine (or group of machines) to run the experiment script on. Delete the ones you don't need. Which experiments run is chosen on the command line, e.g. --only rcvrs=10 --skip groups=be.",
+    "name": "aws-t3",
+    "Aws": {
+      "//": "instance_type defaults to t3.medium. burstable is warn, allow, unlimited, replace, or refuse. \"access\": { \"key_pair\": ..., \"security_group\": ..., \"prefix\": ... } launches with your key pair and security group, or ones named with prefix, instead of tsunami's.",
+      "region": "us-east-1",
+      "instance_type": "m5.large",
+      "burstable": "warn"
+    },
+    "repetitions": 3,
+    "rerun_invalid": 1,
+    "tags": { "owner": "you", "experiment": "burrito" },
+    "disk": { "size_gb": 32 }
+  },
+  {
+    "name": "aws-clients",
+    "Aws": {
+      "//": "machines launch alongside the main one, in its security group; the script gets their private addresses in BURRITO_EXP_ROLES.",
+      "region": "us-east-1",
+      "instance_type": "m5.xlarge",
+      "machines": [{ "role": "client", "instance_type": "m5.large" }]
+    }
+  },
+  {
+    "//": "A service machine, up for the whole run, e.g. to keep redis off the experiment's machine. Nodes with \"uses\": [\"redis\"] get --redis-host <host> --redis-port 6379 after the provider argument, and can wait for it with \"ready\": { \"probes\": [{ \"tcp\": \"redis:6379\" }] }.",
+    "name": "redis",
+    "Aws": { "region": "us-east-1", "instance_type": "m5.large" },
+    "service": { "start": "redis-server --protected-mode no", "ports": { "port": 6379 }, "monitor": "redis-cli info stats" }
+  },
+  {
+    "//": "Each launch gets a new tsunami_* resource group, unless resource_group is set: { \"name\": \"exp\" } makes exp-<machine>-<time> groups instead, and { \"name\": \"exp\", \"reuse\": true } launches into the existing group exp.",
+    "name": "azure-b2ms",
+    "Azure": { "region": "eastus", "instance_type": "Standard_B2ms" }
+  },
+  {
+    "//": "A host you already have. label is the provider name the script and result files see (default baremetal). Dependencies are installed with sudo unless use_sudo is false, or skipped with deps_installed.",
+    "name": "lab-server",
+    "label": "lab",
+    "Baremetal": { "ip": "10.0.0.5", "user": "you", "hosts": [] },
+    "ssh": { "port": 22, "key_path": "~/.ssh/id_ed25519" },
+    "use_sudo": false,
+    "venv": "burrito-venv",
+    "requirements": "requirements.txt"
+  },
+  {
+    "//": "Any free host of an --inventory group; count > 1 makes that many nodes. firewall opens ports (ufw, or iptables) for the run.",
+    "name": "inventory",
+    "Inventory": { "group": "workers", "count": 2 },
+    "firewall": { "allow": ["4242/udp", "7000:7100"] }
+  },
+  {
+    "//": "An instance that is already running, from any provider. It is never launched or terminated.",
+    "name": "existing",
+    "Existing": { "provider": "gcp", "host": "203.0.113.7", "user": "ubuntu" }
+  },
+  {
+    "name": "k8s",
+    "K8s": { "namespace": "default", "resources": { "cpu": "4", "memory": "8Gi" } }
+  },
+  {
+    "name": "qemu",
+    "Qemu": { "image": "focal-server-cloudimg-amd64.img", "vcpus": 2, "memory_mb": 4096 }
+  },
+  {
+    "name": "linode",
+    "Linode": { "region": "us-east", "plan": "g6-standard-2" }
+  },
+  {
+    "name": "vultr",
+    "Vultr": { "region": "ewr", "plan": "vc2-2c-4gb" }
+  },
+  {
+    "name": "oci",
+    "Oci": { "region": "us-ashburn-1", "compartment_id": "ocid1.compartment.oc1..", "subnet_id": "ocid1.subnet.oc1.." }
+  },
+  {
+    "//": "e.g. Chameleon, which needs a lease's reservation.",
+    "name": "chameleon",
+    "OpenStack": {
+      "auth_url": "https://chi.uc.chameleoncloud.org:5000/v3",
+      "flavor": "baremetal",
+      "image": "CC-Ubuntu20.04",
+      "network": "sharednet1",
+      "user": "cc",
+      "reservation": "00000000-0000-0000-0000-000000000000"
+    },
+    "//deps": "Setup runs setup_steps, then installs python3, pip, agenda, and the coord_store (redis, memcached, or etcd).",
+    "setup_steps": [{ "cmd": "sudo sysctl -w net.core.somaxconn=4096" }],
+    "coord_store": "redis",
+    "pip_index_url": "https://pypi.org/simple"
+  }
+]
+"#;
+
+/// Write the example config to `path`, unless something is already there.
+pub fn write_example(path: &Path, force: bool) -> Result<(), Report> {
+    ensure!(
+        force || !path.exists(),
+        "{:?} already exists; pass --force to overwrite it",
+        path
+    );
+    std::fs::write(path, EXAMPLE).wrap_err_with(|| format!("write {:?}", path))?;
+    // an example that doesn't load is worse than none.
+    let nodes = crate::load_nodes(path).wrap_err("example config does not load")?;
+    info!(?path, nodes = nodes.len(), "wrote example config");
+    Ok(())
+}
@@ -0,0 +1,248 @@
+//! An Ansible-style inventory of baremetal hosts, from which nodes are allocated by group.
+//!
+//! ```text
+//! [cluster-a]
+//! 10.0.0.1 ansible_user=ubuntu
+//! 10.0.0.2 ansible_user=ubuntu ansible_port=2222
+//! ```
+//!
+//! A host is leased to a node while the node runs on it, and released afterwards. Leases are
+//! files next to the inventory (in `<inventory>.leases/`), so concurrent runs sharing an inventory
+//! don't pick the same host. Hosts in a group are handed out round-robin.
+
+use color_eyre::eyre::{bail, eyre, Report, WrapErr};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+#[derive(Clone, Debug)]
+pub struct Host {
+    pub host: String,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Inventory {
+    path: PathBuf,
+    groups: BTreeMap<String, Vec<Host>>,
+}
+
+/// A host allocated to us, until this is dropped.
+#[derive(Debug)]
+pub struct Lease {
+    pub host: Host,
+    path: PathBuf,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => debug!(host = ?self.host.host, "released inventory host"),
+            Err(err) => warn!(?err, host = ?self.host.host, "could not release inventory host"),
+        }
+    }
+}
+
+fn parse_host(line: &str) -> Result<Host, Report> {
+    let mut parts = line.split_whitespace();
+    let mut host = Host {
+        host: parts.next().unwrap().to_owned(),
+        user: None,
+        port: None,
+    };
+    for var in parts {
+        match var.split_once('=') {
+            Some(("ansible_host", h)) => host.host = h.to_owned(),
+            Some(("ansible_user", u)) => host.user = Some(u.to_owned()),
+            Some(("ansible_port", p)) => {
+                host.port = Some(
+                    p.parse()
+                        .wrap_err_with(|| format!("ansible_port {:?}", p))?,
+                )
+            }
+            Some(_) => (),
+            None => bail!("host variable {:?} is not of the form key=value", var),
+        }
+    }
+
+    Ok(host)
+}
+
+impl Inventory {
+    pub fn load(path: &Path) -> Result<Self, Report> {
+        let s =
+            std::fs::read_to_string(path).wrap_err_with(|| format!("read inventory {:?}", path))?;
+        let mut groups: BTreeMap<String, Vec<Host>> = BTreeMap::new();
+        // hosts before any group header.
+        let mut group = Some("ungrouped".to_owned());
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(g) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                // group variables and groups of groups aren't hosts.
+                group = Some(g.to_owned()).filter(|g| !g.contains(':'));
+                if group.is_none() {
+                    debug!(section = ?g, "skipping inventory section");
+                }
+
+                continue;
+            }
+
+            if let Some(ref g) = group {
+                let host =
+                    parse_host(line).wrap_err_with(|| format!("inventory line {}", i + 1))?;
+                groups.entry(g.clone()).or_default().push(host);
+            }
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            groups,
+        })
+    }
+
+    fn lease_dir(&self) -> PathBuf {
+        self.path.with_extension("leases")
+    }
+
+    /// Lease the next free host in `group`.
+    pub fn allocate(&self, group: &str) -> Result<Lease, Report> {
+        let hosts = self
+            .groups
+            .get(group)
+            .filter(|h| !h.is_empty())
+            .ok_or_else(|| eyre!("no hosts in inventory group {:?}", group))?;
+        let dir = self.lease_dir();
+        std::fs::create_dir_all(&dir)?;
+        let cursor = dir.join(format!("{}.next", group));
+        let start: usize = std::fs::read_to_string(&cursor)
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .unwrap_or(0);
+
+        for i in 0..hosts.len() {
+            let idx = (start + i) % hosts.len();
+            let host = &hosts[idx];
+            let path = dir.join(host.host.replace('/', "_"));
+            if !try_lease(&path)? {
+                continue;
+            }
+
+            std::fs::write(&cursor, (idx + 1).to_string())?;
+            info!(?group, host = ?host.host, "leased inventory host");
+            return Ok(Lease {
+                host: host.clone(),
+                path,
+            });
+        }
+
+        bail!(
+            "all {} hosts in inventory group {:?} are in use",
+            hosts.len(),
+            group
+        )
+    }
+}
+
+/// Take the lease at `path`, if nobody holds it. Leases of processes that have exited are taken
+/// over.
+fn try_lease(path: &Path) -> Result<bool, Report> {
+    for _ in 0..2 {
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(mut f) => {
+                write!(f, "{}", std::process::id())?;
+                return Ok(true);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                let holder = std::fs::read_to_string(path).unwrap_or_default();
+                // an empty lease is still being written.
+                let alive = holder.trim().is_empty()
+                    || holder
+                        .trim()
+                        .parse::<u32>()
+                        .is_ok_and(|pid| Path::new(&format!("/proc/{}", pid)).exists());
+                if alive {
+                    return Ok(false);
+                }
+
+                warn!(?path, ?holder, "taking over stale inventory lease");
+                std::fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e).wrap_err_with(|| format!("lease {:?}", path)),
+        }
+    }
+
+    // somebody else took it over first.
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An inventory of `contents`, in a directory of its own.
+    fn inventory(name: &str, contents: &str) -> Result<Inventory, Report> {
+        let dir =
+            std::env::temp_dir().join(format!("burrito-inventory-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hosts");
+        std::fs::write(&path, contents).unwrap();
+        Inventory::load(&path)
+    }
+
+    #[test]
+    fn parse_groups() {
+        let inv = inventory(
+            "parse",
+            "# comment\n10.0.0.9\n\n[a]\n10.0.0.1 ansible_user=ubuntu\n\
+            h2 ansible_host=10.0.0.2 ansible_port=2222 other=x\n\
+            [a:vars]\nansible_user=root\n[b]\n; comment\n10.0.1.1\n",
+        )
+        .unwrap();
+        let hosts = |g: &str| -> Vec<(String, Option<String>, Option<u16>)> {
+            inv.groups[g]
+                .iter()
+                .map(|h| (h.host.clone(), h.user.clone(), h.port))
+                .collect()
+        };
+        assert_eq!(hosts("ungrouped"), [("10.0.0.9".to_owned(), None, None)]);
+        assert_eq!(
+            hosts("a"),
+            [
+                ("10.0.0.1".to_owned(), Some("ubuntu".to_owned()), None),
+                ("10.0.0.2".to_owned(), None, Some(2222)),
+            ]
+        );
+        assert_eq!(hosts("b"), [("10.0.1.1".to_owned(), None, None)]);
+        assert_eq!(inv.groups.len(), 3);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(inventory("bad-port", "[a]\n10.0.0.1 ansible_port=ssh\n").is_err());
+        assert!(inventory("bad-var", "[a]\n10.0.0.1 ansible_user\n").is_err());
+    }
+
+    #[test]
+    fn allocate_round_robin() {
+        let inv = inventory("allocate", "[a]\n10.0.0.1\n10.0.0.2\n").unwrap();
+        let first = inv.allocate("a").unwrap();
+        let second = inv.allocate("a").unwrap();
+        assert_eq!(first.host.host, "10.0.0.1");
+        assert_eq!(second.host.host, "10.0.0.2");
+        assert!(inv.allocate("a").is_err());
+        drop(first);
+        assert_eq!(inv.allocate("a").unwrap().host.host, "10.0.0.1");
+        assert!(inv.allocate("c").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//! The run as a JUnit XML test report, `junit.xml`, for CI (Jenkins, GitLab) to show.
+//!
+//! Each node is a test suite, and each experiment of each repetition a test case, timed by how
+//! long it ran. An experiment fails if the script reported it failed, or its result file is
+//! missing or invalid; one whose file couldn't be fetched is an error. A node that failed outright
+//! gets a test case of its own, in error.
+
+use crate::exp::RepResult;
+use crate::state::Checkpoint;
+use color_eyre::eyre::{Report, WrapErr};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{debug, info};
+
+enum Outcome {
+    Pass,
+    Failure(String),
+    Error(String),
+}
+
+struct Case {
+    name: String,
+    class: String,
+    secs: f64,
+    outcome: Outcome,
+    /// For the test case's output.
+    notes: Vec<&'static str>,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        // XML can't have other control characters at all, e.g. the escapes of colored output.
+        .replace(
+            |c: char| c.is_control() && !matches!(c, '\t' | '\n' | '\r'),
+            "",
+        )
+}
+
+/// An experiment's name, from its result file's: without `.data`, or any stamp.
+fn exp_name(f: &str) -> &str {
+    let name = f.trim_end_matches(".data");
+    name.split('@').next().unwrap_or(name)
+}
+
+/// How long each experiment in `dir` ran, from its `summary.csv`.
+fn walls(dir: &Path) -> BTreeMap<String, f64> {
+    let Ok(summary) = std::fs::read_to_string(dir.join("summary.csv")) else {
+        debug!(?dir, "no summary, leaving test cases untimed");
+        return Default::default();
+    };
+    summary
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let cols: Vec<&str> = l.split(',').collect();
+            Some((cols.first()?.to_string(), cols.get(8)?.parse().ok()?))
+        })
+        .collect()
+}
+
+fn rep_cases(node: &str, many: bool, r: &RepResult) -> Vec<Case> {
+    let walls = walls(&r.dir);
+    let class = if many {
+        format!("{}.rep{}", node, r.rep)
+    } else {
+        node.to_owned()
+    };
+    let mut files: Vec<&String> = r
+        .files
+        .iter()
+        .chain(&r.invalid)
+        .chain(&r.failed)
+        .chain(&r.missing)
+        .chain(&r.transfer_failed)
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+        .into_iter()
+        .map(|f| {
+            let outcome = if r.failed.contains(f) {
+                Outcome::Failure("the script reported failure".to_owned())
+            } else if r.missing.contains(f) {
+                Outcome::Failure("result file was not produced".to_owned())
+            } else if r.invalid.contains(f) {
+                Outcome::Failure("result file did not validate".to_owned())
+            } else if r.transfer_failed.contains(f) {
+                Outcome::Error("result file could not be fetched".to_owned())
+            } else {
+                Outcome::Pass
+            };
+            let name = exp_name(f).to_owned();
+            Case {
+                secs: walls.get(&name).copied().unwrap_or_default(),
+                name,
+                class: class.clone(),
+                outcome,
+                notes: [
+                    (r.throttled.contains(f), "ran with the cpu throttled"),
+                    (r.stalled.contains(f), "the script stalled on it"),
+                ]
+                .iter()
+                .filter_map(|&(yes, note)| yes.then_some(note))
+                .collect(),
+            }
+        })
+        .collect()
+}
+
+/// Write `out_dir/junit.xml` for the run in `ckpt`, in which `node_failures` failed (with why).
+pub fn write(
+    out_dir: &Path,
+    ckpt: &Checkpoint,
+    node_failures: &[(String, String)],
+) -> Result<(), Report> {
+    let mut suites: BTreeMap<String, Vec<Case>> = BTreeMap::new();
+    for (node, reps) in ckpt.reps() {
+        let many = reps.len() > 1;
+        let cases = suites.entry(node.clone()).or_default();
+        for r in &reps {
+            cases.extend(rep_cases(&node, many, r));
+        }
+    }
+
+    for (node, err) in node_failures {
+        suites.entry(node.clone()).or_default().push(Case {
+            name: "node".to_owned(),
+            class: node.clone(),
+            secs: 0.,
+            outcome: Outcome::Error(err.clone()),
+            notes: vec![],
+        });
+    }
+
+    let count = |cases: &[Case], which: fn(&Outcome) -> bool| {
+        cases.iter().filter(|c| which(&c.outcome)).count()
+    };
+    let is_failure = |o: &Outcome| matches!(o, Outcome::Failure(_));
+    let is_error = |o: &Outcome| matches!(o, Outcome::Error(_));
+    let secs = |cases: &[Case]| cases.iter().map(|c| c.secs).sum::<f64>();
+    let tests: usize = suites.values().map(Vec::len).sum();
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"burrito-cloud-exp\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.1}\">\n",
+        tests,
+        suites.values().map(|c| count(c, is_failure)).sum::<usize>(),
+        suites.values().map(|c| count(c, is_error)).sum::<usize>(),
+        suites.values().map(|c| secs(c)).sum::<f64>(),
+    );
+    for (node, cases) in &suites {
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.1}\">\n",
+            escape(node),
+            cases.len(),
+            count(cases, is_failure),
+            count(cases, is_error),
+            secs(cases),
+        ));
+        for c in cases {
+            xml.push_str(&format!(
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.1}\"",
+                escape(&c.name),
+                escape(&c.class),
+                c.secs
+            ));
+            let body = match c.outcome {
+                Outcome::Pass => String::new(),
+                Outcome::Failure(ref m) => format!("      <failure message=\"{}\"/>\n", escape(m)),
+                Outcome::Error(ref m) => format!("      <error message=\"{}\"/>\n", escape(m)),
+            };
+            let body = if c.notes.is_empty() {
+                body
+            } else {
+                body + &format!("      <system-out>{}</system-out>\n", c.notes.join("; "))
+            };
+            if body.is_empty() {
+                xml.push_str("/>\n");
+            } else {
+                xml.push_str(&format!(">\n{}    </testcase>\n", body));
+            }
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    let path = out_dir.join("junit.xml");
+    std::fs::write(&path, xml).wrap_err("write junit report")?;
+    info!(?path, tests, "wrote junit report");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape(r#"a<b & "c">d"#), "a&lt;b &amp; &quot;c&quot;&gt;d");
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+        assert_eq!(escape("\x1b[31mred\x1b[0m\tok\n"), "[31mred[0m\tok\n");
+    }
+
+    #[test]
+    fn node_failure_attributes() {
+        let dir = std::env::temp_dir().join(format!("burrito-junit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ckpt = Checkpoint::new(dir.join("state.json"));
+        let failures = [(
+            "a<b>&\"c\"".to_owned(),
+            "launch failed: <Error code=\"x\"> & more".to_owned(),
+        )];
+        write(&dir, &ckpt, &failures).unwrap();
+        let xml = std::fs::read_to_string(dir.join("junit.xml")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(xml.contains(r#"<testsuite name="a&lt;b&gt;&amp;&quot;c&quot;" tests="1" failures="0" errors="1""#), "{}", xml);
+        assert!(
+            xml.contains(
+                r#"<error message="launch failed: &lt;Error code=&quot;x&quot;&gt; &amp; more"/>"#
+            ),
+            "{}",
+            xml
+        );
+        assert!(
+            xml.contains(r#"classname="a&lt;b&gt;&amp;&quot;c&quot;""#),
+            "{}",
+            xml
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Running a node in a Kubernetes pod, via `kubectl`.
+//!
+//! The pod runs sshd, which we reach through `kubectl port-forward`, so from setup on (copying the
+//! binary and script, running the experiment, collecting results) a pod is driven like any other
+//! machine. Pods can't reboot, and have no init system. The image must be apt-based (sshd is
+//! installed as root when the pod starts), and our context must be allowed to port-forward.
+
+use crate::ssh::{generate_key, skip_host_key_check};
+use crate::tags::{merge_opts, ProviderOpts};
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tracing::{debug, info, instrument};
+
+/// Where and how to run the pod.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct PodCfg {
+    /// The kubectl context to use. Defaults to the current one.
+    #[serde(default)]
+    pub context: Option<String>,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    /// Resource requests, which are also the limits (e.g. `{"cpu": "4", "memory": "8Gi"}`).
+    #[serde(default)]
+    pub resources: BTreeMap<String, String>,
+    /// An apt-based image. Defaults to `ubuntu:20.04`.
+    #[serde(default = "default_image")]
+    pub image: String,
+    #[serde(default)]
+    pub privileged: bool,
+    /// Use the node's network namespace rather than the pod network.
+    #[serde(default)]
+    pub host_network: bool,
+}
+
+fn default_namespace() -> String {
+    "default".to_owned()
+}
+
+fn default_image() -> String {
+    "ubuntu:20.04".to_owned()
+}
+
+// there's no init in the pod, so there are no services to stop after installing them.
+const BOOTSTRAP: &str = "apt-get update && \
+    DEBIAN_FRONTEND=noninteractive apt-get install -y openssh-server sudo software-properties-common && \
+    ln -sf /bin/true /usr/local/bin/systemctl && \
+    mkdir -p /run/sshd /root/.ssh && echo \"$AUTHORIZED_KEY\" > /root/.ssh/authorized_keys && \
+    exec /usr/sbin/sshd -D -e";
+
+/// A running pod, forwarded to a local port.
+#[derive(Debug)]
+pub struct Pod {
+    name: String,
+    kubectl: Vec<String>,
+    /// The private key to log in as root with.
+    pub key: PathBuf,
+    pub port: u16,
+    forward: Option<Child>,
+}
+
+/// Pod names must be DNS labels.
+fn pod_name(machine_name: &str) -> Result<String, Report> {
+    let mut n: String = machine_name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    n.truncate(40);
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    Ok(format!("{}-{}", n.trim_matches('-'), now.as_secs()))
+}
+
+async fn run(cmd: &mut Command, what: &str) -> Result<String, Report> {
+    let out = cmd.output().await.wrap_err_with(|| what.to_owned())?;
+    ensure!(
+        out.status.success(),
+        "{} failed: {}",
+        what,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+impl Pod {
+    fn kubectl(&self) -> Command {
+        let mut c = Command::new("kubectl");
+        c.args(&self.kubectl);
+        c
+    }
+
+    /// Create a pod for `machine_name`, and wait until we can ssh to it. `opts` are merged into
+    /// the pod's spec.
+    #[instrument(skip(cfg, opts), level = "debug")]
+    pub async fn launch(
+        cfg: &PodCfg,
+        machine_name: &str,
+        opts: &ProviderOpts,
+    ) -> Result<Self, Report> {
+        let name = pod_name(machine_name)?;
+        let mut kubectl = vec!["--namespace".to_owned(), cfg.namespace.clone()];
+        if let Some(ref c) = cfg.context {
+            kubectl.extend(["--context".to_owned(), c.clone()]);
+        }
+
+        let key = std::env::temp_dir().join(format!("burrito-cloud-exp-{}", name));
+        let mut pod = Pod {
+            name,
+            kubectl,
+            key,
+            port: 0,
+            forward: None,
+        };
+        let pubkey = generate_key(&pod.key).await?;
+
+        info!(pod = ?pod.name, namespace = ?cfg.namespace, "creating pod");
+        if let Err(err) = pod.start(cfg, &pubkey, opts).await {
+            pod.delete().await?;
+            return Err(err);
+        }
+
+        Ok(pod)
+    }
+
+    async fn start(
+        &mut self,
+        cfg: &PodCfg,
+        pubkey: &str,
+        opts: &ProviderOpts,
+    ) -> Result<(), Report> {
+        let mut manifest = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Pod",
+            "metadata": {
+                "name": self.name,
+                "labels": { "app": "burrito-cloud-exp" },
+            },
+            "spec": {
+                "restartPolicy": "Never",
+                "hostNetwork": cfg.host_network,
+                "containers": [{
+                    "name": "exp",
+                    "image": cfg.image,
+                    "command": ["sh", "-c", BOOTSTRAP],
+                    "env": [{ "name": "AUTHORIZED_KEY", "value": pubkey }],
+                    "securityContext": { "privileged": cfg.privileged },
+                    "resources": { "requests": cfg.resources, "limits": cfg.resources },
+                    "readinessProbe": {
+                        "tcpSocket": { "port": 22 },
+                        "periodSeconds": 5,
+                    },
+                }],
+            },
+        });
+        merge_opts(&mut manifest["spec"], opts);
+
+        let mut apply = self.kubectl();
+        apply
+            .args(["apply", "-f", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = apply.spawn().wrap_err("kubectl apply")?;
+        {
+            use tokio::io::AsyncWriteExt;
+            let mut stdin = child.stdin.take().unwrap();
+            stdin.write_all(&serde_json::to_vec(&manifest)?).await?;
+        }
+        let out = child.wait_with_output().await?;
+        ensure!(
+            out.status.success(),
+            "create pod failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+
+        // pulling the image and installing sshd takes a while.
+        run(
+            self.kubectl()
+                .args(["wait", "--for=condition=Ready", "--timeout=600s"])
+                .arg(format!("pod/{}", self.name)),
+            "wait for pod",
+        )
+        .await?;
+        self.forward().await?;
+        skip_host_key_check("127.0.0.1")
+    }
+
+    async fn forward(&mut self) -> Result<(), Report> {
+        let mut child = self
+            .kubectl()
+            .args(["port-forward", "--address", "127.0.0.1"])
+            .arg(format!("pod/{}", self.name))
+            .arg(":22")
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .wrap_err("kubectl port-forward")?;
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        // "Forwarding from 127.0.0.1:<port> -> 22"
+        let first = tokio::time::timeout(Duration::from_secs(30), lines.next_line())
+            .await
+            .wrap_err("wait for port-forward")??
+            .ok_or_else(|| eyre!("kubectl port-forward exited"))?;
+        self.port = first
+            .split_whitespace()
+            .nth(2)
+            .and_then(|a| a.rsplit(':').next())
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(|| eyre!("unexpected port-forward output {:?}", first))?;
+        debug!(port = ?self.port, "forwarding pod ssh");
+
+        // it logs every connection, and would block once the pipe fills up.
+        tokio::spawn(async move {
+            while let Ok(Some(l)) = lines.next_line().await {
+                debug!(line = ?l, "port-forward");
+            }
+        });
+        self.forward = Some(child);
+        Ok(())
+    }
+
+    /// Stop forwarding, and delete the pod.
+    pub async fn delete(mut self) -> Result<(), Report> {
+        if let Some(mut f) = self.forward.take() {
+            let _ = f.kill().await;
+        }
+
+        let _ = std::fs::remove_file(&self.key);
+        let _ = std::fs::remove_file(format!("{}.pub", self.key.display()));
+        info!(pod = ?self.name, "deleting pod");
+        run(
+            self.kubectl()
+                .args(["delete", "pod", "--wait=false", "--ignore-not-found"])
+                .arg(&self.name),
+            "delete pod",
+        )
+        .await?;
+        Ok(())
+    }
+}
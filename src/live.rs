@@ -0,0 +1,223 @@
+//! Finding the machines that a pool or a run has up, to get at them by hand.
+
+use crate::exp::REMOTE_PID;
+use crate::node::Node;
+use crate::pool::Pool;
+use crate::ssh::{self, ConnInfo};
+use crate::state::Checkpoint;
+use crate::{load_nodes, node_ids};
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use futures_util::future::join_all;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tracing::{info, warn};
+
+#[derive(Debug)]
+pub struct LiveMachine {
+    /// The node's id.
+    pub id: String,
+    pub conn: ConnInfo,
+    /// The node's configuration, for its ssh options, if we know it.
+    pub node: Option<Node>,
+}
+
+impl LiveMachine {
+    pub fn ssh_command(&self) -> std::process::Command {
+        match self.node {
+            Some(ref n) => n.ssh_command(&self.conn),
+            None => ssh::command(&self.conn, &Default::default(), None),
+        }
+    }
+}
+
+/// The machines in the pool file `pool`, and those in the run state file `state` (with their
+/// configuration from `cfg`, if given). Missing files have no machines.
+pub fn machines(pool: &Path, state: &Path, cfg: Option<&Path>) -> Result<Vec<LiveMachine>, Report> {
+    let mut ms = vec![];
+    if pool.exists() {
+        ms.extend(Pool::load(pool)?.machines.into_iter().map(|m| LiveMachine {
+            id: m.id,
+            conn: m.instance.conn,
+            node: Some(m.node),
+        }));
+    }
+
+    if state.exists() {
+        let nodes = match cfg {
+            Some(c) => {
+                let nodes = load_nodes(c)?;
+                node_ids(&nodes)?.into_iter().zip(nodes).collect()
+            }
+            None => vec![],
+        };
+        for (id, inst) in Checkpoint::load(state)?.instances() {
+            // a pool machine being used by the run.
+            if ms.iter().any(|m| m.id == id) {
+                continue;
+            }
+
+            let node = nodes.iter().find(|(i, _)| *i == id).map(|(_, n)| n.clone());
+            ms.push(LiveMachine {
+                id,
+                conn: inst.conn,
+                node,
+            });
+        }
+    }
+
+    Ok(ms)
+}
+
+/// Replace this process with an interactive ssh session to machine `id`.
+pub fn ssh_into(machines: Vec<LiveMachine>, id: &str) -> Result<(), Report> {
+    let m = machines
+        .into_iter()
+        .find(|m| m.id == id)
+        .ok_or_else(|| eyre!("no live machine for node {:?}", id))?;
+    let mut cmd = m.ssh_command();
+    info!(?id, host = ?m.conn.host, "connecting");
+    use std::os::unix::process::CommandExt;
+    Err(cmd.exec()).wrap_err("exec ssh")
+}
+
+/// Print the process tree and python stacks (from `py-spy dump`, with `native` frames if asked)
+/// of the experiment script running on machine `id`. With `gcore`, the bench binary's name, also
+/// dump the cores of its running processes, and fetch them into `core_dir`. py-spy and gdb are
+/// installed if they aren't there.
+pub async fn inspect(
+    machines: Vec<LiveMachine>,
+    id: &str,
+    native: bool,
+    gcore: Option<&str>,
+    core_dir: &Path,
+) -> Result<(), Report> {
+    let m = machines
+        .into_iter()
+        .find(|m| m.id == id)
+        .ok_or_else(|| eyre!("no live machine for node {:?}", id))?;
+    let (pid_file, python) = match m.node {
+        Some(ref n) => (
+            n.workdir()
+                .map_or(REMOTE_PID.to_owned(), |wd| format!("{}/{}", wd, REMOTE_PID)),
+            n.python(),
+        ),
+        None => (REMOTE_PID.to_owned(), "python3".to_owned()),
+    };
+    let mut cmd = format!(
+        "pid=$(cat {pid_file}) || exit 1; export PATH=$PATH:$HOME/.local/bin; \
+        echo '== processes'; ps -o pid,stat,etime,time,args -g $pid; \
+        command -v py-spy >/dev/null || {{ echo 'installing py-spy' >&2; \
+        {python} -m pip install -q --user py-spy >&2 || sudo -n pip3 install -q py-spy >&2; }}; \
+        for p in $(pgrep -g $pid python); do echo \"== py-spy dump --pid $p\"; \
+        sudo -n $(command -v py-spy) dump {native}--pid $p; done",
+        pid_file = pid_file,
+        python = python,
+        native = if native { "--native " } else { "" },
+    );
+    if let Some(bin) = gcore {
+        cmd.push_str(&format!(
+            "; command -v gcore >/dev/null || {{ echo 'installing gdb' >&2; \
+            sudo -n DEBIAN_FRONTEND=noninteractive apt-get install -y -q gdb >&2; }}; \
+            for p in $(pgrep -g $pid -x {bin}); do echo \"== gcore $p\"; \
+            sudo -n gcore -o /tmp/burrito-exp-core $p >&2 && echo \"core: /tmp/burrito-exp-core.$p\"; done",
+            bin = bin
+        ));
+    }
+
+    info!(?id, host = ?m.conn.host, "inspecting");
+    let out = tokio::process::Command::from(m.ssh_command())
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .wrap_err("run ssh")?;
+    let out = String::from_utf8_lossy(&out.stdout);
+    print!("{}", out);
+
+    let cores: Vec<&str> = out
+        .lines()
+        .filter_map(|l| l.strip_prefix("core: "))
+        .collect();
+    if gcore.is_some() && cores.is_empty() {
+        warn!(bin = ?gcore, "no cores dumped: is the bench binary running?");
+    }
+
+    for core in cores {
+        std::fs::create_dir_all(core_dir)?;
+        let name = Path::new(core)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let to = core_dir.join(format!("{}-{}", id, name));
+        let f = std::fs::File::create(&to).wrap_err_with(|| format!("create {:?}", to))?;
+        let st = tokio::process::Command::from(m.ssh_command())
+            .arg(format!(
+                "sudo -n cat {core} && sudo -n rm -f {core}",
+                core = core
+            ))
+            .stdin(Stdio::null())
+            .stdout(f)
+            .status()
+            .await
+            .wrap_err("fetch core")?;
+        ensure!(st.success(), "could not fetch core {}", core);
+        info!(core = ?to, "fetched core");
+    }
+
+    Ok(())
+}
+
+/// Run `cmd` on all of `machines` at once, printing their output as it comes, each line prefixed
+/// with the node id.
+pub async fn exec(machines: &[LiveMachine], cmd: &[String]) -> Result<(), Report> {
+    ensure!(!machines.is_empty(), "no live machines to run on");
+    let runs = machines.iter().map(|m| async move {
+        let res = exec_one(m, cmd).await;
+        if let Err(ref err) = res {
+            warn!(?err, id = ?m.id, "command failed");
+        }
+
+        res
+    });
+    let failed = join_all(runs)
+        .await
+        .into_iter()
+        .filter(Result::is_err)
+        .count();
+    ensure!(
+        failed == 0,
+        "command failed on {} of {} machines",
+        failed,
+        machines.len()
+    );
+    Ok(())
+}
+
+async fn exec_one(m: &LiveMachine, cmd: &[String]) -> Result<(), Report> {
+    let mut child = tokio::process::Command::from(m.ssh_command())
+        .args(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err("run ssh")?;
+    let out = print_prefixed(child.stdout.take().unwrap(), &m.id, false);
+    let err = print_prefixed(child.stderr.take().unwrap(), &m.id, true);
+    let (st, (), ()) = tokio::join!(child.wait(), out, err);
+    let st = st?;
+    ensure!(st.success(), "exited with {}", st);
+    Ok(())
+}
+
+async fn print_prefixed(r: impl AsyncRead + Unpin, id: &str, stderr: bool) {
+    let mut lines = BufReader::new(r).lines();
+    while let Ok(Some(l)) = lines.next_line().await {
+        if stderr {
+            eprintln!("[{}] {}", id, l);
+        } else {
+            println!("[{}] {}", id, l);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! What a repetition's machine really was, recorded in `machine.json` with its results: the
+//! instance type alone hides which CPU, hypervisor, and network a run got. What software it had
+//! goes in `environment.txt`.
+
+use crate::ratelimit;
+use crate::tags::{ec2_client, Cloud};
+use color_eyre::eyre::{Report, WrapErr};
+use openssh::Session;
+use rusoto_ec2::Ec2;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::debug;
+
+#[derive(serde::Serialize, Debug, Default)]
+pub struct MachineInfo {
+    pub kernel: Option<String>,
+    pub cpu_model: Option<String>,
+    pub hypervisor: Option<String>,
+    /// From the provider's instance metadata service, for AWS and Azure machines.
+    pub instance_type: Option<String>,
+    pub zone: Option<String>,
+    pub instance_id: Option<String>,
+    /// The instance type's network performance tier (AWS), e.g. `Up to 5 Gigabit`.
+    pub network_performance: Option<String>,
+    /// Of the interface the default route goes out of.
+    pub mtu: Option<u32>,
+    /// Everything `lscpu` says.
+    pub lscpu: BTreeMap<String, String>,
+}
+
+/// The command's trimmed stdout, if it succeeds with any.
+async fn output(ssh: &Session, cmd: &str) -> Option<String> {
+    match ssh.shell(cmd).output().await {
+        Ok(out) if out.status.success() => {
+            Some(String::from_utf8_lossy(&out.stdout).trim().to_owned()).filter(|s| !s.is_empty())
+        }
+        Ok(_) | Err(_) => {
+            debug!(?cmd, "could not get machine info");
+            None
+        }
+    }
+}
+
+const AWS_IMDS: &str = "t=$(curl -sf -m 2 -X PUT -H 'X-aws-ec2-metadata-token-ttl-seconds: 60' \
+    http://169.254.169.254/latest/api/token) && \
+    for k in instance-type placement/availability-zone instance-id; do \
+    curl -sf -m 2 -H \"X-aws-ec2-metadata-token: $t\" http://169.254.169.254/latest/meta-data/$k; echo; done";
+
+const AZURE_IMDS: &str = "curl -sf -m 2 -H Metadata:true \
+    'http://169.254.169.254/metadata/instance/compute?api-version=2021-02-01'";
+
+/// Ask the machine (and its provider, for `cloud` machines) what it is.
+pub async fn describe(ssh: &Session, cloud: Option<&Cloud>) -> MachineInfo {
+    let mut info = MachineInfo {
+        kernel: output(ssh, "uname -r").await,
+        mtu: output(ssh, &crate::net::mtu_cmd())
+            .await
+            .and_then(|m| m.parse().ok()),
+        ..Default::default()
+    };
+    if let Some(out) = output(ssh, "lscpu").await {
+        info.lscpu = out
+            .lines()
+            .filter_map(|l| l.split_once(':'))
+            .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+            .collect();
+    }
+
+    info.cpu_model = info.lscpu.get("Model name").cloned();
+    info.hypervisor = info.lscpu.get("Hypervisor vendor").cloned();
+
+    match cloud {
+        Some(c @ Cloud::Aws { region, profile }) => {
+            if let Some(out) = output(ssh, AWS_IMDS).await {
+                let mut lines = out
+                    .lines()
+                    .map(|l| Some(l.to_owned()).filter(|l| !l.is_empty()));
+                info.instance_type = lines.next().flatten();
+                info.zone = lines.next().flatten();
+                info.instance_id = lines.next().flatten();
+            }
+
+            if let Some(ref it) = info.instance_type {
+                match aws_instance_type(c, region, profile.as_deref(), it).await {
+                    Ok(Some(t)) => {
+                        info.network_performance =
+                            t.network_info.and_then(|n| n.network_performance);
+                        // nitro machines just say KVM.
+                        info.hypervisor = t.hypervisor.or(info.hypervisor);
+                    }
+                    Ok(None) => (),
+                    Err(err) => debug!(?err, "could not describe instance type"),
+                }
+            }
+        }
+        Some(Cloud::Azure) => {
+            let compute: Option<serde_json::Value> = output(ssh, AZURE_IMDS)
+                .await
+                .and_then(|o| serde_json::from_str(&o).ok());
+            if let Some(c) = compute {
+                let field = |k: &str| c[k].as_str().filter(|s| !s.is_empty()).map(str::to_owned);
+                info.instance_type = field("vmSize");
+                info.zone = field("zone").or_else(|| field("location"));
+                info.instance_id = field("vmId");
+            }
+        }
+        _ => (),
+    }
+
+    info
+}
+
+async fn aws_instance_type(
+    cloud: &Cloud,
+    region: &str,
+    profile: Option<&str>,
+    instance_type: &str,
+) -> Result<Option<rusoto_ec2::InstanceTypeInfo>, Report> {
+    let client = ec2_client(region, profile)?;
+    ratelimit::acquire(cloud).await;
+    let resp = client
+        .describe_instance_types(rusoto_ec2::DescribeInstanceTypesRequest {
+            instance_types: Some(vec![instance_type.to_owned()]),
+            ..Default::default()
+        })
+        .await
+        .wrap_err("describe instance types")?;
+    Ok(resp.instance_types.and_then(|t| t.into_iter().next()))
+}
+
+/// Describe the machine into `dir/machine.json`.
+pub async fn record(
+    ssh: &Session,
+    cloud: Option<&Cloud>,
+    dir: &Path,
+) -> Result<MachineInfo, Report> {
+    let info = describe(ssh, cloud).await;
+    debug!(?info, "machine info");
+    let f = std::fs::File::create(dir.join("machine.json")).wrap_err("create machine.json")?;
+    serde_json::to_writer_pretty(f, &info).wrap_err("write machine.json")?;
+    Ok(info)
+}
+
+/// What `environment.txt` has, as (section, command).
+fn env_sections(python: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("packages", "dpkg -l".to_owned()),
+        ("python packages", format!("{} -m pip freeze", python)),
+        ("sysctl", "sudo -n sysctl -a 2>/dev/null || sysctl -a 2>/dev/null".to_owned()),
+        (
+            "nic drivers",
+            "for d in $(ls /sys/class/net | grep -vx lo); do echo \"$d:\"; ethtool -i $d 2>/dev/null \
+            || echo \"driver: $(basename $(readlink /sys/class/net/$d/device/driver 2>/dev/null) 2>/dev/null)\"; done"
+                .to_owned(),
+        ),
+        ("redis", "redis-server --version; redis-cli --version".to_owned()),
+    ]
+}
+
+/// Snapshot the machine's software (packages, `python`'s packages, sysctls, NIC drivers and
+/// firmware, redis) into `dir/environment.txt`, to tell what changed between runs. Sections that
+/// can't be had say so.
+pub async fn record_environment(ssh: &Session, python: &str, dir: &Path) -> Result<(), Report> {
+    let mut text = String::new();
+    for (section, cmd) in env_sections(python) {
+        let out = output(ssh, &cmd)
+            .await
+            .unwrap_or_else(|| "(unavailable)".to_owned());
+        text.push_str(&format!("=== {}\n{}\n\n", section, out));
+    }
+
+    let path = dir.join("environment.txt");
+    std::fs::write(&path, text).wrap_err("write environment.txt")?;
+    debug!(?path, "recorded environment");
+    Ok(())
+}
@@ -1,309 +1,1193 @@
-use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use futures_util::stream::StreamExt;
 use openssh::Session;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use tracing::{debug, info, instrument, warn};
 use tracing_error::ErrorLayer;
 use tracing_subscriber::prelude::*;
-use tsunami::providers::{aws, azure, baremetal};
-use tsunami::Tsunami;
+
+mod aggregate;
+mod azure;
+mod budget;
+mod catalog;
+mod compare;
+mod control;
+mod dag;
+mod db;
+mod deps;
+mod disk;
+mod ec2;
+mod events;
+mod exp;
+mod firewall;
+mod heartbeat;
+mod init;
+mod inventory;
+mod junit;
+mod k8s;
+mod live;
+mod machine;
+mod net;
+mod nic;
+mod node;
+mod oci;
+mod openstack;
+mod perms;
+mod pool;
+mod post;
+mod progress;
+mod provenance;
+mod qemu;
+mod queue;
+mod ratelimit;
+mod ready;
+mod redis;
+mod retry;
+mod schedule;
+mod secrets;
+mod serve;
+mod service;
+mod setup;
+mod sidecar;
+mod ssh;
+mod state;
+mod summary;
+mod sweep;
+mod tags;
+mod teardown;
+mod throttle;
+mod transfer;
+mod vps;
+use node::{Node, OnError, Order, RunOpts};
 
 #[derive(Debug, Clone, StructOpt)]
 struct Opt {
+    // these are only optional so that subcommands don't need them.
     /// Node config
     #[structopt(short, long)]
-    cfg: PathBuf,
+    cfg: Option<PathBuf>,
+    /// Run several node configs, each into its own directory: a directory of `*.json` configs,
+    /// or a file listing config paths, one per line
+    #[structopt(long, conflicts_with = "cfg")]
+    queue: Option<PathBuf>,
+    /// Run this many of the `--queue`'s configs at once
+    #[structopt(long, default_value = "1")]
+    queue_jobs: usize,
+    /// Launch no more cloud machines once the run (or the whole `--queue`) has used this many
+    /// instance-hours
+    #[structopt(long)]
+    budget_hours: Option<f64>,
+    /// Once the budget is used up, running nodes `finish` their repetitions, or `abandon` them
+    /// after the current one
+    #[structopt(long, default_value = "finish")]
+    budget_policy: budget::Policy,
 
     /// Location of the bench binary to copy
     #[structopt(short, long)]
-    bench_bin: PathBuf,
+    bench_bin: Option<PathBuf>,
+    /// Bench binary for machines of another architecture, as `arch=path` with `arch` as `uname -m`
+    /// reports it, e.g. `aarch64=target/aarch64-unknown-linux-gnu/release/bench` (repeatable)
+    #[structopt(long = "bench-bin-for", parse(try_from_str = parse_arch_bin))]
+    arch_bins: Vec<(String, PathBuf)>,
     /// Location of the experiment script to copy
     #[structopt(short, long)]
-    script: PathBuf,
+    script: Option<PathBuf>,
+    /// Build info to record for the bench binary (e.g. its burrito commit), instead of the first
+    /// line of its `--version`
+    #[structopt(long)]
+    bench_meta: Option<String>,
+    /// Put the script's commit and the bench binary's hash in result file names
+    #[structopt(long)]
+    stamp_results: bool,
+    /// Run nodes even if a run with the same node config, sweep, bench binary, and script already
+    /// completed under `--out-dir`
+    #[structopt(long)]
+    force: bool,
+    /// Before launching anything, check the credentials can make every provider API call the run
+    /// needs, and print a minimal policy granting them if not
+    #[structopt(long)]
+    check_permissions: bool,
+
+    /// Tag launched cloud resources with `key=value` (repeatable), in addition to per-node tags
+    #[structopt(long = "tag", parse(try_from_str = tags::parse_tag))]
+    tags: Vec<(String, String)>,
+
+    /// Run each node this many times, overriding the per-node `repetitions`
+    #[structopt(long)]
+    reps: Option<usize>,
+    /// Directory to write collected results into
+    #[structopt(long, default_value = ".")]
+    out_dir: PathBuf,
+    /// Label for this run (e.g. the burrito branch under test); results go in `<out-dir>/<name>`
+    #[structopt(long)]
+    name: Option<String>,
+    /// After the run, combine all results under the output directory into `results.csv`
+    #[structopt(long)]
+    aggregate: bool,
+    /// After the run, write it as a JUnit XML test report, `junit.xml`, one test case per
+    /// experiment
+    #[structopt(long)]
+    junit: bool,
+    /// Send the run's progress (nodes starting, experiments finishing, ...) as JSON events to
+    /// this `http://` or `https://` endpoint, or unix socket
+    #[structopt(long)]
+    events: Option<events::Target>,
+    /// Write `heartbeat.json` to the output directory this often, for `status` (0 to not)
+    #[structopt(long, default_value = "60")]
+    heartbeat_secs: u64,
+    /// Also send each heartbeat as an `--events` event
+    #[structopt(long)]
+    heartbeat_events: bool,
+    /// Record each node's results in this SQLite database
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    /// Only run experiments matching these parameters, e.g. `rcvrs=10,batch=opt` (repeatable)
+    #[structopt(long)]
+    only: Vec<sweep::Filter>,
+    /// Don't run experiments matching these parameters, e.g. `groups=be` (repeatable)
+    #[structopt(long)]
+    skip: Vec<sweep::Filter>,
+    /// Don't pause for manual inspection before tearing machines down
+    #[structopt(long)]
+    no_pause: bool,
+    /// Continue the run checkpointed in this state file (normally `<out-dir>/state.json`)
+    #[structopt(long)]
+    resume: Option<PathBuf>,
+    /// Pool file: runs use its machines for the nodes they match, instead of launching new ones
+    /// (`pool` subcommands default to `pool.json`)
+    #[structopt(long)]
+    pool: Option<PathBuf>,
+    /// Ansible-style inventory of baremetal hosts, for nodes that pick a host from a group
+    #[structopt(long)]
+    inventory: Option<PathBuf>,
+    /// After the run, compare its results with this earlier run's, and fail if any experiment
+    /// regressed by more than `--fail-threshold`
+    #[structopt(long)]
+    baseline: Option<PathBuf>,
+    /// Percentage latency growth over `--baseline` that counts as a regression, e.g. `5%`
+    #[structopt(long, default_value = "5%", parse(try_from_str = parse_pct))]
+    fail_threshold: f64,
+    /// After the run, re-run on the same pool machines whenever the script, node config, or bench
+    /// binary changes
+    #[structopt(long)]
+    watch: bool,
+    /// Limit each upload and result collection to this bandwidth, e.g. `2M` (KiB/s without a
+    /// unit, as for rsync)
+    #[structopt(long, parse(try_from_str = transfer::parse_bwlimit))]
+    bwlimit: Option<u64>,
+    /// Where stored secrets are: an age-encrypted file, or `keyring`. Defaults to
+    /// `~/.config/burrito-exp/secrets.age`, if it exists
+    #[structopt(long)]
+    secrets: Option<secrets::Store>,
+    /// When a node fails: `abort` the run, or `continue` with the other nodes and report every
+    /// failure at the end
+    #[structopt(long, default_value = "abort")]
+    on_error: OnError,
+    /// For CI and cron: log only warnings, nodes' phase changes, and the final summary, without
+    /// colors, never pause, and never read stdin. `RUST_LOG` still overrides the logging
+    #[structopt(long, visible_alias = "ci")]
+    quiet: bool,
+
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
 }
 
-async fn with_launcher(
-    launcher: &mut impl tsunami::Tsunami,
-    machine_name: &str,
-    script_remote_path: &Path,
-    bench_remote_path: &Path,
-    prov: &str,
-) -> Result<(), Report> {
-    let conns = launcher.connect_all().await?;
-    let vm = conns.get(machine_name).unwrap();
-    do_exp(&vm.ssh, script_remote_path, bench_remote_path, prov).await
+#[derive(Debug, Clone, StructOpt)]
+enum Cmd {
+    /// Compare the results of two runs, flagging experiments whose latency regressed
+    Compare {
+        /// Output directory of the baseline run
+        a: PathBuf,
+        /// Output directory of the run to check
+        b: PathBuf,
+        /// Flag experiments whose mean or percentile latency grew by more than this percentage
+        #[structopt(long, default_value = "10")]
+        threshold_pct: f64,
+    },
+    /// Run as a service that accepts experiment jobs over HTTP
+    Serve {
+        /// Address to listen on
+        #[structopt(long, default_value = "127.0.0.1:8080")]
+        listen: std::net::SocketAddr,
+        /// How many jobs to run at once
+        #[structopt(long, default_value = "1")]
+        concurrency: usize,
+        /// File holding a token that requests must carry, as `Authorization: Bearer <token>`.
+        /// Required unless listening on a loopback address
+        #[structopt(long)]
+        token_file: Option<PathBuf>,
+    },
+    /// Launch and set up all the `--cfg` nodes at once, into the pool, for a later `execute`
+    Prepare,
+    /// Run the experiments on the machines from `prepare`, leaving them up for `pool down`
+    Execute {
+        /// `sequential` (each node's repetitions in turn) or `interleaved` (the first repetition
+        /// of each node, then the second, ...)
+        #[structopt(long, default_value = "sequential")]
+        order: Order,
+    },
+    /// Show the run's progress from its heartbeat, failing if it has stopped or appears hung
+    Status {
+        /// Count the run as hung after this many minutes without progress
+        #[structopt(long, default_value = "30")]
+        stale_mins: u64,
+    },
+    /// Open an interactive ssh session on a node's machine, from the pool or the run's state file
+    Ssh {
+        /// The node's id (its name, if it has one)
+        node: String,
+    },
+    /// Dump the process tree and python stacks (with py-spy, installed if need be) of a node's
+    /// running experiment script
+    Inspect {
+        /// The node's id (its name, if it has one)
+        node: String,
+        /// Include native frames in the python stacks
+        #[structopt(long)]
+        native: bool,
+        /// Also gcore the running bench binary (`--bench-bin`), fetching the cores into
+        /// `<out-dir>/cores`
+        #[structopt(long)]
+        gcore: bool,
+    },
+    /// Run a command on live machines, from the pool or the run's state file, e.g.
+    /// `exec --all -- top -bn1`
+    Exec {
+        /// Run on every live machine
+        #[structopt(long)]
+        all: bool,
+        /// Run on this node's machine (repeatable)
+        #[structopt(long = "node")]
+        nodes: Vec<String>,
+        #[structopt(required = true, last = true)]
+        cmd: Vec<String>,
+    },
+    /// Re-run the sweep on a schedule until interrupted, each run in a directory named for when it
+    /// started, and compare each run to the last
+    Schedule {
+        /// When to run, as a cron expression in local time, e.g. `0 2 * * *` for 2am every day
+        cron: schedule::Cron,
+        /// Run this shell command before each run, e.g. to build the latest bench binary
+        #[structopt(long)]
+        build: Option<String>,
+        /// Flag experiments whose latency grew by more than this percentage since the last run
+        #[structopt(long, default_value = "10")]
+        threshold_pct: f64,
+        /// Run this shell command when a run regresses, with `BURRITO_EXP_RUN` and
+        /// `BURRITO_EXP_BASELINE` set to the two runs' directories
+        #[structopt(long)]
+        notify: Option<String>,
+    },
+    /// Check the credentials can make every provider API call the `--cfg` nodes need, and print
+    /// a minimal AWS IAM policy (or Azure role) granting just those
+    CheckPermissions,
+    /// Write a starter node config, with an example node for each provider
+    Init {
+        /// Where to write it
+        #[structopt(default_value = "nodes.json")]
+        path: PathBuf,
+        /// Overwrite the file if it exists
+        #[structopt(long)]
+        force: bool,
+    },
+    /// Print a shell completion script, e.g. `completions bash > /etc/bash_completion.d/burrito`
+    Completions {
+        #[structopt(possible_values = &structopt::clap::Shell::variants())]
+        shell: structopt::clap::Shell,
+    },
+    /// Manage stored secrets: credentials and webhook URLs that stand in for environment
+    /// variables of the same name
+    Secrets {
+        #[structopt(subcommand)]
+        cmd: SecretsCmd,
+    },
+    /// Keep a pool of set-up machines running across runs
+    Pool {
+        #[structopt(subcommand)]
+        cmd: PoolCmd,
+    },
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
-enum Node {
-    Aws { region: String },
-    Azure { region: String },
-    Baremetal { ip: String, user: String },
+#[derive(Debug, Clone, StructOpt)]
+enum SecretsCmd {
+    /// Store a secret, reading its value from stdin
+    Set { name: String },
+    /// Remove a secret
+    Rm { name: String },
+    /// List the stored secrets' names
+    List,
 }
 
-impl Node {
-    #[instrument]
-    async fn run(self, bench_bin: PathBuf, script: PathBuf) -> Result<(), Report> {
-        let bench_remote_path = Path::new(bench_bin.file_name().unwrap()).to_path_buf();
-        let script_remote_path = Path::new(script.file_name().unwrap()).to_path_buf();
-
-        info!("starting machines");
-
-        const MACHINE_NAME: &str = "burrito-test-machine";
-        let chmod_cmd = format!("chmod +x {}", bench_remote_path.to_str().unwrap());
-        match self {
-            Node::Aws { region } => {
-                let srp = script_remote_path.clone();
-                let brp = bench_remote_path.clone();
-                let mut aws_launcher = aws::Launcher::default();
-                aws_launcher.set_mode(aws::LaunchMode::TrySpot { hours: 6 });
-                let ami = ubuntu_ami::get_latest(
-                    &region,
-                    Some("focal"),
-                    None,
-                    Some("hvm:ebs-ssd"),
-                    Some("amd64"),
-                )
-                .await
-                .map_err(|e| eyre!(e))?;
-                let m = aws::Setup::default()
-                    .region(region.clone().parse()?, ami, "ubuntu")
-                    .instance_type("t3.medium")
-                    .setup(move |vm| {
-                        let bench_remote_path = brp.clone();
-                        let bench_bin = bench_bin.clone();
-                        let script = script.clone();
-                        let script_remote_path = srp.clone();
-                        let chmod_cmd = chmod_cmd.clone();
-                        Box::pin(async move {
-                            install_deps(&vm.ssh).await?;
-                            write_file(&vm.ssh, &bench_bin, bench_remote_path.as_path()).await?;
-                            let ok = vm.ssh.shell(&chmod_cmd).status().await?;
-                            ensure!(ok.success(), "chmod bench");
-                            write_file(&vm.ssh, &script, script_remote_path.as_path()).await?;
-                            Ok(())
-                        })
-                    });
-                if let Err(e) = aws_launcher
-                    .spawn(
-                        vec![(MACHINE_NAME.to_owned(), m)],
-                        Some(std::time::Duration::from_secs(180)),
-                    )
-                    .await
-                {
-                    aws_launcher.terminate_all().await?;
-                    return Err(e);
+#[derive(Debug, Clone, StructOpt)]
+enum PoolCmd {
+    /// Launch and set up the `--cfg` nodes that aren't already in the pool
+    Up,
+    /// Show the pool's machines, and whether they are reachable
+    Status,
+    /// Terminate the pool's machines
+    Down,
+}
+
+fn main() -> Result<(), Report> {
+    color_eyre::install()?;
+    let opt = Opt::from_args();
+    let filter = match std::env::var_os("RUST_LOG") {
+        None if opt.quiet => tracing_subscriber::EnvFilter::new(format!("warn,{}=info", STATUS)),
+        _ => tracing_subscriber::EnvFilter::from_default_env(),
+    };
+    let subscriber = tracing_subscriber::registry();
+    let subscriber = subscriber
+        .with(tracing_subscriber::fmt::layer().with_ansi(!opt.quiet))
+        .with(filter)
+        .with(ErrorLayer::default());
+    let d = tracing::Dispatch::new(subscriber);
+    d.init();
+    info!(?opt, "starting");
+    let store = opt.secrets.clone().or_else(secrets::default_store);
+    if let Some(Cmd::Secrets { ref cmd }) = opt.cmd {
+        let store = store.unwrap_or(secrets::Store::Age(
+            secrets::default_path().wrap_err("find the secrets file")?,
+        ));
+        return match cmd {
+            SecretsCmd::Set { name } => store.set(name, &secrets::read_value()?),
+            SecretsCmd::Rm { name } => store.remove(name),
+            SecretsCmd::List => {
+                for name in store.load()?.keys() {
+                    println!("{}", name);
                 }
 
-                //wait_for_continue();
-
-                let res = with_launcher(
-                    &mut aws_launcher,
-                    MACHINE_NAME,
-                    &script_remote_path,
-                    bench_remote_path.as_path(),
-                    "aws",
-                )
-                .await;
-                aws_launcher.terminate_all().await?;
-                res
+                Ok(())
             }
-            Node::Azure { region: r } => {
-                let srp = script_remote_path.clone();
-                let brp = bench_remote_path.clone();
-                let mut az_launcher = azure::Launcher::default();
-                let m = azure::Setup::default()
-                    .region(r.clone().parse()?)
-                    .image("Canonical:0001-com-ubuntu-server-focal:20_04-lts:latest".to_owned())
-                    .instance_type("Standard_B2ms".to_owned())
-                    .setup(move |vm| {
-                        let bench_remote_path = brp.clone();
-                        let bench_bin = bench_bin.clone();
-                        let script = script.clone();
-                        let script_remote_path = srp.clone();
-                        let chmod_cmd = chmod_cmd.clone();
-                        Box::pin(async move {
-                            install_deps(&vm.ssh).await?;
-                            write_file(&vm.ssh, &bench_bin, bench_remote_path.as_path()).await?;
-                            let ok = vm.ssh.shell(&chmod_cmd).status().await?;
-                            ensure!(ok.success(), "chmod bench");
-                            write_file(&vm.ssh, &script, script_remote_path.as_path()).await?;
-                            Ok(())
-                        })
-                    });
-                if let Err(e) = az_launcher
-                    .spawn(vec![(MACHINE_NAME.to_owned(), m)], None)
-                    .await
-                {
-                    az_launcher.terminate_all().await?;
-                    return Err(e);
+        };
+    }
+
+    // these change the environment, which is only safe before there are other threads.
+    if let Some(ref s) = store {
+        s.export().wrap_err("load secrets")?;
+    }
+
+    ssh::install_wrapper().wrap_err("install ssh wrapper")?;
+
+    tokio::runtime::Runtime::new()
+        .wrap_err("start runtime")?
+        .block_on(dispatch(opt))
+}
+
+async fn dispatch(opt: Opt) -> Result<(), Report> {
+    if opt.quiet {
+        detach_stdin()?;
+    }
+
+    match opt.cmd {
+        Some(Cmd::Compare {
+            ref a,
+            ref b,
+            threshold_pct,
+        }) => {
+            let deltas = compare::compare(a, b, threshold_pct)?;
+            compare::print_deltas(&deltas);
+            Ok(())
+        }
+        Some(Cmd::Serve {
+            listen,
+            concurrency,
+            ref token_file,
+        }) => {
+            let token = token_file
+                .as_deref()
+                .map(|p| {
+                    std::fs::read_to_string(p)
+                        .wrap_err_with(|| format!("read token file {:?}", p))
+                        .map(|t| t.trim().to_owned())
+                })
+                .transpose()?;
+            let defaults = serve::Defaults {
+                out_dir: opt.out_dir.clone(),
+                db: opt.db.clone(),
+                tags: opt.tags.iter().cloned().collect(),
+                on_error: opt.on_error,
+                heartbeat: std::time::Duration::from_secs(opt.heartbeat_secs),
+                budget: budget::Budget::new(opt.budget_hours, opt.budget_policy)?,
+            };
+            serve::serve(listen, concurrency, token, defaults).await
+        }
+        Some(Cmd::Prepare) => prepare(&opt).await,
+        Some(Cmd::CheckPermissions) => {
+            let cfg = opt
+                .cfg
+                .as_deref()
+                .ok_or_else(|| eyre!("--cfg is required"))?;
+            perms::check(&load_nodes(cfg)?, true).await
+        }
+        Some(Cmd::Init { ref path, force }) => init::write_example(path, force),
+        Some(Cmd::Completions { shell }) => {
+            Opt::clap().gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut std::io::stdout());
+            Ok(())
+        }
+        Some(Cmd::Schedule {
+            ref cron,
+            ref build,
+            threshold_pct,
+            ref notify,
+        }) => {
+            schedule(
+                &opt,
+                cron,
+                build.as_deref(),
+                threshold_pct,
+                notify.as_deref(),
+            )
+            .await
+        }
+        Some(Cmd::Status { stale_mins }) => heartbeat::status(
+            &run_dir(&opt),
+            std::time::Duration::from_secs(stale_mins * 60),
+        ),
+        Some(Cmd::Ssh { ref node }) => live::ssh_into(live_machines(&opt)?, node),
+        Some(Cmd::Inspect {
+            ref node,
+            native,
+            gcore,
+        }) => {
+            let bench = match opt.bench_bin {
+                Some(ref b) if gcore => Some(b.file_name().unwrap().to_string_lossy().into_owned()),
+                None if gcore => bail!("--gcore needs --bench-bin, to know what to dump"),
+                _ => None,
+            };
+            live::inspect(
+                live_machines(&opt)?,
+                node,
+                native,
+                bench.as_deref(),
+                &run_dir(&opt).join("cores"),
+            )
+            .await
+        }
+        Some(Cmd::Exec {
+            all,
+            ref nodes,
+            ref cmd,
+        }) => {
+            ensure!(
+                all == nodes.is_empty(),
+                "pass either --all or the --node(s) to run on"
+            );
+            let mut machines = live_machines(&opt)?;
+            if !all {
+                for n in nodes {
+                    ensure!(
+                        machines.iter().any(|m| &m.id == n),
+                        "no live machine for node {:?}",
+                        n
+                    );
                 }
 
-                let res = with_launcher(
-                    &mut az_launcher,
-                    MACHINE_NAME,
-                    &script_remote_path,
-                    bench_remote_path.as_path(),
-                    "azure",
-                )
-                .await;
-                az_launcher.terminate_all().await?;
-                res
+                machines.retain(|m| nodes.contains(&m.id));
             }
-            Node::Baremetal { ip: i, user: u } => {
-                let mut launcher = baremetal::Machine::default();
-                let srp = script_remote_path.clone();
-                let brp = bench_remote_path.clone();
-                let m =
-                    tsunami::providers::baremetal::Setup::new((i.as_str(), 22), Some(u.clone()))?
-                        .setup(move |vm| {
-                            let bench_remote_path = brp.clone();
-                            let bench_bin = bench_bin.clone();
-                            let script = script.clone();
-                            let script_remote_path = srp.clone();
-                            let chmod_cmd = chmod_cmd.clone();
-                            Box::pin(async move {
-                                install_deps(&vm.ssh).await?;
-                                write_file(&vm.ssh, &bench_bin, bench_remote_path.as_path())
-                                    .await?;
-                                let ok = vm.ssh.shell(&chmod_cmd).status().await?;
-                                ensure!(ok.success(), "chmod bench");
-                                write_file(&vm.ssh, &script, script_remote_path.as_path()).await?;
-                                Ok(())
-                            })
-                        });
-                // termination doesn't matter here
-                launcher
-                    .spawn(vec![(MACHINE_NAME.to_owned(), m)], None)
-                    .await?;
-                let conns = launcher.connect_all().await?;
-                let vm = conns.get(MACHINE_NAME).unwrap();
-                do_exp(
-                    &vm.ssh,
-                    &script_remote_path,
-                    bench_remote_path.as_path(),
-                    "gcp",
-                )
-                .await
+
+            live::exec(&machines, cmd).await
+        }
+        Some(Cmd::Pool { ref cmd }) => {
+            let path = pool_path(&opt);
+            match cmd {
+                PoolCmd::Up => prepare(&opt).await,
+                PoolCmd::Status => pool::status(&path).await,
+                PoolCmd::Down => pool::down(&path).await,
             }
         }
+        Some(Cmd::Secrets { .. }) => unreachable!(),
+        Some(Cmd::Execute { .. }) | None => run(opt).await,
+    }
+}
+
+/// The log target of what `--quiet` still shows.
+pub(crate) const STATUS: &str = "status";
+
+/// Point stdin at `/dev/null`, so nothing we run can wait on it.
+fn detach_stdin() -> Result<(), Report> {
+    use std::os::unix::io::AsRawFd;
+    let null = std::fs::File::open("/dev/null").wrap_err("open /dev/null")?;
+    // replacing fd 0 is atomic, and stdin is only ever used through its fd.
+    let r = unsafe { libc::dup2(null.as_raw_fd(), 0) };
+    ensure!(
+        r == 0,
+        "redirect stdin: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(())
+}
+
+fn parse_arch_bin(s: &str) -> Result<(String, PathBuf), Report> {
+    match s.split_once('=') {
+        Some((arch, path)) if !arch.is_empty() && !path.is_empty() => {
+            Ok((arch.to_owned(), PathBuf::from(path)))
+        }
+        _ => bail!("{:?} is not of the form arch=path", s),
+    }
+}
+
+/// Where the run's results and state file go.
+fn run_dir(opt: &Opt) -> PathBuf {
+    match opt.name {
+        Some(ref n) => opt.out_dir.join(n),
+        None => opt.out_dir.clone(),
+    }
+}
+
+fn live_machines(opt: &Opt) -> Result<Vec<live::LiveMachine>, Report> {
+    let state = opt
+        .resume
+        .clone()
+        .unwrap_or_else(|| run_dir(opt).join("state.json"));
+    live::machines(&pool_path(opt), &state, opt.cfg.as_deref())
+}
+
+fn pool_path(opt: &Opt) -> PathBuf {
+    opt.pool
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("pool.json"))
+}
+
+async fn prepare(opt: &Opt) -> Result<(), Report> {
+    let (nodes, opts) = run_opts(opt, opt.out_dir.clone())?;
+    check_inputs(&opts)?;
+    catalog::check(&nodes).await?;
+    if opts.check_permissions {
+        perms::check(&nodes, false).await?;
     }
+    pool::up(nodes, &opts, &pool_path(opt)).await
 }
 
-async fn do_exp(
-    ssh: &Session,
-    script_remote_path: &Path,
-    bench_remote_path: &Path,
-    prov: &str,
+#[instrument(skip(opt), fields(name = ?opt.name))]
+async fn run(opt: Opt) -> Result<(), Report> {
+    if let Some(ref q) = opt.queue {
+        return run_queue(&opt, q).await;
+    }
+
+    let out_dir = run_dir(&opt);
+    let (nodes, mut run_opts) = run_opts(&opt, out_dir.clone())?;
+    ensure!(
+        !opt.watch || run_opts.pool.is_some(),
+        "--watch re-runs on pool machines: pass --pool, or use execute"
+    );
+    if let Some(Cmd::Execute { order }) = opt.cmd {
+        let pool = run_opts.pool.as_ref().unwrap();
+        for (n, id) in nodes.iter().zip(node_ids(&nodes)?) {
+            ensure!(
+                pool.get(&id, n).is_some(),
+                "node {:?} has no matching pool machine, run prepare first",
+                id
+            );
+        }
+
+        run_opts.order = order;
+    }
+
+    run_opts.control = control::Control::listen(&out_dir.join("control.sock"))?;
+    // whatever did finish is still worth aggregating.
+    let mut res = run_nodes(nodes, &run_opts).await;
+    if opt.aggregate {
+        res = res.and(aggregate::aggregate(&out_dir, &out_dir.join("results.csv")));
+    }
+
+    res = res.and_then(|_| check_baseline(&opt, &out_dir));
+
+    if opt.watch {
+        if let Err(err) = res {
+            warn!(?err, "run failed");
+        }
+
+        res = watch(&opt, &out_dir, run_opts.order, &run_opts.control).await;
+    }
+
+    run_opts.control.close();
+    res
+}
+
+/// Run each config of the queue at `path` into its own directory in the run's.
+async fn run_queue(opt: &Opt, path: &Path) -> Result<(), Report> {
+    ensure!(
+        !opt.watch && opt.resume.is_none() && opt.cmd.is_none(),
+        "--queue can't be combined with --watch, --resume, or execute"
+    );
+    ensure!(opt.queue_jobs > 0, "--queue-jobs must be at least 1");
+    // two configs' nodes could match the same pool machine.
+    ensure!(
+        opt.queue_jobs == 1 || opt.pool.is_none(),
+        "pool machines run one config at a time: drop --queue-jobs or --pool"
+    );
+    let runs = queue::load(path)?;
+    let total = runs.len();
+    let root = run_dir(opt);
+    let budget = budget::Budget::new(opt.budget_hours, opt.budget_policy)?;
+    info!(target: STATUS, configs = total, jobs = opt.queue_jobs, "running queue");
+
+    let abort = std::sync::atomic::AtomicBool::new(false);
+    let (root, abort, budget) = (&root, &abort, &budget);
+    let results: Vec<(String, Option<Result<(), Report>>)> = futures_util::stream::iter(runs)
+        .map(|(cfg, name)| async move {
+            // under --on-error abort, what already started finishes, and nothing else starts.
+            if abort.load(std::sync::atomic::Ordering::SeqCst) {
+                return (name, None);
+            }
+
+            info!(target: STATUS, ?cfg, ?name, "starting queued config");
+            let res = run_queued(opt, &cfg, &root.join(&name), budget).await;
+            if res.is_err() && opt.on_error == OnError::Abort {
+                abort.store(true, std::sync::atomic::Ordering::SeqCst);
+            }
+
+            (name, Some(res))
+        })
+        .buffered(opt.queue_jobs)
+        .collect()
+        .await;
+    info!(
+        target: STATUS,
+        instance_hours = %format!("{:.2}", budget.used()),
+        "queue done"
+    );
+
+    let mut failed = vec![];
+    let mut first_err = None;
+    for (name, res) in results {
+        match res {
+            None => info!(?name, "queued config not run"),
+            Some(Ok(())) => info!(target: STATUS, ?name, "queued config done"),
+            Some(Err(err)) => {
+                warn!(?name, err = %format!("{:#}", err), "queued config failed");
+                first_err.get_or_insert((name.clone(), err));
+                failed.push(name);
+            }
+        }
+    }
+
+    match first_err {
+        Some((name, err)) if opt.on_error == OnError::Abort => {
+            Err(err.wrap_err(format!("queued config {:?}", name)))
+        }
+        _ => {
+            ensure!(
+                failed.is_empty(),
+                "{} of {} queued configs failed: {}",
+                failed.len(),
+                total,
+                failed.join(", ")
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Run the nodes of `cfg` into `out_dir`, as a run of one config would, out of the queue's
+/// `budget`.
+async fn run_queued(
+    opt: &Opt,
+    cfg: &Path,
+    out_dir: &Path,
+    budget: &budget::Budget,
 ) -> Result<(), Report> {
-    let mut cmd = ssh.command("python3");
-    cmd.arg(script_remote_path.to_str().unwrap());
-    cmd.arg(Path::new(".").join(bench_remote_path).to_str().unwrap());
-    cmd.arg(prov);
-    info!(?cmd, "running");
-
-    let out = cmd.output().await?;
-    if !out.status.success() {
-        warn!("script failed");
-        println!("{}", String::from_utf8(out.stderr).unwrap());
+    let mut opt = opt.clone();
+    opt.cfg = Some(cfg.to_path_buf());
+    let (nodes, mut run_opts) = run_opts(&opt, out_dir.to_path_buf())?;
+    run_opts.budget = budget.clone();
+    run_opts.control = control::Control::listen(&out_dir.join("control.sock"))?;
+    let mut res = run_nodes(nodes, &run_opts).await;
+    if opt.aggregate {
+        res = res.and(aggregate::aggregate(out_dir, &out_dir.join("results.csv")));
     }
 
-    tokio::fs::write(format!("{}.log", prov), out.stdout).await?;
-    info!("done, getting files");
-    let mut sftp = ssh.sftp();
-
-    //let inter_req_times = [0, 25, 50, 75, 100];
-    let inter_req_times = [75];
-    let num_receivers = [1, 2, 5, 10];
-    let num_groups = [0, 1, 2, 5, 10]; // + be
-    let batch_sizes = [1, 5, 10];
-    let batch_types = ["loop", "opt"];
-    let impls = ["client", "service"];
-
-    let mut fnames = vec![];
-    for inter_req in &inter_req_times[..] {
-        for batch_size in &batch_sizes[..] {
-            for batch_type in &batch_types[..] {
-                for rcvrs in &num_receivers[..] {
-                    for imp in impls {
-                        fnames.push(format!(
-                            "exp-{}-be-{}ms-{}rcvrs-{}batch-{}-{}.data",
-                            prov, inter_req, rcvrs, batch_size, batch_type, imp
-                        ));
-                    }
+    res = res.and_then(|_| check_baseline(&opt, out_dir));
+    run_opts.control.close();
+    res
+}
 
-                    for grps in &num_groups[..] {
-                        for imp in impls {
-                            fnames.push(format!(
-                                "exp-{}-ord:{}g-{}ms-{}rcvrs-{}batch-{}-{}.data",
-                                prov, grps, inter_req, rcvrs, batch_size, batch_type, imp
-                            ));
-                        }
-                    }
+/// Fail if the results in `out_dir` regressed from `--baseline`'s.
+fn check_baseline(opt: &Opt, out_dir: &Path) -> Result<(), Report> {
+    let baseline = match opt.baseline {
+        Some(ref b) => b,
+        None => return Ok(()),
+    };
+
+    let deltas = compare::compare(baseline, out_dir, opt.fail_threshold)?;
+    // a gate that compared nothing checked nothing.
+    ensure!(
+        !deltas.is_empty(),
+        "no experiments in common with {:?}",
+        baseline
+    );
+    compare::print_deltas(&deltas);
+    let regressed = deltas.iter().filter(|d| d.regressed).count();
+    ensure!(
+        regressed == 0,
+        "{} experiments regressed by more than {}% from {:?}",
+        regressed,
+        opt.fail_threshold,
+        baseline
+    );
+    Ok(())
+}
+
+fn parse_pct(s: &str) -> Result<f64, Report> {
+    let pct: f64 = s
+        .trim_end_matches('%')
+        .parse()
+        .wrap_err_with(|| format!("{:?} is not a percentage", s))?;
+    ensure!(pct >= 0., "{:?} is negative", s);
+    Ok(pct)
+}
+
+async fn sh(cmd: &str, env: &[(&str, &Path)]) -> Result<(), Report> {
+    let st = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .envs(env.iter().copied())
+        .status()
+        .await
+        .wrap_err_with(|| format!("run {:?}", cmd))?;
+    ensure!(st.success(), "{:?} failed", cmd);
+    Ok(())
+}
+
+/// Run every time `cron` says to, forever.
+async fn schedule(
+    opt: &Opt,
+    cron: &schedule::Cron,
+    build: Option<&str>,
+    threshold_pct: f64,
+    notify: Option<&str>,
+) -> Result<(), Report> {
+    let base = run_dir(opt);
+    let mut last: Option<PathBuf> = None;
+    loop {
+        let next = cron.next_after(chrono::Local::now())?;
+        info!(%next, "waiting for the next scheduled run");
+        let wait = (next - chrono::Local::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        let name = next.format("%Y-%m-%dT%H-%M").to_string();
+        let dir = base.join(&name);
+        if let Some(cmd) = build {
+            info!(?cmd, "building");
+            if let Err(err) = sh(cmd, &[]).await {
+                warn!(?err, "build failed, skipping this run");
+                continue;
+            }
+        }
+
+        let mut run_opt = opt.clone();
+        run_opt.cmd = None;
+        run_opt.out_dir = base.clone();
+        run_opt.name = Some(name);
+        run_opt.resume = None;
+        run_opt.watch = false;
+        if let Err(err) = run(run_opt).await {
+            warn!(?err, run = ?dir, "scheduled run failed");
+            continue;
+        }
+
+        let prev = match last.replace(dir.clone()) {
+            Some(p) => p,
+            None => continue,
+        };
+        let deltas = match compare::compare(&prev, &dir, threshold_pct) {
+            Ok(d) => d,
+            Err(err) => {
+                warn!(?err, "could not compare with the last run");
+                continue;
+            }
+        };
+        compare::print_deltas(&deltas);
+        let regressed = deltas.iter().filter(|d| d.regressed).count();
+        if regressed > 0 {
+            warn!(?regressed, baseline = ?prev, run = ?dir, "scheduled run regressed");
+            if let Some(cmd) = notify {
+                let env = [
+                    ("BURRITO_EXP_RUN", dir.as_path()),
+                    ("BURRITO_EXP_BASELINE", prev.as_path()),
+                ];
+                if let Err(err) = sh(cmd, &env).await {
+                    warn!(?err, "could not notify");
                 }
             }
         }
     }
+}
+
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Re-run every time one of the input files changes, until interrupted.
+async fn watch(
+    opt: &Opt,
+    out_dir: &Path,
+    order: Order,
+    control: &control::Control,
+) -> Result<(), Report> {
+    let mut inputs: Vec<PathBuf> = opt
+        .cfg
+        .iter()
+        .chain(&opt.script)
+        .chain(&opt.bench_bin)
+        .cloned()
+        .collect();
+    inputs.extend(opt.arch_bins.iter().map(|(_, b)| b.clone()));
+    let mtimes = |inputs: &[PathBuf]| -> Vec<Option<std::time::SystemTime>> {
+        inputs
+            .iter()
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect()
+    };
 
-    //let fnames = ["transition-25ms-aws-ord5g.data"];
-    let tot = fnames.len();
-    let mut gotten = 0;
-    for fname in &fnames[..] {
-        let f = sftp.read_from(&fname).await;
-        if let Err(err) = f {
-            // the file not existing is not necessarily a problem, it's possible that experiment
-            // was not run this time.
-            warn!(?err, ?fname, "file error");
-        } else {
-            let mut f = f.unwrap();
-            let mut local = tokio::fs::File::create(&fname).await?;
-            tokio::io::copy(&mut f, &mut local).await?;
-            f.close().await?;
-            gotten += 1;
+    let mut last = mtimes(&inputs);
+    loop {
+        if control.stopping() {
+            return Ok(());
+        }
+
+        info!(files = ?inputs, "watching for changes");
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            let now = mtimes(&inputs);
+            if now != last {
+                last = now;
+                break;
+            }
+        }
+
+        info!("inputs changed, re-running");
+        let (nodes, mut run_opts) = match run_opts(opt, out_dir.to_path_buf()) {
+            Ok(r) => r,
+            Err(err) => {
+                warn!(?err, "could not load inputs");
+                continue;
+            }
+        };
+        // this is a new run, not a continuation of the last one.
+        run_opts.checkpoint = state::Checkpoint::new(out_dir.join("state.json"));
+        run_opts.order = order;
+        run_opts.control = control.clone();
+        let mut res = run_nodes(nodes, &run_opts).await;
+        if opt.aggregate {
+            res = res.and(aggregate::aggregate(out_dir, &out_dir.join("results.csv")));
+        }
+
+        res = res.and_then(|_| check_baseline(opt, out_dir));
+
+        if let Err(err) = res {
+            warn!(?err, "run failed");
+        }
+
+        if control.stopping() {
+            return Ok(());
         }
     }
+}
 
-    info!(considered = ?tot, ?gotten, "done getting files");
+/// The nodes to run, and how to run them, from the command line.
+fn run_opts(opt: &Opt, out_dir: PathBuf) -> Result<(Vec<Node>, RunOpts), Report> {
+    let cfg = opt.cfg.clone().ok_or_else(|| eyre!("--cfg is required"))?;
+    let bench_bin = opt
+        .bench_bin
+        .clone()
+        .ok_or_else(|| eyre!("--bench-bin is required"))?;
+    let script = opt
+        .script
+        .clone()
+        .ok_or_else(|| eyre!("--script is required"))?;
+    let nodes = load_nodes(&cfg)?;
 
-    wait_for_continue();
-    Ok(())
+    let run_opts = RunOpts {
+        bench_bin,
+        arch_bins: opt.arch_bins.iter().cloned().collect(),
+        script,
+        tags: opt.tags.iter().cloned().collect(),
+        reps: opt.reps,
+        out_dir: out_dir.clone(),
+        label: opt.name.clone(),
+        db: opt.db.clone(),
+        filters: sweep::Filters {
+            only: opt.only.clone(),
+            skip: opt.skip.clone(),
+        },
+        pause: !opt.no_pause && !opt.quiet,
+        checkpoint: match opt.resume {
+            Some(ref p) => state::Checkpoint::load(p)?,
+            None => state::Checkpoint::new(out_dir.join("state.json")),
+        },
+        pool: match opt.cmd {
+            Some(Cmd::Execute { .. }) => Some(pool::Pool::load(&pool_path(opt))?),
+            _ => opt.pool.as_deref().map(pool::Pool::load).transpose()?,
+        },
+        order: Order::Sequential,
+        on_error: opt.on_error,
+        control: Default::default(),
+        bwlimit: opt.bwlimit,
+        services: Default::default(),
+        bench_meta: opt.bench_meta.clone(),
+        stamp_results: opt.stamp_results,
+        provenance: Default::default(),
+        dedup: (!opt.force).then(|| opt.out_dir.clone()),
+        check_permissions: opt.check_permissions,
+        junit: opt.junit,
+        events: opt
+            .events
+            .clone()
+            .map_or_else(Default::default, events::Events::start),
+        heartbeat: std::time::Duration::from_secs(opt.heartbeat_secs),
+        heartbeat_events: opt.heartbeat_events,
+        budget: budget::Budget::new(opt.budget_hours, opt.budget_policy)?,
+        inventory: opt
+            .inventory
+            .as_deref()
+            .map(inventory::Inventory::load)
+            .transpose()?,
+    };
+    Ok((nodes, run_opts))
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Report> {
-    color_eyre::install()?;
-    let subscriber = tracing_subscriber::registry();
-    let subscriber = subscriber
-        .with(tracing_subscriber::fmt::layer())
-        .with(tracing_subscriber::EnvFilter::from_default_env())
-        .with(ErrorLayer::default());
-    let d = tracing::Dispatch::new(subscriber);
-    d.init();
-    let opt = Opt::from_args();
-    info!(?opt, "starting");
+pub(crate) fn load_nodes(cfg: &Path) -> Result<Vec<Node>, Report> {
+    let cfg_file = std::fs::File::open(cfg).wrap_err(eyre!("Open cfg file {:?}", cfg))?;
+    let nodes: Vec<Node> = serde_json::from_reader(cfg_file).wrap_err("parse cfg file json")?;
+    Ok(nodes.into_iter().flat_map(Node::expand).collect())
+}
 
+fn check_inputs(opts: &RunOpts) -> Result<(), Report> {
     ensure!(
-        opt.bench_bin.exists(),
+        opts.bench_bin.exists(),
         "Bench binary {:?} not found",
-        opt.bench_bin
+        opts.bench_bin
     );
+    for (arch, bin) in &opts.arch_bins {
+        ensure!(bin.exists(), "{} bench binary {:?} not found", arch, bin);
+    }
+
     ensure!(
-        opt.script.exists(),
+        opts.script.exists(),
         "Script path {:?} not found",
-        opt.script
+        opts.script
     );
+    Ok(())
+}
 
-    let cfg_file = std::fs::File::open(&opt.cfg).wrap_err(eyre!("Open cfg file {:?}", &opt.cfg))?;
-    let nodes: Vec<Node> = serde_json::from_reader(cfg_file).wrap_err("parse cfg file json")?;
+/// Identifiers for each of `nodes`, unique so that their results don't overwrite each other.
+pub(crate) fn node_ids(nodes: &[Node]) -> Result<Vec<String>, Report> {
+    let mut names: Vec<&str> = nodes.iter().filter_map(Node::name).collect();
+    names.sort_unstable();
+    if let Some(w) = names.windows(2).find(|w| w[0] == w[1]) {
+        bail!("more than one node is named {:?}", w[0]);
+    }
 
+    let mut ids: Vec<String> = vec![];
     for n in nodes {
-        n.run(opt.bench_bin.clone(), opt.script.clone()).await?;
+        let base = n.id();
+        let mut id = base.clone();
+        let mut i = 1;
+        while ids.contains(&id) {
+            i += 1;
+            id = format!("{}-{}", base, i);
+        }
+
+        ids.push(id);
+    }
+
+    Ok(ids)
+}
+
+/// Run each of `nodes` in turn, or, if any depend on others, as their dependencies allow. Service
+/// nodes come up first, and stay up until the others are done.
+pub(crate) async fn run_nodes(nodes: Vec<Node>, opts: &RunOpts) -> Result<(), Report> {
+    check_inputs(opts)?;
+    let ids = node_ids(&nodes)?;
+    catalog::check(&nodes).await?;
+    if opts.check_permissions {
+        perms::check(&nodes, false).await?;
+    }
+    let provenance =
+        provenance::Provenance::collect(&opts.bench_bin, &opts.script, opts.bench_meta.clone());
+    provenance.write(&opts.out_dir)?;
+    let _heartbeat = heartbeat::Heartbeat::start(
+        &opts.out_dir,
+        opts.heartbeat,
+        &opts.events,
+        &opts.checkpoint,
+        opts.heartbeat_events,
+    );
+    opts.events
+        .emit("run_started", serde_json::json!({ "nodes": ids }));
+    let services = service::up(&nodes, &ids, opts).await?;
+    let mut opts = opts.clone();
+    opts.services = services.endpoints.clone();
+    opts.provenance = provenance;
+    let opts = &opts;
+    let (nodes, ids): (Vec<Node>, Vec<String>) = nodes
+        .into_iter()
+        .zip(ids)
+        .filter(|(n, _)| n.service().is_none())
+        .unzip();
+    let ran = run_in_order(&nodes, &ids, opts).await;
+    services.down(opts).await;
+    let write_junit = |failures: &[(String, String)]| {
+        if opts.junit {
+            if let Err(err) = junit::write(&opts.out_dir, &opts.checkpoint, failures) {
+                warn!(?err, "could not write junit report");
+            }
+        }
+    };
+    // nodes that failed, with why, under --on-error continue.
+    let node_failures = match ran {
+        Ok(f) => f,
+        Err(err) => {
+            write_junit(&[("run".to_owned(), format!("{:#}", err))]);
+            opts.events.emit(
+                "run_done",
+                serde_json::json!({ "error": format!("{:#}", err) }),
+            );
+            opts.events.flush().await;
+            return Err(err);
+        }
+    };
+    let failed_nodes: Vec<&str> = node_failures.iter().map(|(n, _)| n.as_str()).collect();
+    opts.events
+        .emit("run_done", serde_json::json!({ "failed": failed_nodes }));
+    opts.events.flush().await;
+    write_junit(
+        &node_failures
+            .iter()
+            .map(|(n, err)| (n.clone(), format!("{:#}", err)))
+            .collect::<Vec<_>>(),
+    );
+
+    let timings = opts.checkpoint.timings();
+    if !timings.is_empty() {
+        match summary::write_timings(&opts.out_dir, &timings) {
+            Ok(table) => println!("seconds spent, by phase:\n{}", table),
+            Err(err) => warn!(?err, "could not write phase timings"),
+        }
+    }
+
+    info!(
+        target: STATUS,
+        instance_hours = %format!("{:.2}", opts.budget.used()),
+        "cloud instance-hours used so far"
+    );
+
+    let (up, down) = opts.checkpoint.throughput();
+    let rate = |t: &transfer::Throughput| t.mb_per_s().map(|r| format!("{:.2}", r));
+    info!(
+        target: STATUS,
+        files = up.files,
+        bytes = up.bytes,
+        mb_per_s = ?rate(&up),
+        "uploaded over the run"
+    );
+    info!(
+        target: STATUS,
+        files = down.files,
+        bytes = down.bytes,
+        mb_per_s = ?rate(&down),
+        "collected over the run"
+    );
+
+    for (node, rep, file) in opts.checkpoint.missing_files() {
+        info!(?node, ?rep, ?file, "result file was not produced");
+    }
+
+    for (node, rep, file) in opts.checkpoint.unfetched_files() {
+        warn!(?node, ?rep, ?file, "result file could not be fetched");
+    }
+
+    let failed = opts.checkpoint.failed_experiments();
+    for (node, rep, exp) in &failed {
+        warn!(?node, ?rep, ?exp, "experiment failed");
+    }
+
+    for (node, err) in &node_failures {
+        warn!(?node, err = %format!("{:#}", err), "node failed");
     }
 
+    ensure!(
+        node_failures.is_empty(),
+        "{} nodes failed: {}",
+        node_failures.len(),
+        node_failures
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    ensure!(
+        failed.is_empty(),
+        "{} experiments reported failure",
+        failed.len()
+    );
     Ok(())
 }
 
-async fn write_file(vm: &Session, local_path: &Path, remote_path: &Path) -> Result<(), Report> {
+/// Run `nodes` in `opts.order`, returning the ones that failed under `--on-error continue`.
+async fn run_in_order(
+    nodes: &[Node],
+    ids: &[String],
+    opts: &RunOpts,
+) -> Result<Vec<(String, Report)>, Report> {
+    let mut node_failures: Vec<(String, Report)> = vec![];
+    let mut on_failure = |id: &str, err: Report| match opts.on_error {
+        OnError::Abort => Err(err),
+        OnError::Continue => {
+            warn!(node = ?id, err = %format!("{:#}", err), "node failed, continuing with the others");
+            node_failures.push((id.to_owned(), err));
+            Ok(())
+        }
+    };
+    let dag = nodes.iter().any(|n| !n.depends_on().is_empty());
+    match opts.order {
+        Order::Sequential if dag => return dag::run(nodes, ids, opts).await,
+        Order::Interleaved if dag => bail!("nodes with depends_on can't be run interleaved"),
+        Order::Sequential => {
+            for (n, id) in nodes.iter().zip(ids) {
+                if let Err(err) = n.run(opts, id, None).await {
+                    on_failure(id, err)?;
+                }
+
+                opts.control.node_done();
+                if opts.control.stopping() {
+                    warn!("stopping early by request");
+                    break;
+                }
+            }
+        }
+        Order::Interleaved => {
+            let most = nodes.iter().map(|n| n.reps(opts)).max().unwrap_or(0);
+            let mut down: Vec<&str> = vec![];
+            'rounds: for round in 1..=most {
+                for (n, id) in nodes.iter().zip(ids) {
+                    if down.contains(&id.as_str()) {
+                        continue;
+                    }
+
+                    if let Err(err) = n.run(opts, id, Some(round)).await {
+                        down.push(id);
+                        on_failure(id, err)?;
+                    }
+
+                    opts.control.node_done();
+                    if opts.control.stopping() {
+                        warn!("stopping early by request");
+                        break 'rounds;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(node_failures)
+}
+
+pub(crate) async fn write_file(
+    vm: &Session,
+    local_path: &Path,
+    remote_path: &Path,
+) -> Result<(), Report> {
     let mut sftp = vm.sftp();
     debug!(?local_path, ?remote_path, "writing file");
     let mut w = sftp
@@ -324,47 +1208,3 @@ fn wait_for_continue() {
     let mut iterator = stdin.lock().lines();
     iterator.next().unwrap().unwrap();
 }
-
-async fn install_deps(ssh: &Session) -> Result<(), Report> {
-    apt_install(ssh).await?;
-    let st = ssh
-        .shell("sudo pip3 install agenda")
-        .status()
-        .await
-        .wrap_err("pip install")?;
-    ensure!(st.success(), "pip install");
-    Ok(())
-}
-
-async fn apt_install(ssh: &Session) -> Result<(), Report> {
-    let mut count = 0;
-    loop {
-        count += 1;
-        let res = async {
-            let status = ssh
-                .shell("sudo add-apt-repository -y ppa:redislabs/redis")
-                .status()
-                .await
-                .wrap_err("redis repository add failed")?;
-            ensure!(status.success(), "redis apt-add-repository failed");
-            let status = ssh.shell(
-                "sudo apt update && sudo DEBIAN_FRONTEND=noninteractive apt install -y python3-pip redis && sudo /etc/init.d/redis-server stop",
-            ).status().await.wrap_err("apt install failed")?;
-            ensure!(status.success(), "apt install failed");
-            Ok(())
-        }
-        .await;
-
-        if res.is_ok() {
-            return res;
-        } else {
-            warn!(?res, "apt failed");
-        }
-
-        if count > 15 {
-            return res;
-        }
-
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-    }
-}
@@ -0,0 +1,179 @@
+//! Checks on the network between a node's machines: IPv6 addresses and connectivity, and the
+//! MTU.
+//!
+//! With `ip_version` `v6` or `dual`, each repetition first finds a global IPv6 address for every
+//! machine of the node and checks the script's machine reaches the others over IPv6, so a run
+//! meant to exercise v6 paths can't quietly fall back to v4. The script gets its own address in
+//! `BURRITO_EXP_IPV6`; `v6` passes the other machines' v6 addresses in `BURRITO_EXP_HOSTS`, and
+//! `dual` in `BURRITO_EXP_HOSTS6`, alongside the usual v4 ones.
+//!
+//! With an `mtu`, setup sets it on each machine's default-route interface, and each repetition
+//! checks that packets that big get from the script's machine to the node's other machines
+//! unfragmented. Jumbo frames change how much batching pays off, so a silently smaller path MTU
+//! would skew results.
+
+use color_eyre::eyre::{ensure, eyre, Report};
+use openssh::Session;
+use tracing::info;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    #[default]
+    V4,
+    V6,
+    Dual,
+}
+
+pub const IPV6_ENV: &str = "BURRITO_EXP_IPV6";
+pub const HOSTS6_ENV: &str = "BURRITO_EXP_HOSTS6";
+
+/// The IPv6 addresses of a node's machines.
+#[derive(Clone, Debug)]
+pub struct V6 {
+    /// The script's machine's.
+    pub addr: String,
+    /// Every machine of a multi-host node, as `user@addr`, starting with the script's.
+    pub hosts: Vec<String>,
+}
+
+/// The first usable global address in `ip -6 -o addr` output.
+fn parse_v6_addr(out: &str) -> Option<String> {
+    out.lines()
+        .filter(|l| !l.contains("deprecated") && !l.contains("tentative"))
+        .find_map(|l| {
+            let mut words = l.split_whitespace();
+            words.find(|w| *w == "inet6")?;
+            Some(words.next()?.split('/').next()?.to_owned())
+        })
+}
+
+const SHOW_V6: &str = "ip -6 -o addr show scope global";
+
+/// The global IPv6 address of the machine `ssh` is a session to.
+async fn v6_addr(ssh: &Session) -> Result<String, Report> {
+    let out = ssh.shell(SHOW_V6).output().await?;
+    ensure!(out.status.success(), "{} failed", SHOW_V6);
+    parse_v6_addr(&String::from_utf8_lossy(&out.stdout))
+        .ok_or_else(|| eyre!("machine has no global IPv6 address"))
+}
+
+/// The global IPv6 address of the node's other machine `user_host`, asked for over ssh from the
+/// script's machine, which reaches the node's other machines anyway.
+async fn other_v6_addr(ssh: &Session, user_host: &str) -> Result<String, Report> {
+    let out = ssh
+        .command("ssh")
+        .args([
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "StrictHostKeyChecking=accept-new",
+        ])
+        .args([user_host, SHOW_V6])
+        .output()
+        .await?;
+    ensure!(
+        out.status.success(),
+        "could not ask {} for its addresses: {}",
+        user_host,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    parse_v6_addr(&String::from_utf8_lossy(&out.stdout))
+        .ok_or_else(|| eyre!("{} has no global IPv6 address", user_host))
+}
+
+/// Find the IPv6 addresses of the node's machines `hosts` (`user@host`, the script's machine,
+/// which `ssh` is a session to, first), and check the script's machine reaches the others.
+pub async fn check_v6(ssh: &Session, hosts: &[String]) -> Result<V6, Report> {
+    let addr = v6_addr(ssh).await?;
+    let mut v6_hosts = vec![];
+    for (i, h) in hosts.iter().enumerate() {
+        let user = h.split_once('@').map_or("", |(u, _)| u);
+        let a = if i == 0 {
+            addr.clone()
+        } else {
+            let a = other_v6_addr(ssh, h).await?;
+            let out = ssh
+                .command("ping")
+                .args(["-6", "-c", "3", "-W", "2", &a])
+                .output()
+                .await?;
+            ensure!(
+                out.status.success(),
+                "{} is not reachable over IPv6 at {}",
+                h,
+                a
+            );
+            a
+        };
+        v6_hosts.push(format!("{}@{}", user, a));
+    }
+
+    info!(?addr, hosts = ?v6_hosts, "IPv6 connectivity checks out");
+    Ok(V6 {
+        addr,
+        hosts: v6_hosts,
+    })
+}
+
+/// The interface the default route goes out of.
+const DEFAULT_DEV: &str = "$(ip route show default | awk '{ print $5; exit }')";
+
+/// The MTU of the machine's default-route interface.
+pub fn mtu_cmd() -> String {
+    format!("cat /sys/class/net/{}/mtu", DEFAULT_DEV)
+}
+
+/// Set the MTU of the machine's default-route interface.
+pub async fn set_mtu(ssh: &Session, mtu: u32) -> Result<(), Report> {
+    let out = ssh
+        .shell(format!("sudo ip link set dev {} mtu {}", DEFAULT_DEV, mtu))
+        .output()
+        .await?;
+    ensure!(
+        out.status.success(),
+        "could not set the mtu to {}: {}",
+        mtu,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    info!(?mtu, "set mtu");
+    Ok(())
+}
+
+/// Check the machine `ssh` is a session to has MTU `mtu`, and gets packets that big unfragmented
+/// to the node's other machines (`hosts`, as `user@host`, after the first, which is this one).
+pub async fn check_mtu(ssh: &Session, hosts: &[String], mtu: u32) -> Result<(), Report> {
+    let out = ssh.shell(mtu_cmd()).output().await?;
+    let got: Option<u32> = String::from_utf8_lossy(&out.stdout).trim().parse().ok();
+    ensure!(got == Some(mtu), "interface mtu is {:?}, not {}", got, mtu);
+
+    for h in hosts.iter().skip(1) {
+        let addr = h.split_once('@').map_or(h.as_str(), |(_, a)| a);
+        // the payload, less the IP and ICMP headers.
+        let header = if addr.contains(':') { 48 } else { 28 };
+        let size = (mtu - header).to_string();
+        let out = ssh
+            .command("ping")
+            .args(["-M", "do", "-c", "3", "-W", "2", "-s", &size, addr])
+            .output()
+            .await?;
+        ensure!(
+            out.status.success(),
+            "{}-byte packets don't get to {} unfragmented: {}",
+            mtu,
+            h,
+            String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .last()
+                .unwrap_or("")
+                .trim()
+        );
+    }
+
+    info!(
+        ?mtu,
+        others = hosts.len().saturating_sub(1),
+        "mtu checks out"
+    );
+    Ok(())
+}
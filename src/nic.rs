@@ -0,0 +1,140 @@
+//! NIC interrupt and queue tuning, to cut run-to-run variance in latency tails: `irqbalance`
+//! moving interrupts around mid-experiment, or every queue's interrupts landing on one CPU,
+//! shows up as noise in exactly the percentiles we measure.
+//!
+//! Setup stops `irqbalance`, optionally sets the number of queues (RSS), pins the interrupts of
+//! the default-route interface round-robin over the chosen CPUs, spreads receive processing over
+//! them (RPS), and ties each transmit queue to one of them (XPS). The original settings go in
+//! the host's undo file (see [`crate::setup::undo`]); cloud machines are torn down anyway.
+
+use crate::setup::UNDO_FILE;
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use openssh::Session;
+use tracing::info;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct NicTuning {
+    /// The CPUs to pin interrupts to, e.g. `0-3,8`. Defaults to every CPU.
+    #[serde(default)]
+    pub cpus: Option<String>,
+    /// Set this many combined queues with `ethtool -L`.
+    #[serde(default)]
+    pub queues: Option<u32>,
+    #[serde(default = "yes")]
+    pub rps: bool,
+    #[serde(default = "yes")]
+    pub xps: bool,
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// Expand a CPU list like `0-3,8`.
+fn parse_cpus(s: &str) -> Result<Vec<u32>, Report> {
+    let mut cpus = vec![];
+    for part in s.split(',').map(str::trim) {
+        let bad = || eyre!("cpu list {:?} must be like 0-3,8", s);
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let a: u32 = a.parse().map_err(|_| bad())?;
+                let b: u32 = b.parse().map_err(|_| bad())?;
+                if a > b {
+                    return Err(bad());
+                }
+
+                cpus.extend(a..=b);
+            }
+            None => cpus.push(part.parse().map_err(|_| bad())?),
+        }
+    }
+
+    // masks are built with shell arithmetic, which is 64 bits.
+    ensure!(
+        cpus.iter().all(|&c| c < 64),
+        "nic tuning only handles cpus 0 through 63"
+    );
+    Ok(cpus)
+}
+
+impl NicTuning {
+    pub fn check(&self) -> Result<(), Report> {
+        if let Some(ref c) = self.cpus {
+            parse_cpus(c)?;
+        }
+
+        Ok(())
+    }
+
+    fn script(&self) -> Result<String, Report> {
+        let cpus = match self.cpus {
+            Some(ref c) => parse_cpus(c)?
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(" "),
+            None => "$(seq 0 $(( $(nproc) - 1 )))".to_owned(),
+        };
+        let mut s = format!(
+            "set -e; u={undo}; dev=$(ip route show default | awk '{{ print $5; exit }}'); \
+            set -- {cpus}; n=$#; \
+            if systemctl is-active -q irqbalance; then \
+            sudo systemctl stop irqbalance; echo 'sudo systemctl start irqbalance' >> $u; fi; ",
+            undo = UNDO_FILE,
+            cpus = cpus,
+        );
+        if let Some(q) = self.queues {
+            s.push_str(&format!(
+                "old=$(ethtool -l $dev | awk '/Current/ {{ c = 1 }} c && /Combined/ {{ print $2; exit }}'); \
+                sudo ethtool -L $dev combined {q}; echo \"sudo ethtool -L $dev combined $old\" >> $u; ",
+                q = q
+            ));
+        }
+
+        // the i'th of the listed cpus.
+        s.push_str("nth() { shift $(( $1 % n + 1 )); echo $1; }; ");
+        // kernel-managed interrupts refuse new affinities, and are left as they are.
+        s.push_str(
+            "i=0; for irq in $(ls /sys/class/net/$dev/device/msi_irqs 2> /dev/null); do \
+            f=/proc/irq/$irq/smp_affinity_list; [ -e $f ] || continue; \
+            echo \"echo $(cat $f) | sudo tee $f > /dev/null\" >> $u; \
+            nth $i \"$@\" | sudo tee $f > /dev/null 2>&1 || true; i=$((i + 1)); done; ",
+        );
+        if self.rps {
+            s.push_str(
+                "m=0; for c in \"$@\"; do m=$(( m | (1 << c) )); done; \
+                for f in /sys/class/net/$dev/queues/rx-*/rps_cpus; do [ -e $f ] || continue; \
+                echo \"echo $(cat $f) | sudo tee $f > /dev/null\" >> $u; \
+                printf '%x\\n' $m | sudo tee $f > /dev/null; done; ",
+            );
+        }
+
+        if self.xps {
+            s.push_str(
+                "i=0; for f in /sys/class/net/$dev/queues/tx-*/xps_cpus; do [ -e $f ] || continue; \
+                echo \"echo $(cat $f) | sudo tee $f > /dev/null\" >> $u; \
+                printf '%x\\n' $(( 1 << $(nth $i \"$@\") )) | sudo tee $f > /dev/null; \
+                i=$((i + 1)); done; ",
+            );
+        }
+
+        Ok(s)
+    }
+
+    pub async fn apply(&self, ssh: &Session) -> Result<(), Report> {
+        let out = ssh
+            .command("bash")
+            .arg("-c")
+            .arg(self.script()?)
+            .output()
+            .await
+            .wrap_err("tune nic")?;
+        ensure!(
+            out.status.success(),
+            "nic tuning failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        info!(cpus = ?self.cpus, queues = ?self.queues, rps = self.rps, xps = self.xps, "tuned nic");
+        Ok(())
+    }
+}
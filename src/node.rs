@@ -0,0 +1,2005 @@
+//! Node configuration, and launching/driving one node through setup and the experiment.
+
+use crate::azure::ResourceGroup;
+use crate::budget::Budget;
+use crate::control::Control;
+use crate::db::{record_run, RunRecord};
+use crate::deps::{CoordStore, DepsCfg, KernelCfg};
+use crate::disk::DiskCfg;
+use crate::ec2::{self, AwsAccess};
+use crate::events::Events;
+use crate::exp::{
+    run_reps, write_index, DebugWrapper, DiskGuard, Exp, Hooks, RepResult, StallGuard,
+};
+use crate::firewall::Firewall;
+use crate::inventory::Inventory;
+use crate::k8s::{Pod, PodCfg};
+use crate::net::IpVersion;
+use crate::nic::NicTuning;
+use crate::oci::{self, OciCfg};
+use crate::openstack::{self, OpenStackCfg};
+use crate::pool::Pool;
+use crate::post::{PostProcess, ResultsUpload};
+use crate::provenance::{self, Provenance};
+use crate::qemu::{Vm, VmCfg};
+use crate::ratelimit;
+use crate::ready::Readiness;
+use crate::redis::RedisCfg;
+use crate::retry::{ErrorClass, RetryPolicy};
+use crate::service::{Endpoint, ServiceCfg};
+use crate::setup::{self, RemoteSetup, Scratch, SetupStep};
+use crate::sidecar::Sidecar;
+use crate::ssh::{
+    generate_key, reconnect, skip_host_key_check, ConnInfo, ProxyJump, SshCfg, TempKey,
+};
+use crate::state::{timed, Checkpoint, Instance, NodeCheckpoint, Phase};
+use crate::sweep::Filters;
+use crate::tags::{Cloud, ProviderOpts, Tags};
+use crate::teardown;
+use crate::throttle::{is_burstable, non_burstable, Burstable, ThrottleCfg};
+use crate::transfer;
+use crate::vps;
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use openssh::Session;
+use rusoto_core::credential::{ProfileProvider, ProvideAwsCredentials};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, instrument, warn};
+use tsunami::providers::{aws, azure, baremetal};
+use tsunami::Tsunami;
+
+/// The machine name for nodes without a `name`.
+const DEFAULT_MACHINE_NAME: &str = "burrito-test-machine";
+
+/// Settings for the whole run, from the command line.
+#[derive(Clone, Debug)]
+pub struct RunOpts {
+    pub bench_bin: PathBuf,
+    /// Bench binaries for machines of other architectures, by `uname -m` name.
+    pub arch_bins: BTreeMap<String, PathBuf>,
+    pub script: PathBuf,
+    pub tags: Tags,
+    /// Overrides each node's `repetitions`.
+    pub reps: Option<usize>,
+    pub out_dir: PathBuf,
+    /// The run's `--name`.
+    pub label: Option<String>,
+    /// Record results in this SQLite database.
+    pub db: Option<PathBuf>,
+    pub filters: Filters,
+    /// Wait for the user before tearing down each machine.
+    pub pause: bool,
+    pub checkpoint: Checkpoint,
+    /// Run on these machines instead of launching new ones, where they match.
+    pub pool: Option<Pool>,
+    /// Hosts for inventory nodes.
+    pub inventory: Option<Inventory>,
+    pub order: Order,
+    pub on_error: OnError,
+    pub control: Control,
+    /// Where to send the run's progress events.
+    pub events: Events,
+    /// How often to write the run's heartbeat, if at all.
+    pub heartbeat: std::time::Duration,
+    /// Send the heartbeat as an event too.
+    pub heartbeat_events: bool,
+    /// Limit on transfers to and from each machine, in bytes per second.
+    pub bwlimit: Option<u64>,
+    /// The run's service nodes, by id.
+    pub services: BTreeMap<String, Endpoint>,
+    /// Build info for the bench binary, instead of what its `--version` says.
+    pub bench_meta: Option<String>,
+    /// Put the build's stamp in result file names.
+    pub stamp_results: bool,
+    /// What built the bench binary and script, found when the run starts.
+    pub provenance: Provenance,
+    /// Skip nodes that already ran to completion, with the same configuration, somewhere under
+    /// this directory.
+    pub dedup: Option<PathBuf>,
+    /// Check the credentials allow every API call the nodes need before launching.
+    pub check_permissions: bool,
+    /// Write the run as a JUnit XML report once it is done.
+    pub junit: bool,
+    /// Instance-hours the run may use, shared by every run of a `--queue`.
+    pub budget: Budget,
+}
+
+/// The order to run nodes' repetitions in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Order {
+    /// All of each node's repetitions, one node after another.
+    #[default]
+    Sequential,
+    /// The first repetition of every node, then the second, and so on, so that the nodes see
+    /// similar conditions. Only for pool machines.
+    Interleaved,
+}
+
+impl std::str::FromStr for Order {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "sequential" => Order::Sequential,
+            "interleaved" => Order::Interleaved,
+            _ => bail!("unknown order {:?}, expected sequential or interleaved", s),
+        })
+    }
+}
+
+/// What to do when a node fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OnError {
+    /// Stop the run.
+    #[default]
+    Abort,
+    /// Go on with the other nodes, and fail the run at the end.
+    Continue,
+}
+
+impl std::str::FromStr for OnError {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "abort" => OnError::Abort,
+            "continue" => OnError::Continue,
+            _ => bail!("unknown error policy {:?}, expected abort or continue", s),
+        })
+    }
+}
+
+/// What to do with a machine once it is launched and set up.
+enum Then<'a> {
+    /// Run these repetitions on it, then tear it down.
+    Run(&'a Exp, Range<usize>),
+    /// Leave it running, for the pool. tsunami deletes its copy of a cloud machine's key when we
+    /// exit, so the key is copied here.
+    Keep(&'a Path),
+}
+
+impl<'a> Then<'a> {
+    /// The experiment, if the machine is to be torn down once it is done.
+    fn exp(&self) -> Option<&'a Exp> {
+        match *self {
+            Then::Run(exp, _) => Some(exp),
+            Then::Keep(_) => None,
+        }
+    }
+}
+
+enum Outcome {
+    Ran(Vec<RepResult>),
+    Kept(Instance),
+}
+
+/// Use the launcher's machine `machine_name`. `others` are the (role, machine name) of the other
+/// machines of a multi-machine node.
+async fn with_launcher(
+    launcher: &mut impl tsunami::Tsunami,
+    machine_name: &str,
+    others: &[(String, String)],
+    cloud: Cloud,
+    tags: &Tags,
+    then: Then<'_>,
+    torn_down: &mut Vec<teardown::Resource>,
+) -> Result<Outcome, Report> {
+    let conns = launcher.connect_all().await?;
+    let vm = conns.get(machine_name).unwrap();
+    let mut roles = vec![(MAIN_ROLE, vm)];
+    roles.extend(
+        others
+            .iter()
+            .map(|(r, m)| (r.as_str(), conns.get(m).unwrap())),
+    );
+    for (_, m) in &roles {
+        if let Err(err) = cloud.tag(&m.public_ip, tags).await {
+            warn!(?err, host = ?m.public_ip, "could not tag cloud resources");
+        }
+
+        match teardown::resources(&cloud, &m.public_ip).await {
+            Ok(r) => torn_down.extend(r),
+            Err(err) => warn!(?err, host = ?m.public_ip, "teardown won't be verified"),
+        }
+    }
+
+    let conn = ConnInfo::from_machine(vm, 22);
+    if others.is_empty() {
+        return use_machine(conn, Some(cloud), then).await;
+    }
+
+    let (exp, reps) = match then {
+        Then::Run(exp, reps) => (exp, reps),
+        Then::Keep(_) => bail!("multi-machine nodes can't be kept in a pool"),
+    };
+    share_key(vm).await?;
+    let mut exp = exp.clone();
+    exp.roles = roles
+        .iter()
+        .map(|(r, m)| {
+            let ip = m.private_ip.as_deref().unwrap_or(&m.public_ip);
+            (r.to_string(), format!("{}@{}", m.username, ip))
+        })
+        .collect();
+    exp.hosts = exp.roles.iter().map(|(_, h)| h.clone()).collect();
+    info!(roles = ?exp.roles, "launched machines");
+    use_machine(conn, Some(cloud), Then::Run(&exp, reps)).await
+}
+
+/// Let `vm` ssh to the machines launched with it, which share its key.
+async fn share_key(vm: &tsunami::Machine<'_>) -> Result<(), Report> {
+    let key = match vm.private_key {
+        Some(ref k) => k,
+        None => return Ok(()),
+    };
+    crate::write_file(&vm.ssh, key, Path::new(".ssh/burrito-exp-key")).await?;
+    let st = vm
+        .ssh
+        .shell(
+            "chmod 600 .ssh/burrito-exp-key && { grep -qs burrito-exp-key .ssh/config || \
+            printf 'Host *\n  IdentityFile ~/.ssh/burrito-exp-key\n  StrictHostKeyChecking accept-new\n' >> .ssh/config; }",
+        )
+        .status()
+        .await?;
+    ensure!(
+        st.success(),
+        "could not set up the ssh key for the other machines"
+    );
+    Ok(())
+}
+
+async fn use_machine(
+    mut conn: ConnInfo,
+    cloud: Option<Cloud>,
+    then: Then<'_>,
+) -> Result<Outcome, Report> {
+    match then {
+        Then::Run(exp, reps) => {
+            exp.ckpt.update(|s| {
+                s.phase = Phase::Running;
+                s.instance = Some(Instance {
+                    conn: conn.clone(),
+                    cloud: cloud.clone(),
+                });
+            });
+            Ok(Outcome::Ran(run_reps(&conn, exp, reps).await?))
+        }
+        Then::Keep(key_path) => {
+            // known hosts' keys are the user's own.
+            if let (Some(k), Some(_)) = (conn.key_path.as_ref(), cloud.as_ref()) {
+                if let Some(d) = key_path.parent() {
+                    std::fs::create_dir_all(d)?;
+                }
+
+                std::fs::copy(k, key_path).wrap_err("copy machine's ssh key")?;
+                conn.key_path = Some(key_path.to_path_buf());
+            }
+
+            Ok(Outcome::Kept(Instance { conn, cloud }))
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub enum Provider {
+    Aws {
+        region: String,
+        /// Use this profile from the AWS credentials file instead of the default credentials.
+        #[serde(default)]
+        profile: Option<String>,
+        /// Defaults to `t3.medium`, or `g4dn.xlarge` for GPU nodes.
+        #[serde(default)]
+        instance_type: Option<String>,
+        /// What to do if the instance type is burstable (t2, t3, t3a, t4g): their CPU credits
+        /// can run out partway through a sweep.
+        #[serde(default)]
+        burstable: Burstable,
+        /// More machines to launch alongside this one, e.g. clients for a service on this one.
+        /// The script runs on this machine (role `main`), and is told every machine's role and
+        /// private address. They share its region, availability zone, and security group.
+        #[serde(default)]
+        machines: Vec<AwsMachine>,
+        /// The key pair and security group to launch with, instead of ones tsunami makes.
+        #[serde(default)]
+        access: Option<AwsAccess>,
+    },
+    Azure {
+        region: String,
+        /// Defaults to `Standard_B2ms`, or `Standard_NC4as_T4_v3` for GPU nodes.
+        #[serde(default)]
+        instance_type: Option<String>,
+        /// The resource group to launch into, instead of a new `tsunami_*` one.
+        #[serde(default)]
+        resource_group: Option<ResourceGroup>,
+    },
+    Baremetal {
+        ip: String,
+        user: String,
+        /// More machines to set up alongside this one, concurrently, for experiments that span
+        /// several hosts. The script runs on `ip`, and is told about all of them.
+        #[serde(default)]
+        hosts: Vec<BaremetalHost>,
+    },
+    /// Any free host from a group of the `--inventory`. `count` greater than one makes this that
+    /// many nodes.
+    Inventory {
+        group: String,
+        #[serde(default = "default_count")]
+        count: usize,
+        /// For hosts without an `ansible_user`.
+        #[serde(default)]
+        user: Option<String>,
+    },
+    /// An already-running instance from any provider: we set it up and run the experiment, but
+    /// never launch or terminate it.
+    Existing {
+        /// The provider name passed to the experiment script.
+        provider: String,
+        host: String,
+        user: String,
+    },
+    /// A pod on a Kubernetes cluster.
+    K8s(PodCfg),
+    /// A qemu VM on this machine.
+    Qemu(VmCfg),
+    /// `plan` is a Linode type, e.g. `g6-standard-2`.
+    Linode {
+        region: String,
+        plan: String,
+    },
+    /// `plan` is a Vultr plan, e.g. `vc2-2c-4gb`.
+    Vultr {
+        region: String,
+        plan: String,
+    },
+    Oci(OciCfg),
+    /// A server on an OpenStack cloud, e.g. Chameleon.
+    OpenStack(OpenStackCfg),
+}
+
+fn default_count() -> usize {
+    1
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct BaremetalHost {
+    pub ip: String,
+    pub user: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct AwsMachine {
+    pub role: String,
+    /// Defaults to the node's instance type.
+    #[serde(default)]
+    pub instance_type: Option<String>,
+    /// Defaults to the node's image, the latest Ubuntu 20.04.
+    #[serde(default)]
+    pub ami: Option<String>,
+    /// The image's user.
+    #[serde(default = "default_ami_user")]
+    pub user: String,
+}
+
+fn default_ami_user() -> String {
+    "ubuntu".to_owned()
+}
+
+/// The role of the machine the script runs on, in a multi-machine node.
+const MAIN_ROLE: &str = "main";
+
+const AWS_INSTANCE_TYPE: &str = "t3.medium";
+const AZURE_INSTANCE_TYPE: &str = "Standard_B2ms";
+const AWS_GPU_INSTANCE_TYPE: &str = "g4dn.xlarge";
+const AZURE_GPU_INSTANCE_TYPE: &str = "Standard_NC4as_T4_v3";
+
+impl Provider {
+    fn has_known_host(&self) -> bool {
+        matches!(
+            self,
+            Provider::Baremetal { .. } | Provider::Inventory { .. } | Provider::Existing { .. }
+        )
+    }
+
+    /// All of a multi-host node's machines, as `user@ip`, with the one the script runs on first.
+    /// Empty for single-machine nodes.
+    fn hosts(&self) -> Vec<String> {
+        match self {
+            Provider::Baremetal { ip, user, hosts } if !hosts.is_empty() => {
+                std::iter::once(format!("{}@{}", user, ip))
+                    .chain(hosts.iter().map(|h| format!("{}@{}", h.user, h.ip)))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The provider name passed to the experiment script.
+    fn name(&self) -> &str {
+        match self {
+            Provider::Aws { .. } => "aws",
+            Provider::Azure { .. } => "azure",
+            Provider::Baremetal { .. } | Provider::Inventory { .. } => "baremetal",
+            Provider::Existing { provider, .. } => provider,
+            Provider::K8s(_) => "k8s",
+            Provider::Qemu(_) => "qemu",
+            Provider::Linode { .. } => "linode",
+            Provider::Vultr { .. } => "vultr",
+            Provider::Oci(_) => "oci",
+            Provider::OpenStack(_) => "openstack",
+        }
+    }
+
+    /// How many machines a launch starts.
+    fn machine_count(&self) -> usize {
+        match self {
+            Provider::Aws { machines, .. } => 1 + machines.len(),
+            _ => 1,
+        }
+    }
+
+    /// The API to go through for machines we launch.
+    fn cloud(&self) -> Option<Cloud> {
+        match self {
+            Provider::Aws {
+                region, profile, ..
+            } => Some(Cloud::Aws {
+                region: region.clone(),
+                profile: profile.clone(),
+            }),
+            Provider::Azure { .. } => Some(Cloud::Azure),
+            Provider::Linode { .. } => Some(Cloud::Linode),
+            Provider::Vultr { .. } => Some(Cloud::Vultr),
+            Provider::Oci(cfg) => Some(cfg.cloud()),
+            Provider::OpenStack(cfg) => Some(cfg.cloud()),
+            _ => None,
+        }
+    }
+
+    fn region(&self) -> Option<&str> {
+        match self {
+            Provider::Aws { region, .. }
+            | Provider::Azure { region, .. }
+            | Provider::Linode { region, .. }
+            | Provider::Vultr { region, .. } => Some(region),
+            Provider::Oci(cfg) => Some(&cfg.region),
+            _ => None,
+        }
+    }
+
+    fn instance_type(&self, gpu: bool) -> Option<&str> {
+        match (self, gpu) {
+            (
+                Provider::Aws {
+                    instance_type,
+                    burstable,
+                    ..
+                },
+                false,
+            ) => {
+                let t = instance_type.as_deref().unwrap_or(AWS_INSTANCE_TYPE);
+                match burstable {
+                    Burstable::Replace => Some(non_burstable(t).unwrap_or(t)),
+                    _ => Some(t),
+                }
+            }
+            (Provider::Aws { instance_type, .. }, true) => {
+                Some(instance_type.as_deref().unwrap_or(AWS_GPU_INSTANCE_TYPE))
+            }
+            (Provider::Azure { instance_type, .. }, false) => {
+                Some(instance_type.as_deref().unwrap_or(AZURE_INSTANCE_TYPE))
+            }
+            (Provider::Azure { instance_type, .. }, true) => {
+                Some(instance_type.as_deref().unwrap_or(AZURE_GPU_INSTANCE_TYPE))
+            }
+            (Provider::Linode { plan, .. }, _) | (Provider::Vultr { plan, .. }, _) => Some(plan),
+            (Provider::Oci(cfg), _) => Some(&cfg.shape),
+            (Provider::OpenStack(cfg), _) => Some(&cfg.flavor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct Node {
+    /// Used for the machine, in logs and tags, and for the node's output directory.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(flatten)]
+    provider: Provider,
+    /// The provider name passed to the script, and used in result file names, logs, and the
+    /// default output directory. Defaults to the provider's own name (`baremetal` for baremetal
+    /// and inventory hosts, `provider` for existing ones).
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(flatten)]
+    deps: DepsCfg,
+    /// Extra setup steps (commands and reboots), run before installing dependencies.
+    #[serde(default)]
+    setup_steps: Vec<SetupStep>,
+    /// How long to wait for the machine to come back after a `reboot` setup step.
+    #[serde(default = "default_reboot_timeout_secs")]
+    reboot_timeout_secs: u64,
+    #[serde(default)]
+    ssh: SshCfg,
+    /// Reach the machine through this gateway host.
+    #[serde(default)]
+    proxy_jump: Option<ProxyJump>,
+    /// Tags for launched cloud resources (e.g. owner, experiment, git sha, ttl).
+    #[serde(default)]
+    tags: Tags,
+    /// Run the experiment this many times.
+    #[serde(default = "default_repetitions")]
+    repetitions: usize,
+    /// Launch (and set up) a new instance for every repetition, rather than reusing one.
+    #[serde(default)]
+    fresh_instance: bool,
+    /// Re-run experiments whose result files are empty or malformed, up to this many times.
+    #[serde(default)]
+    rerun_invalid: usize,
+    /// When experiments count as having run with the CPU throttled (by steal, or by running out
+    /// of burst credits), and whether to re-run them.
+    #[serde(default)]
+    throttle: ThrottleCfg,
+    /// Remove result files and logs left on the machine by earlier runs before each repetition,
+    /// so they can't be collected as this run's.
+    #[serde(default)]
+    clean: bool,
+    /// Don't collect result files last modified before the repetition started: they are
+    /// leftovers from an earlier run. An alternative to `clean`.
+    #[serde(default)]
+    skip_stale: bool,
+    /// Commands to run before each repetition starts, e.g. to start a metrics collector or flush
+    /// state left over from the last one.
+    #[serde(default)]
+    pre_exp: Hooks,
+    /// Commands to run after each repetition's results are collected, e.g. to clean up scratch
+    /// space.
+    #[serde(default)]
+    post_exp: Hooks,
+    /// Run this locally on the output directory once all repetitions are collected.
+    #[serde(default)]
+    post_process: Option<PostProcess>,
+    /// Push the output directory to object storage once everything else is done.
+    #[serde(default)]
+    results_upload: Option<ResultsUpload>,
+    /// Remote directory to upload into, run the script in, and collect results from, instead of
+    /// the ssh user's home directory, e.g. so concurrent runs on a shared host don't trample each
+    /// other. Relative to the home directory, unless absolute.
+    #[serde(default)]
+    workdir: Option<String>,
+    /// Run the experiment from the instance's local NVMe disk, copying results back to the root
+    /// disk once it is done.
+    #[serde(default)]
+    scratch: Option<Scratch>,
+    /// Run on a GPU instance type (unless `instance_type` is given), and set up the NVIDIA driver
+    /// and CUDA toolkit.
+    #[serde(default)]
+    gpu: bool,
+    /// Abort the experiment if the machine is running out of disk space.
+    #[serde(default)]
+    disk_guard: Option<DiskGuard>,
+    /// Collect diagnostics (and optionally retry) when the script goes quiet for too long.
+    #[serde(default)]
+    stall: Option<StallGuard>,
+    /// Kill the script (failing the repetition) if it is still running after this long.
+    #[serde(default = "default_script_timeout_secs")]
+    script_timeout_secs: u64,
+    /// Run some experiments again with the bench under a tracer or debugger, and fetch the
+    /// traces.
+    #[serde(default)]
+    debug_wrapper: Option<DebugWrapper>,
+    /// Configure the experiment's redis: written to `redis.conf`, and started by us or the
+    /// script.
+    #[serde(default)]
+    redis: Option<RedisCfg>,
+    /// More binaries (e.g. burrito's discovery service, `localname-ctl`) to upload, and run
+    /// alongside each repetition's script.
+    #[serde(default)]
+    services: Vec<Sidecar>,
+    /// Root disk size and type, for AWS and Azure nodes.
+    #[serde(default)]
+    disk: Option<DiskCfg>,
+    /// Retry launching after transient provider errors (throttling, capacity, network).
+    #[serde(default = "default_launch_retry")]
+    launch_retry: RetryPolicy,
+    /// Passed through to the provider's launch request, for knobs not modelled here: merged into
+    /// the create request (linode, vultr) or pod spec (k8s), or given to the launch command as
+    /// `--key value` (oci, openstack). AWS nodes only take `availability_zone`, which is all
+    /// tsunami exposes.
+    #[serde(default)]
+    provider_opts: ProviderOpts,
+    /// How to copy the bench binary and script over, and results back.
+    #[serde(default)]
+    transfer: transfer::Backend,
+    /// Retry failed uploads, and downloads of result files.
+    #[serde(default = "transfer::default_retry")]
+    transfer_retry: RetryPolicy,
+    /// Nodes (by name; expanded inventory nodes are `<name>-1`, ...) to finish successfully before
+    /// this one starts. Nodes that don't depend on each other run at the same time.
+    #[serde(default)]
+    depends_on: Vec<String>,
+    /// Run a service for other nodes on this machine, instead of experiments.
+    #[serde(default)]
+    service: Option<ServiceCfg>,
+    /// Service nodes whose host and ports to pass to the script.
+    #[serde(default)]
+    uses: Vec<String>,
+    /// Wait for these to pass before each repetition's script starts.
+    #[serde(default)]
+    ready: Option<Readiness>,
+    /// Ports to open in the firewall of baremetal, inventory, and existing hosts during setup,
+    /// and close again at teardown.
+    #[serde(default)]
+    firewall: Option<Firewall>,
+    /// `v6` or `dual` run the experiment over IPv6 (see [`crate::net`]).
+    #[serde(default)]
+    ip_version: IpVersion,
+    /// Set on each machine's default-route interface, e.g. 9001 for jumbo frames within an AWS VPC,
+    /// and checked between the node's machines before each repetition.
+    #[serde(default)]
+    mtu: Option<u32>,
+    /// Pin the NIC's interrupts and set up its queues on each machine (see [`crate::nic`]).
+    #[serde(default)]
+    nic_tuning: Option<NicTuning>,
+    /// Install and boot into a newer kernel during setup, if the image's is older.
+    #[serde(default)]
+    kernel: Option<KernelCfg>,
+}
+
+fn default_reboot_timeout_secs() -> u64 {
+    300
+}
+
+fn default_script_timeout_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_repetitions() -> usize {
+    1
+}
+
+fn default_launch_retry() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 4,
+        initial_backoff_ms: 30_000,
+        max_backoff_ms: 600_000,
+    }
+}
+
+impl Node {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn machine_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(DEFAULT_MACHINE_NAME)
+    }
+
+    /// An identifier for this node, to keep its results apart from other nodes'.
+    pub fn id(&self) -> String {
+        if let Some(ref n) = self.name {
+            return n.clone();
+        }
+
+        let place = match self.provider {
+            Provider::Aws { ref region, .. }
+            | Provider::Azure { ref region, .. }
+            | Provider::Linode { ref region, .. }
+            | Provider::Vultr { ref region, .. } => region,
+            Provider::Oci(ref cfg) => &cfg.region,
+            Provider::OpenStack(ref cfg) => &cfg.flavor,
+            Provider::Baremetal { ref ip, .. } => ip,
+            Provider::Inventory { ref group, .. } => group,
+            Provider::Existing { ref host, .. } => host,
+            Provider::K8s(ref p) => &p.namespace,
+            Provider::Qemu(_) => "local",
+        };
+        format!("{}-{}", self.label(), place)
+    }
+
+    pub fn depends_on(&self) -> &[String] {
+        &self.depends_on
+    }
+
+    pub fn service(&self) -> Option<&ServiceCfg> {
+        self.service.as_ref()
+    }
+
+    pub fn uses(&self) -> &[String] {
+        &self.uses
+    }
+
+    /// What the script, result files, and logs call this node's provider.
+    pub fn label(&self) -> &str {
+        self.label
+            .as_deref()
+            .unwrap_or_else(|| self.provider.name())
+    }
+
+    /// The nodes this config entry stands for: inventory nodes with a `count` are that many
+    /// nodes.
+    pub fn expand(self) -> Vec<Node> {
+        let count = match self.provider {
+            Provider::Inventory { count, .. } if count > 1 => count,
+            _ => return vec![self],
+        };
+
+        (1..=count)
+            .map(|i| {
+                let mut n = self.clone();
+                if let Provider::Inventory { ref mut count, .. } = n.provider {
+                    *count = 1;
+                }
+
+                // names have to be unique.
+                n.name = self.name.as_ref().map(|name| format!("{}-{}", name, i));
+                n
+            })
+            .collect()
+    }
+
+    pub fn reps(&self, opts: &RunOpts) -> usize {
+        opts.reps.unwrap_or(self.repetitions)
+    }
+
+    /// Run the node's experiments, collecting results into `<out_dir>/<id>`.
+    ///
+    /// With `until`, only run up to that many repetitions (on a pool machine); calling this again
+    /// with a larger `until` picks up from there.
+    #[instrument(skip(self, opts), fields(name = %self.machine_name(), provider = ?self.provider))]
+    pub async fn run(&self, opts: &RunOpts, id: &str, until: Option<usize>) -> Result<(), Report> {
+        self.check()?;
+        let started_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let reps = self.reps(opts);
+        ensure!(reps > 0, "need at least one repetition");
+
+        let out_dir = opts.out_dir.join(id);
+        let ckpt = opts.checkpoint.node(id);
+        let prev = ckpt.get();
+        if prev.phase == Phase::Done {
+            info!("already done, skipping");
+            return Ok(());
+        }
+
+        let hash = self.config_hash(opts, reps)?;
+        if let (Some(root), Some(h), None) = (&opts.dedup, &hash, &prev.instance) {
+            if let Some(dir) = provenance::find_run(root, h).filter(|_| prev.done.is_empty()) {
+                info!(
+                    ?dir,
+                    "identical configuration already ran, skipping (--force runs it again)"
+                );
+                return Ok(());
+            }
+        }
+
+        let pooled = opts
+            .pool
+            .as_ref()
+            .and_then(|p| p.get(id, self))
+            .map(|m| &m.instance);
+        ensure!(
+            until.is_none() || pooled.is_some(),
+            "only pool machines can run a node's repetitions piecemeal"
+        );
+        let stop = until.map_or(reps, |u| u.min(reps));
+
+        // repetitions complete in order.
+        let mut results = prev.done.clone();
+        if results.len() >= stop {
+            return Ok(());
+        }
+
+        let events = opts.events.with("node", id);
+        events.emit("node_started", serde_json::json!({}));
+
+        let res = match pooled {
+            Some(inst) => {
+                self.run_pooled(opts, &out_dir, reps, results.len()..stop, &ckpt, inst)
+                    .await
+            }
+            None => {
+                self.run_remaining(opts, &out_dir, reps, results.len(), &ckpt, prev.instance)
+                    .await
+            }
+        };
+        match res {
+            Ok(r) => results.extend(r),
+            Err(err) => {
+                ckpt.update(|s| s.phase = Phase::Failed);
+                events.emit(
+                    "node_failed",
+                    serde_json::json!({ "error": format!("{:#}", err) }),
+                );
+                return Err(err);
+            }
+        }
+
+        // a skipped node is done with what it has.
+        if results.len() < reps && !opts.control.skip_node() {
+            return Ok(());
+        }
+
+        ckpt.update(|s| {
+            s.phase = Phase::Done;
+            s.instance = None;
+        });
+        events.emit("node_done", serde_json::json!({}));
+
+        if let Some(ref h) = hash {
+            std::fs::write(out_dir.join(provenance::HASH_FILE), h)
+                .wrap_err("write configuration hash")?;
+        }
+
+        if reps > 1 {
+            write_index(&out_dir, opts.label.as_deref(), &opts.provenance, &results)?;
+        }
+
+        if let Some(ref p) = self.post_process {
+            if let Err(err) = p.run(&out_dir).await {
+                warn!(?err, "post-processing failed");
+            }
+        }
+
+        if let Some(ref db) = opts.db {
+            let tags = self.tags(opts);
+            let run = RunRecord {
+                started_at,
+                label: opts.label.as_deref(),
+                provider: self.label(),
+                region: self.provider.region(),
+                instance_type: self.provider.instance_type(self.gpu),
+                out_dir: &out_dir,
+                tags: serde_json::to_string(&tags)?,
+                config: serde_json::to_string(self)?,
+            };
+            record_run(db, &run, &results)?;
+        }
+
+        if let Some(ref u) = self.results_upload {
+            if let Err(err) = u.upload(&opts.out_dir).await {
+                warn!(?err, "could not upload results");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A hash of everything that decides the node's results, or `None` if a binary or the script
+    /// can't be hashed.
+    fn config_hash(&self, opts: &RunOpts, reps: usize) -> Result<Option<String>, Report> {
+        let mut arch_bins = BTreeMap::new();
+        for (arch, bin) in &opts.arch_bins {
+            match provenance::sha256(bin) {
+                Some(h) => arch_bins.insert(arch, h),
+                None => return Ok(None),
+            };
+        }
+
+        let (Some(bench), Some(script)) = (
+            &opts.provenance.bench_sha256,
+            &opts.provenance.script_sha256,
+        ) else {
+            return Ok(None);
+        };
+        let spec = serde_json::json!({
+            "node": self,
+            "reps": reps,
+            "filters": opts.filters,
+            "bench": bench,
+            "arch_bins": arch_bins,
+            "script": script,
+        });
+        Ok(provenance::sha256_of(&serde_json::to_vec(&spec)?))
+    }
+
+    /// The provider this node launches on, regardless of `label`.
+    pub fn provider_name(&self) -> &str {
+        self.provider.name()
+    }
+
+    /// The cloud, region, and instance types this node launches in, for providers whose catalog
+    /// we can check.
+    pub fn placement(&self) -> Option<(Cloud, String, Vec<String>)> {
+        let cloud = self.provider.cloud()?;
+        if !matches!(
+            cloud,
+            Cloud::Aws { .. } | Cloud::Azure | Cloud::Linode | Cloud::Vultr
+        ) {
+            return None;
+        }
+
+        let region = self.provider.region()?.to_owned();
+        let t = self.provider.instance_type(self.gpu)?;
+        let mut types = vec![t.to_owned()];
+        if let Provider::Aws { ref machines, .. } = self.provider {
+            types.extend(machines.iter().filter_map(|m| m.instance_type.clone()));
+        }
+
+        types.sort();
+        types.dedup();
+        Some((cloud, region, types))
+    }
+
+    /// What this node does with its provider's API, for checking the credentials allow it.
+    pub fn api_use(&self) -> Option<crate::perms::Use> {
+        use crate::perms::{Launch, Use};
+        let launch = match self.provider {
+            Provider::Aws { ref access, .. } => access.clone().map_or(Launch::Tsunami, Launch::Aws),
+            Provider::Azure {
+                ref resource_group, ..
+            } => resource_group
+                .clone()
+                .map_or(Launch::Tsunami, Launch::Azure),
+            _ => return None,
+        };
+        let instance_type = self.provider.instance_type(self.gpu)?.to_owned();
+        let (burstable, unlimited) = match self.provider {
+            Provider::Aws { burstable, .. } if is_burstable(&instance_type) => {
+                (true, burstable == Burstable::Unlimited)
+            }
+            _ => (false, false),
+        };
+        Some(Use {
+            cloud: self.provider.cloud()?,
+            instance_type,
+            launch,
+            availability_zone: self.provider_opts.contains_key("availability_zone"),
+            disk: self.disk.is_some(),
+            burstable,
+            unlimited,
+        })
+    }
+
+    /// Reject configurations we can't act on.
+    fn check(&self) -> Result<(), Report> {
+        let label = self.label();
+        ensure!(
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)),
+            "label {:?} must be letters, digits, -, _ and .",
+            label
+        );
+
+        if let Provider::Aws { burstable, .. } = self.provider {
+            let t = self.provider.instance_type(self.gpu).unwrap();
+            if is_burstable(t) {
+                match burstable {
+                    Burstable::Warn => {
+                        warn!(instance_type = ?t, "burstable instance type: its cpu credits can run out, throttling the experiment")
+                    }
+                    Burstable::Refuse => bail!(
+                        "{} is burstable, and burstable instance types are refused",
+                        t
+                    ),
+                    Burstable::Replace => bail!("no non-burstable replacement for {}", t),
+                    Burstable::Allow | Burstable::Unlimited => (),
+                }
+            }
+        }
+
+        ensure!(
+            self.disk.is_none()
+                || matches!(self.provider, Provider::Aws { .. } | Provider::Azure { .. }),
+            "disk is only supported for aws and azure nodes"
+        );
+        ensure!(
+            self.ip_version == IpVersion::V4
+                || !matches!(self.provider, Provider::Aws { .. } | Provider::Azure { .. }),
+            "aws and azure nodes are launched into IPv4-only networks, so can't use ip_version {:?}",
+            self.ip_version
+        );
+        ensure!(
+            self.mtu.is_none_or(|m| (576..=65535).contains(&m)),
+            "mtu {:?} must be between 576 and 65535",
+            self.mtu
+        );
+        if let Some(ref t) = self.nic_tuning {
+            t.check()?;
+        }
+        if let Some(ref k) = self.kernel {
+            k.check()?;
+        }
+        if let Provider::Azure {
+            resource_group: Some(ref rg),
+            ..
+        } = self.provider
+        {
+            rg.check()?;
+        }
+        if let Some(ref d) = self.debug_wrapper {
+            d.check()?;
+        }
+        let mut names = std::collections::BTreeSet::new();
+        for sc in &self.services {
+            sc.check()?;
+            ensure!(
+                names.insert(sc.name()),
+                "service binary {:?} is given twice",
+                sc.name()
+            );
+        }
+        if let Some(ref r) = self.redis {
+            r.check()?;
+            ensure!(
+                self.deps.coord_store == CoordStore::Redis,
+                "redis is configured, but the coord_store is {:?}",
+                self.deps.coord_store
+            );
+        }
+        if let Some(ref f) = self.firewall {
+            ensure!(
+                self.provider.has_known_host(),
+                "firewall is only for baremetal, inventory, and existing nodes; cloud nodes use security groups"
+            );
+            f.check()?;
+        }
+
+        if let Provider::K8s(_) = self.provider {
+            ensure!(
+                !self.setup_steps.iter().any(|s| matches!(s, SetupStep::Reboot))
+                    && self.scratch.is_none()
+                    && self.kernel.is_none()
+                    && !self.gpu,
+                "kubernetes nodes can't reboot, or use scratch, kernel, or gpu (request gpus in resources instead)"
+            );
+        }
+        if let Provider::Aws { ref machines, .. } = self.provider {
+            let mut roles = std::collections::BTreeSet::from([MAIN_ROLE]);
+            for m in machines {
+                ensure!(
+                    !m.role.is_empty()
+                        && m.role
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                    "machine role {:?} must be letters, digits, - and _",
+                    m.role
+                );
+                ensure!(roles.insert(&m.role), "machine role {:?} is taken", m.role);
+            }
+        }
+
+        match self.provider {
+            Provider::Aws { .. } => ensure!(
+                self.provider_opts.keys().all(|k| k == "availability_zone"),
+                "aws nodes only take availability_zone in provider_opts"
+            ),
+            Provider::Linode { .. }
+            | Provider::Vultr { .. }
+            | Provider::Oci(_)
+            | Provider::OpenStack(_)
+            | Provider::K8s(_) => (),
+            _ => ensure!(
+                self.provider_opts.is_empty(),
+                "provider_opts are only supported for aws, linode, vultr, oci, openstack, and k8s nodes"
+            ),
+        }
+
+        // cloud machines' addresses aren't known until tsunami has already connected to them,
+        // and tsunami picks their port and key itself. An aws key pair's key is the exception.
+        let mut ssh = self.ssh.clone();
+        if let Provider::Aws {
+            access: Some(ref a),
+            ref machines,
+            ..
+        } = self.provider
+        {
+            a.check()?;
+            ensure!(
+                machines.is_empty(),
+                "aws nodes with access settings launch a single machine"
+            );
+            ensure!(
+                a.key_pair.is_none() || ssh.key_path.take().is_some(),
+                "an aws key_pair needs its private key in ssh.key_path"
+            );
+        }
+        ensure!(
+            self.provider.has_known_host()
+                || (self.proxy_jump.is_none() && !ssh.is_host_specific()),
+            "proxy_jump and ssh port/key_path/options are only supported for baremetal and existing nodes \
+             (to reach a VM in a private subnet, launch it yourself and use it as an existing node)"
+        );
+        Ok(())
+    }
+
+    /// Whether `other` describes the same machine, set up the same way, as this node.
+    pub fn same_machine(&self, other: &Node) -> bool {
+        let spec = |n: &Node| {
+            serde_json::json!([
+                n.provider,
+                n.deps,
+                n.setup_steps,
+                n.ssh,
+                n.proxy_jump,
+                n.disk,
+                n.scratch,
+                n.gpu,
+                n.provider_opts
+            ])
+        };
+        spec(self) == spec(other)
+    }
+
+    /// An `ssh` to an instance of this node, for interactive use.
+    pub fn ssh_command(&self, conn: &ConnInfo) -> std::process::Command {
+        crate::ssh::command(conn, &self.ssh, self.proxy_jump.as_ref())
+    }
+
+    /// Connect to an instance of this node that we set up earlier.
+    pub async fn reach(&self, conn: &ConnInfo) -> Result<Session, Report> {
+        if self.provider.has_known_host() {
+            self.ssh
+                .apply_to_host(&conn.host, self.proxy_jump.as_ref())?;
+        }
+
+        conn.connect(Some(Duration::from_secs(10))).await
+    }
+
+    /// The run-wide tags, plus this node's, plus its name.
+    fn tags(&self, opts: &RunOpts) -> Tags {
+        let mut tags = opts.tags.clone();
+        tags.extend(self.tags.clone());
+        if let Some(ref n) = self.name {
+            // shown in the EC2 console's name column.
+            tags.entry("Name".to_owned()).or_insert_with(|| n.clone());
+        }
+
+        tags
+    }
+
+    /// Run repetitions `next..reps`: first on the checkpointed instance `prev` if it is still
+    /// up, then on newly launched ones.
+    async fn run_remaining(
+        &self,
+        opts: &RunOpts,
+        out_dir: &Path,
+        reps: usize,
+        mut next: usize,
+        ckpt: &NodeCheckpoint,
+        prev: Option<Instance>,
+    ) -> Result<Vec<RepResult>, Report> {
+        let mut results = vec![];
+        // only the main machine of a multi-machine node is checkpointed, so it starts over.
+        let multi =
+            matches!(self.provider, Provider::Aws { ref machines, .. } if !machines.is_empty());
+        if let Some(inst) = prev.as_ref().filter(|_| multi) {
+            if let Some(ref cloud) = inst.cloud {
+                warn!(host = ?inst.conn.host, "not resuming a multi-machine node, relaunching");
+                if let Err(err) = cloud.terminate(&inst.conn.host).await {
+                    warn!(?err, host = ?inst.conn.host, "could not terminate checkpointed instance");
+                }
+            }
+
+            ckpt.update(|s| s.instance = None);
+        }
+
+        if let Some(inst) = prev.filter(|_| next < reps && !multi) {
+            match self.reach(&inst.conn).await {
+                Ok(_) => {
+                    info!(host = ?inst.conn.host, "resuming on still-running instance");
+                    let until = if self.fresh_instance { next + 1 } else { reps };
+                    let exp = self.exp(opts, out_dir, reps, ckpt)?;
+                    let res = run_reps(&inst.conn, &exp, next..until).await;
+                    // tsunami doesn't know about this instance anymore, so we clean it up.
+                    if let Some(ref cloud) = inst.cloud {
+                        if let Err(err) = cloud.terminate(&inst.conn.host).await {
+                            warn!(?err, host = ?inst.conn.host, "could not terminate resumed instance");
+                        }
+                    }
+
+                    ckpt.update(|s| s.instance = None);
+                    results.extend(res?);
+                    next = until;
+                }
+                Err(err) => {
+                    warn!(?err, "checkpointed instance is unreachable, relaunching");
+                    ckpt.update(|s| s.instance = None);
+                }
+            }
+        }
+
+        let launches: Vec<Range<usize>> = if self.fresh_instance {
+            (next..reps).map(|r| r..r + 1).collect()
+        } else {
+            std::iter::once(next..reps)
+                .filter(|r| !r.is_empty())
+                .collect()
+        };
+        for l in launches {
+            if opts.control.skip_node() {
+                break;
+            }
+
+            results.extend(self.launch(opts, out_dir, reps, l, ckpt).await?);
+        }
+
+        Ok(results)
+    }
+
+    /// Run repetitions `run` (out of `reps`) on an already set-up pool machine, which stays up
+    /// afterwards.
+    async fn run_pooled(
+        &self,
+        opts: &RunOpts,
+        out_dir: &Path,
+        reps: usize,
+        run: Range<usize>,
+        ckpt: &NodeCheckpoint,
+        inst: &Instance,
+    ) -> Result<Vec<RepResult>, Report> {
+        info!(host = ?inst.conn.host, "using pool machine");
+        if self.fresh_instance {
+            warn!("fresh_instance has no effect on pool machines");
+        }
+
+        let ssh = self
+            .reach(&inst.conn)
+            .await
+            .wrap_err("connect to pool machine")?;
+        // the bench binary and script have likely changed since the pool came up.
+        self.remote_setup(opts, Some(ckpt))
+            .upload(&ssh, &inst.conn)
+            .await?;
+        ckpt.update(|s| {
+            s.phase = Phase::Running;
+            // resuming must never terminate a pool machine.
+            s.instance = Some(Instance {
+                conn: inst.conn.clone(),
+                cloud: None,
+            });
+        });
+        let mut exp = self.exp(opts, out_dir, reps, ckpt)?;
+        // the machine stays up for inspection anyway.
+        exp.pause = false;
+        run_reps(&inst.conn, &exp, run).await
+    }
+
+    fn exp(
+        &self,
+        opts: &RunOpts,
+        out_dir: &Path,
+        reps: usize,
+        ckpt: &NodeCheckpoint,
+    ) -> Result<Exp, Report> {
+        let mut args = vec![];
+        for s in &self.uses {
+            let e = opts
+                .services
+                .get(s)
+                .ok_or_else(|| eyre!("node {:?} uses {:?}, which isn't running", self.id(), s))?;
+            args.extend(e.args(s));
+        }
+
+        Ok(Exp {
+            python: self.python(),
+            script_remote_path: Path::new(opts.script.file_name().unwrap()).to_path_buf(),
+            bench_remote_path: Path::new(opts.bench_bin.file_name().unwrap()).to_path_buf(),
+            prov: self.label().to_owned(),
+            ssh: self.ssh.clone(),
+            out_dir: out_dir.to_path_buf(),
+            log_dir: opts.out_dir.join("logs"),
+            node: self.id(),
+            reps,
+            rerun_invalid: self.rerun_invalid,
+            filters: opts.filters.clone(),
+            pause: opts.pause,
+            workdir: self.workdir(),
+            scratch_dir: self.scratch.as_ref().map(|s| s.mount.clone()),
+            disk_guard: self.disk_guard.clone(),
+            stall: self.stall.clone(),
+            script_timeout: std::time::Duration::from_secs(self.script_timeout_secs),
+            debug: self.debug_wrapper.clone(),
+            redis: self.redis.clone(),
+            sidecars: self.services.clone(),
+            hosts: self.provider.hosts(),
+            roles: vec![],
+            args,
+            ready: self.ready.as_ref().map(|r| r.resolve(&opts.services)),
+            ip_version: self.ip_version,
+            mtu: self.mtu,
+            env: vec![],
+            stamp: opts.stamp_results.then(|| opts.provenance.stamp()),
+            clean: self.clean,
+            skip_stale: self.skip_stale,
+            pre_exp: self.pre_exp.clone(),
+            post_exp: self.post_exp.clone(),
+            control: opts.control.clone(),
+            events: opts.events.with("node", ckpt.id()),
+            budget: opts.budget.clone(),
+            cloud: self.provider.cloud(),
+            throttle: self.throttle.clone(),
+            transfer: self.transfer,
+            bwlimit: opts.bwlimit,
+            transfer_retry: self.transfer_retry.clone(),
+            ckpt: ckpt.clone(),
+        })
+    }
+
+    /// The python the script runs with.
+    pub fn python(&self) -> String {
+        self.deps.python()
+    }
+
+    /// The working directory, with any `~/` dropped: sftp doesn't expand it.
+    pub fn workdir(&self) -> Option<String> {
+        self.workdir.as_ref().map(|w| {
+            w.strip_prefix("~/")
+                .unwrap_or(w)
+                .trim_end_matches('/')
+                .to_owned()
+        })
+    }
+
+    fn remote_setup(&self, opts: &RunOpts, ckpt: Option<&NodeCheckpoint>) -> RemoteSetup {
+        RemoteSetup {
+            bench_bin: opts.bench_bin.clone(),
+            arch_bins: opts.arch_bins.clone(),
+            bench_remote_path: Path::new(opts.bench_bin.file_name().unwrap()).to_path_buf(),
+            script: opts.script.clone(),
+            script_remote_path: Path::new(opts.script.file_name().unwrap()).to_path_buf(),
+            sidecars: self.services.clone(),
+            deps: self.deps.clone(),
+            steps: self.setup_steps.clone(),
+            reboot_timeout: std::time::Duration::from_secs(self.reboot_timeout_secs),
+            ssh_port: self.ssh.port(),
+            unlimited_credits: match self.provider {
+                Provider::Aws {
+                    burstable: Burstable::Unlimited,
+                    ..
+                } if is_burstable(self.provider.instance_type(self.gpu).unwrap()) => {
+                    self.provider.cloud()
+                }
+                _ => None,
+            },
+            disk: self
+                .disk
+                .clone()
+                .and_then(|d| Some((self.provider.cloud()?, d))),
+            scratch: self.scratch.clone(),
+            firewall: self.firewall.clone(),
+            mtu: self.mtu,
+            nic_tuning: self.nic_tuning.clone(),
+            kernel: self.kernel.clone(),
+            gpu: self.gpu,
+            workdir: self.workdir(),
+            transfer: self.transfer,
+            bwlimit: opts.bwlimit,
+            transfer_retry: self.transfer_retry.clone(),
+            ckpt: ckpt.cloned(),
+            launched: std::time::Instant::now(),
+        }
+    }
+
+    /// Bring up a machine and run repetitions `launch_reps` (out of `reps`) on it.
+    async fn launch(
+        &self,
+        opts: &RunOpts,
+        out_dir: &Path,
+        reps: usize,
+        launch_reps: Range<usize>,
+        ckpt: &NodeCheckpoint,
+    ) -> Result<Vec<RepResult>, Report> {
+        let _meter = match self.provider.cloud() {
+            Some(_) => Some(opts.budget.launch(self.provider.machine_count())?),
+            None => None,
+        };
+        info!(reps = ?launch_reps, "starting machines");
+        let exp = self.exp(opts, out_dir, reps, ckpt)?;
+        let launched = self.retry_launch(
+            || {
+                // nothing started on a previous instance is coming back.
+                ckpt.update(|s| {
+                    s.phase = Phase::Launching;
+                    s.started_rep = None;
+                    s.fetched.clear();
+                });
+                self.bring_up(opts, Then::Run(&exp, launch_reps.clone()))
+            },
+            // once experiments have started, the failure is theirs.
+            || ckpt.get().phase != Phase::Launching,
+        );
+        match launched.await? {
+            Outcome::Ran(r) => Ok(r),
+            Outcome::Kept(_) => unreachable!(),
+        }
+    }
+
+    /// Bring up and set up a machine, and leave it running. A cloud machine's ssh key is copied
+    /// to `key_path`.
+    #[instrument(skip(self, opts, key_path), fields(name = %self.machine_name(), provider = ?self.provider))]
+    pub async fn provision(&self, opts: &RunOpts, key_path: PathBuf) -> Result<Instance, Report> {
+        self.check()?;
+        info!("starting machines");
+        let launched = self.retry_launch(|| self.bring_up(opts, Then::Keep(&key_path)), || false);
+        match launched.await? {
+            Outcome::Kept(i) => Ok(i),
+            Outcome::Ran(_) => unreachable!(),
+        }
+    }
+
+    /// Call `launch` until it succeeds or fails with a non-transient error. `started` says whether
+    /// the failed attempt got as far as running experiments, in which case it isn't retried.
+    async fn retry_launch<T, F, Fut>(
+        &self,
+        mut launch: F,
+        started: impl Fn() -> bool,
+    ) -> Result<T, Report>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Report>>,
+    {
+        let policy = &self.launch_retry;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let err = match launch().await {
+                Ok(t) => return Ok(t),
+                Err(e) => e,
+            };
+
+            let class = ErrorClass::of(&err);
+            if started() || !class.is_transient() {
+                return Err(err);
+            }
+
+            if attempt >= policy.max_attempts {
+                return Err(err.wrap_err(eyre!("launch failed after {} attempts", attempt)));
+            }
+
+            let backoff = policy.backoff(attempt);
+            warn!(?attempt, ?backoff, ?class, err = %format!("{:#}", err), "launch failed, retrying");
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    async fn bring_up(&self, opts: &RunOpts, then: Then<'_>) -> Result<Outcome, Report> {
+        let tags = self.tags(opts);
+        let ckpt = match then {
+            Then::Run(exp, _) => Some(&exp.ckpt),
+            Then::Keep(_) => None,
+        };
+        let rs = self.remote_setup(opts, ckpt);
+        match self.provider.clone() {
+            Provider::Aws {
+                access: Some(_), ..
+            } => self.run_self_launched(rs, &tags, then).await,
+            Provider::Aws {
+                region, profile, ..
+            } => {
+                let cloud = Cloud::Aws {
+                    region: region.clone(),
+                    profile: profile.clone(),
+                };
+                match profile {
+                    None => {
+                        self.run_aws(aws::Launcher::default(), &region, rs, cloud, &tags, then)
+                            .await
+                    }
+                    Some(profile) => {
+                        let launcher = aws::Launcher::default().with_credentials(move || {
+                            let mut p = ProfileProvider::new()?;
+                            p.set_profile(profile.clone());
+                            Ok(p)
+                        });
+                        self.run_aws(launcher, &region, rs, cloud, &tags, then)
+                            .await
+                    }
+                }
+            }
+            Provider::Azure {
+                resource_group: Some(_),
+                ..
+            } => self.run_self_launched(rs, &tags, then).await,
+            Provider::Azure { region: r, .. } => {
+                let mut az_launcher = azure::Launcher::default();
+                let m = azure::Setup::default()
+                    .region(r.clone().parse()?)
+                    .image(crate::azure::IMAGE.to_owned())
+                    .instance_type(self.provider.instance_type(self.gpu).unwrap().to_owned())
+                    .setup(move |vm| {
+                        let rs = rs.clone();
+                        Box::pin(async move { rs.run(vm).await })
+                    });
+                ratelimit::acquire(&Cloud::Azure).await;
+                if let Err(e) = az_launcher
+                    .spawn(vec![(self.machine_name().to_owned(), m)], None)
+                    .await
+                {
+                    ratelimit::acquire(&Cloud::Azure).await;
+                    az_launcher.terminate_all().await?;
+                    return Err(e);
+                }
+
+                let exp = then.exp();
+                let mut torn_down = vec![];
+                let res = with_launcher(
+                    &mut az_launcher,
+                    self.machine_name(),
+                    &[],
+                    Cloud::Azure,
+                    &tags,
+                    then,
+                    &mut torn_down,
+                )
+                .await;
+                if exp.is_some() || res.is_err() {
+                    ratelimit::acquire(&Cloud::Azure).await;
+                    teardown::after(az_launcher.terminate_all().await, &torn_down).await?;
+                    if let Some(exp) = exp {
+                        exp.ckpt.update(|s| s.instance = None);
+                    }
+                }
+
+                res
+            }
+            Provider::Baremetal { ip, user, hosts } => {
+                let key = self.ssh.key_path.clone();
+                let port = self.ssh.port();
+                let others: Vec<_> = hosts
+                    .iter()
+                    .map(|h| self.setup_known_host(&h.ip, &h.user, port, key.clone(), rs.clone()))
+                    .collect();
+                let (conn, others) = futures_util::future::join(
+                    self.setup_known_host(&ip, &user, port, key.clone(), rs),
+                    futures_util::future::join_all(others),
+                )
+                .await;
+                let mut set_up = vec![];
+                let mut first_err = None;
+                let others = others.into_iter().map(|o| o.wrap_err("set up other hosts"));
+                for r in std::iter::once(conn).chain(others) {
+                    match r {
+                        Ok(c) => set_up.push(c),
+                        Err(err) => {
+                            first_err.get_or_insert(err);
+                        }
+                    }
+                }
+                if let Some(err) = first_err {
+                    // undo what we did to the hosts that did get set up.
+                    for c in &set_up {
+                        self.undo_setup(c).await;
+                    }
+
+                    return Err(err);
+                }
+
+                let conn = set_up.remove(0);
+                let others = set_up;
+                let keep = then.exp().is_none();
+                let res = use_machine(conn.clone(), None, then).await;
+                if !keep {
+                    for c in std::iter::once(&conn).chain(&others) {
+                        self.undo_setup(c).await;
+                    }
+                }
+
+                res
+            }
+            Provider::Inventory { group, user, .. } => {
+                let inventory = opts
+                    .inventory
+                    .as_ref()
+                    .ok_or_else(|| eyre!("inventory nodes need an --inventory"))?;
+                // released once we are done with the host, however that goes.
+                let lease = inventory.allocate(&group)?;
+                let h = &lease.host;
+                let user = h
+                    .user
+                    .clone()
+                    .or(user)
+                    .ok_or_else(|| eyre!("no user for inventory host {:?}", h.host))?;
+                let port = h.port.unwrap_or_else(|| self.ssh.port());
+                let key = self.ssh.key_path.clone();
+                self.run_known_host(&h.host, &user, port, key, None, rs, then)
+                    .await
+            }
+            Provider::Existing { host, user, .. } => {
+                info!(?host, "using existing instance");
+                let key = self.ssh.key_path.clone();
+                self.run_known_host(&host, &user, self.ssh.port(), key, None, rs, then)
+                    .await
+            }
+            Provider::Linode { .. }
+            | Provider::Vultr { .. }
+            | Provider::Oci(_)
+            | Provider::OpenStack(_) => self.run_self_launched(rs, &tags, then).await,
+            Provider::K8s(cfg) => {
+                // the pod is only reachable while we forward its port.
+                ensure!(
+                    then.exp().is_some(),
+                    "kubernetes nodes can't be kept running in a pool"
+                );
+                let exp = then.exp();
+                let pod = Pod::launch(&cfg, self.machine_name(), &self.provider_opts).await?;
+                let key = Some(pod.key.clone());
+                let res = self
+                    .run_known_host("127.0.0.1", "root", pod.port, key, None, rs, then)
+                    .await;
+                if let Err(err) = pod.delete().await {
+                    warn!(?err, "could not delete pod");
+                }
+
+                if let Some(exp) = exp {
+                    exp.ckpt.update(|s| s.instance = None);
+                }
+
+                res
+            }
+            Provider::Qemu(cfg) => {
+                // the vm goes away with us.
+                ensure!(then.exp().is_some(), "qemu nodes can't be kept in a pool");
+                let exp = then.exp();
+                let vm = Vm::launch(&cfg, self.machine_name()).await?;
+                let key = Some(vm.key.clone());
+                let res = self
+                    .run_known_host("127.0.0.1", &cfg.user, vm.port, key, None, rs, then)
+                    .await;
+                if let Err(err) = vm.destroy().await {
+                    warn!(?err, "could not destroy vm");
+                }
+
+                if let Some(exp) = exp {
+                    exp.ckpt.update(|s| s.instance = None);
+                }
+
+                res
+            }
+        }
+    }
+
+    async fn run_aws<P>(
+        &self,
+        mut aws_launcher: aws::Launcher<P>,
+        region: &str,
+        mut rs: RemoteSetup,
+        cloud: Cloud,
+        tags: &Tags,
+        then: Then<'_>,
+    ) -> Result<Outcome, Report>
+    where
+        P: ProvideAwsCredentials + Send + Sync + 'static,
+    {
+        aws_launcher.set_mode(aws::LaunchMode::TrySpot { hours: 6 });
+        let ami = timed(
+            rs.ckpt.as_ref(),
+            "ami_lookup",
+            ubuntu_ami::get_latest(
+                region,
+                Some("focal"),
+                None,
+                Some("hvm:ebs-ssd"),
+                Some("amd64"),
+            ),
+        )
+        .await
+        .map_err(|e| eyre!(e))?;
+        rs.launched = std::time::Instant::now();
+        let az = match self.provider_opts.get("availability_zone") {
+            Some(z) => aws::AvailabilityZoneSpec::Specify(
+                z.as_str()
+                    .ok_or_else(|| eyre!("availability_zone must be a string"))?
+                    .to_owned(),
+            ),
+            None => aws::AvailabilityZoneSpec::Any,
+        };
+        // every machine gets the same setup, and the same availability zone spec, which puts them
+        // under one tsunami region launcher and so in one security group.
+        let setup = |ami: &str, user: &str, instance_type: &str| -> Result<aws::Setup, Report> {
+            let rs = rs.clone();
+            Ok(aws::Setup::default()
+                .region(region.parse()?, ami, user)
+                .availability_zone(az.clone())
+                .instance_type(instance_type)
+                .setup(move |vm| {
+                    let rs = rs.clone();
+                    Box::pin(async move { rs.run(vm).await })
+                }))
+        };
+        let instance_type = self.provider.instance_type(self.gpu).unwrap();
+        let mut machines = vec![(
+            self.machine_name().to_owned(),
+            setup(&ami, "ubuntu", instance_type)?,
+        )];
+        let mut others = vec![];
+        if let Provider::Aws {
+            machines: ref ms, ..
+        } = self.provider
+        {
+            for m in ms {
+                let name = format!("{}-{}", self.machine_name(), m.role);
+                machines.push((
+                    name.clone(),
+                    setup(
+                        m.ami.as_deref().unwrap_or(&ami),
+                        &m.user,
+                        m.instance_type.as_deref().unwrap_or(instance_type),
+                    )?,
+                ));
+                others.push((m.role.clone(), name));
+            }
+        }
+
+        ratelimit::acquire(&cloud).await;
+        if let Err(e) = aws_launcher
+            .spawn(machines, Some(std::time::Duration::from_secs(180)))
+            .await
+        {
+            ratelimit::acquire(&cloud).await;
+            aws_launcher.terminate_all().await?;
+            return Err(e);
+        }
+
+        //wait_for_continue();
+
+        let exp = then.exp();
+        let mut torn_down = vec![];
+        let res = with_launcher(
+            &mut aws_launcher,
+            self.machine_name(),
+            &others,
+            cloud.clone(),
+            tags,
+            then,
+            &mut torn_down,
+        )
+        .await;
+        if exp.is_some() || res.is_err() {
+            ratelimit::acquire(&cloud).await;
+            teardown::after(aws_launcher.terminate_all().await, &torn_down).await?;
+            if let Some(exp) = exp {
+                exp.ckpt.update(|s| s.instance = None);
+            }
+        }
+
+        res
+    }
+
+    /// Launch an instance from a provider tsunami doesn't support, then drive it like a known
+    /// host.
+    async fn run_self_launched(
+        &self,
+        rs: RemoteSetup,
+        tags: &Tags,
+        then: Then<'_>,
+    ) -> Result<Outcome, Report> {
+        let cloud = self.provider.cloud().unwrap();
+        let key = std::env::temp_dir().join(format!(
+            "burrito-cloud-exp-{}-{}",
+            std::process::id(),
+            self.machine_name()
+        ));
+        // kept machines' keys have been copied to the pool, so it goes however this ends.
+        let _key = TempKey(key.clone());
+        let pubkey = generate_key(&key).await?;
+        let mut login_key = key.clone();
+        let mut made = ec2::Made::default();
+        let (ip, user) = match self.provider {
+            Provider::Aws {
+                ref region,
+                ref profile,
+                access: Some(ref access),
+                ..
+            } => {
+                let ami = timed(
+                    rs.ckpt.as_ref(),
+                    "ami_lookup",
+                    ubuntu_ami::get_latest(
+                        region,
+                        Some("focal"),
+                        None,
+                        Some("hvm:ebs-ssd"),
+                        Some("amd64"),
+                    ),
+                )
+                .await
+                .map_err(|e| eyre!(e))?;
+                let zone = match self.provider_opts.get("availability_zone") {
+                    Some(z) => Some(
+                        z.as_str()
+                            .ok_or_else(|| eyre!("availability_zone must be a string"))?,
+                    ),
+                    None => None,
+                };
+                if access.key_pair.is_some() {
+                    login_key = self.ssh.key_path.clone().unwrap();
+                }
+
+                let client = crate::tags::ec2_client(region, profile.as_deref())?;
+                ratelimit::acquire(&cloud).await;
+                let launched = ec2::launch(
+                    &client,
+                    access,
+                    &ami,
+                    self.provider.instance_type(self.gpu).unwrap(),
+                    zone,
+                    self.machine_name(),
+                    &pubkey,
+                    tags,
+                    &mut made,
+                )
+                .await;
+                match launched {
+                    Ok(ip) => (ip, "ubuntu"),
+                    Err(err) => {
+                        if let Err(e) = ec2::cleanup(region, profile.as_deref(), &made).await {
+                            warn!(err = ?e, "could not clean up after failed launch");
+                        }
+
+                        return Err(err);
+                    }
+                }
+            }
+            Provider::Linode {
+                ref region,
+                ref plan,
+            }
+            | Provider::Vultr {
+                ref region,
+                ref plan,
+            } => {
+                let mut opts = self.provider_opts.clone();
+                if self.ip_version != IpVersion::V4 && matches!(cloud, Cloud::Vultr) {
+                    // linodes always get an IPv6 address.
+                    opts.entry("enable_ipv6").or_insert(true.into());
+                }
+
+                (
+                    vps::launch(&cloud, region, plan, self.machine_name(), &pubkey, &opts).await?,
+                    "root",
+                )
+            }
+            Provider::Azure {
+                ref region,
+                resource_group: Some(ref rg),
+                ..
+            } => (
+                crate::azure::launch(
+                    rg,
+                    region,
+                    self.provider.instance_type(self.gpu).unwrap(),
+                    self.machine_name(),
+                    &pubkey,
+                    tags,
+                )
+                .await?,
+                "ubuntu",
+            ),
+            Provider::Oci(ref cfg) => (
+                oci::launch(cfg, self.machine_name(), &pubkey, tags, &self.provider_opts).await?,
+                "ubuntu",
+            ),
+            Provider::OpenStack(ref cfg) => (
+                openstack::launch(cfg, self.machine_name(), &key, &self.provider_opts).await?,
+                cfg.user.as_str(),
+            ),
+            _ => unreachable!(),
+        };
+        let exp = then.exp();
+        let res = async {
+            if let Err(err) = cloud.tag(&ip, tags).await {
+                warn!(?err, "could not tag cloud resources");
+            }
+
+            skip_host_key_check(&ip)?;
+            let conn = ConnInfo {
+                host: ip.clone(),
+                user: user.to_owned(),
+                key_path: Some(login_key.clone()),
+                port: 22,
+                keepalive: None,
+            };
+            // it takes a little while after booting for sshd to come up.
+            reconnect(&conn, Duration::from_secs(300)).await?;
+            self.run_known_host(
+                &ip,
+                user,
+                22,
+                Some(login_key.clone()),
+                Some(cloud.clone()),
+                rs,
+                then,
+            )
+            .await
+        }
+        .await;
+        if exp.is_some() || res.is_err() {
+            let terminated = match cloud {
+                Cloud::Aws {
+                    ref region,
+                    ref profile,
+                } if made.instance_id.is_some() => {
+                    ec2::cleanup(region, profile.as_deref(), &made).await
+                }
+                _ => cloud.terminate(&ip).await,
+            };
+            if let Err(err) = terminated {
+                warn!(?err, ?ip, "could not terminate instance");
+            }
+
+            if let Some(exp) = exp {
+                exp.ckpt.update(|s| s.instance = None);
+            }
+        }
+
+        res
+    }
+
+    /// Drive a machine that is already running, via tsunami's baremetal provider. `cloud` is set
+    /// if we launched it.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_known_host(
+        &self,
+        host: &str,
+        user: &str,
+        port: u16,
+        key_path: Option<PathBuf>,
+        cloud: Option<Cloud>,
+        rs: RemoteSetup,
+        then: Then<'_>,
+    ) -> Result<Outcome, Report> {
+        let conn = self
+            .setup_known_host(host, user, port, key_path, rs)
+            .await?;
+        let keep = then.exp().is_none();
+        let res = use_machine(conn.clone(), cloud, then).await;
+        if !keep {
+            self.undo_setup(&conn).await;
+        }
+
+        res
+    }
+
+    /// Undo setup's firewall and NIC changes to the host at `conn`, if it made any.
+    async fn undo_setup(&self, conn: &ConnInfo) {
+        if self.firewall.is_none() && self.nic_tuning.is_none() {
+            return;
+        }
+
+        match self.reach(conn).await {
+            Ok(ssh) => setup::undo(&ssh).await,
+            Err(err) => warn!(?err, host = ?conn.host, "could not reach host to undo setup"),
+        }
+    }
+
+    async fn setup_known_host(
+        &self,
+        host: &str,
+        user: &str,
+        port: u16,
+        key_path: Option<PathBuf>,
+        mut rs: RemoteSetup,
+    ) -> Result<ConnInfo, Report> {
+        self.ssh.apply_to_host(host, self.proxy_jump.as_ref())?;
+
+        // a baremetal launcher only sets up one machine.
+        let mut launcher = baremetal::Machine::default();
+        let mut m = baremetal::Setup::new((host, port), Some(user.to_owned()))?;
+        if let Some(k) = key_path {
+            m = m.key_path(k);
+        }
+
+        rs.ssh_port = port;
+
+        let m = m.setup(move |vm| {
+            let rs = rs.clone();
+            Box::pin(async move { rs.run(vm).await })
+        });
+        // termination doesn't matter here
+        launcher
+            .spawn(vec![(self.machine_name().to_owned(), m)], None)
+            .await
+            .wrap_err_with(|| format!("set up {}", host))?;
+        let conns = launcher.connect_all().await?;
+        let vm = conns.get(self.machine_name()).unwrap();
+        Ok(ConnInfo::from_machine(vm, port))
+    }
+}
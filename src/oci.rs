@@ -0,0 +1,299 @@
+//! Launching, tagging, and terminating Oracle Cloud instances, through the `oci` CLI (whose
+//! configuration, in `~/.oci/config`, has the credentials).
+//!
+//! By default, nodes get the always-free Ampere A1 (arm64) shape, so bench binaries need to be
+//! built for aarch64 (see `--bench-bin-for`).
+
+use crate::ratelimit;
+use crate::tags::{cli_opts, with_marker, Cloud, ProviderOpts, Tags};
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use serde_json::{json, Value};
+use tracing::{info, instrument, warn};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct OciCfg {
+    pub region: String,
+    pub compartment_id: String,
+    /// The subnet to put the instance's VNIC in. It must allow ssh in.
+    pub subnet_id: String,
+    #[serde(default = "default_shape")]
+    pub shape: String,
+    /// For flexible shapes. Defaults to 1 OCPU and 6 GB.
+    #[serde(default)]
+    pub ocpus: Option<f64>,
+    #[serde(default)]
+    pub memory_gb: Option<f64>,
+    /// Defaults to the region's first.
+    #[serde(default)]
+    pub availability_domain: Option<String>,
+    /// Use this profile from the OCI config file instead of `DEFAULT`.
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+fn default_shape() -> String {
+    "VM.Standard.A1.Flex".to_owned()
+}
+
+impl OciCfg {
+    pub fn cloud(&self) -> Cloud {
+        Cloud::Oci {
+            region: self.region.clone(),
+            compartment_id: self.compartment_id.clone(),
+            profile: self.profile.clone(),
+        }
+    }
+}
+
+async fn oci(cloud: &Cloud, args: &[&str]) -> Result<Value, Report> {
+    let (region, profile) = match cloud {
+        Cloud::Oci {
+            region, profile, ..
+        } => (region, profile),
+        _ => unreachable!(),
+    };
+    ratelimit::acquire(cloud).await;
+    let mut cmd = tokio::process::Command::new("oci");
+    if let Some(p) = profile {
+        cmd.args(["--profile", p]);
+    }
+
+    let out = cmd
+        .args(["--region", region, "--output", "json"])
+        .args(args)
+        .output()
+        .await
+        .wrap_err_with(|| format!("oci {}", args.join(" ")))?;
+    ensure!(
+        out.status.success(),
+        "oci {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    if out.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Value::Null);
+    }
+
+    Ok(serde_json::from_slice(&out.stdout)?)
+}
+
+fn compartment(cloud: &Cloud) -> &str {
+    match cloud {
+        Cloud::Oci { compartment_id, .. } => compartment_id,
+        _ => unreachable!(),
+    }
+}
+
+async fn public_ip(cloud: &Cloud, id: &str) -> Result<Option<String>, Report> {
+    let vnics = oci(
+        cloud,
+        &["compute", "instance", "list-vnics", "--instance-id", id],
+    )
+    .await?;
+    Ok(vnics["data"][0]["public-ip"].as_str().map(str::to_owned))
+}
+
+/// Launch an instance, letting `pubkey` log in as `ubuntu`. Returns its public IP once it is
+/// running. `opts` are passed to `oci compute instance launch`.
+#[instrument(skip(cfg, pubkey, tags, opts), fields(shape = %cfg.shape), level = "debug")]
+pub async fn launch(
+    cfg: &OciCfg,
+    label: &str,
+    pubkey: &str,
+    tags: &Tags,
+    opts: &ProviderOpts,
+) -> Result<String, Report> {
+    let cloud = cfg.cloud();
+    let ad = match cfg.availability_domain {
+        Some(ref ad) => ad.clone(),
+        None => {
+            let ads = oci(
+                &cloud,
+                &[
+                    "iam",
+                    "availability-domain",
+                    "list",
+                    "--compartment-id",
+                    &cfg.compartment_id,
+                ],
+            )
+            .await?;
+            ads["data"][0]["name"]
+                .as_str()
+                .ok_or_else(|| eyre!("no availability domains in {}", cfg.region))?
+                .to_owned()
+        }
+    };
+
+    // the image has to match the shape's architecture.
+    let images = oci(
+        &cloud,
+        &[
+            "compute",
+            "image",
+            "list",
+            "--compartment-id",
+            &cfg.compartment_id,
+            "--operating-system",
+            "Canonical Ubuntu",
+            "--operating-system-version",
+            "20.04",
+            "--shape",
+            &cfg.shape,
+            "--sort-by",
+            "TIMECREATED",
+            "--sort-order",
+            "DESC",
+            "--limit",
+            "1",
+        ],
+    )
+    .await?;
+    let image = images["data"][0]["id"]
+        .as_str()
+        .ok_or_else(|| eyre!("no ubuntu 20.04 image for shape {}", cfg.shape))?
+        .to_owned();
+
+    let metadata = json!({ "ssh_authorized_keys": pubkey }).to_string();
+    let tags = serde_json::to_string(&with_marker(tags))?;
+    let shape_config = json!({
+        "ocpus": cfg.ocpus.unwrap_or(1.),
+        "memoryInGBs": cfg.memory_gb.unwrap_or(6.),
+    })
+    .to_string();
+    let mut args = vec![
+        "compute",
+        "instance",
+        "launch",
+        "--compartment-id",
+        &cfg.compartment_id,
+        "--availability-domain",
+        &ad,
+        "--subnet-id",
+        &cfg.subnet_id,
+        "--image-id",
+        &image,
+        "--shape",
+        &cfg.shape,
+        "--display-name",
+        label,
+        "--assign-public-ip",
+        "true",
+        "--metadata",
+        &metadata,
+        "--freeform-tags",
+        &tags,
+        "--wait-for-state",
+        "RUNNING",
+    ];
+    if cfg.shape.ends_with(".Flex") {
+        args.extend(["--shape-config", &shape_config]);
+    }
+
+    let extra = cli_opts(opts);
+    args.extend(extra.iter().map(String::as_str));
+
+    let inst = oci(&cloud, &args).await?;
+    let id = inst["data"]["id"]
+        .as_str()
+        .ok_or_else(|| eyre!("unexpected launch response {}", inst))?
+        .to_owned();
+    let ip = match public_ip(&cloud, &id).await {
+        Ok(Some(ip)) => ip,
+        res => {
+            // the caller never learns of the instance, so it can't clean up after us.
+            if let Err(err) = delete(&cloud, &id).await {
+                warn!(
+                    ?err,
+                    ?id,
+                    "could not terminate instance without a public ip"
+                );
+            }
+            return Err(res
+                .err()
+                .unwrap_or_else(|| eyre!("instance {} has no public ip", id)));
+        }
+    };
+    info!(?id, ?ip, "launched instance");
+    Ok(ip)
+}
+
+/// The id of the running instance with public IP `ip`.
+async fn find_instance(cloud: &Cloud, ip: &str) -> Result<String, Report> {
+    let insts = oci(
+        cloud,
+        &[
+            "compute",
+            "instance",
+            "list",
+            "--all",
+            "--lifecycle-state",
+            "RUNNING",
+            "--compartment-id",
+            compartment(cloud),
+        ],
+    )
+    .await?;
+    for id in insts["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|i| i["id"].as_str())
+    {
+        if public_ip(cloud, id).await?.as_deref() == Some(ip) {
+            return Ok(id.to_owned());
+        }
+    }
+
+    Err(eyre!("no instance with ip {}", ip))
+}
+
+/// Tag the instance with public IP `public_ip`.
+#[instrument(skip(tags), level = "debug")]
+pub async fn tag(cloud: &Cloud, public_ip: &str, tags: &Tags) -> Result<(), Report> {
+    let id = find_instance(cloud, public_ip).await?;
+    let tags = serde_json::to_string(&with_marker(tags))?;
+    oci(
+        cloud,
+        &[
+            "compute",
+            "instance",
+            "update",
+            "--force",
+            "--instance-id",
+            &id,
+            "--freeform-tags",
+            &tags,
+        ],
+    )
+    .await?;
+    info!(?id, "tagged instance");
+    Ok(())
+}
+
+/// Terminate the instance with public IP `public_ip`, and its boot volume.
+#[instrument(level = "debug")]
+pub async fn terminate(cloud: &Cloud, public_ip: &str) -> Result<(), Report> {
+    let id = find_instance(cloud, public_ip).await?;
+    delete(cloud, &id).await
+}
+
+/// Terminate instance `id`, with its boot volume.
+async fn delete(cloud: &Cloud, id: &str) -> Result<(), Report> {
+    oci(
+        cloud,
+        &[
+            "compute",
+            "instance",
+            "terminate",
+            "--force",
+            "--preserve-boot-volume",
+            "false",
+            "--instance-id",
+            id,
+        ],
+    )
+    .await?;
+    info!(?id, "terminated instance");
+    Ok(())
+}
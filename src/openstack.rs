@@ -0,0 +1,219 @@
+//! Launching, tagging, and deleting OpenStack servers (e.g. on Chameleon), through the
+//! `openstack` CLI.
+//!
+//! Credentials come from the usual `OS_*` environment variables (e.g. from the cloud's openrc
+//! file) or `clouds.yaml`; the node's `auth_url` overrides the endpoint. Each server gets a new
+//! floating IP, which is released along with it.
+
+use crate::ratelimit;
+use crate::tags::{cli_opts, with_marker, Cloud, ProviderOpts, Tags};
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use serde_json::Value;
+use std::path::Path;
+use tracing::{info, instrument, warn};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct OpenStackCfg {
+    pub auth_url: String,
+    pub flavor: String,
+    pub image: String,
+    /// The network to attach the server to.
+    pub network: String,
+    /// The external network to allocate the floating IP from.
+    #[serde(default = "default_floating_network")]
+    pub floating_network: String,
+    /// The image's default user (`cc` on Chameleon's images).
+    #[serde(default = "default_user")]
+    pub user: String,
+    /// A lease's reservation id, for clouds (like Chameleon) that need one.
+    #[serde(default)]
+    pub reservation: Option<String>,
+}
+
+fn default_floating_network() -> String {
+    "public".to_owned()
+}
+
+fn default_user() -> String {
+    "ubuntu".to_owned()
+}
+
+impl OpenStackCfg {
+    pub fn cloud(&self) -> Cloud {
+        Cloud::OpenStack {
+            auth_url: self.auth_url.clone(),
+        }
+    }
+}
+
+async fn openstack(cloud: &Cloud, args: &[&str]) -> Result<Value, Report> {
+    let auth_url = match cloud {
+        Cloud::OpenStack { auth_url } => auth_url,
+        _ => unreachable!(),
+    };
+    ratelimit::acquire(cloud).await;
+    let out = tokio::process::Command::new("openstack")
+        .args(["--os-auth-url", auth_url])
+        .args(args)
+        .output()
+        .await
+        .wrap_err_with(|| format!("openstack {}", args.join(" ")))?;
+    ensure!(
+        out.status.success(),
+        "openstack {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    if out.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Value::Null);
+    }
+
+    Ok(serde_json::from_slice(&out.stdout)?)
+}
+
+/// Launch a server, authorizing the public half of `key` for the image's user. Returns its
+/// floating IP once the server is active. `opts` are passed to `openstack server create`.
+#[instrument(skip(cfg, key, opts), fields(flavor = %cfg.flavor), level = "debug")]
+pub async fn launch(
+    cfg: &OpenStackCfg,
+    label: &str,
+    key: &Path,
+    opts: &ProviderOpts,
+) -> Result<String, Report> {
+    let cloud = cfg.cloud();
+    let pubkey = format!("{}.pub", key.display());
+    let keypair = format!("{}-{}", label, std::process::id());
+    openstack(
+        &cloud,
+        &[
+            "keypair",
+            "create",
+            "--public-key",
+            &pubkey,
+            &keypair,
+            "-f",
+            "json",
+        ],
+    )
+    .await?;
+
+    let hint = cfg
+        .reservation
+        .as_ref()
+        .map(|r| format!("reservation={}", r));
+    let mut args = vec![
+        "server",
+        "create",
+        "--flavor",
+        &cfg.flavor,
+        "--image",
+        &cfg.image,
+        "--network",
+        &cfg.network,
+        "--key-name",
+        &keypair,
+        "--wait",
+        "-f",
+        "json",
+    ];
+    if let Some(ref h) = hint {
+        args.extend(["--hint", h]);
+    }
+
+    let extra = cli_opts(opts);
+    args.extend(extra.iter().map(String::as_str));
+
+    args.push(label);
+    let server = openstack(&cloud, &args).await;
+    // the key is copied into the server when it is created.
+    if let Err(err) = openstack(&cloud, &["keypair", "delete", &keypair]).await {
+        warn!(?err, ?keypair, "could not delete keypair");
+    }
+
+    let server = server?;
+    let id = server["id"]
+        .as_str()
+        .ok_or_else(|| eyre!("unexpected server create output {}", server))?
+        .to_owned();
+    let ip = async {
+        let fip = openstack(
+            &cloud,
+            &[
+                "floating",
+                "ip",
+                "create",
+                &cfg.floating_network,
+                "-f",
+                "json",
+            ],
+        )
+        .await?;
+        let ip = fip["floating_ip_address"]
+            .as_str()
+            .ok_or_else(|| eyre!("unexpected floating ip create output {}", fip))?
+            .to_owned();
+        openstack(&cloud, &["server", "add", "floating", "ip", &id, &ip]).await?;
+        Ok::<_, Report>(ip)
+    }
+    .await;
+    match ip {
+        Ok(ip) => {
+            info!(?id, ?ip, "launched server");
+            Ok(ip)
+        }
+        Err(err) => {
+            if let Err(err) = openstack(&cloud, &["server", "delete", &id]).await {
+                warn!(?err, ?id, "could not delete server");
+            }
+
+            Err(err)
+        }
+    }
+}
+
+/// The id of the server with (floating) IP `ip`.
+async fn find_server(cloud: &Cloud, ip: &str) -> Result<String, Report> {
+    let servers = openstack(cloud, &["server", "list", "-f", "json"]).await?;
+    servers
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|s| {
+            // either a string like "net=10.0.0.5, 129.114.0.3", or a map of addresses by network.
+            let nets = s["Networks"].to_string();
+            nets.split(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .any(|a| a == ip)
+        })
+        .and_then(|s| s["ID"].as_str())
+        .map(str::to_owned)
+        .ok_or_else(|| eyre!("no server with ip {}", ip))
+}
+
+/// Set the tags as properties of the server with IP `public_ip`.
+#[instrument(skip(tags), level = "debug")]
+pub async fn tag(cloud: &Cloud, public_ip: &str, tags: &Tags) -> Result<(), Report> {
+    let id = find_server(cloud, public_ip).await?;
+    let props: Vec<String> = with_marker(tags)
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    let mut args = vec!["server", "set"];
+    for p in &props {
+        args.extend(["--property", p]);
+    }
+
+    args.push(&id);
+    openstack(cloud, &args).await?;
+    info!(?id, "tagged server");
+    Ok(())
+}
+
+/// Delete the server with IP `public_ip`, and release the floating IP.
+#[instrument(level = "debug")]
+pub async fn terminate(cloud: &Cloud, public_ip: &str) -> Result<(), Report> {
+    let id = find_server(cloud, public_ip).await?;
+    openstack(cloud, &["server", "delete", &id]).await?;
+    openstack(cloud, &["floating", "ip", "delete", public_ip]).await?;
+    info!(?id, "deleted server");
+    Ok(())
+}
@@ -0,0 +1,618 @@
+//! Checking, before anything is launched, that the credentials can make every provider API call
+//! the nodes will need, and printing a minimal policy that grants just those.
+//!
+//! What's needed follows from how each node launches (through tsunami, or with its own key pair,
+//! security group, or resource group) and what else it asks for (a root disk change, unlimited
+//! CPU credits). On AWS each call is made with `DryRun`, which answers whether it would be
+//! allowed without doing anything; calls whose dry run fails for another reason (a resource-level
+//! policy looking up a made-up id) are reported as unverified rather than missing. On Azure, the
+//! permissions the signed-in account has at the subscription (or reused resource group) are
+//! matched against the actions needed.
+
+use crate::azure::{az, ResourceGroup};
+use crate::ec2::AwsAccess;
+use crate::node::Node;
+use crate::ratelimit;
+use crate::tags::{ec2_client, Cloud};
+use color_eyre::eyre::{bail, eyre, Report, WrapErr};
+use rusoto_ec2::Ec2;
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::{info, warn};
+
+/// What a node does with its provider's API.
+#[derive(Clone, Debug)]
+pub struct Use {
+    pub cloud: Cloud,
+    pub instance_type: String,
+    pub launch: Launch,
+    pub availability_zone: bool,
+    pub disk: bool,
+    pub burstable: bool,
+    pub unlimited: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum Launch {
+    Tsunami,
+    Aws(AwsAccess),
+    Azure(ResourceGroup),
+}
+
+// made-up, but well-formed, ids for the calls that take one.
+const INSTANCE: &str = "i-0123456789abcdef0";
+const VOLUME: &str = "vol-0123456789abcdef0";
+const SECURITY_GROUP: &str = "sg-0123456789abcdef0";
+const AMI: &str = "ami-0123456789abcdef0";
+const SPOT_REQUEST: &str = "sir-01234567";
+const NAME: &str = "burrito-exp-permission-check";
+const PUBKEY: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f";
+
+const AZURE_VM: &[&str] = &[
+    "Microsoft.Compute/virtualMachines/read",
+    "Microsoft.Compute/virtualMachines/write",
+    "Microsoft.Compute/virtualMachines/delete",
+    "Microsoft.Compute/disks/read",
+    "Microsoft.Compute/disks/write",
+    "Microsoft.Compute/disks/delete",
+    "Microsoft.Network/publicIPAddresses/read",
+    "Microsoft.Network/publicIPAddresses/write",
+    "Microsoft.Network/publicIPAddresses/delete",
+    "Microsoft.Network/publicIPAddresses/join/action",
+    "Microsoft.Network/networkInterfaces/read",
+    "Microsoft.Network/networkInterfaces/write",
+    "Microsoft.Network/networkInterfaces/delete",
+    "Microsoft.Network/networkInterfaces/join/action",
+    "Microsoft.Network/networkSecurityGroups/read",
+    "Microsoft.Network/networkSecurityGroups/write",
+    "Microsoft.Network/networkSecurityGroups/delete",
+    "Microsoft.Network/networkSecurityGroups/join/action",
+    "Microsoft.Network/networkSecurityGroups/securityRules/read",
+    "Microsoft.Network/networkSecurityGroups/securityRules/write",
+    "Microsoft.Network/virtualNetworks/read",
+    "Microsoft.Network/virtualNetworks/write",
+    "Microsoft.Network/virtualNetworks/delete",
+    "Microsoft.Network/virtualNetworks/subnets/read",
+    "Microsoft.Network/virtualNetworks/subnets/write",
+    "Microsoft.Network/virtualNetworks/subnets/join/action",
+    // the catalog check.
+    "Microsoft.Compute/skus/read",
+    "Microsoft.Resources/subscriptions/locations/read",
+];
+
+impl Use {
+    /// The API actions, in IAM (`ec2:...`) or Azure RBAC form.
+    pub fn actions(&self) -> BTreeSet<&'static str> {
+        let mut a = BTreeSet::new();
+        match self.cloud {
+            Cloud::Aws { .. } => {
+                a.extend([
+                    "ec2:DescribeRegions",
+                    "ec2:DescribeInstanceTypeOfferings",
+                    "ec2:DescribeInstanceTypes",
+                    "ec2:DescribeInstances",
+                    "ec2:RunInstances",
+                    "ec2:CreateTags",
+                    "ec2:TerminateInstances",
+                ]);
+                let (make_key, make_sg) = match self.launch {
+                    Launch::Aws(ref access) => {
+                        if access
+                            .security_group
+                            .as_ref()
+                            .is_some_and(|sg| !sg.starts_with("sg-"))
+                        {
+                            a.insert("ec2:DescribeSecurityGroups");
+                        }
+                        if access.key_pair.is_none() {
+                            a.insert("ec2:ImportKeyPair");
+                        }
+                        (access.key_pair.is_none(), access.security_group.is_none())
+                    }
+                    _ => {
+                        a.extend([
+                            "ec2:CreateKeyPair",
+                            "ec2:RequestSpotInstances",
+                            "ec2:DescribeSpotInstanceRequests",
+                            "ec2:CancelSpotInstanceRequests",
+                        ]);
+                        if self.availability_zone {
+                            a.insert("ec2:CreatePlacementGroup");
+                        }
+                        (true, true)
+                    }
+                };
+                if make_key {
+                    a.insert("ec2:DeleteKeyPair");
+                }
+                if make_sg {
+                    a.extend([
+                        "ec2:CreateSecurityGroup",
+                        "ec2:AuthorizeSecurityGroupIngress",
+                        "ec2:DeleteSecurityGroup",
+                    ]);
+                }
+                if self.disk {
+                    a.extend(["ec2:ModifyVolume", "ec2:DescribeVolumesModifications"]);
+                }
+                if self.burstable {
+                    a.insert("ec2:DescribeInstanceCreditSpecifications");
+                }
+                if self.unlimited {
+                    a.insert("ec2:ModifyInstanceCreditSpecification");
+                }
+            }
+            Cloud::Azure => {
+                a.extend(AZURE_VM);
+                a.insert("Microsoft.Resources/subscriptions/resourceGroups/read");
+                if !matches!(
+                    self.launch,
+                    Launch::Azure(ResourceGroup { reuse: true, .. })
+                ) {
+                    a.extend([
+                        "Microsoft.Resources/subscriptions/resourceGroups/write",
+                        "Microsoft.Resources/subscriptions/resourceGroups/delete",
+                    ]);
+                }
+                if self.disk {
+                    a.extend([
+                        "Microsoft.Compute/virtualMachines/deallocate/action",
+                        "Microsoft.Compute/virtualMachines/start/action",
+                    ]);
+                }
+            }
+            _ => (),
+        }
+
+        a
+    }
+
+    /// Where the actions are checked: the AWS profile and region, or the Azure resource group
+    /// (`None` for the subscription).
+    fn scope(&self) -> (String, Option<String>) {
+        match (&self.cloud, &self.launch) {
+            (Cloud::Aws { region, profile }, _) => (format!("{:?}", profile), Some(region.clone())),
+            (Cloud::Azure, Launch::Azure(rg)) if rg.reuse => {
+                ("azure".to_owned(), Some(rg.name.clone()))
+            }
+            _ => ("azure".to_owned(), None),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Verdict {
+    Allowed,
+    Missing,
+    /// The check itself didn't answer.
+    Unverified(String),
+}
+
+/// Nodes sharing credentials and a scope, and the actions they need between them.
+struct Group {
+    cloud: Cloud,
+    scope: Option<String>,
+    instance_type: String,
+    actions: BTreeSet<&'static str>,
+}
+
+/// Check the credentials of `nodes` can make every API call the nodes need, printing what was
+/// checked. Print the minimal policy for each set of credentials that is missing something, or
+/// for every one with `always_print`.
+pub async fn check(nodes: &[Node], always_print: bool) -> Result<(), Report> {
+    let mut groups: BTreeMap<(String, Option<String>), Group> = BTreeMap::new();
+    for u in nodes.iter().filter_map(Node::api_use) {
+        let g = groups.entry(u.scope()).or_insert_with(|| Group {
+            cloud: u.cloud.clone(),
+            scope: u.scope().1,
+            instance_type: u.instance_type.clone(),
+            actions: BTreeSet::new(),
+        });
+        g.actions.extend(u.actions());
+    }
+
+    if groups.is_empty() {
+        info!("no nodes use a provider whose permissions we can check");
+        return Ok(());
+    }
+
+    // credentials -> (actions, whether any are missing, the azure scope to assign a role at)
+    let mut policies: BTreeMap<String, (BTreeSet<&str>, bool, Option<String>)> = BTreeMap::new();
+    let mut missing = 0;
+    for ((creds, _), g) in &groups {
+        let (verdicts, azure_scope) = match verify(g).await {
+            Ok(v) => v,
+            Err(err) => {
+                warn!(cloud = ?g.cloud, err = %format!("{:#}", err), "could not check permissions");
+                let why = format!("{:#}", err);
+                let v = g
+                    .actions
+                    .iter()
+                    .map(|a| (*a, Verdict::Unverified(why.clone())))
+                    .collect();
+                (v, None)
+            }
+        };
+
+        match g.cloud {
+            Cloud::Aws {
+                ref region,
+                ref profile,
+            } => println!("aws, profile {:?}, {}:", profile, region),
+            _ => println!(
+                "azure, {}:",
+                g.scope
+                    .as_ref()
+                    .map_or("the subscription".to_owned(), |rg| format!(
+                        "resource group {}",
+                        rg
+                    ))
+            ),
+        }
+        let p = policies
+            .entry(match g.cloud {
+                Cloud::Azure => format!("azure {:?}", g.scope),
+                _ => creds.clone(),
+            })
+            .or_insert((BTreeSet::new(), false, azure_scope));
+        for (a, v) in verdicts {
+            match v {
+                Verdict::Allowed => println!("  ok          {}", a),
+                Verdict::Missing => {
+                    println!("  MISSING     {}", a);
+                    missing += 1;
+                    p.1 = true;
+                }
+                Verdict::Unverified(why) => println!("  unverified  {} ({})", a, why),
+            }
+
+            p.0.insert(a);
+        }
+    }
+
+    for (creds, (actions, lacking, azure_scope)) in &policies {
+        if !(*lacking || always_print) {
+            continue;
+        }
+
+        let actions: Vec<&str> = actions.iter().copied().collect();
+        let (what, policy) = if creds.starts_with("azure") {
+            let scope = azure_scope
+                .clone()
+                .unwrap_or_else(|| "/subscriptions/<subscription id>".to_owned());
+            (
+                "azure custom role, for `az role definition create --role-definition`".to_owned(),
+                serde_json::json!({
+                    "Name": "burrito-cloud-exp",
+                    "IsCustom": true,
+                    "Description": "What burrito-cloud-exp runs need",
+                    "Actions": actions,
+                    "NotActions": [],
+                    "AssignableScopes": [scope],
+                }),
+            )
+        } else {
+            (
+                format!("aws iam policy, for profile {}", creds),
+                serde_json::json!({
+                    "Version": "2012-10-17",
+                    "Statement": [{
+                        "Effect": "Allow",
+                        "Action": actions,
+                        "Resource": "*",
+                    }],
+                }),
+            )
+        };
+        println!(
+            "\nminimal {}:\n{}",
+            what,
+            serde_json::to_string_pretty(&policy)?
+        );
+    }
+
+    if missing > 0 {
+        bail!(
+            "the credentials are missing {} permissions the run needs; the policy printed above grants them",
+            missing
+        );
+    }
+
+    info!(groups = groups.len(), "permissions check out");
+    Ok(())
+}
+
+/// The verdict on each of `g`'s actions, and for Azure, the scope they were checked at.
+async fn verify(g: &Group) -> Result<(Vec<(&'static str, Verdict)>, Option<String>), Report> {
+    match g.cloud {
+        Cloud::Aws {
+            ref region,
+            ref profile,
+        } => {
+            let client = ec2_client(region, profile.as_deref())?;
+            let ami = ubuntu_ami::get_latest(
+                region,
+                Some("focal"),
+                None,
+                Some("hvm:ebs-ssd"),
+                Some("amd64"),
+            )
+            .await
+            .map_err(|e| warn!(err = %e, "could not look up the ami, launches will be unverified"))
+            .unwrap_or_else(|_| AMI.to_owned());
+            let mut verdicts = vec![];
+            for &a in &g.actions {
+                ratelimit::acquire(&g.cloud).await;
+                let res = dry_run(&client, a, &ami, &g.instance_type).await;
+                verdicts.push((a, aws_verdict(res)));
+            }
+
+            Ok((verdicts, None))
+        }
+        Cloud::Azure => {
+            let sub = az(&["account", "show", "--query", "id", "-o", "tsv"]).await?;
+            let scope = match g.scope {
+                Some(ref rg) => format!("/subscriptions/{}/resourceGroups/{}", sub, rg),
+                None => format!("/subscriptions/{}", sub),
+            };
+            let url = format!(
+                "https://management.azure.com{}/providers/Microsoft.Authorization/permissions?api-version=2022-04-01",
+                scope
+            );
+            let perms: serde_json::Value =
+                serde_json::from_str(&az(&["rest", "--method", "get", "--url", &url]).await?)
+                    .wrap_err("parse azure permissions")?;
+            let strs = |v: &serde_json::Value| -> Vec<String> {
+                v.as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|s| s.as_str().map(str::to_owned))
+                    .collect()
+            };
+            let grants: Vec<(Vec<String>, Vec<String>)> = perms["value"]
+                .as_array()
+                .ok_or_else(|| eyre!("no permissions in azure's answer"))?
+                .iter()
+                .map(|p| (strs(&p["actions"]), strs(&p["notActions"])))
+                .collect();
+            let verdicts = g
+                .actions
+                .iter()
+                .map(|&a| {
+                    let ok = grants.iter().any(|(yes, no)| {
+                        yes.iter().any(|p| matches(p, a)) && !no.iter().any(|p| matches(p, a))
+                    });
+                    (
+                        a,
+                        if ok {
+                            Verdict::Allowed
+                        } else {
+                            Verdict::Missing
+                        },
+                    )
+                })
+                .collect();
+            Ok((verdicts, Some(scope)))
+        }
+        _ => bail!("no permissions check for {:?}", g.cloud),
+    }
+}
+
+fn aws_verdict(res: Result<(), String>) -> Verdict {
+    match res {
+        Ok(()) => Verdict::Allowed,
+        Err(e) if e.contains("DryRunOperation") => Verdict::Allowed,
+        Err(e)
+            if ["UnauthorizedOperation", "AuthFailure", "AccessDenied"]
+                .iter()
+                .any(|c| e.contains(c)) =>
+        {
+            Verdict::Missing
+        }
+        Err(e) => Verdict::Unverified(e.lines().next().unwrap_or_default().to_owned()),
+    }
+}
+
+macro_rules! dry_run {
+    ($client:expr, $call:ident, $req:ident { $($f:ident: $v:expr),* $(,)? }) => {
+        $client
+            .$call(rusoto_ec2::$req {
+                dry_run: Some(true),
+                $($f: $v,)*
+                ..Default::default()
+            })
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    };
+}
+
+/// Make the call behind `action` with `DryRun`.
+// some requests are filled in whole, leaving `dry_run!`'s `..Default::default()` nothing to do.
+#[allow(clippy::needless_update)]
+async fn dry_run(
+    c: &rusoto_ec2::Ec2Client,
+    action: &str,
+    ami: &str,
+    instance_type: &str,
+) -> Result<(), String> {
+    let s = |v: &str| Some(v.to_owned());
+    match action {
+        "ec2:DescribeRegions" => dry_run!(c, describe_regions, DescribeRegionsRequest {}),
+        "ec2:DescribeInstanceTypeOfferings" => dry_run!(
+            c,
+            describe_instance_type_offerings,
+            DescribeInstanceTypeOfferingsRequest {}
+        ),
+        "ec2:DescribeInstanceTypes" => {
+            dry_run!(c, describe_instance_types, DescribeInstanceTypesRequest {})
+        }
+        "ec2:DescribeInstances" => dry_run!(c, describe_instances, DescribeInstancesRequest {}),
+        "ec2:DescribeSecurityGroups" => {
+            dry_run!(
+                c,
+                describe_security_groups,
+                DescribeSecurityGroupsRequest {}
+            )
+        }
+        "ec2:DescribeSpotInstanceRequests" => dry_run!(
+            c,
+            describe_spot_instance_requests,
+            DescribeSpotInstanceRequestsRequest {}
+        ),
+        "ec2:DescribeVolumesModifications" => dry_run!(
+            c,
+            describe_volumes_modifications,
+            DescribeVolumesModificationsRequest {}
+        ),
+        "ec2:DescribeInstanceCreditSpecifications" => dry_run!(
+            c,
+            describe_instance_credit_specifications,
+            DescribeInstanceCreditSpecificationsRequest {}
+        ),
+        "ec2:RunInstances" => dry_run!(
+            c,
+            run_instances,
+            RunInstancesRequest {
+                image_id: s(ami),
+                instance_type: s(instance_type),
+                min_count: 1,
+                max_count: 1,
+            }
+        ),
+        "ec2:RequestSpotInstances" => dry_run!(
+            c,
+            request_spot_instances,
+            RequestSpotInstancesRequest {
+                instance_count: Some(1),
+                launch_specification: Some(rusoto_ec2::RequestSpotLaunchSpecification {
+                    image_id: s(ami),
+                    instance_type: s(instance_type),
+                    ..Default::default()
+                }),
+            }
+        ),
+        "ec2:CancelSpotInstanceRequests" => dry_run!(
+            c,
+            cancel_spot_instance_requests,
+            CancelSpotInstanceRequestsRequest {
+                spot_instance_request_ids: vec![SPOT_REQUEST.to_owned()],
+            }
+        ),
+        "ec2:CreateTags" => dry_run!(
+            c,
+            create_tags,
+            CreateTagsRequest {
+                resources: vec![INSTANCE.to_owned()],
+                tags: vec![rusoto_ec2::Tag {
+                    key: s(NAME),
+                    value: s(NAME),
+                }],
+            }
+        ),
+        "ec2:TerminateInstances" => dry_run!(
+            c,
+            terminate_instances,
+            TerminateInstancesRequest {
+                instance_ids: vec![INSTANCE.to_owned()],
+            }
+        ),
+        "ec2:CreateKeyPair" => dry_run!(
+            c,
+            create_key_pair,
+            CreateKeyPairRequest {
+                key_name: NAME.to_owned(),
+            }
+        ),
+        "ec2:ImportKeyPair" => dry_run!(
+            c,
+            import_key_pair,
+            ImportKeyPairRequest {
+                key_name: NAME.to_owned(),
+                public_key_material: PUBKEY.into(),
+            }
+        ),
+        "ec2:DeleteKeyPair" => dry_run!(
+            c,
+            delete_key_pair,
+            DeleteKeyPairRequest { key_name: s(NAME) }
+        ),
+        "ec2:CreateSecurityGroup" => dry_run!(
+            c,
+            create_security_group,
+            CreateSecurityGroupRequest {
+                group_name: NAME.to_owned(),
+                description: NAME.to_owned(),
+            }
+        ),
+        "ec2:AuthorizeSecurityGroupIngress" => dry_run!(
+            c,
+            authorize_security_group_ingress,
+            AuthorizeSecurityGroupIngressRequest {
+                group_id: s(SECURITY_GROUP),
+                ip_protocol: s("tcp"),
+                from_port: Some(22),
+                to_port: Some(22),
+                cidr_ip: s("0.0.0.0/0"),
+            }
+        ),
+        "ec2:DeleteSecurityGroup" => dry_run!(
+            c,
+            delete_security_group,
+            DeleteSecurityGroupRequest {
+                group_id: s(SECURITY_GROUP),
+            }
+        ),
+        "ec2:CreatePlacementGroup" => dry_run!(
+            c,
+            create_placement_group,
+            CreatePlacementGroupRequest {
+                group_name: s(NAME),
+                strategy: s("cluster"),
+            }
+        ),
+        "ec2:ModifyVolume" => dry_run!(
+            c,
+            modify_volume,
+            ModifyVolumeRequest {
+                volume_id: VOLUME.to_owned(),
+                size: Some(16),
+            }
+        ),
+        "ec2:ModifyInstanceCreditSpecification" => dry_run!(
+            c,
+            modify_instance_credit_specification,
+            ModifyInstanceCreditSpecificationRequest {
+                instance_credit_specifications: vec![
+                    rusoto_ec2::InstanceCreditSpecificationRequest {
+                        instance_id: s(INSTANCE),
+                        cpu_credits: s("unlimited"),
+                    }
+                ],
+            }
+        ),
+        a => Err(format!("no dry run for {}", a)),
+    }
+}
+
+/// Whether the Azure action pattern `pattern`, which may have `*` wildcards, covers `action`.
+fn matches(pattern: &str, action: &str) -> bool {
+    let (pattern, action) = (pattern.to_ascii_lowercase(), action.to_ascii_lowercase());
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = action.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
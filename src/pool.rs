@@ -0,0 +1,181 @@
+//! A pool of set-up machines kept running across runs, so iterating on the experiment script
+//! doesn't pay for launching and setting up machines every time.
+//!
+//! `pool up` launches and sets up every node in the config, and records them in the pool file.
+//! A run given `--pool` then uses the pool machine for each node it matches (same id, same machine
+//! and setup configuration), only copying over the bench binary and script. `pool down`
+//! terminates them. AWS machines are spot instances, so they last at most 6 hours.
+//!
+//! `prepare` and `execute` are the same thing as a two-phase run: `prepare` brings every machine
+//! up at once, and `execute` runs the experiments on them, in a chosen order.
+
+use crate::node::{Node, RunOpts};
+use crate::node_ids;
+use crate::state::Instance;
+use color_eyre::eyre::{bail, ensure, Report, WrapErr};
+use futures_util::future::join_all;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct PoolMachine {
+    pub id: String,
+    /// The configuration it was launched and set up with.
+    pub node: Node,
+    pub instance: Instance,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct Pool {
+    pub machines: Vec<PoolMachine>,
+}
+
+impl Pool {
+    pub fn load(path: &Path) -> Result<Self, Report> {
+        let f = std::fs::File::open(path).wrap_err_with(|| format!("open pool file {:?}", path))?;
+        serde_json::from_reader(f).wrap_err("parse pool file")
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Report> {
+        if let Some(d) = path.parent() {
+            std::fs::create_dir_all(d)?;
+        }
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// The pool machine for node `id`, if it was set up like `node`.
+    pub fn get(&self, id: &str, node: &Node) -> Option<&PoolMachine> {
+        self.machines
+            .iter()
+            .find(|m| m.id == id && m.node.same_machine(node))
+    }
+}
+
+/// Where we keep the ssh key of the cloud machine `id`: next to the pool file.
+fn key_path(pool: &Path, id: &str) -> PathBuf {
+    let stem = pool.file_stem().unwrap_or_default().to_string_lossy();
+    pool.with_file_name(format!("{}-keys", stem))
+        .join(format!("{}.pem", id))
+}
+
+/// Bring up any of `nodes` not already in the pool at `path`.
+pub async fn up(nodes: Vec<Node>, opts: &RunOpts, path: &Path) -> Result<(), Report> {
+    let pool = if path.exists() {
+        Pool::load(path)?
+    } else {
+        Pool::default()
+    };
+
+    let ids = node_ids(&nodes)?;
+    let mut todo = vec![];
+    for (node, id) in nodes.into_iter().zip(ids) {
+        if let Some(m) = pool.get(&id, &node) {
+            info!(?id, host = ?m.instance.conn.host, "already in pool");
+            continue;
+        }
+
+        ensure!(
+            !pool.machines.iter().any(|m| m.id == id),
+            "pool machine {:?} was set up with a different configuration, take the pool down first",
+            id
+        );
+        todo.push((node, id));
+    }
+
+    // everything comes up (and warms up) at the same time. Each machine is saved as soon as it
+    // is up, so if we're interrupted, the ones already launched are still in the pool.
+    let pool = Mutex::new(pool);
+    let launched = join_all(todo.into_iter().map(|(node, id)| {
+        let pool = &pool;
+        async move {
+            let res = add(pool, path, node, &id, opts).await;
+            (id, res)
+        }
+    }))
+    .await;
+    let mut failed = 0;
+    for (id, res) in launched {
+        if let Err(err) = res {
+            warn!(?err, ?id, "could not bring up pool machine");
+            failed += 1;
+        }
+    }
+
+    ensure!(failed == 0, "could not bring up {} pool machines", failed);
+    Ok(())
+}
+
+/// Bring up `node` as pool machine `id`, and save it in the pool at `path`.
+async fn add(
+    pool: &Mutex<Pool>,
+    path: &Path,
+    node: Node,
+    id: &str,
+    opts: &RunOpts,
+) -> Result<(), Report> {
+    let instance = node.provision(opts, key_path(path, id)).await?;
+    info!(?id, host = ?instance.conn.host, "added to pool");
+    let mut pool = pool.lock().unwrap();
+    pool.machines.push(PoolMachine {
+        id: id.to_owned(),
+        node,
+        instance,
+    });
+    pool.save(path).wrap_err("save pool file")
+}
+
+/// Print each pool machine, and whether it is reachable.
+pub async fn status(path: &Path) -> Result<(), Report> {
+    let pool = Pool::load(path)?;
+    println!("{:<30} {:<40} status", "id", "host");
+    for m in &pool.machines {
+        let st = match m.node.reach(&m.instance.conn).await {
+            Ok(_) => "up",
+            Err(_) => "unreachable",
+        };
+        println!(
+            "{:<30} {:<40} {}",
+            m.id,
+            format!("{}@{}", m.instance.conn.user, m.instance.conn.host),
+            st
+        );
+    }
+
+    Ok(())
+}
+
+/// Terminate the pool's cloud machines, and forget its machines.
+pub async fn down(path: &Path) -> Result<(), Report> {
+    let mut pool = Pool::load(path)?;
+    let mut left = vec![];
+    for m in pool.machines.drain(..) {
+        if let Some(ref cloud) = m.instance.cloud {
+            if let Err(err) = cloud.terminate(&m.instance.conn.host).await {
+                warn!(?err, id = ?m.id, "could not terminate pool machine");
+                left.push(m);
+                continue;
+            }
+
+            if let Err(err) = std::fs::remove_file(key_path(path, &m.id)) {
+                warn!(?err, id = ?m.id, "could not remove ssh key");
+            }
+        }
+
+        info!(id = ?m.id, "removed from pool");
+    }
+
+    if left.is_empty() {
+        std::fs::remove_file(path)?;
+        return Ok(());
+    }
+
+    let n = left.len();
+    pool.machines = left;
+    pool.save(path)?;
+    bail!("could not terminate {} pool machines", n)
+}
@@ -0,0 +1,94 @@
+//! Local steps run after a node's results are collected.
+
+use color_eyre::eyre::{ensure, Report, WrapErr};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// A local command (e.g. a plotting script) to run on the output directory.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct PostProcess {
+    pub script: PathBuf,
+    /// Passed before the output directory.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl PostProcess {
+    pub async fn run(&self, out_dir: &Path) -> Result<(), Report> {
+        info!(script = ?self.script, ?out_dir, "post-processing");
+        let st = tokio::process::Command::new(&self.script)
+            .args(&self.args)
+            .arg(out_dir)
+            .status()
+            .await
+            .wrap_err_with(|| format!("run {:?}", self.script))?;
+        ensure!(
+            st.success(),
+            "post-processing script {:?} failed",
+            self.script
+        );
+        Ok(())
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadBackend {
+    /// Via `aws s3 sync`.
+    S3,
+    /// Via `az storage blob upload-batch`; `bucket` is the container, and the storage account
+    /// comes from the usual `az` configuration (e.g. `AZURE_STORAGE_ACCOUNT`).
+    Azure,
+    /// Via `gsutil rsync`.
+    Gcs,
+}
+
+/// Where to push the output directory after collection.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ResultsUpload {
+    pub backend: UploadBackend,
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+}
+
+impl ResultsUpload {
+    pub async fn upload(&self, out_dir: &Path) -> Result<(), Report> {
+        let prefix = self.prefix.trim_matches('/');
+        let mut cmd = match self.backend {
+            UploadBackend::S3 => {
+                let mut c = tokio::process::Command::new("aws");
+                c.args(["s3", "sync"])
+                    .arg(out_dir)
+                    .arg(format!("s3://{}/{}", self.bucket, prefix));
+                c
+            }
+            UploadBackend::Azure => {
+                let mut c = tokio::process::Command::new("az");
+                c.args(["storage", "blob", "upload-batch", "--overwrite", "--source"])
+                    .arg(out_dir)
+                    .args(["--destination", &self.bucket]);
+                if !prefix.is_empty() {
+                    c.args(["--destination-path", prefix]);
+                }
+                c
+            }
+            UploadBackend::Gcs => {
+                let mut c = tokio::process::Command::new("gsutil");
+                c.args(["-m", "rsync", "-r"])
+                    .arg(out_dir)
+                    .arg(format!("gs://{}/{}", self.bucket, prefix));
+                c
+            }
+        };
+
+        info!(backend = ?self.backend, bucket = ?self.bucket, ?prefix, "uploading results");
+        let st = cmd.status().await.wrap_err("run upload command")?;
+        ensure!(
+            st.success(),
+            "uploading results to {:?} failed",
+            self.backend
+        );
+        Ok(())
+    }
+}
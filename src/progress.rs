@@ -0,0 +1,254 @@
+//! Following the experiment script's `agenda` output as it runs, to tell which experiment it is
+//! on and how long each one took.
+//!
+//! The script's stdout is timestamped line by line on the machine (see `Exp::start`), so wall
+//! times don't depend on how often we poll. Each agenda task (`->`) is taken to be the start of an
+//! experiment, and the end of the one before it.
+
+use crate::events::Events;
+use std::collections::BTreeMap;
+use tracing::{debug, info, warn};
+
+/// Timestamped stdout lines, written on the machine as the script runs. The last line is only a
+/// timestamp, written once the script exits.
+pub const REMOTE_PROGRESS: &str = "exp.progress";
+
+/// Experiment wall times, in seconds, keyed by result file name where we could tell which one the
+/// task was, and by the task's text otherwise.
+pub type WallTimes = BTreeMap<String, f64>;
+
+#[derive(Debug)]
+pub struct Progress {
+    /// The result files the script was asked for.
+    expected: Vec<String>,
+    /// How much of the progress file we have read.
+    offset: usize,
+    /// The start of a line we haven't seen the end of yet.
+    partial: Vec<u8>,
+    current: Option<(String, f64)>,
+    done: usize,
+    walls: WallTimes,
+    events: Events,
+}
+
+enum Line<'a> {
+    Section(&'a str),
+    Task(&'a str),
+    Subtask(&'a str),
+    Failure(&'a str),
+    Other,
+}
+
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end with a letter.
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+fn parse_line(text: &str) -> Line<'_> {
+    let t = text.trim_start();
+    let rest = |p: &str| t[p.len()..].trim();
+    if t.starts_with("==>") {
+        Line::Section(rest("==>"))
+    } else if t.starts_with("-->") {
+        Line::Subtask(rest("-->"))
+    } else if t.starts_with("->") {
+        Line::Task(rest("->"))
+    } else if t.starts_with("!!") {
+        Line::Failure(rest("!!"))
+    } else {
+        Line::Other
+    }
+}
+
+impl Progress {
+    pub fn new(expected: &[String], events: Events) -> Self {
+        Self {
+            expected: expected.to_vec(),
+            offset: 0,
+            partial: vec![],
+            current: None,
+            done: 0,
+            walls: Default::default(),
+            events,
+        }
+    }
+
+    /// How many bytes of the progress file have been fed in so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The experiment the script is on.
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_ref().map(|(e, _)| e.as_str())
+    }
+
+    /// Feed in the next chunk of the progress file.
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.offset += chunk.len();
+        self.partial.extend_from_slice(chunk);
+        while let Some(i) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=i).collect();
+            let line = String::from_utf8_lossy(&line[..i]).into_owned();
+            let mut parts = line.splitn(2, ' ');
+            match parts.next().and_then(|ts| ts.parse().ok()) {
+                Some(ts) => self.line(ts, parts.next().unwrap_or("")),
+                None => debug!(?line, "malformed progress line"),
+            }
+        }
+    }
+
+    /// Which experiment a task is, going by whether the task mentions its name.
+    fn experiment(&self, task: &str) -> String {
+        self.expected
+            .iter()
+            .filter(|f| {
+                let name = f.trim_end_matches(".data").trim_start_matches("exp-");
+                task.contains(name)
+            })
+            .max_by_key(|f| f.len())
+            .cloned()
+            .unwrap_or_else(|| task.to_owned())
+    }
+
+    fn finish_current(&mut self, ts: f64) {
+        if let Some((exp, start)) = self.current.take() {
+            let wall = ts - start;
+            info!(?exp, ?wall, "experiment finished");
+            self.done += 1;
+            self.events.emit(
+                "exp_done",
+                serde_json::json!({
+                    "exp": exp,
+                    "done": self.done,
+                    "total": self.expected.len(),
+                    "wall_secs": wall,
+                }),
+            );
+            self.walls.insert(exp, wall);
+        }
+    }
+
+    fn line(&mut self, ts: f64, text: &str) {
+        if text.is_empty() {
+            // the script exited.
+            self.finish_current(ts);
+            return;
+        }
+
+        match parse_line(&strip_ansi(text)) {
+            Line::Section(s) => debug!(section = ?s, "script progress"),
+            Line::Subtask(s) => debug!(subtask = ?s, "script progress"),
+            Line::Task(t) => {
+                self.finish_current(ts);
+                let exp = self.experiment(t);
+                info!(
+                    ?exp,
+                    done = ?self.done,
+                    total = ?self.expected.len(),
+                    "experiment started"
+                );
+                self.current = Some((exp, ts));
+            }
+            Line::Failure(f) => {
+                let exp = self.current.as_ref().map(|(e, _)| e.as_str());
+                warn!(?exp, msg = ?f, "script reported failure");
+            }
+            Line::Other => (),
+        }
+    }
+
+    pub fn into_wall_times(self) -> WallTimes {
+        self.walls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agenda_lines() {
+        assert!(matches!(parse_line("==> sweep"), Line::Section("sweep")));
+        assert!(matches!(
+            parse_line("  -> run aws-be"),
+            Line::Task("run aws-be")
+        ));
+        assert!(matches!(
+            parse_line("--> start redis"),
+            Line::Subtask("start redis")
+        ));
+        assert!(matches!(
+            parse_line("!! bench crashed"),
+            Line::Failure("bench crashed")
+        ));
+        assert!(matches!(parse_line("->"), Line::Task("")));
+        assert!(matches!(parse_line("plain output"), Line::Other));
+        assert!(matches!(parse_line(""), Line::Other));
+        assert_eq!(strip_ansi("\x1b[1;32m-> run\x1b[0m"), "-> run");
+    }
+
+    fn progress() -> Progress {
+        let expected = [
+            "exp-aws-be-75ms-1rcvrs-1batch-loop-client.data".to_owned(),
+            "exp-aws-be-75ms-10rcvrs-1batch-loop-client.data".to_owned(),
+        ];
+        Progress::new(&expected, Default::default())
+    }
+
+    #[test]
+    fn wall_times() {
+        let mut p = progress();
+        let first: &[u8] = b"1.0 ==> sweep\n2.0 -> aws-be-75ms-1rcvrs-1batch-loop-client\n";
+        p.feed(first);
+        assert_eq!(
+            p.current(),
+            Some("exp-aws-be-75ms-1rcvrs-1batch-loop-client.data")
+        );
+        let second: &[u8] =
+            b"3.5 -> aws-be-75ms-10rcvrs-1batch-loop-client\n4.0 --> sub\n7.5 other\n8.5\n";
+        p.feed(second);
+        assert_eq!(p.current(), None);
+        assert_eq!(p.offset(), first.len() + second.len());
+        let walls = p.into_wall_times();
+        assert_eq!(walls["exp-aws-be-75ms-1rcvrs-1batch-loop-client.data"], 1.5);
+        assert_eq!(
+            walls["exp-aws-be-75ms-10rcvrs-1batch-loop-client.data"],
+            5.0
+        );
+    }
+
+    #[test]
+    fn partial_lines() {
+        let mut p = progress();
+        p.feed(b"2.0 -> some");
+        assert_eq!(p.current(), None);
+        p.feed(b"thing else\n3.");
+        assert_eq!(p.current(), Some("something else"));
+        p.feed(b"0\n");
+        assert_eq!(p.into_wall_times()["something else"], 1.0);
+    }
+
+    #[test]
+    fn garbage_lines() {
+        let mut p = progress();
+        p.feed(b"not a timestamp -> aws\n\n\xff\xfe -> x\n-> aws\n");
+        assert_eq!(p.current(), None);
+        p.feed(b"1.0 \xff -> odd \xfe\n2.0\n");
+        assert_eq!(p.into_wall_times().len(), 0);
+    }
+}
@@ -0,0 +1,148 @@
+//! Which code produced a run's results, so a curve can be traced back to it months later: the
+//! bench binary's hash and build info, and the script's hash and git commit. Written to
+//! `build.json` in the output directory and to each node's `index.json`, and, with
+//! `--stamp-results`, put in result file names (`exp-<params>@<stamp>.data`).
+//!
+//! A node that runs to completion also leaves a hash of everything that decides its results (see
+//! [`HASH_FILE`]), so that running the same configuration again can be skipped.
+
+use color_eyre::eyre::{Report, WrapErr};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+pub struct Provenance {
+    pub bench_sha256: Option<String>,
+    /// `--bench-meta`, or else the first line of `<bench> --version`, if it runs here.
+    pub bench_meta: Option<String>,
+    pub script_sha256: Option<String>,
+    /// The commit of the repository the script is in, if it is in one.
+    pub script_git_sha: Option<String>,
+    /// Whether the script differs from that commit.
+    pub script_dirty: Option<bool>,
+}
+
+/// The command's trimmed stdout, if it succeeds.
+fn output(cmd: &mut Command) -> Option<String> {
+    match cmd.output() {
+        Ok(o) if o.status.success() => Some(String::from_utf8_lossy(&o.stdout).trim().to_owned()),
+        Ok(_) | Err(_) => {
+            debug!(?cmd, "no output");
+            None
+        }
+    }
+}
+
+/// The hex SHA-256 of the file at `path`.
+pub fn sha256(path: &Path) -> Option<String> {
+    let out = output(Command::new("sha256sum").arg(path))
+        .or_else(|| output(Command::new("shasum").args(["-a", "256"]).arg(path)))?;
+    out.split_whitespace().next().map(str::to_owned)
+}
+
+/// The hex SHA-256 of `data`.
+pub fn sha256_of(data: &[u8]) -> Option<String> {
+    let hash = |cmd: &mut Command| -> Option<String> {
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        child.stdin.take()?.write_all(data).ok()?;
+        let out = child.wait_with_output().ok()?;
+        let out = String::from_utf8_lossy(&out.stdout);
+        out.split_whitespace().next().map(str::to_owned)
+    };
+    hash(&mut Command::new("sha256sum"))
+        .or_else(|| hash(Command::new("shasum").args(["-a", "256"])))
+}
+
+/// Left in a node's output directory when it completes, holding the hash of its configuration:
+/// the node spec, the sweep it ran, and the hashes of the bench binaries and script.
+pub const HASH_FILE: &str = "config.sha256";
+
+/// The output directory of a completed run under `root` (at most three levels down, as in
+/// `<root>/<name>/<node>`) whose configuration hashed to `hash`.
+pub fn find_run(root: &Path, hash: &str) -> Option<PathBuf> {
+    fn walk(dir: &Path, hash: &str, depth: usize) -> Option<PathBuf> {
+        if std::fs::read_to_string(dir.join(HASH_FILE)).is_ok_and(|h| h.trim() == hash) {
+            return Some(dir.to_path_buf());
+        }
+
+        if depth == 0 {
+            return None;
+        }
+
+        std::fs::read_dir(dir)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .find_map(|e| walk(&e.path(), hash, depth - 1))
+    }
+
+    walk(root, hash, 3)
+}
+
+impl Provenance {
+    pub fn collect(bench: &Path, script: &Path, bench_meta: Option<String>) -> Self {
+        let bench_meta = bench_meta.or_else(|| {
+            // a binary for another architecture just fails to run.
+            output(Command::new("timeout").arg("5").arg(bench).arg("--version"))
+                .and_then(|v| v.lines().next().map(str::to_owned))
+                .filter(|v| !v.is_empty())
+        });
+        let dir = script
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let git = |args: &[&str]| output(Command::new("git").arg("-C").arg(dir).args(args));
+        let script_git_sha = git(&["rev-parse", "HEAD"]);
+        let script_dirty = script_git_sha.as_ref().and(
+            script
+                .file_name()
+                .and_then(|f| git(&["status", "--porcelain", "--", &f.to_string_lossy()]))
+                .map(|s| !s.is_empty()),
+        );
+        let p = Self {
+            bench_sha256: sha256(bench),
+            bench_meta,
+            script_sha256: sha256(script),
+            script_git_sha,
+            script_dirty,
+        };
+        info!(stamp = %p.stamp(), bench_meta = ?p.bench_meta, dirty = ?p.script_dirty, "build info");
+        p
+    }
+
+    /// A short tag for result file names: the script's commit (or, if it isn't committed, its
+    /// hash), and the bench binary's hash.
+    pub fn stamp(&self) -> String {
+        let short = |h: &Option<String>| {
+            h.as_deref()
+                .map_or("unknown", |h| &h[..h.len().min(8)])
+                .to_owned()
+        };
+        let script = match self.script_git_sha {
+            Some(_) if self.script_dirty != Some(true) => short(&self.script_git_sha),
+            _ => short(&self.script_sha256),
+        };
+        format!("{}.{}", script, short(&self.bench_sha256))
+    }
+
+    pub fn write(&self, out_dir: &Path) -> Result<(), Report> {
+        std::fs::create_dir_all(out_dir)?;
+        let f = std::fs::File::create(out_dir.join("build.json")).wrap_err("create build.json")?;
+        serde_json::to_writer_pretty(f, self).wrap_err("write build.json")
+    }
+}
+
+/// `fname` with `stamp` in it: `exp-x.data` is `exp-x@<stamp>.data`.
+pub fn stamped(fname: &str, stamp: &str) -> String {
+    match fname.strip_suffix(".data") {
+        Some(stem) => format!("{}@{}.data", stem, stamp),
+        None => format!("{}@{}", fname, stamp),
+    }
+}
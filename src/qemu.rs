@@ -0,0 +1,219 @@
+//! Running a node in a local qemu VM, booted from a cloud image.
+//!
+//! The VM gets a throwaway copy-on-write overlay of the image, and is configured by cloud-init
+//! from a seed disk (made with `cloud-localds`) that authorizes a fresh key. Its ssh port is
+//! forwarded to localhost, and from there it is driven like any other machine. Uses KVM if
+//! `/dev/kvm` is available.
+
+use crate::ssh::{generate_key, skip_host_key_check, ConnInfo};
+use color_eyre::eyre::{bail, ensure, Report, WrapErr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::process::{Child, Command};
+use tracing::{debug, info, instrument};
+
+/// The VM to boot.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct VmCfg {
+    /// A qcow2 cloud image, e.g. Ubuntu's `focal-server-cloudimg-amd64.img`.
+    pub image: PathBuf,
+    #[serde(default = "default_vcpus")]
+    pub vcpus: usize,
+    #[serde(default = "default_memory_mb")]
+    pub memory_mb: usize,
+    /// The size of the VM's disk.
+    #[serde(default = "default_disk_gb")]
+    pub disk_gb: usize,
+    /// The image's default user, which cloud-init authorizes our key for.
+    #[serde(default = "default_user")]
+    pub user: String,
+    #[serde(default = "default_boot_timeout_secs")]
+    pub boot_timeout_secs: u64,
+}
+
+fn default_vcpus() -> usize {
+    2
+}
+
+fn default_memory_mb() -> usize {
+    4096
+}
+
+fn default_disk_gb() -> usize {
+    20
+}
+
+fn default_user() -> String {
+    "ubuntu".to_owned()
+}
+
+fn default_boot_timeout_secs() -> u64 {
+    300
+}
+
+/// A booted VM.
+#[derive(Debug)]
+pub struct Vm {
+    dir: PathBuf,
+    qemu: Option<Child>,
+    pub key: PathBuf,
+    pub port: u16,
+}
+
+async fn run(cmd: &mut Command, what: &str) -> Result<(), Report> {
+    let out = cmd.output().await.wrap_err_with(|| what.to_owned())?;
+    ensure!(
+        out.status.success(),
+        "{} failed: {}",
+        what,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    Ok(())
+}
+
+fn free_port() -> Result<u16, Report> {
+    let l = std::net::TcpListener::bind("127.0.0.1:0")?;
+    Ok(l.local_addr()?.port())
+}
+
+impl Vm {
+    /// Boot a VM for `machine_name`, and wait until we can ssh to it.
+    #[instrument(skip(cfg), level = "debug")]
+    pub async fn launch(cfg: &VmCfg, machine_name: &str) -> Result<Self, Report> {
+        let image = cfg
+            .image
+            .canonicalize()
+            .wrap_err_with(|| format!("vm image {:?}", cfg.image))?;
+        let dir = std::env::temp_dir().join(format!(
+            "burrito-cloud-exp-qemu-{}-{}",
+            std::process::id(),
+            machine_name
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let mut vm = Vm {
+            key: dir.join("id_ed25519"),
+            dir,
+            qemu: None,
+            port: free_port()?,
+        };
+
+        if let Err(err) = vm.boot(cfg, &image, machine_name).await {
+            vm.destroy().await?;
+            return Err(err);
+        }
+
+        Ok(vm)
+    }
+
+    async fn boot(&mut self, cfg: &VmCfg, image: &Path, machine_name: &str) -> Result<(), Report> {
+        let pubkey = generate_key(&self.key).await?;
+        let disk = self.dir.join("disk.qcow2");
+        run(
+            Command::new("qemu-img")
+                .args(["create", "-q", "-f", "qcow2", "-F", "qcow2", "-b"])
+                .arg(image)
+                .arg(&disk)
+                .arg(format!("{}G", cfg.disk_gb)),
+            "create vm disk",
+        )
+        .await?;
+
+        let user_data = self.dir.join("user-data");
+        std::fs::write(
+            &user_data,
+            format!(
+                "#cloud-config\nusers:\n  - name: {}\n    sudo: ALL=(ALL) NOPASSWD:ALL\n    shell: /bin/bash\n    ssh_authorized_keys:\n      - {}\n",
+                cfg.user, pubkey
+            ),
+        )?;
+        let meta_data = self.dir.join("meta-data");
+        std::fs::write(
+            &meta_data,
+            format!(
+                "instance-id: {}\nlocal-hostname: {}\n",
+                machine_name, machine_name
+            ),
+        )?;
+        let seed = self.dir.join("seed.img");
+        run(
+            Command::new("cloud-localds")
+                .arg(&seed)
+                .arg(&user_data)
+                .arg(&meta_data),
+            "create cloud-init seed",
+        )
+        .await?;
+
+        let kvm = Path::new("/dev/kvm").exists();
+        let mut qemu = Command::new("qemu-system-x86_64");
+        if kvm {
+            qemu.args(["-enable-kvm", "-cpu", "host"]);
+        }
+        qemu.arg("-smp")
+            .arg(cfg.vcpus.to_string())
+            .arg("-m")
+            .arg(cfg.memory_mb.to_string())
+            .args(["-display", "none"])
+            .arg("-serial")
+            .arg(format!("file:{}", self.dir.join("console.log").display()))
+            .arg("-drive")
+            .arg(format!("file={},if=virtio,format=qcow2", disk.display()))
+            .arg("-drive")
+            .arg(format!("file={},if=virtio,format=raw", seed.display()))
+            .args(["-device", "virtio-net-pci,netdev=n0", "-netdev"])
+            .arg(format!(
+                "user,id=n0,hostfwd=tcp:127.0.0.1:{}-:22",
+                self.port
+            ))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .kill_on_drop(true);
+        info!(port = ?self.port, ?kvm, vcpus = ?cfg.vcpus, memory_mb = ?cfg.memory_mb, "booting vm");
+        self.qemu = Some(qemu.spawn().wrap_err("start qemu")?);
+        skip_host_key_check("127.0.0.1")?;
+
+        let conn = ConnInfo {
+            host: "127.0.0.1".to_owned(),
+            user: cfg.user.clone(),
+            key_path: Some(self.key.clone()),
+            port: self.port,
+            keepalive: None,
+        };
+        let timeout = Duration::from_secs(cfg.boot_timeout_secs);
+        let start = Instant::now();
+        loop {
+            if let Some(st) = self.qemu.as_mut().unwrap().try_wait()? {
+                bail!("qemu exited with {}", st);
+            }
+
+            match conn.connect(Some(Duration::from_secs(10))).await {
+                Ok(_) => {
+                    info!(elapsed = ?start.elapsed(), "vm is up");
+                    return Ok(());
+                }
+                Err(err) if start.elapsed() > timeout => {
+                    return Err(err.wrap_err(format!(
+                        "vm did not come up within {:?}, see {:?}",
+                        timeout,
+                        self.dir.join("console.log")
+                    )));
+                }
+                Err(err) => debug!(?err, "vm not reachable yet"),
+            }
+
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    /// Kill the VM, and remove its disk.
+    pub async fn destroy(mut self) -> Result<(), Report> {
+        if let Some(mut q) = self.qemu.take() {
+            let _ = q.kill().await;
+        }
+
+        info!(dir = ?self.dir, "destroyed vm");
+        std::fs::remove_dir_all(&self.dir).wrap_err("remove vm directory")?;
+        Ok(())
+    }
+}
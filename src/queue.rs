@@ -0,0 +1,59 @@
+//! A queue of node configs to run one after another (or a few at a time), each into its own run
+//! directory, named after the config file: `<out-dir>/[<name>/]<config stem>`.
+//!
+//! The queue is a directory, whose `*.json` files run in name order, or a file listing config
+//! paths, one per line, relative to the queue file:
+//!
+//! ```text
+//! # baseline first
+//! aws.json
+//! sweeps/azure-big.json
+//! ```
+
+use color_eyre::eyre::{ensure, Report, WrapErr};
+use std::path::{Path, PathBuf};
+
+/// The config files of the queue at `path`, and the run directory name for each.
+pub fn load(path: &Path) -> Result<Vec<(PathBuf, String)>, Report> {
+    let cfgs: Vec<PathBuf> = if path.is_dir() {
+        let mut cfgs = vec![];
+        for e in std::fs::read_dir(path).wrap_err_with(|| format!("read queue {:?}", path))? {
+            let p = e?.path();
+            if p.is_file() && p.extension().is_some_and(|x| x == "json") {
+                cfgs.push(p);
+            }
+        }
+
+        cfgs.sort();
+        cfgs
+    } else {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("read queue {:?}", path))?
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| dir.join(l))
+            .collect()
+    };
+    ensure!(!cfgs.is_empty(), "queue {:?} has no configs", path);
+
+    let mut runs: Vec<(PathBuf, String)> = vec![];
+    for cfg in cfgs {
+        ensure!(cfg.is_file(), "queued config {:?} not found", cfg);
+        let base = cfg
+            .file_stem()
+            .map_or("cfg".into(), |s| s.to_string_lossy())
+            .into_owned();
+        let mut name = base.clone();
+        let mut i = 1;
+        while runs.iter().any(|(_, n)| *n == name) {
+            i += 1;
+            name = format!("{}-{}", base, i);
+        }
+
+        runs.push((cfg, name));
+    }
+
+    Ok(runs)
+}
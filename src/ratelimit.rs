@@ -0,0 +1,71 @@
+//! Rate limiting of cloud API operations, shared by every node talking to the same provider, so
+//! that launching many nodes at once doesn't get us throttled.
+
+use crate::tags::Cloud;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// A token bucket: up to `burst` operations at once, refilling at `per_sec`.
+#[derive(Debug)]
+struct Bucket {
+    burst: f64,
+    per_sec: f64,
+    // tokens available as of the instant.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(burst: f64, per_sec: f64) -> Self {
+        Self {
+            burst,
+            per_sec,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut s = self.state.lock().unwrap();
+                let now = Instant::now();
+                let tokens =
+                    (s.0 + now.duration_since(s.1).as_secs_f64() * self.per_sec).min(self.burst);
+                if tokens >= 1. {
+                    *s = (tokens - 1., now);
+                    return;
+                }
+
+                *s = (tokens, now);
+                Duration::from_secs_f64((1. - tokens) / self.per_sec)
+            };
+
+            debug!(?wait, "rate limiting cloud api call");
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+static BUCKETS: Mutex<BTreeMap<String, Arc<Bucket>>> = Mutex::new(BTreeMap::new());
+
+/// Wait until we may make another API operation (e.g. a launch, tagging, or termination) against
+/// `cloud`.
+pub async fn acquire(cloud: &Cloud) {
+    // EC2 and OCI limit requests per account and region, the others per account (subscription).
+    let (key, burst, per_sec) = match cloud {
+        Cloud::Aws { region, .. } => (format!("aws/{}", region), 5., 2.),
+        Cloud::Azure => ("azure".to_owned(), 3., 1.),
+        Cloud::Linode => ("linode".to_owned(), 5., 1.),
+        Cloud::Vultr => ("vultr".to_owned(), 3., 2.),
+        Cloud::Oci { region, .. } => (format!("oci/{}", region), 5., 2.),
+        Cloud::OpenStack { auth_url } => (format!("openstack/{}", auth_url), 3., 1.),
+    };
+    let bucket = BUCKETS
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| Arc::new(Bucket::new(burst, per_sec)))
+        .clone();
+    bucket.acquire().await
+}
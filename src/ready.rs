@@ -0,0 +1,180 @@
+//! Readiness probes: waiting, before each repetition's script starts, until the services it
+//! talks to (redis, the burrito discovery service, ...) are up, rather than letting the first
+//! experiments fail against a service that isn't listening yet.
+//!
+//! Probes run on the node's machine, in order, each retried until it passes or the timeout (for
+//! all of them together) runs out. What each took is written to a `.ready.log` next to the
+//! script's log.
+
+use crate::service::Endpoint;
+use color_eyre::eyre::{bail, Report, WrapErr};
+use openssh::Session;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct Readiness {
+    pub probes: Vec<Probe>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_timeout_secs() -> u64 {
+    120
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Probe {
+    /// `host:port` accepts connections. `host` may be the name of a service node.
+    Tcp(String),
+    /// `cmd` exits successfully, with `expect` in its output if given. Run in the directory the
+    /// script runs in.
+    Command {
+        cmd: String,
+        #[serde(default)]
+        expect: Option<String>,
+    },
+}
+
+impl std::fmt::Display for Probe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Probe::Tcp(addr) => write!(f, "tcp {}", addr),
+            Probe::Command { cmd, .. } => write!(f, "command {:?}", cmd),
+        }
+    }
+}
+
+impl Readiness {
+    /// Swap service nodes' names in tcp probes for their hosts.
+    pub fn resolve(&self, services: &BTreeMap<String, Endpoint>) -> Self {
+        let probes = self
+            .probes
+            .iter()
+            .map(|p| match p {
+                Probe::Tcp(addr) => match addr.rsplit_once(':') {
+                    Some((h, port)) if services.contains_key(h) => {
+                        Probe::Tcp(format!("{}:{}", services[h].host, port))
+                    }
+                    _ => p.clone(),
+                },
+                p => p.clone(),
+            })
+            .collect();
+        Self {
+            probes,
+            timeout_secs: self.timeout_secs,
+        }
+    }
+
+    /// Wait until every probe passes, writing how it went to `log`.
+    pub async fn wait(&self, ssh: &Session, dir: Option<&str>, log: &Path) -> Result<(), Report> {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(self.timeout_secs);
+        let mut lines = vec![];
+        let mut res = Ok(());
+        for p in &self.probes {
+            let mut attempts = 0;
+            loop {
+                attempts += 1;
+                let why = match p.check(ssh, dir).await {
+                    Ok(()) => {
+                        info!(probe = %p, ?attempts, "ready");
+                        lines.push(format!(
+                            "{:.1}s {}: ready after {} attempts",
+                            start.elapsed().as_secs_f64(),
+                            p,
+                            attempts
+                        ));
+                        break;
+                    }
+                    Err(why) => why,
+                };
+                if start.elapsed() >= timeout {
+                    warn!(probe = %p, ?attempts, %why, "not ready");
+                    lines.push(format!(
+                        "{:.1}s {}: not ready after {} attempts: {}",
+                        start.elapsed().as_secs_f64(),
+                        p,
+                        attempts,
+                        why
+                    ));
+                    res = Err(why);
+                    break;
+                }
+
+                tokio::time::sleep(PROBE_INTERVAL).await;
+            }
+
+            if res.is_err() {
+                break;
+            }
+        }
+
+        lines.push(String::new());
+        tokio::fs::write(log, lines.join("\n"))
+            .await
+            .wrap_err_with(|| format!("write {:?}", log))?;
+        if let Err(why) = res {
+            bail!(
+                "not ready after {}s ({}): {}",
+                self.timeout_secs,
+                log.display(),
+                why
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Probe {
+    /// `Err` says why the probe didn't pass.
+    async fn check(&self, ssh: &Session, dir: Option<&str>) -> Result<(), String> {
+        let out = match self {
+            Probe::Tcp(addr) => {
+                let (host, port) = addr
+                    .rsplit_once(':')
+                    .ok_or_else(|| format!("{:?} is not host:port", addr))?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                ssh.command("timeout")
+                    .args(["5", "bash", "-c"])
+                    .arg(format!("exec 3<>/dev/tcp/{}/{}", host, port))
+                    .output()
+                    .await
+            }
+            Probe::Command { cmd, .. } => {
+                let cmd = match dir {
+                    Some(d) => format!("cd {} && {}", d, cmd),
+                    None => cmd.clone(),
+                };
+                ssh.shell(cmd).output().await
+            }
+        };
+        let out = out.map_err(|e| e.to_string())?;
+        if !out.status.success() {
+            return Err(format!(
+                "exited with {:?}: {}",
+                out.status.code(),
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+
+        if let Probe::Command {
+            expect: Some(want), ..
+        } = self
+        {
+            let got = String::from_utf8_lossy(&out.stdout);
+            if !got.contains(want.as_str()) {
+                return Err(format!("output {:?} lacks {:?}", got.trim(), want));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! The experiment's redis, configured from the node rather than left at the package's defaults.
+//!
+//! Each repetition writes `redis.conf` to the working directory, and redis logs to `redis.log`
+//! there, which is collected with the results. Either we start redis with it before the script
+//! (and shut it down once the repetition is done), or the script does: it finds the file in
+//! [`CONF_ENV`] and the port in [`PORT_ENV`] either way.
+
+use color_eyre::eyre::{ensure, Report, WrapErr};
+use openssh::Session;
+use tracing::{debug, info};
+
+/// The config file's path, for the script.
+pub const CONF_ENV: &str = "BURRITO_EXP_REDIS_CONF";
+/// The port redis listens on.
+pub const PORT_ENV: &str = "BURRITO_EXP_REDIS_PORT";
+
+pub const REMOTE_CONF: &str = "redis.conf";
+pub const REMOTE_LOG: &str = "redis.log";
+
+/// Who starts redis.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Starter {
+    /// We do, before each repetition's script.
+    #[default]
+    Us,
+    /// The script does, with the config file we wrote.
+    Script,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct RedisCfg {
+    /// e.g. `2gb`. Unlimited if not given.
+    #[serde(default)]
+    pub maxmemory: Option<String>,
+    #[serde(default)]
+    pub appendonly: bool,
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub started_by: Starter,
+}
+
+fn default_bind() -> String {
+    "127.0.0.1".to_owned()
+}
+
+fn default_port() -> u16 {
+    6379
+}
+
+impl RedisCfg {
+    pub fn check(&self) -> Result<(), Report> {
+        ensure!(
+            !self.bind.is_empty() && !self.bind.contains(char::is_control),
+            "redis bind address {:?} is not an address",
+            self.bind
+        );
+        if let Some(ref m) = self.maxmemory {
+            ensure!(
+                m.chars().all(|c| c.is_ascii_alphanumeric()),
+                "redis maxmemory {:?} should be like 2gb",
+                m
+            );
+        }
+        Ok(())
+    }
+
+    /// The environment the script gets, for the working directory `wd`.
+    pub fn env(&self, wd: &str) -> Vec<(String, String)> {
+        vec![
+            (CONF_ENV.to_owned(), format!("{}/{}", wd, REMOTE_CONF)),
+            (PORT_ENV.to_owned(), self.port.to_string()),
+        ]
+    }
+
+    /// `redis.conf`, keeping its data and log in `wd` (which the shell writing it expands).
+    fn conf(&self, wd: &str) -> String {
+        let mut conf = format!(
+            "bind {}\nport {}\nappendonly {}\ndir {wd}\nlogfile {wd}/{}\n",
+            self.bind,
+            self.port,
+            if self.appendonly { "yes" } else { "no" },
+            REMOTE_LOG,
+            wd = wd,
+        );
+        if !self.bind.starts_with("127.") {
+            conf.push_str("protected-mode no\n");
+        }
+        if let Some(ref m) = self.maxmemory {
+            conf.push_str(&format!("maxmemory {}\n", m));
+        }
+        if self.started_by == Starter::Us {
+            conf.push_str("daemonize yes\n");
+        }
+        conf
+    }
+
+    /// Write the config into `wd`, emptying the last repetition's log, and start redis with it
+    /// unless the script will.
+    pub async fn start(&self, ssh: &Session, wd: &str) -> Result<(), Report> {
+        let mut cmd = format!(
+            "cd {wd} && rm -f {log} && cat > {conf} <<EOF\n{}EOF\n",
+            self.conf(wd),
+            wd = wd,
+            log = REMOTE_LOG,
+            conf = REMOTE_CONF,
+        );
+        if self.started_by == Starter::Us {
+            // an earlier repetition's redis may still hold the port.
+            cmd.push_str(&format!(
+                "redis-cli -p {port} shutdown nosave >/dev/null 2>&1; \
+                redis-server {wd}/{conf} && \
+                for i in $(seq 50); do redis-cli -p {port} ping >/dev/null 2>&1 && exit 0; sleep 0.2; done; exit 1",
+                port = self.port,
+                wd = wd,
+                conf = REMOTE_CONF,
+            ));
+        }
+
+        let out = ssh.shell(cmd).output().await.wrap_err("start redis")?;
+        ensure!(
+            out.status.success(),
+            "could not start redis: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+        match self.started_by {
+            Starter::Us => info!(port = self.port, "started redis"),
+            Starter::Script => debug!("wrote redis config for the script"),
+        }
+        Ok(())
+    }
+
+    /// Shut down the redis we started.
+    pub async fn stop(&self, ssh: &Session) -> Result<(), Report> {
+        if self.started_by != Starter::Us {
+            return Ok(());
+        }
+
+        let st = ssh
+            .shell(format!("redis-cli -p {} shutdown nosave", self.port))
+            .status()
+            .await
+            .wrap_err("stop redis")?;
+        ensure!(st.success(), "could not shut down redis");
+        debug!("stopped redis");
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+//! Retry policies with exponential backoff.
+
+use std::time::Duration;
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Give up after this many attempts.
+    pub max_attempts: usize,
+    /// Backoff before the second attempt; doubles after each subsequent failure.
+    pub initial_backoff_ms: u64,
+    /// Upper bound on the backoff between attempts.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff_ms: 1_000,
+            max_backoff_ms: 60_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after failed attempt number `attempt` (starting at 1).
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exp = attempt.saturating_sub(1).min(32) as u32;
+        let ms = self
+            .initial_backoff_ms
+            .saturating_mul(2u64.saturating_pow(exp))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(ms)
+    }
+}
+
+/// Why an attempt failed, going by the error message: provider errors come from several SDKs
+/// and CLIs, and this is all they have in common.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    Throttled,
+    Capacity,
+    Network,
+    /// Bad credentials or configuration, or anything we don't recognize: retrying won't help.
+    Fatal,
+}
+
+const FATAL: &[&str] = &[
+    "authfailure",
+    "unauthorizedoperation",
+    "invalidclienttokenid",
+    "signaturedoesnotmatch",
+    "authorizationfailed",
+    "invalidauthenticationtoken",
+    "credentials",
+    "invalidami",
+    "invalid ami",
+    "invalidparameter",
+    "optinrequired",
+];
+
+const THROTTLED: &[&str] = &[
+    "throttl",
+    "rate exceeded",
+    "requestlimitexceeded",
+    "too many requests",
+    "toomanyrequests",
+];
+
+const CAPACITY: &[&str] = &[
+    "insufficientinstancecapacity",
+    "insufficient capacity",
+    "capacity-not-available",
+    "maxspotinstancecountexceeded",
+    "allocationfailed",
+    "skunotavailable",
+    "overconstrainedallocationrequest",
+    "out of host capacity",
+];
+
+const NETWORK: &[&str] = &[
+    "timed out",
+    "timeout",
+    "connection reset",
+    "connection refused",
+    "broken pipe",
+    "dns error",
+    "name resolution",
+    "could not resolve host",
+    "service unavailable",
+    "serviceunavailable",
+    "internalerror",
+    "internal server error",
+    "bad gateway",
+];
+
+impl ErrorClass {
+    pub fn of(err: &color_eyre::Report) -> Self {
+        let msg = format!("{:#}", err).to_lowercase();
+        let any = |pats: &[&str]| pats.iter().any(|p| msg.contains(p));
+        if any(FATAL) {
+            ErrorClass::Fatal
+        } else if any(THROTTLED) {
+            ErrorClass::Throttled
+        } else if any(CAPACITY) {
+            ErrorClass::Capacity
+        } else if any(NETWORK) {
+            ErrorClass::Network
+        } else {
+            ErrorClass::Fatal
+        }
+    }
+
+    pub fn is_transient(self) -> bool {
+        self != ErrorClass::Fatal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::eyre;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let p = RetryPolicy::default();
+        let ms: Vec<u128> = (1..=9).map(|a| p.backoff(a).as_millis()).collect();
+        assert_eq!(
+            ms,
+            [1_000, 2_000, 4_000, 8_000, 16_000, 32_000, 60_000, 60_000, 60_000]
+        );
+        assert_eq!(p.backoff(0), Duration::from_secs(1));
+        assert_eq!(p.backoff(usize::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn backoff_does_not_overflow() {
+        let p = RetryPolicy {
+            max_attempts: 100,
+            initial_backoff_ms: u64::MAX / 2,
+            max_backoff_ms: u64::MAX,
+        };
+        assert_eq!(p.backoff(50), Duration::from_millis(u64::MAX));
+    }
+
+    #[test]
+    fn error_classes() {
+        let class = |msg: &str| ErrorClass::of(&eyre!("{}", msg));
+        assert_eq!(class("Throttling: Rate exceeded"), ErrorClass::Throttled);
+        assert_eq!(class("InsufficientInstanceCapacity"), ErrorClass::Capacity);
+        assert_eq!(class("connection reset by peer"), ErrorClass::Network);
+        assert_eq!(class("AuthFailure: timed out"), ErrorClass::Fatal);
+        assert_eq!(class("something else"), ErrorClass::Fatal);
+        assert!(!ErrorClass::Fatal.is_transient() && ErrorClass::Network.is_transient());
+        // the context counts too.
+        let err = eyre!("connection refused").wrap_err("launch instance");
+        assert_eq!(ErrorClass::of(&err), ErrorClass::Network);
+    }
+}
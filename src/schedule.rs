@@ -0,0 +1,236 @@
+//! Re-running the sweep on a cron schedule, for continuous benchmarking.
+
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use std::ops::RangeInclusive;
+
+/// A cron expression: `minute hour day-of-month month day-of-week`, in local time. Fields are
+/// `*`, numbers, ranges (`1-5`), steps (`*/15`, `0-30/10`), or lists of those. Day of week 0 (or 7)
+/// is Sunday. `@hourly`, `@daily`, and `@weekly` are also accepted.
+#[derive(Clone, Debug)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    // as in cron, if both days of the month and of the week are restricted, either matching is
+    // enough.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(f: &str, range: RangeInclusive<u32>) -> Result<Vec<bool>, Report> {
+    let mut set = vec![false; *range.end() as usize + 1];
+    for part in f.split(',') {
+        let (span, step) = match part.split_once('/') {
+            Some((s, step)) => (
+                s,
+                step.parse::<u32>()
+                    .wrap_err_with(|| format!("step {:?}", step))?,
+            ),
+            None => (part, 1),
+        };
+        ensure!(step > 0, "step in {:?} must be positive", part);
+        let (lo, hi) = if span == "*" {
+            (*range.start(), *range.end())
+        } else {
+            let num = |s: &str| {
+                s.parse::<u32>()
+                    .wrap_err_with(|| format!("{:?} is not a number", s))
+            };
+            match span.split_once('-') {
+                Some((a, b)) => (num(a)?, num(b)?),
+                None => {
+                    let n = num(span)?;
+                    (n, n)
+                }
+            }
+        };
+        ensure!(
+            range.contains(&lo) && range.contains(&hi) && lo <= hi,
+            "{:?} is outside {}-{}",
+            part,
+            range.start(),
+            range.end()
+        );
+        for v in (lo..=hi).step_by(step as usize) {
+            set[v as usize] = true;
+        }
+    }
+
+    Ok(set)
+}
+
+impl std::str::FromStr for Cron {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            s => s,
+        };
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            bail!(
+                "{:?} is not a cron expression (minute hour day-of-month month day-of-week)",
+                s
+            );
+        }
+
+        let mut weekdays = parse_field(fields[4], 0..=7)?;
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0..=59)?,
+            hours: parse_field(fields[1], 0..=23)?,
+            days: parse_field(fields[2], 1..=31)?,
+            months: parse_field(fields[3], 1..=12)?,
+            weekdays,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+}
+
+impl Cron {
+    fn day_matches(&self, d: NaiveDate) -> bool {
+        let day = self.days[d.day() as usize];
+        let weekday = self.weekdays[d.weekday().num_days_from_sunday() as usize];
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        }
+    }
+
+    /// The first matching time after `after`.
+    pub fn next_after(
+        &self,
+        after: chrono::DateTime<Local>,
+    ) -> Result<chrono::DateTime<Local>, Report> {
+        self.next_matching(after.naive_local(), local_time)
+    }
+
+    /// The first matching time after `after` that `to_tz` can place in its time zone.
+    fn next_matching<T>(
+        &self,
+        after: NaiveDateTime,
+        to_tz: impl Fn(NaiveDateTime) -> Option<T>,
+    ) -> Result<T, Report> {
+        let midnight = |d: NaiveDate| {
+            d.and_hms_opt(0, 0, 0)
+                .ok_or_else(|| eyre!("no midnight on {}", d))
+        };
+        let mut t = after.with_second(0).unwrap().with_nanosecond(0).unwrap()
+            + chrono::Duration::minutes(1);
+        // every combination comes around within a few years (e.g. the 29th of February).
+        let limit = t + chrono::Duration::days(5 * 366);
+        while t < limit {
+            if !self.months[t.month() as usize] {
+                let (y, m) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                let d = NaiveDate::from_ymd_opt(y, m, 1)
+                    .ok_or_else(|| eyre!("no date {}-{:02}-01", y, m))?;
+                t = midnight(d)?;
+            } else if !self.day_matches(t.date()) {
+                t = midnight(t.date() + chrono::Duration::days(1))?;
+            } else if !self.hours[t.hour() as usize] {
+                t = t.with_minute(0).unwrap() + chrono::Duration::hours(1);
+            } else if !self.minutes[t.minute() as usize] {
+                t += chrono::Duration::minutes(1);
+            } else if let Some(local) = to_tz(t) {
+                return Ok(local);
+            } else {
+                // skipped over by a daylight saving change.
+                t += chrono::Duration::minutes(1);
+            }
+        }
+
+        Err(eyre!("the schedule never matches"))
+    }
+}
+
+fn local_time(t: NaiveDateTime) -> Option<chrono::DateTime<Local>> {
+    Local.from_local_datetime(&t).earliest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn next(cron: &str, after: &str) -> String {
+        let c: Cron = cron.parse().unwrap();
+        c.next_matching(at(after), Some)
+            .unwrap()
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(next("* * * * *", "2024-03-01 10:00"), "2024-03-01 10:01");
+        assert_eq!(next("30 * * * *", "2024-03-01 10:30"), "2024-03-01 11:30");
+        assert_eq!(next("@daily", "2024-03-01 10:00"), "2024-03-02 00:00");
+    }
+
+    #[test]
+    fn ranges_steps_lists() {
+        assert_eq!(next("0 9-17 * * *", "2024-03-01 17:00"), "2024-03-02 09:00");
+        assert_eq!(next("*/15 * * * *", "2024-03-01 10:01"), "2024-03-01 10:15");
+        assert_eq!(
+            next("0-30/10 * * * *", "2024-03-01 10:30"),
+            "2024-03-01 11:00"
+        );
+        assert_eq!(next("5,50 * * * *", "2024-03-01 10:05"), "2024-03-01 10:50");
+        assert_eq!(next("0 0,12 * * *", "2024-03-01 12:00"), "2024-03-02 00:00");
+    }
+
+    #[test]
+    fn month_rollover() {
+        assert_eq!(next("0 0 1 * *", "2024-01-31 12:00"), "2024-02-01 00:00");
+        assert_eq!(next("0 0 * 1 *", "2024-12-31 23:59"), "2025-01-01 00:00");
+        assert_eq!(next("0 0 31 * *", "2024-04-01 00:00"), "2024-05-31 00:00");
+        assert_eq!(next("0 0 29 2 *", "2025-01-01 00:00"), "2028-02-29 00:00");
+    }
+
+    #[test]
+    fn day_of_week() {
+        // 2024-03-01 is a Friday.
+        assert_eq!(next("0 0 * * 1", "2024-03-01 00:00"), "2024-03-04 00:00");
+        assert_eq!(next("0 0 * * 7", "2024-03-01 00:00"), "2024-03-03 00:00");
+        assert_eq!(next("@weekly", "2024-03-01 00:00"), "2024-03-03 00:00");
+        // either the day of the month or of the week.
+        assert_eq!(next("0 0 15 * 1", "2024-03-01 00:00"), "2024-03-04 00:00");
+        assert_eq!(next("0 0 2 * 1", "2024-03-01 00:00"), "2024-03-02 00:00");
+    }
+
+    #[test]
+    fn bad_expressions() {
+        for c in [
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "0 0 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "x * * * *",
+        ] {
+            assert!(c.parse::<Cron>().is_err(), "{:?} parsed", c);
+        }
+        let c: Cron = "0 0 30 2 *".parse().unwrap();
+        assert!(c.next_matching(at("2024-01-01 00:00"), Some).is_err());
+    }
+}
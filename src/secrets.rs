@@ -0,0 +1,262 @@
+//! Credentials kept out of the node config and the shell environment: API tokens, webhook URLs,
+//! passwords.
+//!
+//! Secrets are named like the environment variables they stand in for (`LINODE_TOKEN`,
+//! `VULTR_API_KEY`, `AZURE_STORAGE_KEY`, a `--notify` command's webhook URL, ...). At startup
+//! they are put in our environment, so everything we run sees them as it would have before;
+//! variables that are already set win.
+//!
+//! They are stored as one JSON object, either in a file encrypted with `age` to the identity in
+//! `BURRITO_EXP_AGE_IDENTITY` (default `~/.config/burrito-exp/age-key.txt`, made with
+//! `age-keygen -o`), or in the OS keyring, through `secret-tool` (Linux) or `security` (macOS).
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+const KEYRING_SERVICE: &str = "burrito-exp";
+const KEYRING_ACCOUNT: &str = "secrets";
+
+/// Where secrets are kept.
+#[derive(Clone, Debug)]
+pub enum Store {
+    /// A file encrypted with `age`.
+    Age(PathBuf),
+    Keyring,
+}
+
+impl std::str::FromStr for Store {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "keyring" => Store::Keyring,
+            p => Store::Age(PathBuf::from(p)),
+        })
+    }
+}
+
+fn config_dir() -> Result<PathBuf, Report> {
+    let home = std::env::var_os("HOME").ok_or_else(|| eyre!("HOME not set"))?;
+    Ok(PathBuf::from(home).join(".config").join("burrito-exp"))
+}
+
+pub fn default_path() -> Result<PathBuf, Report> {
+    Ok(config_dir()?.join("secrets.age"))
+}
+
+/// The default store: the age file, if there is one.
+pub fn default_store() -> Option<Store> {
+    let p = default_path().ok()?;
+    Some(Store::Age(p.clone())).filter(|_| p.exists())
+}
+
+fn age_identity() -> Result<PathBuf, Report> {
+    let id = match std::env::var_os("BURRITO_EXP_AGE_IDENTITY") {
+        Some(p) => PathBuf::from(p),
+        None => config_dir()?.join("age-key.txt"),
+    };
+    ensure!(
+        id.exists(),
+        "no age identity at {:?}: make one with `age-keygen -o {}`, or set BURRITO_EXP_AGE_IDENTITY",
+        id,
+        id.display()
+    );
+    Ok(id)
+}
+
+/// Run `cmd`, feeding it `input`, and return its stdout.
+fn run(cmd: &mut Command, input: &[u8], what: &str) -> Result<Vec<u8>, Report> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err_with(|| format!("run {}", what))?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let out = child.wait_with_output()?;
+    ensure!(
+        out.status.success(),
+        "{} failed: {}",
+        what,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    Ok(out.stdout)
+}
+
+impl Store {
+    /// Everything in the store. A store that doesn't exist yet is empty.
+    pub fn load(&self) -> Result<BTreeMap<String, String>, Report> {
+        let plain = match self {
+            Store::Age(p) => {
+                if !p.exists() {
+                    return Ok(Default::default());
+                }
+
+                let id = age_identity()?;
+                run(
+                    Command::new("age").arg("-d").arg("-i").arg(&id).arg(p),
+                    b"",
+                    "age -d",
+                )?
+            }
+            Store::Keyring if cfg!(target_os = "macos") => {
+                match Command::new("security")
+                    .args(["find-generic-password", "-w", "-s", KEYRING_SERVICE])
+                    .args(["-a", KEYRING_ACCOUNT])
+                    .output()
+                {
+                    Ok(o) if o.status.success() => o.stdout,
+                    Ok(_) => return Ok(Default::default()),
+                    Err(e) => return Err(e).wrap_err("run security"),
+                }
+            }
+            Store::Keyring => {
+                match Command::new("secret-tool")
+                    .args(["lookup", "service", KEYRING_SERVICE])
+                    .args(["account", KEYRING_ACCOUNT])
+                    .output()
+                {
+                    Ok(o) if o.status.success() => o.stdout,
+                    Ok(_) => return Ok(Default::default()),
+                    Err(e) => return Err(e).wrap_err("run secret-tool"),
+                }
+            }
+        };
+
+        if plain.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Default::default());
+        }
+
+        serde_json::from_slice(&plain).wrap_err("parse secrets")
+    }
+
+    fn save(&self, secrets: &BTreeMap<String, String>) -> Result<(), Report> {
+        let plain = serde_json::to_vec(secrets)?;
+        match self {
+            Store::Age(p) => {
+                let id = age_identity()?;
+                let recipient = run(
+                    Command::new("age-keygen").arg("-y").arg(&id),
+                    b"",
+                    "age-keygen -y",
+                )?;
+                let recipient = String::from_utf8(recipient)?;
+                if let Some(d) = p.parent() {
+                    std::fs::create_dir_all(d)?;
+                }
+
+                // write-then-rename, so a failed encryption never loses the old secrets.
+                let tmp = p.with_extension("tmp");
+                run(
+                    Command::new("age")
+                        .args(["-e", "-r", recipient.trim(), "-o"])
+                        .arg(&tmp),
+                    &plain,
+                    "age -e",
+                )?;
+                std::fs::rename(&tmp, p)?;
+            }
+            Store::Keyring if cfg!(target_os = "macos") => {
+                // `security` only takes the password as an argument, so it briefly shows up in
+                // the process list.
+                let st = Command::new("security")
+                    .args(["add-generic-password", "-U", "-s", KEYRING_SERVICE])
+                    .args(["-a", KEYRING_ACCOUNT, "-w"])
+                    .arg(String::from_utf8(plain)?)
+                    .status()
+                    .wrap_err("run security")?;
+                ensure!(st.success(), "could not store secrets in the keychain");
+            }
+            Store::Keyring => {
+                run(
+                    Command::new("secret-tool")
+                        .args(["store", "--label", "burrito-exp secrets"])
+                        .args(["service", KEYRING_SERVICE, "account", KEYRING_ACCOUNT]),
+                    &plain,
+                    "secret-tool store",
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Store `value` as `name`.
+    pub fn set(&self, name: &str, value: &str) -> Result<(), Report> {
+        check_name(name)?;
+        let mut secrets = self.load()?;
+        secrets.insert(name.to_owned(), value.to_owned());
+        self.save(&secrets)?;
+        info!(?name, store = ?self, "stored secret");
+        Ok(())
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), Report> {
+        let mut secrets = self.load()?;
+        if secrets.remove(name).is_none() {
+            bail!("no secret named {:?}", name);
+        }
+
+        self.save(&secrets)
+    }
+
+    /// Put the stored secrets in our environment, where they aren't set already. This sets
+    /// environment variables, so call it before starting any threads.
+    pub fn export(&self) -> Result<(), Report> {
+        for (k, v) in self.load()? {
+            if std::env::var_os(&k).is_none() {
+                debug!(name = ?k, "using stored secret");
+                std::env::set_var(&k, v);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), Report> {
+    ensure!(
+        !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "secret name {:?} must be an environment variable name (letters, digits, and _)",
+        name
+    );
+    Ok(())
+}
+
+/// Read a secret's value from stdin: from a terminal, a line; from a pipe, everything, without
+/// the trailing newline. Never from the command line, where it would end up in shell history.
+pub fn read_value() -> Result<String, Report> {
+    use std::io::Read;
+    let mut v = String::new();
+    if atty_stdin() {
+        eprint!("value: ");
+        let echo = |on: bool| {
+            Command::new("stty")
+                .arg(if on { "echo" } else { "-echo" })
+                .stdin(Stdio::inherit())
+                .status()
+        };
+        echo(false)?;
+        let read = std::io::stdin().read_line(&mut v);
+        echo(true)?;
+        eprintln!();
+        read?;
+    } else {
+        std::io::stdin().read_to_string(&mut v)?;
+    }
+
+    let v = v.trim_end_matches(['\r', '\n']).to_owned();
+    ensure!(!v.is_empty(), "empty secret");
+    Ok(v)
+}
+
+fn atty_stdin() -> bool {
+    // safe: only asks about fd 0.
+    unsafe { libc::isatty(0) == 1 }
+}
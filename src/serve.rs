@@ -0,0 +1,363 @@
+//! Run as a shared service: accept experiment jobs over HTTP, queue them, and run a few at a time.
+//!
+//! - `POST /jobs` with a [`JobSpec`] queues a job, and returns its id.
+//! - `GET /jobs` and `GET /jobs/<id>` report job status.
+//! - `GET /jobs/<id>/results` lists a job's result files, and `GET /jobs/<id>/results/<path>`
+//!   fetches one.
+//!
+//! Submitting a job is running code on this machine: a job names any config file here, and a
+//! config's local hooks (`pre_exp`, `post_exp`, `post_process`) run in a shell. So off loopback,
+//! every request needs the `--token-file` token, and anyone with it can do what we can.
+//!
+//! The error policy, heartbeat interval, and budget (shared by all jobs) come from the command
+//! line. Jobs can't set them, and always run their nodes sequentially, without a pool.
+
+use crate::budget::Budget;
+use crate::inventory::Inventory;
+use crate::node::{OnError, RunOpts};
+use crate::state::Checkpoint;
+use crate::sweep::{Filter, Filters};
+use crate::tags::Tags;
+use crate::{aggregate, load_nodes, run_nodes};
+use color_eyre::eyre::{ensure, Report, WrapErr};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+/// Settings from the command line that apply to every job.
+#[derive(Clone, Debug)]
+pub struct Defaults {
+    /// Each job's results go in `<out_dir>/job-<id>`.
+    pub out_dir: PathBuf,
+    pub db: Option<PathBuf>,
+    pub tags: Tags,
+    pub on_error: OnError,
+    pub heartbeat: std::time::Duration,
+    pub budget: Budget,
+}
+
+/// A submitted job. Paths are on the machine running the service.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct JobSpec {
+    pub cfg: PathBuf,
+    pub bench_bin: PathBuf,
+    /// As with `--bench-bin-for`.
+    #[serde(default)]
+    pub arch_bins: BTreeMap<String, PathBuf>,
+    pub script: PathBuf,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub reps: Option<usize>,
+    #[serde(default)]
+    pub tags: Tags,
+    /// As with `--only`.
+    #[serde(default)]
+    pub only: Vec<String>,
+    /// As with `--skip`.
+    #[serde(default)]
+    pub skip: Vec<String>,
+    /// As with `--inventory`.
+    #[serde(default)]
+    pub inventory: Option<PathBuf>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+#[serde(tag = "state", rename_all = "lowercase")]
+enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed { error: String },
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+struct Job {
+    id: u64,
+    spec: JobSpec,
+    out_dir: PathBuf,
+    #[serde(flatten)]
+    state: JobState,
+}
+
+struct Service {
+    defaults: Defaults,
+    /// What the `Authorization` header must be, if anything.
+    auth: Option<String>,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    next_id: AtomicU64,
+    running: Semaphore,
+}
+
+pub async fn serve(
+    listen: SocketAddr,
+    concurrency: usize,
+    token: Option<String>,
+    defaults: Defaults,
+) -> Result<(), Report> {
+    ensure!(
+        token.is_some() || listen.ip().is_loopback(),
+        "listening on {} needs a --token-file: jobs can run commands on this machine",
+        listen
+    );
+    if let Some(ref t) = token {
+        ensure!(!t.is_empty(), "the token file is empty");
+    }
+
+    let svc = Arc::new(Service {
+        defaults,
+        auth: token.map(|t| format!("Bearer {}", t)),
+        jobs: Default::default(),
+        next_id: AtomicU64::new(0),
+        running: Semaphore::new(concurrency),
+    });
+
+    let make_svc = make_service_fn(move |_conn| {
+        let svc = svc.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let svc = svc.clone();
+                async move { Ok::<_, Infallible>(svc.handle(req).await) }
+            }))
+        }
+    });
+
+    info!(?listen, ?concurrency, "serving");
+    hyper::Server::try_bind(&listen)
+        .wrap_err_with(|| format!("listen on {}", listen))?
+        .serve(make_svc)
+        .await?;
+    Ok(())
+}
+
+fn json(status: StatusCode, body: &impl serde::Serialize) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, err: impl std::fmt::Display) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": err.to_string() }))
+}
+
+fn list_files(root: &Path, dir: &Path, found: &mut Vec<String>) -> std::io::Result<()> {
+    for e in std::fs::read_dir(dir)? {
+        let p = e?.path();
+        if p.is_dir() {
+            list_files(root, &p, found)?;
+        } else if let Ok(rel) = p.strip_prefix(root) {
+            found.push(rel.display().to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `header` is `expected`, taking as long whichever byte differs.
+fn authorized(expected: Option<&str>, header: Option<&[u8]>) -> bool {
+    let (expected, header) = match (expected, header) {
+        (None, _) => return true,
+        (Some(e), Some(h)) => (e.as_bytes(), h),
+        (Some(_), None) => return false,
+    };
+    expected.len() == header.len()
+        && expected
+            .iter()
+            .zip(header)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+impl Service {
+    async fn handle(self: Arc<Self>, req: Request<Body>) -> Response<Body> {
+        let header = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .map(|h| h.as_bytes());
+        if !authorized(self.auth.as_deref(), header) {
+            return error(StatusCode::UNAUTHORIZED, "missing or wrong token");
+        }
+
+        let path: Vec<String> = req
+            .uri()
+            .path()
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned)
+            .collect();
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        match (req.method(), &path[..]) {
+            (&Method::POST, ["jobs"]) => {
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(b) => b,
+                    Err(err) => return error(StatusCode::BAD_REQUEST, err),
+                };
+                match serde_json::from_slice(&body) {
+                    Ok(spec) => match self.submit(spec) {
+                        Ok(id) => json(StatusCode::CREATED, &serde_json::json!({ "id": id })),
+                        Err(err) => error(StatusCode::BAD_REQUEST, format!("{:#}", err)),
+                    },
+                    Err(err) => error(StatusCode::BAD_REQUEST, err),
+                }
+            }
+            (&Method::GET, ["jobs"]) => {
+                let jobs: Vec<Job> = self.jobs.lock().unwrap().values().cloned().collect();
+                json(StatusCode::OK, &jobs)
+            }
+            (&Method::GET, ["jobs", id, rest @ ..]) => {
+                let job = match id.parse().ok().and_then(|id| self.job(id)) {
+                    Some(j) => j,
+                    None => return error(StatusCode::NOT_FOUND, "no such job"),
+                };
+                match rest {
+                    [] => json(StatusCode::OK, &job),
+                    ["results"] => {
+                        let mut files = vec![];
+                        match list_files(&job.out_dir, &job.out_dir, &mut files) {
+                            Ok(()) => json(StatusCode::OK, &files),
+                            Err(err) => error(StatusCode::NOT_FOUND, err),
+                        }
+                    }
+                    ["results", file @ ..] => {
+                        let rel: PathBuf = file.iter().collect();
+                        if rel.components().any(|c| !matches!(c, Component::Normal(_))) {
+                            return error(StatusCode::BAD_REQUEST, "bad path");
+                        }
+
+                        match tokio::fs::read(job.out_dir.join(rel)).await {
+                            Ok(b) => Response::new(Body::from(b)),
+                            Err(err) => error(StatusCode::NOT_FOUND, err),
+                        }
+                    }
+                    _ => error(StatusCode::NOT_FOUND, "not found"),
+                }
+            }
+            _ => error(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+
+    fn job(&self, id: u64) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    fn set_state(&self, id: u64, state: JobState) {
+        if let Some(j) = self.jobs.lock().unwrap().get_mut(&id) {
+            j.state = state;
+        }
+    }
+
+    /// Validate and queue a job.
+    fn submit(self: &Arc<Self>, spec: JobSpec) -> Result<u64, Report> {
+        let parse = |fs: &[String]| -> Result<Vec<Filter>, Report> {
+            fs.iter().map(|f| f.parse()).collect()
+        };
+        let filters = Filters {
+            only: parse(&spec.only)?,
+            skip: parse(&spec.skip)?,
+        };
+        let nodes = load_nodes(&spec.cfg)?;
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let out_dir = self.defaults.out_dir.join(format!("job-{}", id));
+        let mut tags = self.defaults.tags.clone();
+        tags.extend(spec.tags.clone());
+        let opts = RunOpts {
+            bench_bin: spec.bench_bin.clone(),
+            arch_bins: spec.arch_bins.clone(),
+            script: spec.script.clone(),
+            tags,
+            reps: spec.reps,
+            out_dir: out_dir.clone(),
+            label: spec.name.clone(),
+            db: self.defaults.db.clone(),
+            filters,
+            // nobody is watching.
+            pause: false,
+            checkpoint: Checkpoint::new(out_dir.join("state.json")),
+            pool: None,
+            order: Default::default(),
+            on_error: self.defaults.on_error,
+            control: Default::default(),
+            bwlimit: None,
+            services: Default::default(),
+            bench_meta: None,
+            stamp_results: false,
+            provenance: Default::default(),
+            dedup: None,
+            check_permissions: false,
+            junit: false,
+            events: Default::default(),
+            heartbeat: self.defaults.heartbeat,
+            heartbeat_events: false,
+            budget: self.defaults.budget.clone(),
+            inventory: spec.inventory.as_deref().map(Inventory::load).transpose()?,
+        };
+        self.jobs.lock().unwrap().insert(
+            id,
+            Job {
+                id,
+                spec,
+                out_dir,
+                state: JobState::Queued,
+            },
+        );
+        info!(?id, "queued job");
+
+        let svc = self.clone();
+        tokio::spawn(async move {
+            let _permit = svc.running.acquire().await.unwrap();
+            svc.set_state(id, JobState::Running);
+            info!(?id, "starting job");
+            let res = async {
+                let res = run_nodes(nodes, &opts).await;
+                res.and(aggregate::aggregate(
+                    &opts.out_dir,
+                    &opts.out_dir.join("results.csv"),
+                ))
+            }
+            .await;
+            match res {
+                Ok(()) => {
+                    info!(?id, "job done");
+                    svc.set_state(id, JobState::Done);
+                }
+                Err(err) => {
+                    warn!(?id, ?err, "job failed");
+                    svc.set_state(
+                        id,
+                        JobState::Failed {
+                            error: format!("{:#}", err),
+                        },
+                    );
+                }
+            }
+        });
+
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token() {
+        assert!(authorized(None, None));
+        assert!(authorized(None, Some(b"Bearer x")));
+        assert!(authorized(Some("Bearer abc"), Some(b"Bearer abc")));
+        assert!(!authorized(Some("Bearer abc"), None));
+        assert!(!authorized(Some("Bearer abc"), Some(b"Bearer abd")));
+        assert!(!authorized(Some("Bearer abc"), Some(b"Bearer abcd")));
+        assert!(!authorized(Some("Bearer abc"), Some(b"")));
+    }
+}
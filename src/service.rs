@@ -0,0 +1,351 @@
+//! Service machines: nodes that run no experiments of their own, but a long-lived service (the
+//! burrito discovery service, redis, ...) for other nodes' experiments.
+//!
+//! Service nodes come up (and start their service) before any other node runs, and are torn
+//! down once every node is done, so several client nodes' sweeps share one service machine
+//! rather than each launching their own. A node that `uses` a service is told where it is as
+//! script arguments, after the provider: `--<service>-host <host>`, and `--<service>-<port>
+//! <number>` for each of its named ports.
+//!
+//! A service node found in the `--pool` is used as is, and left up.
+//!
+//! Putting the coordination store on a service node of its own (a small instance is plenty)
+//! keeps it from perturbing the bench. While the run goes on, each service machine is watched
+//! from here: every `monitor_interval_secs`, its load, free memory, whether its ports are
+//! listening, and what its `monitor` command says go to `<out-dir>/services/<id>.monitor.log`.
+//! Once the run is done, the service's output is fetched to `<out-dir>/services/<id>.log`.
+
+use crate::node::{Node, RunOpts};
+use crate::state::{Instance, Phase};
+use color_eyre::eyre::{bail, ensure, Report, WrapErr};
+use futures_util::future::join_all;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Where the service's output goes, on its machine.
+const SERVICE_LOG: &str = "burrito-exp-service.log";
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ServiceCfg {
+    /// Run in the background once the machine is set up, e.g. `redis-server --protected-mode no`.
+    /// Without it, setup (e.g. a `setup_steps` systemd unit) is expected to start the service.
+    #[serde(default)]
+    pub start: Option<String>,
+    /// The ports the service listens on, by name. The machine's firewall (or security group)
+    /// has to let the client nodes reach them.
+    pub ports: BTreeMap<String, u16>,
+    /// Run on the machine at every check, its output recorded, e.g. `redis-cli info stats`.
+    #[serde(default)]
+    pub monitor: Option<String>,
+    /// How often to check on the machine; 0 never does.
+    #[serde(default = "default_monitor_interval_secs")]
+    pub monitor_interval_secs: u64,
+}
+
+fn default_monitor_interval_secs() -> u64 {
+    10
+}
+
+/// Where a running service is.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    pub host: String,
+    pub ports: BTreeMap<String, u16>,
+}
+
+impl Endpoint {
+    /// The script arguments telling a node where service `name` is.
+    pub fn args(&self, name: &str) -> Vec<String> {
+        let mut args = vec![format!("--{}-host", name), self.host.clone()];
+        for (p, n) in &self.ports {
+            args.push(format!("--{}-{}", name, p));
+            args.push(n.to_string());
+        }
+
+        args
+    }
+}
+
+/// The service machines of a run, to tear down when it is done.
+#[derive(Debug, Default)]
+pub struct Services {
+    pub endpoints: BTreeMap<String, Endpoint>,
+    /// The ones we brought up, rather than found in the pool.
+    launched: Vec<(String, Instance)>,
+    /// All of them, to get their logs from.
+    machines: Vec<(String, Node, Instance)>,
+    monitors: Vec<tokio::task::JoinHandle<()>>,
+}
+
+/// Fail if a node uses something that isn't one of the service nodes.
+pub fn check(nodes: &[Node], ids: &[String]) -> Result<(), Report> {
+    for (n, id) in nodes.iter().zip(ids) {
+        for s in n.uses() {
+            let svc = ids.iter().position(|i| i == s);
+            ensure!(
+                svc.is_some_and(|j| nodes[j].service().is_some()),
+                "node {:?} uses {:?}, which isn't a service node",
+                id,
+                s
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Bring up the service nodes of `nodes` (with ids `ids`), all at once, and start their services.
+pub async fn up(nodes: &[Node], ids: &[String], opts: &RunOpts) -> Result<Services, Report> {
+    check(nodes, ids)?;
+    let todo: Vec<(&Node, &String)> = nodes
+        .iter()
+        .zip(ids)
+        .filter(|(n, _)| n.service().is_some())
+        .collect();
+    let started = join_all(todo.iter().map(|(n, id)| start(n, id, opts))).await;
+    let mut services = Services::default();
+    let mut failed = vec![];
+    for ((node, id), res) in todo.into_iter().zip(started) {
+        match res {
+            Ok((endpoint, inst, launched)) => {
+                info!(service = ?id, host = ?endpoint.host, ports = ?endpoint.ports, "service up");
+                services.endpoints.insert(id.clone(), endpoint);
+                services
+                    .monitors
+                    .extend(monitor(node, id, &inst, &opts.out_dir));
+                services
+                    .machines
+                    .push((id.clone(), node.clone(), inst.clone()));
+                if launched {
+                    services.launched.push((id.clone(), inst));
+                }
+            }
+            Err(err) => {
+                warn!(service = ?id, err = %format!("{:#}", err), "could not bring up service");
+                failed.push(id.as_str());
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        services.down(opts).await;
+        bail!("could not bring up services {}", failed.join(", "));
+    }
+
+    Ok(services)
+}
+
+/// Bring up (or find) service node `id`, and start its service. Returns its instance too, and
+/// whether we brought it up.
+async fn start(
+    node: &Node,
+    id: &str,
+    opts: &RunOpts,
+) -> Result<(Endpoint, Instance, bool), Report> {
+    let cfg = node.service().expect("service node");
+    let ckpt = opts.checkpoint.node(id);
+    let (inst, launched) = match opts.pool.as_ref().and_then(|p| p.get(id, node)) {
+        Some(m) => (m.instance.clone(), false),
+        None => {
+            // pick up the service machine an interrupted run left behind, if it's still there.
+            let prev = ckpt.get().instance;
+            let prev = match prev {
+                Some(i) if node.reach(&i.conn).await.is_ok() => Some(i),
+                _ => None,
+            };
+            match prev {
+                Some(i) => {
+                    info!(service = ?id, host = ?i.conn.host, "reusing service machine");
+                    (i, true)
+                }
+                None => {
+                    ckpt.update(|s| s.phase = Phase::Launching);
+                    let key = opts
+                        .out_dir
+                        .join("service-keys")
+                        .join(format!("{}.pem", id));
+                    (node.provision(opts, key).await?, true)
+                }
+            }
+        }
+    };
+    ckpt.update(|s| {
+        s.phase = Phase::Running;
+        s.instance = Some(inst.clone()).filter(|_| launched);
+    });
+
+    if let Some(ref cmd) = cfg.start {
+        if let Err(err) = run_start(node, &inst, cmd).await {
+            if let Some(cloud) = inst.cloud.as_ref().filter(|_| launched) {
+                if let Err(err) = cloud.terminate(&inst.conn.host).await {
+                    warn!(?err, host = ?inst.conn.host, "could not terminate service machine");
+                }
+
+                ckpt.update(|s| s.instance = None);
+            }
+
+            ckpt.update(|s| s.phase = Phase::Failed);
+            return Err(err);
+        }
+
+        info!(service = ?id, ?cmd, "started service");
+    }
+
+    let endpoint = Endpoint {
+        host: inst.conn.host.clone(),
+        ports: cfg.ports.clone(),
+    };
+    Ok((endpoint, inst, launched))
+}
+
+async fn fetch_log(node: &Node, inst: &Instance, dir: &Path, id: &str) -> Result<(), Report> {
+    let ssh = node.reach(&inst.conn).await?;
+    let out = ssh
+        .command("cat")
+        .arg(SERVICE_LOG)
+        .output()
+        .await
+        .wrap_err("read service log")?;
+    if !out.status.success() {
+        // services started by setup log elsewhere.
+        debug!(service = ?id, "no service log");
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join(format!("{}.log", id)), out.stdout)?;
+    Ok(())
+}
+
+async fn run_start(node: &Node, inst: &Instance, cmd: &str) -> Result<(), Report> {
+    let ssh = node
+        .reach(&inst.conn)
+        .await
+        .wrap_err("connect to service machine")?;
+    let st = ssh
+        .command("sh")
+        .arg("-c")
+        .arg(format!(
+            "nohup setsid sh -c \"$1\" > {} 2>&1 < /dev/null &",
+            SERVICE_LOG
+        ))
+        .args(["sh", cmd])
+        .status()
+        .await
+        .wrap_err("start service")?;
+    ensure!(st.success(), "could not start service {:?}", cmd);
+    Ok(())
+}
+
+fn services_dir(out_dir: &Path) -> PathBuf {
+    out_dir.join("services")
+}
+
+/// Check on service `id`'s machine until aborted, if its node asks to.
+fn monitor(
+    node: &Node,
+    id: &str,
+    inst: &Instance,
+    out_dir: &Path,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let cfg = node.service().expect("service node");
+    if cfg.monitor_interval_secs == 0 {
+        return None;
+    }
+
+    let ports: Vec<String> = cfg.ports.values().map(u16::to_string).collect();
+    let cmd = format!(
+        "echo \"--- $(date -Is)\"; echo \"load $(cat /proc/loadavg)\"; grep MemAvailable /proc/meminfo; \
+        for p in {}; do ss -Hltn \"sport = :$p\" | grep -q . || echo \"not listening: $p\"; done; {}",
+        ports.join(" "),
+        cfg.monitor.as_deref().unwrap_or("true"),
+    );
+    let path = services_dir(out_dir).join(format!("{}.monitor.log", id));
+    let interval = Duration::from_secs(cfg.monitor_interval_secs);
+    let (node, id, inst) = (node.clone(), id.to_owned(), inst.clone());
+    Some(tokio::spawn(async move {
+        let mut ssh = None;
+        let mut down: Vec<String> = vec![];
+        let mut tick = tokio::time::interval(interval);
+        loop {
+            tick.tick().await;
+            if ssh.is_none() {
+                match node.reach(&inst.conn).await {
+                    Ok(s) => ssh = Some(s),
+                    Err(err) => {
+                        warn!(service = ?id, err = %format!("{:#}", err), "could not reach service machine");
+                        continue;
+                    }
+                }
+            }
+
+            let out = match ssh.as_ref().unwrap().shell(&cmd).output().await {
+                Ok(out) => String::from_utf8_lossy(&out.stdout).into_owned(),
+                Err(err) => {
+                    warn!(service = ?id, ?err, "could not check on service machine");
+                    ssh = None;
+                    continue;
+                }
+            };
+            let now_down: Vec<String> = out
+                .lines()
+                .filter_map(|l| l.strip_prefix("not listening: "))
+                .map(str::to_owned)
+                .collect();
+            if now_down != down {
+                if now_down.is_empty() {
+                    info!(service = ?id, "service listening again");
+                } else {
+                    warn!(service = ?id, ports = ?now_down, "service is not listening");
+                }
+                down = now_down;
+            }
+
+            let res = std::fs::create_dir_all(path.parent().unwrap()).and_then(|_| {
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?
+                    .write_all(out.as_bytes())
+            });
+            if let Err(err) = res {
+                debug!(?err, ?path, "could not record service check");
+            }
+        }
+    }))
+}
+
+impl Services {
+    /// Stop checking on the service machines, fetch their services' output, and terminate the
+    /// ones we launched.
+    pub async fn down(self, opts: &RunOpts) {
+        for m in &self.monitors {
+            m.abort();
+        }
+
+        for (id, node, inst) in &self.machines {
+            if let Err(err) = fetch_log(node, inst, &services_dir(&opts.out_dir), id).await {
+                warn!(?err, service = ?id, "could not fetch service log");
+            }
+        }
+
+        for (id, inst) in self.launched {
+            let ckpt = opts.checkpoint.node(&id);
+            if let Some(ref cloud) = inst.cloud {
+                if let Err(err) = cloud.terminate(&inst.conn.host).await {
+                    warn!(?err, service = ?id, host = ?inst.conn.host, "could not terminate service machine");
+                    continue;
+                }
+            }
+
+            ckpt.update(|s| {
+                s.phase = Phase::Done;
+                s.instance = None;
+            });
+            info!(service = ?id, "service down");
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Per-machine setup, run from inside the tsunami setup callback.
+
+use crate::deps::{
+    check_kernel, install_deps, install_gpu_driver, install_kernel, DepsCfg, KernelCfg,
+};
+use crate::disk::{self, DiskCfg};
+use crate::firewall::Firewall;
+use crate::net;
+use crate::nic::NicTuning;
+use crate::retry::RetryPolicy;
+use crate::sidecar::Sidecar;
+use crate::ssh::{reboot, ConnInfo};
+use crate::state::{timed, NodeCheckpoint};
+use crate::tags::Cloud;
+use crate::throttle;
+use crate::transfer::{self, Throughput, Transfer};
+use color_eyre::eyre::{bail, ensure, Report, WrapErr};
+use openssh::Session;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Commands that undo setup's changes to a host we don't launch (firewall rules, NIC settings),
+/// appended as setup makes them, and run at teardown. Machines kept in a pool keep the changes.
+pub const UNDO_FILE: &str = ".burrito-exp-undo";
+
+/// A setup step to run before dependency installation.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SetupStep {
+    /// Run a shell command; it must succeed.
+    Cmd(String),
+    /// Reboot the machine and wait for it to come back before continuing.
+    Reboot,
+}
+
+/// A local (instance store) disk to format and mount, and run the experiment from.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct Scratch {
+    #[serde(default = "default_scratch_mount")]
+    pub mount: String,
+    /// The block device. By default, the first local NVMe disk (e.g. on i3 or m5d instances, or
+    /// Azure's Lsv2).
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+fn default_scratch_mount() -> String {
+    "/mnt/scratch".to_owned()
+}
+
+impl Scratch {
+    async fn mount(&self, ssh: &Session) -> Result<(), Report> {
+        let dev = match self.device {
+            Some(ref d) => d.clone(),
+            None => "$(lsblk -dpno NAME,MODEL | awk '/Instance Storage|NVMe Direct Disk/ { print $1; exit }')"
+                .to_owned(),
+        };
+        // the disk survives reboots, but not its mount: only format it if it has no filesystem
+        // yet, so a remount after a reboot keeps what's on it.
+        let cmd = format!(
+            "mountpoint -q {mnt} || {{ dev={dev}; [ -n \"$dev\" ] || {{ echo 'no local nvme disk found' >&2; exit 1; }}; \
+            fs=$(sudo blkid -o value -s TYPE \"$dev\"); \
+            case \"$fs\" in ext4) ;; '') sudo mkfs.ext4 -q \"$dev\" ;; *) echo \"$dev has a $fs filesystem, not formatting it\" >&2; exit 1 ;; esac \
+            && sudo mkdir -p {mnt} && sudo mount -o noatime \"$dev\" {mnt}; }} \
+            && sudo chown \"$(id -u):$(id -g)\" {mnt}",
+            mnt = self.mount,
+            dev = dev,
+        );
+        info!(mount = ?self.mount, "mounting scratch disk");
+        let out = ssh
+            .shell(cmd)
+            .output()
+            .await
+            .wrap_err("mount scratch disk")?;
+        ensure!(
+            out.status.success(),
+            "mount scratch disk failed: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        Ok(())
+    }
+}
+
+/// Everything a machine needs before it can run the experiment.
+#[derive(Clone, Debug)]
+pub struct RemoteSetup {
+    pub bench_bin: PathBuf,
+    /// Used instead of `bench_bin` on machines of these architectures.
+    pub arch_bins: BTreeMap<String, PathBuf>,
+    pub bench_remote_path: PathBuf,
+    pub script: PathBuf,
+    pub script_remote_path: PathBuf,
+    /// Uploaded along with the bench.
+    pub sidecars: Vec<Sidecar>,
+    pub deps: DepsCfg,
+    pub steps: Vec<SetupStep>,
+    pub reboot_timeout: Duration,
+    pub ssh_port: u16,
+    /// Switch this burstable AWS machine to unlimited CPU credits first.
+    pub unlimited_credits: Option<Cloud>,
+    /// Change the root disk of this cloud machine first.
+    pub disk: Option<(Cloud, DiskCfg)>,
+    pub scratch: Option<Scratch>,
+    /// Open these ports in the host's firewall.
+    pub firewall: Option<Firewall>,
+    /// Set the default-route interface's MTU.
+    pub mtu: Option<u32>,
+    pub nic_tuning: Option<NicTuning>,
+    /// Boot into at least this kernel.
+    pub kernel: Option<KernelCfg>,
+    /// Install the NVIDIA driver and CUDA toolkit, if `nvidia-smi` doesn't work already.
+    pub gpu: bool,
+    /// Upload into this directory instead of the home directory.
+    pub workdir: Option<String>,
+    pub transfer: transfer::Backend,
+    pub bwlimit: Option<u64>,
+    pub transfer_retry: RetryPolicy,
+    /// Where to record upload throughput and setup timings.
+    pub ckpt: Option<NodeCheckpoint>,
+    /// When the machine was asked for, to time how long it took to come up.
+    pub launched: Instant,
+}
+
+impl RemoteSetup {
+    pub async fn run(&self, vm: &tsunami::Machine<'_>) -> Result<(), Report> {
+        let ckpt = self.ckpt.as_ref();
+        if let Some(c) = ckpt {
+            c.add_time("launch", self.launched.elapsed());
+        }
+
+        timed(ckpt, "setup", self.setup(vm)).await
+    }
+
+    async fn setup(&self, vm: &tsunami::Machine<'_>) -> Result<(), Report> {
+        ensure!(
+            self.deps.use_sudo || !self.steps.iter().any(|s| matches!(s, SetupStep::Reboot)),
+            "reboot setup steps need sudo"
+        );
+        ensure!(
+            self.deps.use_sudo || (self.disk.is_none() && self.scratch.is_none()),
+            "disk and scratch configuration need sudo"
+        );
+        ensure!(
+            self.deps.use_sudo
+                || (self.firewall.is_none() && self.mtu.is_none() && self.nic_tuning.is_none()),
+            "firewall, mtu, and nic tuning configuration need sudo"
+        );
+        ensure!(
+            self.deps.use_sudo || !self.gpu || self.deps.deps_installed,
+            "installing the gpu driver needs sudo"
+        );
+        ensure!(
+            self.deps.use_sudo || self.kernel.is_none(),
+            "installing a kernel needs sudo"
+        );
+
+        // a reboot invalidates tsunami's session, so from then on we use our own.
+        let conn = ConnInfo::from_machine(vm, self.ssh_port);
+        let mut fresh: Option<Session> = None;
+        if let Some(ref cloud) = self.unlimited_credits {
+            throttle::set_unlimited(cloud, &conn.host).await?;
+        }
+
+        if let Some((ref cloud, ref cfg)) = self.disk {
+            fresh = disk::apply(&vm.ssh, &conn, cloud, cfg, self.reboot_timeout).await?;
+        }
+
+        for step in &self.steps {
+            let ssh = fresh.as_ref().unwrap_or(&vm.ssh);
+            match step {
+                SetupStep::Cmd(c) => {
+                    info!(cmd = ?c, "setup step");
+                    let st = ssh.shell(c).status().await.wrap_err("setup step")?;
+                    ensure!(st.success(), "setup step {:?} failed", c);
+                }
+                SetupStep::Reboot => {
+                    fresh = Some(reboot(ssh, &conn, self.reboot_timeout).await?);
+                }
+            }
+        }
+
+        if let Some(ref k) = self.kernel {
+            let ssh = fresh.as_ref().unwrap_or(&vm.ssh);
+            if install_kernel(ssh, &self.deps, k).await? {
+                fresh = Some(reboot(ssh, &conn, self.reboot_timeout).await?);
+            }
+
+            check_kernel(fresh.as_ref().unwrap_or(&vm.ssh), k).await?;
+        }
+
+        if self.gpu {
+            let ssh = fresh.as_ref().unwrap_or(&vm.ssh);
+            if !self.deps.deps_installed && nvidia_smi(ssh).await.is_err() {
+                install_gpu_driver(ssh, &self.deps).await?;
+                fresh = Some(reboot(ssh, &conn, self.reboot_timeout).await?);
+            }
+
+            nvidia_smi(fresh.as_ref().unwrap_or(&vm.ssh)).await?;
+        }
+
+        // after the last reboot, which would undo them.
+        let ssh = fresh.as_ref().unwrap_or(&vm.ssh);
+        if let Some(ref f) = self.firewall {
+            f.apply(ssh).await?;
+        }
+
+        if let Some(mtu) = self.mtu {
+            net::set_mtu(ssh, mtu).await?;
+        }
+
+        if let Some(ref t) = self.nic_tuning {
+            t.apply(ssh).await?;
+        }
+
+        if let Some(ref s) = self.scratch {
+            s.mount(ssh).await?;
+        }
+
+        // package installation and the uploads don't touch each other's files, and each runs
+        // over its own channel of the session.
+        tokio::try_join!(
+            install_deps(ssh, &self.deps, self.ckpt.as_ref()),
+            timed(self.ckpt.as_ref(), "setup.upload", self.upload(ssh, &conn))
+        )?;
+        Ok(())
+    }
+
+    /// Copy the bench binary, script, and service binaries over. This is all an already set-up machine needs.
+    pub async fn upload(&self, ssh: &Session, conn: &ConnInfo) -> Result<(), Report> {
+        let xfer = Transfer::new(
+            ssh,
+            conn,
+            self.transfer,
+            self.bwlimit,
+            self.transfer_retry.clone(),
+        )
+        .await?;
+        let dir = Path::new(self.workdir.as_deref().unwrap_or(""));
+        if let Some(ref wd) = self.workdir {
+            let st = ssh.command("mkdir").args(["-p", wd]).status().await?;
+            ensure!(st.success(), "could not create working directory {}", wd);
+        }
+
+        let bench_remote_path = dir.join(&self.bench_remote_path);
+        let script_remote_path = dir.join(&self.script_remote_path);
+        let start = Instant::now();
+        let bench = async {
+            let bin = self.bench_for(ssh).await?;
+            let t = xfer.upload(ssh, bin, &bench_remote_path).await?;
+            let chmod_cmd = format!("chmod +x {}", bench_remote_path.to_str().unwrap());
+            let ok = ssh.shell(&chmod_cmd).status().await?;
+            ensure!(ok.success(), "chmod bench");
+            Ok::<_, Report>(t)
+        };
+        let script = xfer.upload(ssh, &self.script, &script_remote_path);
+        let (b, s) = tokio::try_join!(bench, script)?;
+        let (mut files, mut bytes) = (b.files + s.files, b.bytes + s.bytes);
+        for sc in &self.sidecars {
+            let t = sc.upload(ssh, &xfer, dir).await?;
+            files += t.files;
+            bytes += t.bytes;
+        }
+        // the uploads overlap, so the rate is over how long they took together.
+        let total = Throughput {
+            files,
+            bytes,
+            secs: start.elapsed().as_secs_f64(),
+        };
+        info!(
+            bytes = total.bytes,
+            mb_per_s = ?total.mb_per_s().map(|r| format!("{:.2}", r)),
+            "uploads done"
+        );
+        if let Some(ref c) = self.ckpt {
+            c.update(|st| st.uploaded.add(total));
+        }
+
+        Ok(())
+    }
+
+    /// The bench binary for the machine's architecture.
+    async fn bench_for(&self, ssh: &Session) -> Result<&Path, Report> {
+        let out = ssh.command("uname").arg("-m").output().await?;
+        ensure!(out.status.success(), "uname -m");
+        let arch = String::from_utf8(out.stdout)?.trim().to_owned();
+        if let Some(b) = self.arch_bins.get(&arch) {
+            info!(?arch, bin = ?b, "using bench binary for architecture");
+            return Ok(b);
+        }
+
+        if let Some(built) = elf_arch(&self.bench_bin).filter(|a| *a != arch) {
+            bail!(
+                "bench binary {:?} is for {}, but the machine is {}: pass --bench-bin-for {}=<path>",
+                self.bench_bin,
+                built,
+                arch,
+                arch
+            );
+        }
+
+        Ok(&self.bench_bin)
+    }
+}
+
+/// The architecture an ELF binary was built for, as `uname -m` names it, if we can tell.
+fn elf_arch(path: &Path) -> Option<&'static str> {
+    use std::io::Read;
+    let mut header = [0u8; 20];
+    std::fs::File::open(path)
+        .ok()?
+        .read_exact(&mut header)
+        .ok()?;
+    if &header[..4] != b"\x7fELF" {
+        return None;
+    }
+
+    // e_machine, assuming a little-endian binary.
+    match u16::from_le_bytes([header[18], header[19]]) {
+        0x3e => Some("x86_64"),
+        0xb7 => Some("aarch64"),
+        _ => None,
+    }
+}
+
+/// Check the GPU driver works, and log the GPUs it finds.
+async fn nvidia_smi(ssh: &Session) -> Result<(), Report> {
+    let out = ssh
+        .command("nvidia-smi")
+        .arg("-L")
+        .output()
+        .await
+        .wrap_err("run nvidia-smi")?;
+    ensure!(
+        out.status.success(),
+        "nvidia-smi failed: {}",
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    info!(gpus = %String::from_utf8_lossy(&out.stdout).trim(), "gpu driver ok");
+    Ok(())
+}
+
+/// Undo setup's changes to the host `ssh` is a session to, newest first. Failures are only logged:
+/// the experiment is over by now.
+pub async fn undo(ssh: &Session) {
+    let cmd = format!(
+        "[ ! -e {f} ] || {{ tac {f} | sh && rm {f}; }}",
+        f = UNDO_FILE
+    );
+    match ssh.shell(cmd).output().await {
+        Ok(o) if o.status.success() => info!("undid setup changes"),
+        Ok(o) => warn!(
+            stderr = %String::from_utf8_lossy(&o.stderr).trim(),
+            "could not undo setup changes"
+        ),
+        Err(err) => warn!(?err, "could not undo setup changes"),
+    }
+}
@@ -0,0 +1,170 @@
+//! More binaries to run alongside the bench, e.g. burrito's discovery service and
+//! `localname-ctl`: uploaded with it, started (and health-checked) in the working directory
+//! before each repetition's script, and stopped once the repetition is done, their logs
+//! collected with the results.
+
+use crate::transfer::{Throughput, Transfer};
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use openssh::Session;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct Sidecar {
+    /// The local binary.
+    pub bin: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Where its output goes, relative to the working directory. Defaults to `<bin>.log`.
+    #[serde(default)]
+    pub logfile: Option<String>,
+    /// A command that succeeds once it is ready, e.g. `nc -z localhost 4242`. Without one, it
+    /// only has to stay up for a second.
+    #[serde(default)]
+    pub health: Option<String>,
+    #[serde(default = "default_health_timeout_secs")]
+    pub health_timeout_secs: u64,
+}
+
+fn default_health_timeout_secs() -> u64 {
+    30
+}
+
+impl Sidecar {
+    /// Its remote file name.
+    pub fn name(&self) -> &str {
+        self.bin.file_name().and_then(|n| n.to_str()).unwrap_or("")
+    }
+
+    pub fn logfile(&self) -> String {
+        self.logfile
+            .clone()
+            .unwrap_or_else(|| format!("{}.log", self.name()))
+    }
+
+    fn pid_file(&self) -> String {
+        format!("{}.pid", self.name())
+    }
+
+    pub fn check(&self) -> Result<(), Report> {
+        ensure!(
+            !self.name().is_empty() && self.bin.is_file(),
+            "service binary {:?} is not a file",
+            self.bin
+        );
+        Ok(())
+    }
+
+    /// Copy the binary into `dir`.
+    pub async fn upload(
+        &self,
+        ssh: &Session,
+        xfer: &Transfer,
+        dir: &Path,
+    ) -> Result<Throughput, Report> {
+        let remote = dir.join(self.name());
+        let t = xfer.upload(ssh, &self.bin, &remote).await?;
+        let st = ssh
+            .command("chmod")
+            .arg("+x")
+            .arg(remote.to_str().unwrap())
+            .status()
+            .await?;
+        ensure!(st.success(), "chmod {}", self.name());
+        Ok(t)
+    }
+
+    /// Start it in `wd`, and wait for it to be healthy.
+    async fn start(&self, ssh: &Session, wd: &str) -> Result<(), Report> {
+        let bin = format!("./{}", self.name());
+        let st = ssh
+            .command("sh")
+            .arg("-c")
+            .arg(format!(
+                "cd {} && {{ nohup setsid \"$@\" > {} 2>&1 < /dev/null & echo $! > {}; }}",
+                wd,
+                self.logfile(),
+                self.pid_file()
+            ))
+            .arg("sh")
+            .arg(&bin)
+            .args(&self.args)
+            .status()
+            .await
+            .wrap_err_with(|| format!("start {}", self.name()))?;
+        ensure!(st.success(), "could not start {}", self.name());
+
+        let alive = format!("kill -0 $(cat {}/{})", wd, self.pid_file());
+        let wait = match self.health {
+            Some(_) => format!(
+                "for i in $(seq {}); do sh -c \"$1\" >/dev/null 2>&1 && exit 0; {} || exit 2; sleep 1; done; exit 1",
+                self.health_timeout_secs.max(1),
+                alive
+            ),
+            None => format!("sleep 1; {} || exit 2", alive),
+        };
+        let st = ssh
+            .command("sh")
+            .arg("-c")
+            .arg(wait)
+            .arg("sh")
+            .arg(self.health.as_deref().unwrap_or(""))
+            .status()
+            .await
+            .wrap_err_with(|| format!("health-check {}", self.name()))?;
+        match st.code() {
+            Some(0) => Ok(()),
+            Some(2) => Err(eyre!(
+                "{} exited on starting; see its log, {}",
+                self.name(),
+                self.logfile()
+            )),
+            _ => Err(eyre!(
+                "{} was not healthy after {}s",
+                self.name(),
+                self.health_timeout_secs
+            )),
+        }
+    }
+
+    /// Stop it, noting if it didn't last the repetition.
+    async fn stop(&self, ssh: &Session, wd: &str) -> Result<(), Report> {
+        let out = ssh
+            .shell(format!(
+                "cd {} && pid=$(cat {}) && if kill -0 $pid 2>/dev/null; then kill -TERM -- -$pid; else echo exited; fi",
+                wd,
+                self.pid_file()
+            ))
+            .output()
+            .await
+            .wrap_err_with(|| format!("stop {}", self.name()))?;
+        ensure!(out.status.success(), "could not stop {}", self.name());
+        if String::from_utf8_lossy(&out.stdout).trim() == "exited" {
+            warn!(service = ?self.name(), log = ?self.logfile(), "service binary exited during the repetition");
+        }
+        Ok(())
+    }
+}
+
+/// Start `sidecars` in `wd`, in order. If one doesn't come up, those started are stopped again.
+pub async fn start_all(ssh: &Session, sidecars: &[Sidecar], wd: &str) -> Result<(), Report> {
+    for (i, s) in sidecars.iter().enumerate() {
+        if let Err(err) = s.start(ssh, wd).await {
+            stop_all(ssh, &sidecars[..=i], wd).await;
+            return Err(err);
+        }
+
+        info!(service = ?s.name(), args = ?s.args, "started service binary");
+    }
+
+    Ok(())
+}
+
+/// Stop `sidecars`, last started first.
+pub async fn stop_all(ssh: &Session, sidecars: &[Sidecar], wd: &str) {
+    for s in sidecars.iter().rev() {
+        if let Err(err) = s.stop(ssh, wd).await {
+            warn!(?err, service = ?s.name(), "could not stop service binary");
+        }
+    }
+}
@@ -0,0 +1,351 @@
+//! SSH connection helpers beyond what tsunami manages for us.
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use openssh::{Session, SessionBuilder};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, instrument};
+
+/// Per-node ssh options.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct SshCfg {
+    /// Send a keepalive after this many seconds of silence from the server (`ServerAliveInterval`),
+    /// so NAT boxes don't drop idle connections during long experiments.
+    pub keepalive_secs: u64,
+    /// How long to keep trying to re-establish a dropped connection before giving up on the run.
+    pub reconnect_timeout_secs: u64,
+    /// ssh port on the target. Only configurable for baremetal nodes.
+    pub port: Option<u16>,
+    /// Private key to authenticate with. Only configurable for baremetal nodes.
+    pub key_path: Option<PathBuf>,
+    /// Extra `ssh_config` options (e.g. `{"Ciphers": "aes128-gcm@openssh.com"}`).
+    pub options: BTreeMap<String, String>,
+}
+
+impl Default for SshCfg {
+    fn default() -> Self {
+        Self {
+            keepalive_secs: 30,
+            reconnect_timeout_secs: 600,
+            port: None,
+            key_path: None,
+            options: Default::default(),
+        }
+    }
+}
+
+impl SshCfg {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(22)
+    }
+
+    /// Whether this configuration needs to know the target's address in advance.
+    pub fn is_host_specific(&self) -> bool {
+        self.port.is_some() || self.key_path.is_some() || !self.options.is_empty()
+    }
+
+    /// Apply `options` and `jump` to connections to `host`.
+    pub fn apply_to_host(&self, host: &str, jump: Option<&ProxyJump>) -> Result<(), Report> {
+        let mut opts: HostOptions = self
+            .options
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        if let Some(j) = jump {
+            opts.push(("ProxyJump".to_owned(), j.spec()));
+        }
+
+        set_host_options(host, opts)
+    }
+}
+
+/// How to reach a machine over ssh, independent of any live session.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ConnInfo {
+    pub host: String,
+    pub user: String,
+    pub key_path: Option<PathBuf>,
+    pub port: u16,
+    pub keepalive: Option<Duration>,
+}
+
+impl ConnInfo {
+    pub fn from_machine(vm: &tsunami::Machine<'_>, port: u16) -> Self {
+        Self {
+            host: vm.public_ip.clone(),
+            user: vm.username.clone(),
+            key_path: vm.private_key.clone(),
+            port,
+            keepalive: None,
+        }
+    }
+
+    pub fn with_cfg(self, cfg: &SshCfg) -> Self {
+        Self {
+            keepalive: Some(Duration::from_secs(cfg.keepalive_secs)).filter(|d| !d.is_zero()),
+            ..self
+        }
+    }
+
+    pub async fn connect(&self, timeout: Option<Duration>) -> Result<Session, Report> {
+        let mut sess = SessionBuilder::default();
+        sess.user(self.user.clone()).port(self.port);
+        if let Some(ref k) = self.key_path {
+            sess.keyfile(k);
+        }
+
+        if let Some(t) = timeout {
+            sess.connect_timeout(t);
+        }
+
+        if let Some(k) = self.keepalive {
+            sess.server_alive_interval(k);
+        }
+
+        let s = sess
+            .connect(&self.host)
+            .await
+            .wrap_err_with(|| format!("connect to {}@{}", self.user, self.host))?;
+        Ok(s)
+    }
+}
+
+async fn boot_id(ssh: &Session) -> Result<String, Report> {
+    let out = ssh
+        .command("cat")
+        .arg("/proc/sys/kernel/random/boot_id")
+        .output()
+        .await?;
+    ensure!(out.status.success(), "read boot_id");
+    Ok(String::from_utf8(out.stdout)?.trim().to_owned())
+}
+
+/// Reboot the machine and return a new session once it is reachable again.
+///
+/// `ssh` is unusable afterwards.
+#[instrument(skip(ssh), level = "debug")]
+pub async fn reboot(ssh: &Session, conn: &ConnInfo, timeout: Duration) -> Result<Session, Report> {
+    let before = boot_id(ssh).await?;
+    info!("rebooting");
+    // the connection drops from under us, so the exit status is meaningless.
+    let _ = ssh.command("sudo").arg("reboot").status().await;
+
+    let start = Instant::now();
+    // give the machine a chance to actually go down before reconnecting.
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    loop {
+        match conn.connect(Some(Duration::from_secs(10))).await {
+            Ok(s) => match boot_id(&s).await {
+                Ok(after) if after != before => {
+                    info!(elapsed = ?start.elapsed(), "reconnected after reboot");
+                    return Ok(s);
+                }
+                Ok(_) => debug!("machine has not rebooted yet"),
+                Err(err) => debug!(?err, "reconnected but could not read boot id"),
+            },
+            Err(err) => debug!(?err, "not reachable yet"),
+        }
+
+        if start.elapsed() > timeout {
+            bail!("machine did not come back within {:?} of reboot", timeout);
+        }
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Keep trying to connect until `timeout` elapses.
+#[instrument(level = "debug")]
+pub async fn reconnect(conn: &ConnInfo, timeout: Duration) -> Result<Session, Report> {
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match conn.connect(Some(Duration::from_secs(10))).await {
+            Ok(s) => {
+                info!(?attempt, elapsed = ?start.elapsed(), "reconnected");
+                return Ok(s);
+            }
+            Err(err) if start.elapsed() > timeout => {
+                return Err(err.wrap_err(format!("could not reconnect within {:?}", timeout)));
+            }
+            Err(err) => debug!(?attempt, ?err, "reconnect failed"),
+        }
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Generate a passwordless key pair at `path` (and `path.pub`), and return the public key.
+pub async fn generate_key(path: &Path) -> Result<String, Report> {
+    let _ = std::fs::remove_file(path);
+    let out = tokio::process::Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", ""])
+        .arg("-f")
+        .arg(path)
+        .output()
+        .await
+        .wrap_err("ssh-keygen")?;
+    ensure!(
+        out.status.success(),
+        "ssh-keygen failed: {}",
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    let pubkey = std::fs::read_to_string(format!("{}.pub", path.display()))?;
+    Ok(pubkey.trim().to_owned())
+}
+
+/// A generated key pair, removed when this is dropped.
+#[derive(Debug)]
+pub struct TempKey(pub PathBuf);
+
+impl Drop for TempKey {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+        let _ = std::fs::remove_file(format!("{}.pub", self.0.display()));
+    }
+}
+
+/// Don't check `host`'s host key: it's a new machine behind an address (or a port forwarded to
+/// localhost) that other machines have had before it.
+pub fn skip_host_key_check(host: &str) -> Result<(), Report> {
+    set_host_options(
+        host,
+        vec![
+            ("StrictHostKeyChecking".to_owned(), "no".to_owned()),
+            ("UserKnownHostsFile".to_owned(), "/dev/null".to_owned()),
+        ],
+    )
+}
+
+/// An `ssh` to `conn`, with `cfg`'s options, through `jump`. Arguments added to it are the remote
+/// command.
+pub fn command(conn: &ConnInfo, cfg: &SshCfg, jump: Option<&ProxyJump>) -> std::process::Command {
+    let mut cmd = std::process::Command::new("ssh");
+    cmd.arg("-p").arg(conn.port.to_string());
+    if let Some(ref k) = conn.key_path {
+        cmd.arg("-i").arg(k);
+    }
+
+    if let Some(j) = jump {
+        cmd.arg("-J").arg(j.spec());
+    }
+
+    for (k, v) in &cfg.options {
+        cmd.arg("-o").arg(format!("{}={}", k, v));
+    }
+
+    // ssh would take options after the destination too, which could be the remote command's.
+    cmd.arg("--").arg(format!("{}@{}", conn.user, conn.host));
+    cmd
+}
+
+/// A gateway host to reach the target through (`ProxyJump`).
+///
+/// Only for baremetal and existing nodes: launched VMs are waited for and set up over their public
+/// address, so a VM in a private subnet can't be launched here. Launch it (and its bastion)
+/// yourself, and use it as an `existing` node with `proxy_jump` set.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ProxyJump {
+    pub host: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+impl ProxyJump {
+    fn spec(&self) -> String {
+        let mut s = String::new();
+        if let Some(ref u) = self.user {
+            s.push_str(u);
+            s.push('@');
+        }
+        s.push_str(&self.host);
+        if let Some(p) = self.port {
+            s.push_str(&format!(":{}", p));
+        }
+        s
+    }
+}
+
+// openssh's SessionBuilder (and therefore tsunami) can't pass arbitrary `-o` options to ssh. So,
+// we put a wrapper `ssh` first on $PATH at startup, which runs the real ssh with a generated
+// config file once there is one. The config file is re-read on every invocation, so hosts can be
+// added to it at any time.
+type HostOptions = Vec<(String, String)>;
+static HOST_OPTIONS: std::sync::Mutex<Vec<(String, HostOptions)>> =
+    std::sync::Mutex::new(Vec::new());
+static WRAPPER_DIR: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+fn find_in_path(bin: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|d| d.join(bin))
+        .find(|p| p.is_file())
+}
+
+/// Put the wrapper `ssh` first on $PATH. This sets an environment variable, so call it before
+/// starting any threads.
+pub fn install_wrapper() -> Result<(), Report> {
+    if WRAPPER_DIR.get().is_some() {
+        return Ok(());
+    }
+
+    let real_ssh = find_in_path("ssh").ok_or_else(|| eyre!("ssh not found in $PATH"))?;
+    let d = std::env::temp_dir().join(format!("burrito-cloud-exp-ssh-{}", std::process::id()));
+    std::fs::create_dir_all(&d)?;
+    let wrapper = d.join("ssh");
+    let config = d.join("config");
+    let _ = std::fs::remove_file(&config);
+    std::fs::write(
+        &wrapper,
+        format!(
+            "#!/bin/sh\n[ -f '{cfg}' ] && exec '{ssh}' -F '{cfg}' \"$@\"\nexec '{ssh}' \"$@\"\n",
+            ssh = real_ssh.display(),
+            cfg = config.display()
+        ),
+    )?;
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let path =
+        std::env::join_paths(std::iter::once(d.clone()).chain(std::env::split_paths(&path)))?;
+    std::env::set_var("PATH", path);
+    debug!(dir = ?d, ?real_ssh, "installed ssh wrapper");
+    let _ = WRAPPER_DIR.set(d);
+    Ok(())
+}
+
+/// Apply extra ssh options (as in `ssh_config`) to every connection to `host`.
+pub fn set_host_options(host: &str, opts: HostOptions) -> Result<(), Report> {
+    if opts.is_empty() {
+        return Ok(());
+    }
+
+    let dir = WRAPPER_DIR
+        .get()
+        .ok_or_else(|| eyre!("the ssh wrapper isn't installed"))?;
+    let mut hosts = HOST_OPTIONS.lock().unwrap();
+    hosts.retain(|(h, _)| h != host);
+    hosts.push((host.to_owned(), opts));
+
+    let mut cfg = String::new();
+    for (h, opts) in hosts.iter() {
+        cfg.push_str(&format!("Host {}\n", h));
+        for (k, v) in opts {
+            cfg.push_str(&format!("    {} {}\n", k, v));
+        }
+    }
+
+    // -F replaces the usual config files, so pull them back in after our entries.
+    cfg.push_str("Match all\n    Include ~/.ssh/config\n    Include /etc/ssh/ssh_config\n");
+    // write-then-rename, so a concurrent ssh never reads half a config.
+    let tmp = dir.join("config.tmp");
+    std::fs::write(&tmp, cfg)?;
+    std::fs::rename(&tmp, dir.join("config"))?;
+    Ok(())
+}
@@ -0,0 +1,237 @@
+//! Checkpointing a run's progress to a state file, so a crashed run can be resumed.
+
+use crate::exp::RepResult;
+use crate::ssh::ConnInfo;
+use crate::tags::Cloud;
+use crate::transfer::Throughput;
+use crate::STATUS;
+use color_eyre::eyre::{Report, WrapErr};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    #[default]
+    Pending,
+    Launching,
+    Running,
+    Done,
+    Failed,
+}
+
+/// A machine we may be able to pick up where we left off on.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct Instance {
+    pub conn: ConnInfo,
+    /// Set if we launched it, and so need to terminate it.
+    pub cloud: Option<Cloud>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct NodeState {
+    pub phase: Phase,
+    /// The set-up machine currently running this node's experiments.
+    pub instance: Option<Instance>,
+    /// Repetitions that have been fully collected, in order.
+    pub done: Vec<RepResult>,
+    /// The repetition whose script has been started remotely but not yet collected.
+    pub started_rep: Option<usize>,
+    /// Files of `started_rep` already fetched.
+    pub fetched: Vec<String>,
+    /// When `started_rep` was started, by the machine's clock, in seconds since the epoch.
+    pub started_at: Option<u64>,
+    /// Uploads to every machine this node ran on.
+    pub uploaded: Throughput,
+    /// Seconds spent in each phase (`launch`, `setup.pip`, `run`, ...), summed over the node's
+    /// machines and launches.
+    pub timings: BTreeMap<String, f64>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default)]
+struct RunState {
+    nodes: BTreeMap<String, NodeState>,
+}
+
+/// A handle to the run's state file. Every update is written out immediately.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    state: Arc<Mutex<RunState>>,
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            state: Default::default(),
+        }
+    }
+
+    /// Continue from the state in `path`, and keep writing updates to it.
+    pub fn load(path: &Path) -> Result<Self, Report> {
+        let f =
+            std::fs::File::open(path).wrap_err_with(|| format!("open state file {:?}", path))?;
+        let state = serde_json::from_reader(f).wrap_err("parse state file")?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    pub fn node(&self, id: &str) -> NodeCheckpoint {
+        NodeCheckpoint {
+            ckpt: self.clone(),
+            id: id.to_owned(),
+        }
+    }
+
+    /// The machines nodes are running on, by node id.
+    pub fn instances(&self) -> Vec<(String, Instance)> {
+        let s = self.state.lock().unwrap();
+        s.nodes
+            .iter()
+            .filter_map(|(id, n)| Some((id.clone(), n.instance.clone()?)))
+            .collect()
+    }
+
+    /// Every experiment the script reported as failed, as `(node, rep, file)`.
+    pub fn failed_experiments(&self) -> Vec<(String, usize, String)> {
+        self.done_files(|r| &r.failed)
+    }
+
+    /// Result files that never turned up, as `(node, rep, file)`.
+    pub fn missing_files(&self) -> Vec<(String, usize, String)> {
+        self.done_files(|r| &r.missing)
+    }
+
+    /// Result files that were there but could not be fetched, as `(node, rep, file)`.
+    pub fn unfetched_files(&self) -> Vec<(String, usize, String)> {
+        self.done_files(|r| &r.transfer_failed)
+    }
+
+    /// Everything uploaded, and everything collected, over the run.
+    pub fn throughput(&self) -> (Throughput, Throughput) {
+        let s = self.state.lock().unwrap();
+        let up = s.nodes.values().map(|n| n.uploaded).sum();
+        let down = s
+            .nodes
+            .values()
+            .flat_map(|n| n.done.iter().map(|r| r.collected))
+            .sum();
+        (up, down)
+    }
+
+    /// Every node's phase, by node id.
+    pub fn phases(&self) -> BTreeMap<String, Phase> {
+        let s = self.state.lock().unwrap();
+        s.nodes
+            .iter()
+            .map(|(id, n)| (id.clone(), n.phase))
+            .collect()
+    }
+
+    /// Every node's finished repetitions, by node id.
+    pub fn reps(&self) -> BTreeMap<String, Vec<RepResult>> {
+        let s = self.state.lock().unwrap();
+        s.nodes
+            .iter()
+            .map(|(id, n)| (id.clone(), n.done.clone()))
+            .collect()
+    }
+
+    /// Every node's phase timings, by node id.
+    pub fn timings(&self) -> BTreeMap<String, BTreeMap<String, f64>> {
+        let s = self.state.lock().unwrap();
+        s.nodes
+            .iter()
+            .filter(|(_, n)| !n.timings.is_empty())
+            .map(|(id, n)| (id.clone(), n.timings.clone()))
+            .collect()
+    }
+
+    fn done_files(
+        &self,
+        which: impl Fn(&RepResult) -> &Vec<String>,
+    ) -> Vec<(String, usize, String)> {
+        let which = &which;
+        let s = self.state.lock().unwrap();
+        s.nodes
+            .iter()
+            .flat_map(|(id, n)| {
+                n.done
+                    .iter()
+                    .flat_map(move |r| which(r).iter().map(move |f| (id.clone(), r.rep, f.clone())))
+            })
+            .collect()
+    }
+
+    fn write(&self, state: &RunState) -> Result<(), Report> {
+        if let Some(d) = self.path.parent() {
+            std::fs::create_dir_all(d)?;
+        }
+
+        // write-then-rename, so a crash never leaves a truncated state file.
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// One node's part of the checkpoint.
+#[derive(Clone, Debug)]
+pub struct NodeCheckpoint {
+    ckpt: Checkpoint,
+    id: String,
+}
+
+impl NodeCheckpoint {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get(&self) -> NodeState {
+        let s = self.ckpt.state.lock().unwrap();
+        s.nodes.get(&self.id).cloned().unwrap_or_default()
+    }
+
+    /// Update this node's state and write the state file.
+    ///
+    /// Failing to checkpoint isn't worth aborting the experiment over, so errors are only logged.
+    pub fn update(&self, f: impl FnOnce(&mut NodeState)) {
+        let mut s = self.ckpt.state.lock().unwrap();
+        let n = s.nodes.entry(self.id.clone()).or_default();
+        let was = n.phase;
+        f(n);
+        if n.phase != was {
+            info!(target: STATUS, node = %self.id, phase = ?n.phase, "node phase");
+        }
+
+        if let Err(err) = self.ckpt.write(&s) {
+            warn!(?err, path = ?self.ckpt.path, "could not write state file");
+        }
+    }
+
+    /// Add `d` to the time spent in `phase`.
+    pub fn add_time(&self, phase: &str, d: Duration) {
+        debug!(?phase, took = ?d, "phase done");
+        self.update(|s| *s.timings.entry(phase.to_owned()).or_default() += d.as_secs_f64());
+    }
+}
+
+/// Run `f`, adding how long it took to `phase` of `ckpt`'s node, if there is one.
+pub async fn timed<F: Future>(ckpt: Option<&NodeCheckpoint>, phase: &str, f: F) -> F::Output {
+    let start = Instant::now();
+    let out = f.await;
+    if let Some(c) = ckpt {
+        c.add_time(phase, start.elapsed());
+    }
+
+    out
+}
@@ -0,0 +1,303 @@
+//! Quick summary statistics over collected `.data` files.
+//!
+//! `.data` files are space-separated with a header row; we summarize the `req_latency_us` column.
+
+use crate::exp::ExpStatuses;
+use crate::progress::WallTimes;
+use crate::throttle::Monitor;
+use crate::transfer::Throughput;
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::{info, warn};
+
+const LATENCY_COLUMN: &str = "req_latency_us";
+
+/// Latency statistics for one experiment configuration.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub count: usize,
+    pub mean: f64,
+    pub stddev: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub p99: f64,
+}
+
+impl Stats {
+    pub fn from_samples(mut samples: Vec<f64>) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        samples.sort_by(f64::total_cmp);
+        let n = samples.len();
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let var = if n > 1 {
+            samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64
+        } else {
+            0.
+        };
+        // nearest-rank percentiles.
+        let pct = |p: f64| samples[((p * n as f64).ceil() as usize).clamp(1, n) - 1];
+        Some(Self {
+            count: n,
+            mean,
+            stddev: var.sqrt(),
+            p50: pct(0.50),
+            p95: pct(0.95),
+            p99: pct(0.99),
+        })
+    }
+}
+
+/// Read the latency samples from a `.data` file.
+pub fn read_latencies(path: &Path) -> Result<Vec<f64>, Report> {
+    let contents =
+        std::fs::read_to_string(path).wrap_err_with(|| format!("read {}", path.display()))?;
+    let mut lines = contents.lines();
+    let header = lines
+        .next()
+        .ok_or_else(|| eyre!("{} is empty", path.display()))?;
+    let ncols = header.split_whitespace().count();
+    let col = header
+        .split_whitespace()
+        .position(|c| c == LATENCY_COLUMN)
+        .ok_or_else(|| eyre!("{} has no {} column", path.display(), LATENCY_COLUMN))?;
+
+    let mut samples = vec![];
+    for (i, l) in lines.enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let fields: Vec<&str> = l.split_whitespace().collect();
+        ensure!(
+            fields.len() == ncols,
+            "{}:{}: expected {} columns, got {}",
+            path.display(),
+            i + 2,
+            ncols,
+            fields.len()
+        );
+        let v = fields[col];
+        let x: f64 = v
+            .parse()
+            .wrap_err_with(|| format!("{}:{}: parse {:?}", path.display(), i + 2, v))?;
+        ensure!(
+            x.is_finite(),
+            "{}:{}: latency {:?} is not a number",
+            path.display(),
+            i + 2,
+            v
+        );
+        samples.push(x);
+    }
+
+    ensure!(!samples.is_empty(), "{} has no samples", path.display());
+    Ok(samples)
+}
+
+/// Summarize each of `files` (in `dir`) into `dir/summary.csv`, along with how long each took to
+/// run, whether the script said it succeeded, whether the machine throttled it, how fast it was
+/// collected, and whether the script stalled on it. Files that don't parse are listed, but marked
+/// invalid and without statistics.
+pub fn write_summary(
+    dir: &Path,
+    files: &[String],
+    walls: &WallTimes,
+    statuses: &ExpStatuses,
+    monitor: &Monitor,
+    rates: &BTreeMap<String, Throughput>,
+    stalled: &[String],
+) -> Result<(), Report> {
+    let mut out = String::from(
+        "experiment,valid,count,mean,stddev,p50,p95,p99,wall_secs,script_ok,steal_pct,min_credits,throttled,fetch_mb_per_s,stalled\n",
+    );
+    let fetch_rate = |f: &String| {
+        rates
+            .get(f)
+            .and_then(Throughput::mb_per_s)
+            .map(|r| format!("{:.2}", r))
+            .unwrap_or_default()
+    };
+    let throttling = |f: &String| match monitor.exps().get(f) {
+        Some(t) => format!(
+            "{},{},{}",
+            t.steal_pct()
+                .map(|s| format!("{:.1}", s))
+                .unwrap_or_default(),
+            t.min_credits
+                .map(|c| format!("{:.1}", c))
+                .unwrap_or_default(),
+            monitor.is_throttled(t)
+        ),
+        None => ",,".to_owned(),
+    };
+    for f in files {
+        let name = f.trim_end_matches(".data");
+        let wall = walls
+            .get(f)
+            .map(|w| format!("{:.1}", w))
+            .unwrap_or_default();
+        let script_ok = statuses
+            .get(f)
+            .map(|st| st.ok.to_string())
+            .unwrap_or_default();
+        let stats = match read_latencies(&dir.join(f)) {
+            Ok(s) => Stats::from_samples(s).unwrap(),
+            Err(err) => {
+                warn!(?err, file = ?f, "invalid result file");
+                out.push_str(&format!(
+                    "{},false,,,,,,,{},{},{},{},{}\n",
+                    name,
+                    wall,
+                    script_ok,
+                    throttling(f),
+                    fetch_rate(f),
+                    stalled.contains(f)
+                ));
+                continue;
+            }
+        };
+
+        out.push_str(&format!(
+            "{},true,{},{:.1},{:.1},{},{},{},{},{},{},{},{}\n",
+            name,
+            stats.count,
+            stats.mean,
+            stats.stddev,
+            stats.p50,
+            stats.p95,
+            stats.p99,
+            wall,
+            script_ok,
+            throttling(f),
+            fetch_rate(f),
+            stalled.contains(f)
+        ));
+    }
+
+    // experiments that failed without producing anything.
+    for (f, st) in statuses.iter().filter(|(f, _)| !files.contains(f)) {
+        let wall = walls
+            .get(f)
+            .map(|w| format!("{:.1}", w))
+            .unwrap_or_default();
+        out.push_str(&format!(
+            "{},false,,,,,,,{},{},{},,{}\n",
+            f.trim_end_matches(".data"),
+            wall,
+            st.ok,
+            throttling(f),
+            stalled.contains(f)
+        ));
+    }
+
+    let path = dir.join("summary.csv");
+    std::fs::write(&path, out).wrap_err("write summary")?;
+    info!(?path, "wrote summary");
+    Ok(())
+}
+
+/// The phases [`write_timings`] lays out first, in the order they happen.
+const PHASES: &[&str] = &[
+    "ami_lookup",
+    "launch",
+    "setup",
+    "setup.packages",
+    "setup.pip",
+    "setup.upload",
+    "ready",
+    "run",
+    "collect",
+];
+
+/// Write each node's phase timings to `out_dir/timings.csv`, and return them as a table to print.
+pub fn write_timings(
+    out_dir: &Path,
+    timings: &BTreeMap<String, BTreeMap<String, f64>>,
+) -> Result<String, Report> {
+    let mut phases: Vec<&str> = PHASES.to_vec();
+    for t in timings.values() {
+        for p in t.keys() {
+            if !phases.contains(&p.as_str()) {
+                phases.push(p);
+            }
+        }
+    }
+
+    let mut csv = format!("node,{}\n", phases.join(","));
+    let width = timings.keys().map(String::len).max().unwrap_or(0).max(4);
+    let mut table = format!("{:<width$}", "node", width = width);
+    for p in &phases {
+        table.push_str(&format!(" {:>14}", p));
+    }
+
+    table.push('\n');
+    for (node, t) in timings {
+        let secs: Vec<String> = phases
+            .iter()
+            .map(|p| t.get(*p).map(|s| format!("{:.1}", s)).unwrap_or_default())
+            .collect();
+        csv.push_str(&format!("{},{}\n", node, secs.join(",")));
+        table.push_str(&format!("{:<width$}", node, width = width));
+        for s in &secs {
+            table.push_str(&format!(" {:>14}", if s.is_empty() { "-" } else { s }));
+        }
+
+        table.push('\n');
+    }
+
+    std::fs::create_dir_all(out_dir)?;
+    let path = out_dir.join("timings.csv");
+    std::fs::write(&path, csv).wrap_err("write timings")?;
+    info!(?path, "wrote phase timings");
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_percentiles() {
+        let s = Stats::from_samples((1..=100).rev().map(f64::from).collect()).unwrap();
+        assert_eq!(s.count, 100);
+        assert_eq!(s.mean, 50.5);
+        assert_eq!((s.p50, s.p95, s.p99), (50., 95., 99.));
+    }
+
+    #[test]
+    fn stats_one_sample() {
+        let s = Stats::from_samples(vec![7.]).unwrap();
+        assert_eq!((s.stddev, s.p50, s.p99), (0., 7., 7.));
+        assert!(Stats::from_samples(vec![]).is_none());
+    }
+
+    #[test]
+    fn stats_nan_does_not_panic() {
+        assert!(Stats::from_samples(vec![3., f64::NAN, 1.]).is_some());
+    }
+
+    fn data(name: &str, contents: &str) -> Result<Vec<f64>, Report> {
+        let dir = std::env::temp_dir().join(format!("burrito-summary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("{}.data", name));
+        std::fs::write(&path, contents).unwrap();
+        let res = read_latencies(&path);
+        let _ = std::fs::remove_file(&path);
+        res
+    }
+
+    #[test]
+    fn latencies_by_column() {
+        let got = data("good", "rcvrs req_latency_us\n1 10.5\n\n2 12\n").unwrap();
+        assert_eq!(got, vec![10.5, 12.]);
+    }
+
+    #[test]
+    fn latencies_reject_bad_files() {
+        assert!(data("empty", "").is_err());
+        assert!(data("no-column", "rcvrs other\n1 2\n").is_err());
+        assert!(data("short", "rcvrs req_latency_us\n1\n").is_err());
+        assert!(data("nan", "rcvrs req_latency_us\n1 NaN\n").is_err());
+        assert!(data("no-samples", "rcvrs req_latency_us\n").is_err());
+    }
+}
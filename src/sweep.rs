@@ -0,0 +1,329 @@
+//! The experiment script's parameter sweep, and filtering it.
+
+use color_eyre::eyre::{bail, eyre, Report};
+
+/// The parameters of one experiment, as encoded in its result file's name:
+/// `exp-{provider}-{be|ord:{groups}g}-{inter_req}ms-{rcvrs}rcvrs-{batch}batch-{type}-{impl}.data`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExpParams {
+    pub provider: String,
+    /// `None` for best-effort.
+    pub groups: Option<usize>,
+    pub inter_req_ms: usize,
+    pub rcvrs: usize,
+    pub batch: usize,
+    pub batch_type: String,
+    pub imp: String,
+}
+
+pub const PARAM_COLUMNS: [&str; 7] = [
+    "provider",
+    "groups",
+    "inter_req_ms",
+    "rcvrs",
+    "batch",
+    "batch_type",
+    "impl",
+];
+
+impl ExpParams {
+    pub fn from_filename(fname: &str) -> Result<Self, Report> {
+        let err = || eyre!("unexpected result file name {:?}", fname);
+        let stem = fname
+            .strip_prefix("exp-")
+            .and_then(|s| s.strip_suffix(".data"))
+            // without any `@<stamp>` of the build that produced it.
+            .map(|s| s.split_once('@').map_or(s, |(s, _)| s))
+            .ok_or_else(err)?;
+        // the provider name may itself contain '-', so parse from the right.
+        let mut parts = stem.rsplitn(7, '-');
+        let mut next = || parts.next().ok_or_else(err);
+        let imp = next()?.to_owned();
+        let batch_type = next()?.to_owned();
+        let num = |s: &str, suffix: &str| -> Result<usize, Report> {
+            s.strip_suffix(suffix)
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(err)
+        };
+        let batch = num(next()?, "batch")?;
+        let rcvrs = num(next()?, "rcvrs")?;
+        let inter_req_ms = num(next()?, "ms")?;
+        let groups = match next()? {
+            "be" => None,
+            o => Some(num(o.strip_prefix("ord:").ok_or_else(err)?, "g")?),
+        };
+        let provider = next()?.to_owned();
+        Ok(Self {
+            provider,
+            groups,
+            inter_req_ms,
+            rcvrs,
+            batch,
+            batch_type,
+            imp,
+        })
+    }
+
+    pub fn filename(&self) -> String {
+        format!("exp-{}.data", self.name())
+    }
+
+    /// The file name stem this was parsed from, without the `exp-` prefix.
+    pub fn name(&self) -> String {
+        let mode = match self.groups {
+            None => "be".to_owned(),
+            Some(g) => format!("ord:{}g", g),
+        };
+        format!(
+            "{}-{}-{}ms-{}rcvrs-{}batch-{}-{}",
+            self.provider,
+            mode,
+            self.inter_req_ms,
+            self.rcvrs,
+            self.batch,
+            self.batch_type,
+            self.imp
+        )
+    }
+
+    pub fn columns(&self) -> [String; 7] {
+        [
+            self.provider.clone(),
+            self.groups.map(|g| g.to_string()).unwrap_or_default(),
+            self.inter_req_ms.to_string(),
+            self.rcvrs.to_string(),
+            self.batch.to_string(),
+            self.batch_type.clone(),
+            self.imp.clone(),
+        ]
+    }
+}
+
+/// The experiments the script's sweep runs for provider `prov`.
+pub fn expected(prov: &str) -> Vec<ExpParams> {
+    //let inter_req_times = [0, 25, 50, 75, 100];
+    let inter_req_times = [75];
+    let num_receivers = [1, 2, 5, 10];
+    let num_groups = [0, 1, 2, 5, 10]; // + be
+    let batch_sizes = [1, 5, 10];
+    let batch_types = ["loop", "opt"];
+    let impls = ["client", "service"];
+
+    let mut exps = vec![];
+    for inter_req in &inter_req_times[..] {
+        for batch_size in &batch_sizes[..] {
+            for batch_type in &batch_types[..] {
+                for rcvrs in &num_receivers[..] {
+                    let groups = std::iter::once(None).chain(num_groups.iter().map(|g| Some(*g)));
+                    for grps in groups {
+                        for imp in impls {
+                            exps.push(ExpParams {
+                                provider: prov.to_owned(),
+                                groups: grps,
+                                inter_req_ms: *inter_req,
+                                rcvrs: *rcvrs,
+                                batch: *batch_size,
+                                batch_type: batch_type.to_string(),
+                                imp: imp.to_owned(),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    exps
+}
+
+const FILTER_KEYS: [&str; 7] = [
+    "provider",
+    "groups",
+    "inter_req",
+    "rcvrs",
+    "batch",
+    "type",
+    "impl",
+];
+
+/// A conjunction of `key=value` conditions on experiment parameters, e.g. `rcvrs=10,groups=be`.
+///
+/// `groups` is `be` or a number of groups. `batch` matches either the batch size or the batch type.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct Filter(Vec<(String, String)>);
+
+impl std::str::FromStr for Filter {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut conds = vec![];
+        for c in s.split(',').filter(|c| !c.is_empty()) {
+            let mut kv = c.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if FILTER_KEYS.contains(&k) => {
+                    conds.push((k.to_owned(), v.to_owned()))
+                }
+                (Some(k), Some(_)) => bail!(
+                    "unknown filter key {:?}, expected one of {:?}",
+                    k,
+                    FILTER_KEYS
+                ),
+                _ => {
+                    return Err(eyre!(
+                        "filter condition {:?} is not of the form key=value",
+                        c
+                    ))
+                }
+            }
+        }
+
+        Ok(Self(conds))
+    }
+}
+
+// in node configs, filters are written as on the command line.
+impl<'de> serde::Deserialize<'de> for Filter {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, p: &ExpParams) -> bool {
+        self.0.iter().all(|(k, v)| match k.as_str() {
+            "provider" => p.provider == *v,
+            "groups" => match p.groups {
+                None => v == "be",
+                Some(g) => g.to_string() == *v,
+            },
+            "inter_req" => p.inter_req_ms.to_string() == v.trim_end_matches("ms"),
+            "rcvrs" => p.rcvrs.to_string() == *v,
+            "batch" => p.batch.to_string() == *v || p.batch_type == *v,
+            "type" => p.batch_type == *v,
+            "impl" => p.imp == *v,
+            _ => unreachable!(),
+        })
+    }
+}
+
+/// Which parts of the sweep to run.
+#[derive(serde::Serialize, Clone, Debug, Default)]
+pub struct Filters {
+    /// If non-empty, only experiments matching one of these.
+    pub only: Vec<Filter>,
+    /// Never experiments matching any of these.
+    pub skip: Vec<Filter>,
+}
+
+impl Filters {
+    pub fn is_empty(&self) -> bool {
+        self.only.is_empty() && self.skip.is_empty()
+    }
+
+    pub fn includes(&self, p: &ExpParams) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|f| f.matches(p)))
+            && !self.skip.iter().any(|f| f.matches(p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filename_roundtrip() {
+        for p in expected("aws-us-east-1") {
+            assert_eq!(ExpParams::from_filename(&p.filename()).unwrap(), p);
+        }
+
+        let p =
+            ExpParams::from_filename("exp-gcp-ord:5g-75ms-10rcvrs-1batch-opt-client.data").unwrap();
+        assert_eq!(p.provider, "gcp");
+        assert_eq!(p.groups, Some(5));
+        assert_eq!((p.inter_req_ms, p.rcvrs, p.batch), (75, 10, 1));
+        assert_eq!((&p.batch_type[..], &p.imp[..]), ("opt", "client"));
+    }
+
+    #[test]
+    fn filename_with_stamp() {
+        let p = ExpParams::from_filename("exp-gcp-be-75ms-2rcvrs-5batch-loop-service@1a2b3c.data")
+            .unwrap();
+        assert_eq!(p.groups, None);
+        assert_eq!(p.imp, "service");
+        assert_eq!(
+            p.filename(),
+            "exp-gcp-be-75ms-2rcvrs-5batch-loop-service.data"
+        );
+    }
+
+    #[test]
+    fn bad_filenames() {
+        for f in [
+            "gcp-be-75ms-2rcvrs-5batch-loop-service.data",
+            "exp-gcp-be-75ms-2rcvrs-5batch-loop-service.csv",
+            "exp-gcp-be-75-2rcvrs-5batch-loop-service.data",
+            "exp-gcp-5g-75ms-2rcvrs-5batch-loop-service.data",
+            "exp-be-75ms-2rcvrs-5batch-loop-service.data",
+        ] {
+            assert!(ExpParams::from_filename(f).is_err(), "{:?} parsed", f);
+        }
+    }
+
+    fn params(groups: Option<usize>, batch: usize, batch_type: &str) -> ExpParams {
+        ExpParams {
+            provider: "aws".to_owned(),
+            groups,
+            inter_req_ms: 75,
+            rcvrs: 10,
+            batch,
+            batch_type: batch_type.to_owned(),
+            imp: "client".to_owned(),
+        }
+    }
+
+    #[test]
+    fn filter_parse() {
+        assert!("".parse::<Filter>().unwrap().0.is_empty());
+        let f: Filter = "rcvrs=10,groups=be".parse().unwrap();
+        assert_eq!(f.0.len(), 2);
+        assert!("color=red".parse::<Filter>().is_err());
+        assert!("rcvrs".parse::<Filter>().is_err());
+        assert!(serde_json::from_str::<Filter>("\"impl=client\"").is_ok());
+        assert!(serde_json::from_str::<Filter>("\"impl\"").is_err());
+    }
+
+    #[test]
+    fn filter_matches() {
+        let f = |s: &str| s.parse::<Filter>().unwrap();
+        let be = params(None, 5, "loop");
+        let ord = params(Some(2), 1, "opt");
+        assert!(f("").matches(&be));
+        assert!(f("groups=be").matches(&be) && !f("groups=be").matches(&ord));
+        assert!(f("groups=2").matches(&ord) && !f("groups=2").matches(&be));
+        assert!(f("inter_req=75ms").matches(&be) && f("inter_req=75").matches(&be));
+        assert!(f("batch=5").matches(&be) && f("batch=loop").matches(&be));
+        assert!(!f("batch=opt").matches(&be) && f("type=opt").matches(&ord));
+        assert!(f("rcvrs=10,impl=client").matches(&be));
+        assert!(!f("rcvrs=10,impl=service").matches(&be));
+    }
+
+    #[test]
+    fn filters_include() {
+        let f = |s: &str| s.parse::<Filter>().unwrap();
+        let be = params(None, 5, "loop");
+        let ord = params(Some(2), 1, "opt");
+        let all = Filters::default();
+        assert!(all.is_empty() && all.includes(&be));
+        let only = Filters {
+            only: vec![f("groups=be"), f("batch=1")],
+            skip: vec![],
+        };
+        assert!(only.includes(&be) && only.includes(&ord));
+        let skip = Filters {
+            only: vec![f("rcvrs=10")],
+            skip: vec![f("type=opt")],
+        };
+        assert!(skip.includes(&be) && !skip.includes(&ord));
+    }
+}
@@ -0,0 +1,289 @@
+//! Tag launched cloud resources so cost dashboards and cleanup scripts can attribute them, and
+//! terminate instances tsunami is no longer managing (after resuming a run).
+//!
+//! tsunami doesn't tell us the ids of the resources it creates, so we look them up by the
+//! machine's public IP.
+
+use crate::azure;
+use crate::oci;
+use crate::openstack;
+use crate::ratelimit;
+use crate::vps;
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use rusoto_core::credential::ProfileProvider;
+use rusoto_core::request::HttpClient;
+use rusoto_ec2::Ec2;
+use std::collections::BTreeMap;
+use tracing::{debug, info, instrument};
+
+pub type Tags = BTreeMap<String, String>;
+
+/// Applied to everything we launch, in addition to the configured tags.
+const MARKER_TAG: &str = "burrito-cloud-exp";
+
+/// Parse a `key=value` tag from the command line.
+pub fn parse_tag(s: &str) -> Result<(String, String), Report> {
+    let mut kv = s.splitn(2, '=');
+    match (kv.next(), kv.next()) {
+        (Some(k), Some(v)) if !k.is_empty() => Ok((k.to_owned(), v.to_owned())),
+        _ => bail!("tag {:?} is not of the form key=value", s),
+    }
+}
+
+/// Options passed through to a provider's launch request as they are, for knobs we don't model.
+pub type ProviderOpts = serde_json::Map<String, serde_json::Value>;
+
+/// `opts` as command-line options: `key: value` is `--key value` (with anything but a string as
+/// JSON), and `key: null` is just `--key`.
+pub fn cli_opts(opts: &ProviderOpts) -> Vec<String> {
+    let mut args = vec![];
+    for (k, v) in opts {
+        args.push(format!("--{}", k));
+        match v {
+            serde_json::Value::Null => (),
+            serde_json::Value::String(s) => args.push(s.clone()),
+            v => args.push(v.to_string()),
+        }
+    }
+
+    args
+}
+
+/// Merge `opts` into the JSON object `body`, replacing what was there.
+pub fn merge_opts(body: &mut serde_json::Value, opts: &ProviderOpts) {
+    if let Some(b) = body.as_object_mut() {
+        b.extend(opts.clone());
+    }
+}
+
+pub fn with_marker(tags: &Tags) -> Tags {
+    let mut t = tags.clone();
+    t.entry(MARKER_TAG.to_owned())
+        .or_insert_with(|| "true".to_owned());
+    t
+}
+
+pub fn ec2_client(region: &str, profile: Option<&str>) -> Result<rusoto_ec2::Ec2Client, Report> {
+    Ok(match profile {
+        None => rusoto_ec2::Ec2Client::new(region.parse()?),
+        Some(p) => {
+            let mut creds = ProfileProvider::new()?;
+            creds.set_profile(p);
+            rusoto_ec2::Ec2Client::new_with(HttpClient::new()?, creds, region.parse()?)
+        }
+    })
+}
+
+/// The running EC2 instances with public IP `public_ip`.
+pub async fn find_instances(
+    client: &rusoto_ec2::Ec2Client,
+    public_ip: &str,
+) -> Result<Vec<rusoto_ec2::Instance>, Report> {
+    let filter = |name: &str, value: &str| rusoto_ec2::Filter {
+        name: Some(name.to_owned()),
+        values: Some(vec![value.to_owned()]),
+    };
+    let resp = client
+        .describe_instances(rusoto_ec2::DescribeInstancesRequest {
+            filters: Some(vec![
+                filter("ip-address", public_ip),
+                filter("instance-state-name", "running"),
+            ]),
+            ..Default::default()
+        })
+        .await
+        .wrap_err("describe instances")?;
+    Ok(resp
+        .reservations
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|r| r.instances.unwrap_or_default())
+        .collect())
+}
+
+/// Tag the EC2 instance with public IP `public_ip`, and its volumes.
+#[instrument(skip(tags), level = "debug")]
+pub async fn tag_aws(
+    region: &str,
+    profile: Option<&str>,
+    public_ip: &str,
+    tags: &Tags,
+) -> Result<(), Report> {
+    let client = ec2_client(region, profile)?;
+    let mut resources = vec![];
+    for inst in find_instances(&client, public_ip).await? {
+        resources.extend(inst.instance_id);
+        resources.extend(
+            inst.block_device_mappings
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|b| b.ebs.and_then(|e| e.volume_id)),
+        );
+    }
+
+    ensure!(!resources.is_empty(), "no instance with ip {}", public_ip);
+    debug!(?resources, "tagging");
+    client
+        .create_tags(rusoto_ec2::CreateTagsRequest {
+            resources,
+            tags: with_marker(tags)
+                .into_iter()
+                .map(|(k, v)| rusoto_ec2::Tag {
+                    key: Some(k),
+                    value: Some(v),
+                })
+                .collect(),
+            ..Default::default()
+        })
+        .await
+        .wrap_err("create tags")?;
+    info!("tagged instance");
+    Ok(())
+}
+
+/// Terminate the EC2 instance with public IP `public_ip`.
+///
+/// The security group and key pair tsunami made for it are left behind.
+#[instrument(level = "debug")]
+pub async fn terminate_aws(
+    region: &str,
+    profile: Option<&str>,
+    public_ip: &str,
+) -> Result<(), Report> {
+    let client = ec2_client(region, profile)?;
+    let instance_ids: Vec<String> = find_instances(&client, public_ip)
+        .await?
+        .into_iter()
+        .filter_map(|i| i.instance_id)
+        .collect();
+    ensure!(
+        !instance_ids.is_empty(),
+        "no instance with ip {}",
+        public_ip
+    );
+    client
+        .terminate_instances(rusoto_ec2::TerminateInstancesRequest {
+            instance_ids: instance_ids.clone(),
+            ..Default::default()
+        })
+        .await
+        .wrap_err("terminate instances")?;
+    info!(?instance_ids, "terminated instance");
+    Ok(())
+}
+
+/// The resource group and name of the Azure VM with public IP `public_ip`.
+pub async fn azure_vm(public_ip: &str) -> Result<(String, String), Report> {
+    let query = format!("[?publicIps=='{}'].[resourceGroup, name]", public_ip);
+    let vms = azure::az(&["vm", "list", "-d", "-o", "tsv", "--query", &query]).await?;
+    let mut vm = vms
+        .lines()
+        .next()
+        .ok_or_else(|| eyre!("no vm with ip {}", public_ip))?
+        .split('\t');
+    match (vm.next(), vm.next()) {
+        (Some(rg), Some(name)) => Ok((rg.to_owned(), name.to_owned())),
+        _ => bail!("unexpected az vm list output {:?}", vms),
+    }
+}
+
+/// Tag the resource group containing the Azure VM with public IP `public_ip`.
+#[instrument(skip(tags), level = "debug")]
+pub async fn tag_azure(public_ip: &str, tags: &Tags) -> Result<(), Report> {
+    let (rg, name) = azure_vm(public_ip).await?;
+    if azure::is_shared(&rg, &name).await? {
+        debug!(
+            ?rg,
+            "vm in a shared resource group was tagged when launched"
+        );
+        return Ok(());
+    }
+
+    let tags: Vec<String> = with_marker(tags)
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    let mut args = vec!["group", "update", "--name", &rg, "--tags"];
+    args.extend(tags.iter().map(String::as_str));
+    azure::az(&args).await?;
+    info!(?rg, "tagged resource group");
+    Ok(())
+}
+
+/// Delete the resource group containing the Azure VM with public IP `public_ip`, or if we
+/// launched it into a group we didn't make, just the VM.
+#[instrument(level = "debug")]
+pub async fn terminate_azure(public_ip: &str) -> Result<(), Report> {
+    let (rg, name) = azure_vm(public_ip).await?;
+    if azure::is_shared(&rg, &name).await? {
+        return azure::delete_vm(&rg, &name).await;
+    }
+
+    azure::az(&["group", "delete", "--yes", "--no-wait", "--name", &rg]).await?;
+    info!(?rg, "deleted resource group");
+    Ok(())
+}
+
+/// Which provider's API to go through.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub enum Cloud {
+    Aws {
+        region: String,
+        profile: Option<String>,
+    },
+    Azure,
+    Linode,
+    Vultr,
+    Oci {
+        region: String,
+        compartment_id: String,
+        profile: Option<String>,
+    },
+    OpenStack {
+        auth_url: String,
+    },
+}
+
+impl Cloud {
+    pub async fn tag(&self, public_ip: &str, tags: &Tags) -> Result<(), Report> {
+        ratelimit::acquire(self).await;
+        match self {
+            Cloud::Aws { region, profile } => {
+                tag_aws(region, profile.as_deref(), public_ip, tags).await
+            }
+            Cloud::Azure => tag_azure(public_ip, tags).await,
+            Cloud::Linode | Cloud::Vultr => vps::tag(self, public_ip, tags).await,
+            Cloud::Oci { .. } => oci::tag(self, public_ip, tags).await,
+            Cloud::OpenStack { .. } => openstack::tag(self, public_ip, tags).await,
+        }
+    }
+
+    pub async fn terminate(&self, public_ip: &str) -> Result<(), Report> {
+        ratelimit::acquire(self).await;
+        match self {
+            Cloud::Aws { region, profile } => {
+                terminate_aws(region, profile.as_deref(), public_ip).await
+            }
+            Cloud::Azure => terminate_azure(public_ip).await,
+            Cloud::Linode | Cloud::Vultr => vps::terminate(self, public_ip).await,
+            Cloud::Oci { .. } => oci::terminate(self, public_ip).await,
+            Cloud::OpenStack { .. } => openstack::terminate(self, public_ip).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let tag = |k: &str, v: &str| (k.to_owned(), v.to_owned());
+        assert_eq!(parse_tag("owner=alice").unwrap(), tag("owner", "alice"));
+        assert_eq!(parse_tag("ttl=").unwrap(), tag("ttl", ""));
+        assert_eq!(parse_tag("note=a=b").unwrap(), tag("note", "a=b"));
+        assert!(parse_tag("owner").is_err());
+        assert!(parse_tag("=alice").is_err());
+        assert!(parse_tag("").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Checking that machines tsunami tore down are really gone: the EC2 instances terminated, and
+//! the Azure resource groups (or VMs, in groups we didn't make) deleted. A teardown that silently
+//! fails keeps billing.
+//!
+//! The resources are looked up (by public IP, see [`crate::tags`]) while the machines are up.
+//! After teardown, they're polled until they're gone; anything still there halfway through is
+//! deleted again, and anything still there at the end fails the node.
+
+use crate::azure;
+use crate::tags::{azure_vm, ec2_client, Cloud};
+use color_eyre::eyre::{bail, eyre, Report, WrapErr};
+use rusoto_ec2::Ec2;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+const POLL: Duration = Duration::from_secs(15);
+/// Azure resource group deletion takes minutes.
+const TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+#[derive(Clone, Debug)]
+pub enum Resource {
+    AwsInstance {
+        region: String,
+        profile: Option<String>,
+        id: String,
+    },
+    AzureGroup(String),
+    /// A VM in a resource group we didn't make.
+    AzureVm {
+        rg: String,
+        name: String,
+    },
+}
+
+/// The resources of the machine with public IP `public_ip`, to check on after teardown.
+pub async fn resources(cloud: &Cloud, public_ip: &str) -> Result<Vec<Resource>, Report> {
+    Ok(match cloud {
+        Cloud::Aws { region, profile } => {
+            let client = ec2_client(region, profile.as_deref())?;
+            crate::tags::find_instances(&client, public_ip)
+                .await?
+                .into_iter()
+                .filter_map(|i| i.instance_id)
+                .map(|id| Resource::AwsInstance {
+                    region: region.clone(),
+                    profile: profile.clone(),
+                    id,
+                })
+                .collect()
+        }
+        Cloud::Azure => {
+            let (rg, name) = azure_vm(public_ip).await?;
+            if azure::is_shared(&rg, &name).await? {
+                vec![Resource::AzureVm { rg, name }]
+            } else {
+                vec![Resource::AzureGroup(rg)]
+            }
+        }
+        _ => vec![],
+    })
+}
+
+impl Resource {
+    async fn gone(&self) -> Result<bool, Report> {
+        match self {
+            Resource::AwsInstance {
+                region,
+                profile,
+                id,
+            } => {
+                let resp = ec2_client(region, profile.as_deref())?
+                    .describe_instances(rusoto_ec2::DescribeInstancesRequest {
+                        instance_ids: Some(vec![id.clone()]),
+                        ..Default::default()
+                    })
+                    .await;
+                // instances that are long gone aren't described at all.
+                let resp = match resp {
+                    Err(e) if e.to_string().contains("InvalidInstanceID.NotFound") => {
+                        return Ok(true)
+                    }
+                    r => r.wrap_err("describe instances")?,
+                };
+                let state = resp
+                    .reservations
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flat_map(|r| r.instances.unwrap_or_default())
+                    .find_map(|i| i.state.and_then(|s| s.name));
+                debug!(?id, ?state, "instance state");
+                Ok(state.is_none_or(|s| s == "terminated"))
+            }
+            Resource::AzureGroup(rg) => {
+                let exists = azure::az(&["group", "exists", "--name", rg]).await?;
+                Ok(exists == "false")
+            }
+            Resource::AzureVm { rg, name } => azure::vm_gone(rg, name).await,
+        }
+    }
+
+    /// Ask for the resource to be deleted again.
+    async fn delete(&self) -> Result<(), Report> {
+        match self {
+            Resource::AwsInstance {
+                region,
+                profile,
+                id,
+            } => {
+                ec2_client(region, profile.as_deref())?
+                    .terminate_instances(rusoto_ec2::TerminateInstancesRequest {
+                        instance_ids: vec![id.clone()],
+                        ..Default::default()
+                    })
+                    .await
+                    .wrap_err("terminate instances")?;
+            }
+            Resource::AzureGroup(rg) => {
+                azure::az(&["group", "delete", "--yes", "--no-wait", "--name", rg]).await?;
+            }
+            Resource::AzureVm { rg, name } => azure::delete_vm(rg, name).await?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Verify `resources` are gone after tsunami's teardown (which returned `terminated`), even if
+/// that failed: verifying deletes whatever is left again, and fails on what still won't go.
+pub async fn after(terminated: Result<(), Report>, resources: &[Resource]) -> Result<(), Report> {
+    match (terminated, verify(resources).await) {
+        (Ok(()), verified) => verified,
+        (Err(t), Ok(())) => Err(t.wrap_err("terminate machines")),
+        (Err(t), Err(v)) => Err(eyre!("terminate machines: {:#}; and then, {:#}", t, v)),
+    }
+}
+
+/// Wait for `resources` to be gone, deleting them again if they're slow to go.
+pub async fn verify(resources: &[Resource]) -> Result<(), Report> {
+    if resources.is_empty() {
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let mut left = resources.to_vec();
+    let mut retried = false;
+    loop {
+        let mut still = vec![];
+        for r in left {
+            match r.gone().await {
+                Ok(true) => (),
+                Ok(false) => still.push(r),
+                Err(err) => {
+                    warn!(?err, resource = ?r, "could not check on torn down resource");
+                    still.push(r);
+                }
+            }
+        }
+
+        left = still;
+        if left.is_empty() {
+            info!(resources = resources.len(), "teardown verified");
+            return Ok(());
+        }
+
+        if !retried && start.elapsed() > TIMEOUT / 2 {
+            retried = true;
+            for r in &left {
+                warn!(resource = ?r, "still there after teardown, deleting it again");
+                if let Err(err) = r.delete().await {
+                    warn!(?err, resource = ?r, "could not delete resource");
+                }
+            }
+        }
+
+        if start.elapsed() > TIMEOUT {
+            for r in &left {
+                error!(resource = ?r, "TEARDOWN FAILED: resource is still there, delete it by hand");
+            }
+
+            bail!(
+                "teardown failed, {} resources are still there and may be billing: {:?}",
+                left.len(),
+                left
+            );
+        }
+
+        tokio::time::sleep(POLL).await;
+    }
+}
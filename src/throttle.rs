@@ -0,0 +1,401 @@
+//! Noticing experiments the machine slowed down under: CPU steal from the hypervisor, and, on
+//! burstable AWS instances (t2, t3, t4g) in standard credit mode, running out of CPU credits.
+//! Either quietly ruins throughput numbers.
+//!
+//! Steal is sampled from `/proc/stat` as the script runs, and attributed to whichever experiment
+//! the script is on. Credit balances come from CloudWatch (through the `aws` CLI), which only
+//! updates them every 5 minutes, so they are checked that often.
+
+use crate::machine::MachineInfo;
+use crate::ratelimit;
+use crate::tags::{ec2_client, find_instances, Cloud};
+use color_eyre::eyre::{ensure, eyre, Report, WrapErr};
+use openssh::Session;
+use rusoto_ec2::Ec2;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct ThrottleCfg {
+    /// Experiments that ran with more CPU steal than this (in percent) are throttled.
+    #[serde(default = "default_max_steal_pct")]
+    pub max_steal_pct: f64,
+    /// Experiments that ran while the CPU credit balance was at or below this are throttled.
+    #[serde(default = "default_min_credits")]
+    pub min_credits: f64,
+    /// Re-run throttled experiments, up to this many times.
+    #[serde(default)]
+    pub rerun: usize,
+}
+
+impl Default for ThrottleCfg {
+    fn default() -> Self {
+        Self {
+            max_steal_pct: default_max_steal_pct(),
+            min_credits: default_min_credits(),
+            rerun: 0,
+        }
+    }
+}
+
+fn default_max_steal_pct() -> f64 {
+    10.0
+}
+
+fn default_min_credits() -> f64 {
+    1.0
+}
+
+const CREDIT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How an experiment's machine behaved while it ran.
+#[derive(Clone, Debug, Default)]
+pub struct ExpThrottle {
+    steal_ticks: u64,
+    total_ticks: u64,
+    /// The lowest CPU credit balance seen, for burstable instances.
+    pub min_credits: Option<f64>,
+}
+
+impl ExpThrottle {
+    pub fn steal_pct(&self) -> Option<f64> {
+        Some(self.steal_ticks as f64 * 100.0 / self.total_ticks as f64)
+            .filter(|_| self.total_ticks > 0)
+    }
+}
+
+pub type Throttling = BTreeMap<String, ExpThrottle>;
+
+/// A burstable instance whose credits can run out.
+#[derive(Debug)]
+struct Credits {
+    cloud: Cloud,
+    instance_id: String,
+    last: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Monitor {
+    cfg: ThrottleCfg,
+    // cumulative (steal, total) ticks at the last sample.
+    last: Option<(u64, u64)>,
+    credits: Option<Credits>,
+    exps: Throttling,
+}
+
+pub fn is_burstable(instance_type: &str) -> bool {
+    ["t2.", "t3.", "t3a.", "t4g."]
+        .iter()
+        .any(|p| instance_type.starts_with(p))
+}
+
+/// What to do about a node on a burstable instance type, which a sweep can run out of credits on.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Burstable {
+    /// Launch it anyway, but say so.
+    #[default]
+    Warn,
+    Allow,
+    /// Switch the instance to unlimited CPU credits before setting it up, so it never throttles
+    /// (bursting past the baseline is billed instead).
+    Unlimited,
+    /// Launch the non-burstable type with the same number of vCPUs instead, see
+    /// [`non_burstable`].
+    Replace,
+    /// Don't launch it.
+    Refuse,
+}
+
+/// The general-purpose, non-burstable instance type with as many vCPUs as `instance_type`, and at
+/// least as much memory.
+pub fn non_burstable(instance_type: &str) -> Option<&'static str> {
+    let (family, size) = instance_type.split_once('.')?;
+    let family = match family {
+        "t2" | "t3" => ["m5.large", "m5.xlarge", "m5.2xlarge"],
+        "t3a" => ["m5a.large", "m5a.xlarge", "m5a.2xlarge"],
+        "t4g" => ["m6g.large", "m6g.xlarge", "m6g.2xlarge"],
+        _ => return None,
+    };
+    match size {
+        "nano" | "micro" | "small" | "medium" | "large" => Some(family[0]),
+        "xlarge" => Some(family[1]),
+        "2xlarge" => Some(family[2]),
+        _ => None,
+    }
+}
+
+/// Switch the EC2 instance with public IP `public_ip` to unlimited CPU credits, if it is
+/// burstable.
+pub async fn set_unlimited(cloud: &Cloud, public_ip: &str) -> Result<(), Report> {
+    let (region, profile) = match cloud {
+        Cloud::Aws { region, profile } => (region, profile),
+        _ => unreachable!(),
+    };
+    let client = ec2_client(region, profile.as_deref())?;
+    ratelimit::acquire(cloud).await;
+    let inst = find_instances(&client, public_ip)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| eyre!("no instance with ip {}", public_ip))?;
+    // the other machines of a multi-machine node needn't be burstable.
+    if !inst.instance_type.as_deref().is_some_and(is_burstable) {
+        debug!(?inst.instance_type, "not burstable, leaving credits alone");
+        return Ok(());
+    }
+
+    let id = inst
+        .instance_id
+        .ok_or_else(|| eyre!("no instance id for {}", public_ip))?;
+    ratelimit::acquire(cloud).await;
+    let resp = client
+        .modify_instance_credit_specification(
+            rusoto_ec2::ModifyInstanceCreditSpecificationRequest {
+                instance_credit_specifications: vec![
+                    rusoto_ec2::InstanceCreditSpecificationRequest {
+                        instance_id: Some(id.clone()),
+                        cpu_credits: Some("unlimited".to_owned()),
+                    },
+                ],
+                ..Default::default()
+            },
+        )
+        .await
+        .wrap_err("modify instance credit specification")?;
+    if let Some(e) = resp
+        .unsuccessful_instance_credit_specifications
+        .and_then(|u| u.into_iter().next())
+    {
+        return Err(eyre!(
+            "could not set {} to unlimited credits: {:?}",
+            id,
+            e.error.and_then(|e| e.message)
+        ));
+    }
+
+    info!(?id, "switched to unlimited cpu credits");
+    Ok(())
+}
+
+impl Monitor {
+    /// Watch the machine described by `info`. Credits are only watched for burstable AWS
+    /// instances in standard mode: unlimited ones are charged for bursting instead.
+    pub async fn new(cfg: ThrottleCfg, cloud: Option<&Cloud>, info: Option<&MachineInfo>) -> Self {
+        let credits = match (cloud, info) {
+            (
+                Some(c @ Cloud::Aws { region, profile }),
+                Some(MachineInfo {
+                    instance_type: Some(it),
+                    instance_id: Some(id),
+                    ..
+                }),
+            ) if is_burstable(it) => match credit_mode(c, region, profile.as_deref(), id).await {
+                Ok(Some(mode)) if mode == "unlimited" => {
+                    debug!(?id, "unlimited burstable instance");
+                    None
+                }
+                Ok(mode) => {
+                    info!(?id, instance_type = ?it, ?mode, "watching cpu credits");
+                    Some(Credits {
+                        cloud: c.clone(),
+                        instance_id: id.clone(),
+                        last: None,
+                    })
+                }
+                Err(err) => {
+                    warn!(?err, "could not get cpu credit mode, not watching credits");
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        Self {
+            cfg,
+            last: None,
+            credits,
+            exps: Default::default(),
+        }
+    }
+
+    /// Sample the machine, attributing what happened since the last sample to `current`.
+    pub async fn sample(&mut self, ssh: &Session, current: Option<&str>) {
+        match proc_stat(ssh).await {
+            Ok(now) => {
+                if let (Some(cur), Some((steal, total))) = (current, self.last) {
+                    let e = self.exps.entry(cur.to_owned()).or_default();
+                    e.steal_ticks += now.0.saturating_sub(steal);
+                    e.total_ticks += now.1.saturating_sub(total);
+                }
+
+                self.last = Some(now);
+            }
+            Err(err) => debug!(?err, "could not sample cpu steal"),
+        }
+
+        let (credits, cur) = match (self.credits.as_mut(), current) {
+            (Some(c), Some(cur)) if c.last.is_none_or(|l| l.elapsed() >= CREDIT_INTERVAL) => {
+                (c, cur)
+            }
+            _ => return,
+        };
+        credits.last = Some(Instant::now());
+        match credit_balance(&credits.cloud, &credits.instance_id).await {
+            Ok(Some(bal)) => {
+                debug!(?bal, exp = ?cur, "cpu credit balance");
+                let e = self.exps.entry(cur.to_owned()).or_default();
+                e.min_credits = Some(e.min_credits.map_or(bal, |m: f64| m.min(bal)));
+            }
+            Ok(None) => debug!("no cpu credit balance yet"),
+            Err(err) => debug!(?err, "could not get cpu credit balance"),
+        }
+    }
+
+    /// Whether `e` ran throttled.
+    pub fn is_throttled(&self, e: &ExpThrottle) -> bool {
+        e.steal_pct().is_some_and(|s| s > self.cfg.max_steal_pct)
+            || e.min_credits.is_some_and(|c| c <= self.cfg.min_credits)
+    }
+
+    /// The experiments that ran throttled.
+    pub fn throttled(&self) -> Vec<String> {
+        self.exps
+            .iter()
+            .filter(|(_, e)| self.is_throttled(e))
+            .map(|(f, _)| f.clone())
+            .collect()
+    }
+
+    /// Forget what we saw of `fnames`, before running them again.
+    pub fn forget(&mut self, fnames: &[String]) {
+        for f in fnames {
+            self.exps.remove(f);
+        }
+    }
+
+    pub fn exps(&self) -> &Throttling {
+        &self.exps
+    }
+
+    pub fn rerun(&self) -> usize {
+        self.cfg.rerun
+    }
+}
+
+/// Cumulative (steal, total) CPU ticks.
+async fn proc_stat(ssh: &Session) -> Result<(u64, u64), Report> {
+    let out = ssh
+        .command("head")
+        .args(["-n1", "/proc/stat"])
+        .output()
+        .await?;
+    ensure!(out.status.success(), "could not read /proc/stat");
+    let line = String::from_utf8_lossy(&out.stdout);
+    // cpu user nice system idle iowait irq softirq steal guest guest_nice; guest time is already
+    // counted in user time.
+    let ticks: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .take(8)
+        .map(|t| t.parse())
+        .collect::<Result<_, _>>()
+        .wrap_err_with(|| format!("parse {:?}", line.trim()))?;
+    ensure!(
+        ticks.len() == 8,
+        "unexpected /proc/stat line {:?}",
+        line.trim()
+    );
+    Ok((ticks[7], ticks.iter().sum()))
+}
+
+async fn credit_mode(
+    cloud: &Cloud,
+    region: &str,
+    profile: Option<&str>,
+    instance_id: &str,
+) -> Result<Option<String>, Report> {
+    let client = ec2_client(region, profile)?;
+    ratelimit::acquire(cloud).await;
+    let resp = client
+        .describe_instance_credit_specifications(
+            rusoto_ec2::DescribeInstanceCreditSpecificationsRequest {
+                instance_ids: Some(vec![instance_id.to_owned()]),
+                ..Default::default()
+            },
+        )
+        .await
+        .wrap_err("describe instance credit specifications")?;
+    Ok(resp
+        .instance_credit_specifications
+        .and_then(|s| s.into_iter().next())
+        .and_then(|s| s.cpu_credits))
+}
+
+/// The lowest CPU credit balance CloudWatch has for the last 10 minutes.
+async fn credit_balance(cloud: &Cloud, instance_id: &str) -> Result<Option<f64>, Report> {
+    let (region, profile) = match cloud {
+        Cloud::Aws { region, profile } => (region, profile),
+        _ => unreachable!(),
+    };
+    let end = chrono::Utc::now();
+    let start = end - chrono::Duration::minutes(10);
+    let mut cmd = tokio::process::Command::new("aws");
+    cmd.args([
+        "cloudwatch",
+        "get-metric-statistics",
+        "--namespace",
+        "AWS/EC2",
+        "--metric-name",
+        "CPUCreditBalance",
+        "--statistics",
+        "Minimum",
+        "--period",
+        "300",
+        "--output",
+        "json",
+        "--region",
+        region,
+    ])
+    .arg("--dimensions")
+    .arg(format!("Name=InstanceId,Value={}", instance_id))
+    .arg("--start-time")
+    .arg(start.to_rfc3339())
+    .arg("--end-time")
+    .arg(end.to_rfc3339());
+    if let Some(p) = profile {
+        cmd.args(["--profile", p]);
+    }
+
+    ratelimit::acquire(cloud).await;
+    let out = cmd.output().await.wrap_err("aws cloudwatch")?;
+    ensure!(
+        out.status.success(),
+        "aws cloudwatch get-metric-statistics failed: {}",
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+    let resp: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    Ok(resp["Datapoints"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|d| d["Minimum"].as_f64())
+        .reduce(f64::min))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_burstable_types() {
+        assert_eq!(non_burstable("t3.micro"), Some("m5.large"));
+        assert_eq!(non_burstable("t2.large"), Some("m5.large"));
+        assert_eq!(non_burstable("t3a.xlarge"), Some("m5a.xlarge"));
+        assert_eq!(non_burstable("t4g.2xlarge"), Some("m6g.2xlarge"));
+        assert_eq!(non_burstable("t3.4xlarge"), None);
+        assert_eq!(non_burstable("m5.large"), None);
+        assert_eq!(non_burstable("t3"), None);
+    }
+}
@@ -0,0 +1,374 @@
+//! Copying files to and from machines: over the session's sftp channel, or with `rsync` over
+//! `ssh`, which compresses, only sends what changed, and keeps partial files to resume from.
+//! That is much faster for big binaries on slow links.
+//!
+//! Either can be held to a bandwidth limit, so collecting results doesn't get in the way of
+//! experiments still running over the same link.
+//!
+//! Every transfer is logged with how fast it went, so slow runs can be told apart into slow
+//! machines and slow links.
+
+use crate::retry::RetryPolicy;
+use crate::ssh::ConnInfo;
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use openssh::Session;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, warn};
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// `rsync` if both ends have it, sftp otherwise.
+    #[default]
+    Auto,
+    Sftp,
+    Rsync,
+}
+
+/// How to move files to and from one machine.
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    conn: ConnInfo,
+    rsync: bool,
+    /// In bytes per second.
+    bwlimit: Option<u64>,
+    retry: RetryPolicy,
+}
+
+/// How much was moved, and how long it took.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Throughput {
+    pub files: usize,
+    pub bytes: u64,
+    pub secs: f64,
+}
+
+impl Throughput {
+    pub fn add(&mut self, other: Throughput) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+        self.secs += other.secs;
+    }
+
+    /// In megabytes (10^6 bytes) per second, if anything took a measurable time.
+    pub fn mb_per_s(&self) -> Option<f64> {
+        Some(self.bytes as f64 / 1e6 / self.secs).filter(|_| self.secs > 0.)
+    }
+}
+
+impl std::iter::Sum for Throughput {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        let mut t = Self::default();
+        for x in iter {
+            t.add(x);
+        }
+        t
+    }
+}
+
+/// Log and measure one file's transfer, which took `start.elapsed()`.
+fn measured(dir: &str, file: &Path, start: Instant) -> Throughput {
+    let t = Throughput {
+        files: 1,
+        // the file's size, rather than what crossed the wire: rsync may have compressed it, or
+        // sent only what changed.
+        bytes: std::fs::metadata(file).map(|m| m.len()).unwrap_or(0),
+        secs: start.elapsed().as_secs_f64(),
+    };
+    info!(
+        direction = dir,
+        ?file,
+        bytes = t.bytes,
+        secs = %format!("{:.2}", t.secs),
+        mb_per_s = ?t.mb_per_s().map(|r| format!("{:.2}", r)),
+        "transferred file"
+    );
+    t
+}
+
+/// What became of a download.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fetched {
+    Got(Throughput),
+    /// There was nothing to fetch.
+    Absent,
+}
+
+pub fn default_retry() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 4,
+        initial_backoff_ms: 1_000,
+        max_backoff_ms: 30_000,
+    }
+}
+
+/// Parse a bandwidth limit, in KiB/s like rsync's, or with a `K`, `M`, or `G` suffix. Returns
+/// bytes per second.
+pub fn parse_bwlimit(s: &str) -> Result<u64, Report> {
+    let (num, unit) = match s.trim().to_ascii_uppercase() {
+        t if t.ends_with('G') => (t.trim_end_matches('G').to_owned(), 1 << 30),
+        t if t.ends_with('M') => (t.trim_end_matches('M').to_owned(), 1 << 20),
+        t => (t.trim_end_matches('K').to_owned(), 1 << 10),
+    };
+    let n: f64 = num
+        .parse()
+        .wrap_err_with(|| format!("{:?} is not a bandwidth", s))?;
+    ensure!(
+        n.is_finite() && n > 0.,
+        "bandwidth limit {:?} must be positive",
+        s
+    );
+    Ok((n * unit as f64) as u64)
+}
+
+const CHUNK: usize = 64 * 1024;
+
+/// Copy `r` to `w`, no faster than `limit` bytes per second.
+async fn copy_paced<R, W>(r: &mut R, w: &mut W, limit: Option<u64>) -> Result<u64, Report>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let limit = match limit {
+        Some(l) => l,
+        None => return Ok(tokio::io::copy(r, w).await?),
+    };
+
+    let start = Instant::now();
+    let mut buf = vec![0; CHUNK];
+    let mut done = 0;
+    loop {
+        let n = r.read(&mut buf).await?;
+        if n == 0 {
+            w.flush().await?;
+            return Ok(done);
+        }
+
+        w.write_all(&buf[..n]).await?;
+        done += n as u64;
+        let due = Duration::from_secs_f64(done as f64 / limit as f64);
+        if let Some(ahead) = due.checked_sub(start.elapsed()) {
+            tokio::time::sleep(ahead).await;
+        }
+    }
+}
+
+async fn has_rsync(ssh: &Session) -> bool {
+    let local = tokio::process::Command::new("rsync")
+        .arg("--version")
+        .output()
+        .await
+        .is_ok_and(|o| o.status.success());
+    local
+        && ssh
+            .shell("command -v rsync")
+            .status()
+            .await
+            .is_ok_and(|s| s.success())
+}
+
+impl Transfer {
+    /// Pick the backend for the machine at `conn`, which `ssh` is a session to.
+    pub async fn new(
+        ssh: &Session,
+        conn: &ConnInfo,
+        backend: Backend,
+        bwlimit: Option<u64>,
+        retry: RetryPolicy,
+    ) -> Result<Self, Report> {
+        let rsync = match backend {
+            Backend::Sftp => false,
+            Backend::Rsync => {
+                ensure!(
+                    has_rsync(ssh).await,
+                    "rsync transfers need rsync installed here and on {}",
+                    conn.host
+                );
+                true
+            }
+            Backend::Auto => has_rsync(ssh).await,
+        };
+        debug!(host = ?conn.host, ?rsync, ?bwlimit, "picked transfer backend");
+        Ok(Self {
+            conn: conn.clone(),
+            rsync,
+            bwlimit,
+            retry,
+        })
+    }
+
+    /// Copy `local` to `remote` (relative to the home directory, unless absolute), retrying
+    /// failed attempts.
+    pub async fn upload(
+        &self,
+        ssh: &Session,
+        local: &Path,
+        remote: &Path,
+    ) -> Result<Throughput, Report> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let start = Instant::now();
+            let err = match self.try_upload(ssh, local, remote).await {
+                Ok(()) => return Ok(measured("up", local, start)),
+                Err(e) => e,
+            };
+            if attempt >= self.retry.max_attempts {
+                return Err(err.wrap_err(eyre!(
+                    "upload {:?} failed after {} attempts",
+                    local,
+                    attempt
+                )));
+            }
+
+            let backoff = self.retry.backoff(attempt);
+            warn!(?local, ?attempt, ?backoff, err = %format!("{:#}", err), "upload failed, retrying");
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    /// Copy `remote` to `local`, retrying failed attempts. A file that isn't there is
+    /// [`Fetched::Absent`], not an error.
+    pub async fn download(
+        &self,
+        ssh: &Session,
+        remote: &str,
+        local: &Path,
+    ) -> Result<Fetched, Report> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let start = Instant::now();
+            let err = match self.try_download(ssh, remote, local).await {
+                Ok(()) => return Ok(Fetched::Got(measured("down", local, start))),
+                Err(e) => e,
+            };
+            if attempt == 1 && is_absent(ssh, remote).await {
+                let _ = tokio::fs::remove_file(local).await;
+                return Ok(Fetched::Absent);
+            }
+
+            if attempt >= self.retry.max_attempts {
+                return Err(err.wrap_err(eyre!(
+                    "download {} failed after {} attempts",
+                    remote,
+                    attempt
+                )));
+            }
+
+            let backoff = self.retry.backoff(attempt);
+            warn!(?remote, ?attempt, ?backoff, err = %format!("{:#}", err), "download failed, retrying");
+            tokio::time::sleep(backoff).await;
+        }
+    }
+
+    async fn try_upload(&self, ssh: &Session, local: &Path, remote: &Path) -> Result<(), Report> {
+        if !self.rsync {
+            if self.bwlimit.is_none() {
+                return crate::write_file(ssh, local, remote).await;
+            }
+
+            let mut sftp = ssh.sftp();
+            let mut w = sftp
+                .write_to(remote)
+                .await
+                .wrap_err("Open remote file for writing")?;
+            let mut f = tokio::fs::File::open(local).await?;
+            copy_paced(&mut f, &mut w, self.bwlimit).await?;
+            w.close().await?;
+            return Ok(());
+        }
+
+        let dst = format!("{}:{}", self.destination(), remote.display());
+        self.rsync(local.as_os_str(), dst.as_ref()).await
+    }
+
+    async fn try_download(&self, ssh: &Session, remote: &str, local: &Path) -> Result<(), Report> {
+        if !self.rsync {
+            let mut sftp = ssh.sftp();
+            let mut f = sftp.read_from(remote).await?;
+            let mut l = tokio::fs::File::create(local).await?;
+            copy_paced(&mut f, &mut l, self.bwlimit).await?;
+            f.close().await?;
+            return Ok(());
+        }
+
+        let src = format!("{}:{}", self.destination(), remote);
+        self.rsync(src.as_ref(), local.as_os_str()).await
+    }
+
+    fn destination(&self) -> String {
+        // rsync takes bracketed IPv6 addresses.
+        if self.conn.host.contains(':') {
+            format!("{}@[{}]", self.conn.user, self.conn.host)
+        } else {
+            format!("{}@{}", self.conn.user, self.conn.host)
+        }
+    }
+
+    /// The `ssh` rsync should connect with: the same port and key as our session, and the
+    /// host's configured options from the ssh config.
+    fn ssh_command(&self) -> String {
+        let mut cmd = format!(
+            "ssh -p {} -o BatchMode=yes -o StrictHostKeyChecking=accept-new",
+            self.conn.port
+        );
+        if let Some(ref k) = self.conn.key_path {
+            cmd.push_str(&format!(" -i '{}'", k.display()));
+        }
+
+        if let Some(k) = self.conn.keepalive {
+            cmd.push_str(&format!(" -o ServerAliveInterval={}", k.as_secs()));
+        }
+
+        cmd
+    }
+
+    async fn rsync(&self, src: &std::ffi::OsStr, dst: &std::ffi::OsStr) -> Result<(), Report> {
+        let mut cmd = tokio::process::Command::new("rsync");
+        cmd.args(["-az", "--partial", "-e"]).arg(self.ssh_command());
+        if let Some(l) = self.bwlimit {
+            cmd.arg(format!("--bwlimit={}K", (l / 1024).max(1)));
+        }
+
+        let out = cmd.arg(src).arg(dst).output().await.wrap_err("run rsync")?;
+        if !out.status.success() {
+            bail!(
+                "rsync {:?} failed ({:?}): {}",
+                src,
+                out.status.code(),
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `remote` certainly doesn't exist, as opposed to us not being able to tell.
+async fn is_absent(ssh: &Session, remote: &str) -> bool {
+    ssh.command("test")
+        .args(["-e", remote])
+        .status()
+        .await
+        .is_ok_and(|s| s.code() == Some(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bwlimits() {
+        assert_eq!(parse_bwlimit("100").unwrap(), 100 * 1024);
+        assert_eq!(parse_bwlimit("100k").unwrap(), 100 * 1024);
+        assert_eq!(parse_bwlimit(" 2M ").unwrap(), 2 << 20);
+        assert_eq!(parse_bwlimit("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_bwlimit("0.5").unwrap(), 512);
+        for bad in ["", "M", "fast", "10MB", "0", "-5M", "inf", "nan"] {
+            assert!(parse_bwlimit(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Launching, tagging, and terminating Linode and Vultr instances through their HTTP APIs.
+//!
+//! tsunami doesn't know about these providers, so we launch the instance ourselves (authorizing a
+//! fresh key for root) and then drive it like a known host. API tokens come from `LINODE_TOKEN`
+//! and `VULTR_API_KEY`. Like tsunami's providers, we find instances by public IP afterwards.
+
+use crate::ratelimit;
+use crate::tags::{merge_opts, Cloud, ProviderOpts, Tags};
+use color_eyre::eyre::{bail, ensure, eyre, Report, WrapErr};
+use serde_json::{json, Value};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, instrument, warn};
+
+/// Ubuntu 20.04, like the other providers.
+const LINODE_IMAGE: &str = "linode/ubuntu20.04";
+const VULTR_OS_ID: u64 = 387;
+
+/// How long a new instance may take to get an address and boot.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(600);
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        401 | 403 => "unauthorized, check the api token's credentials",
+        429 => "too many requests",
+        500 => "internal server error",
+        502 => "bad gateway",
+        503 => "service unavailable",
+        _ => "request failed",
+    }
+}
+
+/// Make a request to `cloud`'s API, and return the response body.
+pub(crate) async fn api(
+    cloud: &Cloud,
+    method: &str,
+    path: &str,
+    body: Option<&Value>,
+) -> Result<Value, Report> {
+    let (base, var) = match cloud {
+        Cloud::Linode => ("https://api.linode.com/v4", "LINODE_TOKEN"),
+        Cloud::Vultr => ("https://api.vultr.com/v2", "VULTR_API_KEY"),
+        _ => bail!("{:?} has no http api", cloud),
+    };
+    let token = std::env::var(var).wrap_err_with(|| format!("{} not set", var))?;
+
+    ratelimit::acquire(cloud).await;
+    let mut cmd = tokio::process::Command::new("curl");
+    // the token goes over stdin, to keep it out of the process list.
+    cmd.args([
+        "-sS",
+        "-X",
+        method,
+        "-H",
+        "@-",
+        "-H",
+        "Content-Type: application/json",
+    ])
+    .args(["-w", "\n%{http_code}"]);
+    if let Some(b) = body {
+        cmd.arg("--data-binary").arg(b.to_string());
+    }
+
+    let mut child = cmd
+        .arg(format!("{}{}", base, path))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err("run curl")?;
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        stdin
+            .write_all(format!("Authorization: Bearer {}\n", token).as_bytes())
+            .await?;
+    }
+    let out = child.wait_with_output().await?;
+    ensure!(
+        out.status.success(),
+        "{} {}: {}",
+        method,
+        path,
+        String::from_utf8_lossy(&out.stderr).trim()
+    );
+
+    let out = String::from_utf8(out.stdout)?;
+    let (resp, code) = out.rsplit_once('\n').unwrap_or(("", &out));
+    let code: u16 = code.trim().parse()?;
+    ensure!(
+        code < 300,
+        "{} {}: {} {}: {}",
+        method,
+        path,
+        code,
+        reason(code),
+        resp
+    );
+    if resp.trim().is_empty() {
+        return Ok(Value::Null);
+    }
+
+    serde_json::from_str(resp).wrap_err_with(|| format!("parse response to {} {}", method, path))
+}
+
+fn with_opts(mut body: Value, opts: &ProviderOpts) -> Value {
+    merge_opts(&mut body, opts);
+    body
+}
+
+/// Tags as the `key=value` strings these providers take.
+fn tag_list(tags: &Tags) -> Vec<String> {
+    crate::tags::with_marker(tags)
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect()
+}
+
+/// Launch an instance of `plan` in `region`, letting `pubkey` log in as root. Returns its public
+/// IP once it has booted.
+#[instrument(skip(pubkey, opts), level = "debug")]
+pub async fn launch(
+    cloud: &Cloud,
+    region: &str,
+    plan: &str,
+    label: &str,
+    pubkey: &str,
+    opts: &ProviderOpts,
+) -> Result<String, Report> {
+    let id = match cloud {
+        Cloud::Linode => {
+            let root_pass = format!("{:x}{:x}", rand_u64(), rand_u64());
+            let resp = api(
+                cloud,
+                "POST",
+                "/linode/instances",
+                Some(&with_opts(
+                    json!({
+                        "region": region,
+                        "type": plan,
+                        "image": LINODE_IMAGE,
+                        "label": label,
+                        "root_pass": root_pass,
+                        "authorized_keys": [pubkey],
+                    }),
+                    opts,
+                )),
+            )
+            .await?;
+            resp["id"].to_string()
+        }
+        Cloud::Vultr => {
+            let key = api(
+                cloud,
+                "POST",
+                "/ssh-keys",
+                Some(&json!({ "name": label, "ssh_key": pubkey })),
+            )
+            .await?;
+            let key_id = key["ssh_key"]["id"]
+                .as_str()
+                .ok_or_else(|| eyre!("unexpected ssh key response {}", key))?
+                .to_owned();
+            let resp = api(
+                cloud,
+                "POST",
+                "/instances",
+                Some(&with_opts(
+                    json!({
+                        "region": region,
+                        "plan": plan,
+                        "os_id": VULTR_OS_ID,
+                        "label": label,
+                        "sshkey_id": [key_id],
+                    }),
+                    opts,
+                )),
+            )
+            .await;
+            // the key is copied into the instance when it is created.
+            if let Err(err) = api(cloud, "DELETE", &format!("/ssh-keys/{}", key_id), None).await {
+                warn!(?err, ?key_id, "could not delete ssh key");
+            }
+            resp?["instance"]["id"]
+                .as_str()
+                .ok_or_else(|| eyre!("unexpected instance response"))?
+                .to_owned()
+        }
+        _ => bail!("{:?} is launched through tsunami", cloud),
+    };
+    info!(?id, "launched instance");
+
+    // the caller only learns of the instance once it is up, so it can't clean up after us.
+    match wait_running(cloud, &id).await {
+        Ok(ip) => Ok(ip),
+        Err(err) => {
+            if let Err(err) = delete(cloud, &id).await {
+                warn!(?err, ?id, "could not delete instance that did not come up");
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Wait for instance `id` to be running, and return its public IP.
+async fn wait_running(cloud: &Cloud, id: &str) -> Result<String, Report> {
+    let start = Instant::now();
+    loop {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let (ip, up) = match cloud {
+            Cloud::Linode => {
+                let inst = api(cloud, "GET", &format!("/linode/instances/{}", id), None).await?;
+                (
+                    inst["ipv4"][0].as_str().unwrap_or_default().to_owned(),
+                    inst["status"] == "running",
+                )
+            }
+            _ => {
+                let inst = api(cloud, "GET", &format!("/instances/{}", id), None).await?;
+                let inst = &inst["instance"];
+                (
+                    inst["main_ip"].as_str().unwrap_or_default().to_owned(),
+                    inst["status"] == "active" && inst["power_status"] == "running",
+                )
+            }
+        };
+        if up && !ip.is_empty() && ip != "0.0.0.0" {
+            info!(?id, ?ip, elapsed = ?start.elapsed(), "instance is running");
+            return Ok(ip);
+        }
+
+        if start.elapsed() > BOOT_TIMEOUT {
+            bail!("instance {} did not start within {:?}", id, BOOT_TIMEOUT);
+        }
+
+        debug!(?id, "instance not running yet");
+    }
+}
+
+/// A random enough root password, which we never use (we log in with the key) but Linode
+/// requires.
+fn rand_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+    h.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    h.finish()
+}
+
+/// The id of `cloud`'s instance with public IP `public_ip`.
+async fn find_instance(cloud: &Cloud, public_ip: &str) -> Result<String, Report> {
+    let (path, list, ip) = match cloud {
+        Cloud::Linode => ("/linode/instances?page_size=500", "data", "/ipv4/0"),
+        _ => ("/instances?per_page=500", "instances", "/main_ip"),
+    };
+    let resp = api(cloud, "GET", path, None).await?;
+    resp[list]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|i| i.pointer(ip).and_then(Value::as_str) == Some(public_ip))
+        .map(|i| match &i["id"] {
+            Value::String(s) => s.clone(),
+            id => id.to_string(),
+        })
+        .ok_or_else(|| eyre!("no instance with ip {}", public_ip))
+}
+
+/// Tag the instance with public IP `public_ip`.
+#[instrument(skip(tags), level = "debug")]
+pub async fn tag(cloud: &Cloud, public_ip: &str, tags: &Tags) -> Result<(), Report> {
+    let id = find_instance(cloud, public_ip).await?;
+    let body = json!({ "tags": tag_list(tags) });
+    match cloud {
+        Cloud::Linode => {
+            api(
+                cloud,
+                "PUT",
+                &format!("/linode/instances/{}", id),
+                Some(&body),
+            )
+            .await?
+        }
+        _ => api(cloud, "PATCH", &format!("/instances/{}", id), Some(&body)).await?,
+    };
+    info!(?id, "tagged instance");
+    Ok(())
+}
+
+/// Delete the instance with public IP `public_ip`.
+#[instrument(level = "debug")]
+pub async fn terminate(cloud: &Cloud, public_ip: &str) -> Result<(), Report> {
+    let id = find_instance(cloud, public_ip).await?;
+    delete(cloud, &id).await
+}
+
+async fn delete(cloud: &Cloud, id: &str) -> Result<(), Report> {
+    let path = match cloud {
+        Cloud::Linode => format!("/linode/instances/{}", id),
+        _ => format!("/instances/{}", id),
+    };
+    api(cloud, "DELETE", &path, None).await?;
+    info!(?id, "terminated instance");
+    Ok(())
+}